@@ -0,0 +1,199 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Runs two pipelines on the same inputs and compares their outputs, for A/B evaluation
+// of preprocessing changes.
+
+
+use std::path::{Path, PathBuf};
+
+use rhai::{Dynamic, Map, Array, FLOAT, INT};
+
+use crate::compute::{CInstance, ImageMetrics};
+use crate::decode;
+use crate::json;
+use crate::{RED, CLEAR};
+
+
+/// Comparison of the outputs of the two pipelines for one input
+struct Comparison {
+    file: PathBuf,
+    /// `None` when the outputs have different dimentions
+    metrics: Option<ImageMetrics>
+}
+
+
+/// Runs the pipelines `a` and `b` on every image of `src` (a file or a directory) and prints
+/// aggregate statistics of the differences between their outputs, also written as json to `report`.
+/// Returns whether the comparison could be done.
+pub fn run_abtest(a: &mut CInstance, b: &mut CInstance, src: &Path, report: Option<&Path>) -> bool {
+    let files = if src.is_dir() {
+        let mut files: Vec<PathBuf> = match std::fs::read_dir(src) {
+            Ok(entries) => entries.filter_map(|f| f.ok())
+                .filter(|f| f.file_type().map(|t| t.is_file()).unwrap_or(false))
+                .map(|f| f.path())
+                .collect(),
+            Err(e) => {
+                eprintln!("{}Could not read files in `{}`: {}{}", RED, src.display(), e, CLEAR);
+                return false;
+            }
+        };
+        files.sort();
+        files
+    } else {
+        vec![src.to_path_buf()]
+    };
+
+    let mut comparisons = Vec::with_capacity(files.len());
+    for file in files {
+        let img = match decode::decode_image(&file, Some(a.max_size())) {
+            Ok(img) => img,
+            Err(e) => {
+                eprintln!("Warning: skipping `{}`: {}", file.display(), e);
+                continue;
+            }
+        };
+        let max_size = a.max_size();
+        let img = if img.width() as usize > max_size.0 || img.height() as usize > max_size.1 {
+            a.resize_to_fit(&img, max_size)
+        } else {
+            img
+        };
+
+        let out_a = a.compute(&img);
+        let out_b = b.compute(&img);
+        let metrics = if out_a.dimensions() == out_b.dimensions() {
+            Some(a.compare(&out_a, &out_b))
+        } else {
+            eprintln!("Warning: the outputs for `{}` have different dimentions ({}x{} and {}x{})",
+                file.display(), out_a.width(), out_a.height(), out_b.width(), out_b.height());
+            None
+        };
+        comparisons.push(Comparison { file, metrics });
+    }
+
+    if comparisons.is_empty() {
+        eprintln!("{}There are no images to compare in `{}`{}", RED, src.display(), CLEAR);
+        return false;
+    }
+
+    print_summary(&comparisons);
+
+    if let Some(report) = report {
+        let json = json::format_map(&report_map(&comparisons));
+        if let Err(e) = std::fs::write(report, json) {
+            eprintln!("{}Could not write `{}`: {}{}", RED, report.display(), e, CLEAR);
+            return false;
+        }
+    }
+    true
+}
+
+
+/// Mean, standard deviation, minimum and maximum of some values
+fn stats(values: &[f64]) -> (f64, f64, f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0, 0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let var = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64;
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    (mean, var.sqrt(), min, max)
+}
+
+
+fn print_summary(comparisons: &[Comparison]) {
+    let metrics: Vec<(&Path, ImageMetrics)> = comparisons.iter()
+        .filter_map(|c| c.metrics.map(|m| (c.file.as_path(), m)))
+        .collect();
+    let identical = metrics.iter().filter(|(_, m)| m.max_diff == 0).count();
+    let mismatched = comparisons.len() - metrics.len();
+
+    println!("Compared {} images: {} identical, {} different, {} with different dimentions",
+        comparisons.len(), identical, metrics.len() - identical, mismatched);
+    if metrics.is_empty() {
+        return;
+    }
+
+    let mse: Vec<f64> = metrics.iter().map(|(_, m)| m.mse).collect();
+    let psnr: Vec<f64> = metrics.iter().map(|(_, m)| m.psnr()).filter(|p| p.is_finite()).collect();
+    let max_diff: Vec<f64> = metrics.iter().map(|(_, m)| m.max_diff as f64).collect();
+
+    println!("{:<10}{:>12}{:>12}{:>12}{:>12}", "", "mean", "std", "min", "max");
+    for (name, values) in [("mse", &mse), ("psnr (dB)", &psnr), ("max diff", &max_diff)] {
+        let (mean, std, min, max) = stats(values);
+        println!("{:<10}{:>12.4}{:>12.4}{:>12.4}{:>12.4}", name, mean, std, min, max);
+    }
+
+    if let Some((file, worst)) = metrics.iter().max_by(|a, b| a.1.mse.total_cmp(&b.1.mse)) {
+        if worst.mse > 0.0 {
+            println!("Largest difference: `{}` (mse {:.4}, psnr {:.2} dB)", file.display(), worst.mse, worst.psnr());
+        }
+    }
+}
+
+
+/// Json report of the comparisons, with the metrics of every image
+fn report_map(comparisons: &[Comparison]) -> Map {
+    let mut images = Array::new();
+    for c in comparisons {
+        let mut image = Map::new();
+        image.insert("file".into(), c.file.display().to_string().into());
+        if let Some(m) = c.metrics {
+            image.insert("mse".into(), (m.mse as FLOAT).into());
+            // identical images have an infinite psnr, which json cannot hold
+            let psnr = m.psnr();
+            image.insert("psnr".into(), if psnr.is_finite() { (psnr as FLOAT).into() } else { Dynamic::UNIT });
+            image.insert("max_diff".into(), (m.max_diff as INT).into());
+        } else {
+            image.insert("dimentions_differ".into(), true.into());
+        }
+        images.push(image.into());
+    }
+
+    let mse: Vec<f64> = comparisons.iter().filter_map(|c| c.metrics.map(|m| m.mse)).collect();
+    let psnr: Vec<f64> = comparisons.iter().filter_map(|c| c.metrics.map(|m| m.psnr())).filter(|p| p.is_finite()).collect();
+    let mut summary = Map::new();
+    for (name, values) in [("mse", &mse), ("psnr", &psnr)] {
+        let (mean, std, min, max) = stats(values);
+        let mut stat = Map::new();
+        stat.insert("mean".into(), (mean as FLOAT).into());
+        stat.insert("std".into(), (std as FLOAT).into());
+        stat.insert("min".into(), (min as FLOAT).into());
+        stat.insert("max".into(), (max as FLOAT).into());
+        summary.insert(name.into(), stat.into());
+    }
+    summary.insert("count".into(), (comparisons.len() as INT).into());
+    summary.insert("identical".into(),
+        (comparisons.iter().filter(|c| c.metrics.map(|m| m.max_diff == 0).unwrap_or(false)).count() as INT).into());
+
+    let mut report = Map::new();
+    report.insert("summary".into(), summary.into());
+    report.insert("images".into(), images.into());
+    report
+}
@@ -0,0 +1,107 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::{RgbImage, Frame, Delay};
+use image::codecs::gif::{GifEncoder, Repeat};
+
+
+/// Container of an animation, chosen from the output file extension
+#[derive(Clone, Copy)]
+pub enum AnimationFormat {
+    Gif,
+    Apng
+}
+
+
+impl AnimationFormat {
+
+
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let ext = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+
+        match ext.as_str() {
+            "gif" => Ok(Self::Gif),
+            "png" | "apng" => Ok(Self::Apng),
+            "webp" => Err(String::from("Animated WebP output is not supported by the image encoder, use gif or apng.")),
+            _ => Err(format!("Unknown animation format `{}` (expected gif or apng).", ext))
+        }
+    }
+}
+
+
+/// Saves the frames as an animation looping forever, each frame being shown `delay_ms` milliseconds
+pub fn save_animation(frames: &[RgbImage], path: &Path, delay_ms: u32) -> Result<(), String> {
+    let format = AnimationFormat::from_path(path)?;
+    let file = File::create(path)
+        .map_err(|e| format!("Could not create `{}`: {}", path.display(), e))?;
+
+    match format {
+        AnimationFormat::Gif => save_gif(frames, file, delay_ms),
+        AnimationFormat::Apng => save_apng(frames, file, delay_ms)
+    }
+}
+
+
+fn save_gif(frames: &[RgbImage], file: File, delay_ms: u32) -> Result<(), String> {
+    use image::buffer::ConvertBuffer;
+
+    let mut encoder = GifEncoder::new(BufWriter::new(file));
+    encoder.set_repeat(Repeat::Infinite).map_err(|e| e.to_string())?;
+
+    let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+    encoder.encode_frames(frames.iter().map(|f| Frame::from_parts(f.convert(), 0, 0, delay)))
+        .map_err(|e| format!("Could not encode gif: {}", e))
+}
+
+
+fn save_apng(frames: &[RgbImage], file: File, delay_ms: u32) -> Result<(), String> {
+    let (width, height) = match frames.first() {
+        Some(f) => f.dimensions(),
+        None => return Err(String::from("No frame to save."))
+    };
+    if frames.iter().any(|f| f.dimensions() != (width, height)) {
+        return Err(String::from("Every frame of an apng must have the same dimentions."));
+    }
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0).map_err(|e| e.to_string())?;
+    encoder.set_frame_delay(delay_ms.min(u16::MAX as u32) as u16, 1000).map_err(|e| e.to_string())?;
+
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    for frame in frames {
+        writer.write_image_data(frame.as_raw())
+            .map_err(|e| format!("Could not encode apng: {}", e))?;
+    }
+    writer.finish().map_err(|e| e.to_string())
+}
@@ -0,0 +1,91 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Times the pipeline on a single image, uploads and downloads included.
+
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use image::{Rgb, RgbImage};
+
+use crate::compute::CInstance;
+use crate::decode;
+use crate::{RED, CLEAR};
+
+
+/// Runs the pipeline `warmup` times, then `iterations` timed times, on the image `src` (a synthetic
+/// image of the maximum dimentions otherwise) and prints the timings.
+/// Returns whether the benchmark could be run.
+pub fn run_bench(compute: &mut CInstance, src: Option<&Path>, iterations: usize, warmup: usize) -> bool {
+    if iterations == 0 {
+        eprintln!("{}Provide at least one iteration.{}", RED, CLEAR);
+        return false;
+    }
+
+    let max_size = compute.max_size();
+    let img = match src {
+        Some(src) => match decode::decode_image(src, Some(max_size)) {
+            Ok(img) if img.width() as usize > max_size.0 || img.height() as usize > max_size.1 => {
+                compute.resize_to_fit(&img, max_size)
+            }
+            Ok(img) => img,
+            Err(e) => {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                return false;
+            }
+        },
+        None => RgbImage::from_fn(max_size.0 as u32, max_size.1 as u32, |x, y| {
+            Rgb([(x * 7 + y * 13) as u8, (x * 3 + y * 5) as u8, (x ^ y) as u8])
+        })
+    };
+    let img = compute.declaration().format.convert_srgb(img);
+
+    for _ in 0..warmup {
+        compute.compute(&img);
+    }
+
+    let mut times: Vec<Duration> = (0..iterations).map(|_| {
+        let start = Instant::now();
+        compute.compute(&img);
+        start.elapsed()
+    }).collect();
+    times.sort();
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mean = times.iter().sum::<Duration>() / iterations as u32;
+    let pixels = (img.width() * img.height()) as f64;
+
+    println!("{}x{} image, {} iterations after {} warm-up runs", img.width(), img.height(), iterations, warmup);
+    println!("  mean:       {:.3} ms", ms(mean));
+    println!("  median:     {:.3} ms", ms(times[iterations / 2]));
+    println!("  min:        {:.3} ms", ms(times[0]));
+    println!("  max:        {:.3} ms", ms(times[iterations - 1]));
+    println!("  throughput: {:.1} images/s, {:.1} megapixels/s",
+        1.0 / mean.as_secs_f64(), pixels / mean.as_secs_f64() / 1e6);
+    true
+}
@@ -0,0 +1,95 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use std::path::Path;
+
+use image::RgbImage;
+
+use qcms::{Profile, Transform, DataType, Intent};
+
+
+/// Color profile the outputs are tagged with
+#[derive(Clone)]
+pub enum OutputProfile {
+    Srgb,
+    /// ICC profile data
+    Icc(Vec<u8>)
+}
+
+
+/// Converts the images with an embedded ICC profile to the working space
+pub struct ColorManagement {
+    working_space: Box<Profile>,
+    /// ICC data of the working space, none for sRGB
+    working_space_icc: Option<Vec<u8>>
+}
+
+
+impl ColorManagement {
+
+
+    /// Uses the ICC profile at `working_space` as the working space, sRGB by default
+    pub fn new(working_space: Option<&Path>) -> Result<Self, String> {
+        let (mut profile, icc) = match working_space {
+            Some(path) => {
+                let icc = std::fs::read(path)
+                    .map_err(|e| format!("Could not read color profile `{}`: {}", path.display(), e))?;
+                let profile = Profile::new_from_slice(&icc, false)
+                    .ok_or_else(|| format!("`{}` is not a valid ICC profile", path.display()))?;
+                (profile, Some(icc))
+            }
+            None => (Profile::new_sRGB(), None)
+        };
+        profile.precache_output_transform();
+
+        Ok(Self {
+            working_space: profile,
+            working_space_icc: icc
+        })
+    }
+
+
+    /// Converts an image from the ICC profile `icc` to the working space
+    pub fn to_working_space(&self, img: &mut RgbImage, icc: &[u8]) -> Result<(), String> {
+        let profile = Profile::new_from_slice(icc, false).ok_or("invalid ICC profile")?;
+        if self.working_space_icc.is_none() && profile.is_sRGB() {
+            return Ok(());
+        }
+
+        let transform = Transform::new(&profile, &self.working_space, DataType::RGB8, Intent::Perceptual)
+            .ok_or("unsupported ICC profile")?;
+        transform.apply(img);
+        Ok(())
+    }
+
+
+    pub fn output_profile(&self) -> OutputProfile {
+        match &self.working_space_icc {
+            Some(icc) => OutputProfile::Icc(icc.clone()),
+            None => OutputProfile::Srgb
+        }
+    }
+}
@@ -23,77 +23,435 @@ SOFTWARE.
 */
 
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
-use std::cell::{RefCell, RefMut, Ref};
+use std::cell::{Cell, RefCell, RefMut, Ref, OnceCell};
+use std::path::{Path, PathBuf, Component};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ocl::{ProQue, Buffer, Program, Platform, Device};
+
+use rhai::{Engine, Dynamic, Scope, AST, Map, Array, INT};
+use rhai::module_resolvers::DummyModuleResolver;
+
+use image::{RgbImage, GrayImage, DynamicImage};
+
+use crate::package::Package;
+use crate::thermal::{ThermalGuard, ThermalLimits};
+use crate::warnings;
+use crate::{RED, GREEN, CLEAR};
+
+
+mod builtins;
+mod random;
+mod declare;
+mod passes;
+mod cv;
+mod depth;
+mod outputs;
+mod initcache;
+mod metrics;
+mod gray;
+mod rgba;
+mod debug;
+mod clock;
+mod math;
+
+pub use random::{sample_indices, sampled};
+pub use declare::{Declaration, SourceKind};
+pub use depth::FloatMapping;
+use passes::Pass;
+
+
+/// Kind of device to run on
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeviceType {
+    Gpu,
+    Cpu,
+    Accelerator,
+    Any
+}
+
+
+/// Devices to build the queue on, in order of preference.
+/// A device given by index on the platform of index `platform` (the default platform otherwise), or as
+/// the first device whose name matches the regex `name` ignoring case, is the only candidate.
+/// Otherwise the devices of `device_type` are candidates, by default the GPUs then the CPUs then the others.
+pub fn device_candidates(platform: Option<usize>, device: Option<usize>, name: Option<&str>,
+        device_type: Option<DeviceType>) -> Result<Vec<(Platform, Device)>, String> {
+    use ocl::flags::{DeviceType as Flags, DEVICE_TYPE_GPU, DEVICE_TYPE_CPU, DEVICE_TYPE_ACCELERATOR};
+
+    // listing the platforms panics when there are none
+    if ocl::core::get_platform_ids().unwrap_or_default().is_empty() {
+        return Err(String::from("No OpenCL platform found (see `imgproc list`)"));
+    }
+
+    let platforms = match platform {
+        Some(i) => {
+            let platforms = Platform::list();
+            vec![*platforms.get(i).ok_or_else(|| format!("There is no platform {} ({} available, see `imgproc list`)",
+                i, platforms.len()))?]
+        }
+        None if name.is_some() || device_type.is_some() => Platform::list(),
+        None => vec![Platform::default()]
+    };
+
+    if let Some(pattern) = name {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("Invalid device name pattern: {}", e))?;
+        let selected = platforms.iter()
+            .flat_map(|&p| Device::list(p, None).unwrap_or_default().into_iter().map(move |d| (p, d)))
+            .find(|(_, d)| d.name().map(|n| regex.is_match(&n)).unwrap_or(false))
+            .ok_or_else(|| format!("No device name matches `{}` (see `imgproc list`)", pattern))?;
+        return Ok(vec![selected]);
+    }
+
+    if device.is_some() || (platform.is_some() && device_type.is_none()) {
+        let platform = platforms[0];
+        let devices = Device::list(platform, None)
+            .map_err(|e| format!("Could not list the devices of the platform: {}", e))?;
+        let i = device.unwrap_or(0);
+        let device = *devices.get(i).ok_or_else(|| format!("There is no device {} on the platform ({} available, see `imgproc list`)",
+            i, devices.len()))?;
+        return Ok(vec![(platform, device)]);
+    }
+
+    let order: Vec<Option<Flags>> = match device_type {
+        None => vec![Some(DEVICE_TYPE_GPU), Some(DEVICE_TYPE_CPU), Some(DEVICE_TYPE_ACCELERATOR)],
+        Some(DeviceType::Gpu) => vec![Some(DEVICE_TYPE_GPU)],
+        Some(DeviceType::Cpu) => vec![Some(DEVICE_TYPE_CPU)],
+        Some(DeviceType::Accelerator) => vec![Some(DEVICE_TYPE_ACCELERATOR)],
+        Some(DeviceType::Any) => vec![None]
+    };
+
+    let platforms = if device_type.is_none() { Platform::list() } else { platforms };
+    let mut candidates = Vec::new();
+    for flags in order {
+        for &p in &platforms {
+            // no device of the type is an error for OpenCL
+            candidates.extend(Device::list(p, flags).unwrap_or_default().into_iter().map(|d| (p, d)));
+        }
+    }
+
+    if candidates.is_empty() && device_type.is_some() {
+        return Err(String::from("No device of this type found (see `imgproc list`)"));
+    }
+    Ok(candidates)
+}
+
+
+/// Settings of a compute instance
+#[derive(Clone, Default)]
+pub struct ComputeOptions {
+    pub verbose: bool,
+    /// Devices to try to build the queue on, in order, the default device when there are none
+    pub devices: Vec<(Platform, Device)>,
+    /// Log every kernel launch with its resolved arguments
+    pub trace_kernels: bool,
+    /// Allocate the dynamic images at the dimentions of the image being processed
+    /// instead of the maximum dimentions, reallocating them when the dimentions change
+    pub realloc_buffers: bool,
+    /// Seed of the random numbers of the pipeline
+    pub seed: u64,
+    /// Images used by the `random_background` built-in
+    pub backgrounds: Vec<PathBuf>,
+    /// Share of the time the device may be busy, in percent, sleeping after each kernel to keep to it
+    pub throttle: Option<u8>,
+    /// Limits of the GPU sensors, the processing being paused while they are exceeded
+    pub thermal: ThermalLimits,
+    /// Copy the inputs to the outputs instead of running the pipeline
+    pub passthrough: Option<Passthrough>,
+    /// Constants given to `init()` and `run()`, by name, their values being parsed by `param_value`
+    pub params: Vec<(String, String)>,
+    /// Mapping of the float outputs to 8-bit values, with the scale of the values before clipping
+    /// (clipping values from 0 to 1 when not set)
+    pub float_mapping: Option<(FloatMapping, f32)>,
+    /// Directory of the buffers cached by `init()`
+    pub init_cache: Option<PathBuf>,
+    /// Print the statements, variable changes and kernel calls of the run of the first image
+    pub debug_script: bool,
+    /// The configuration comes from a package, whose `sandbox` settings are not trusted
+    pub package_config: bool
+}
+
+
+/// Replacement of the pipeline, for checking the I/O path on its own
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum Passthrough {
+    /// Copy the input buffer to the output buffer on the device
+    Copy,
+    /// Run the built-in identity kernel, to also check the kernel launches
+    Identity
+}
+
+
+/// Error metrics between two images
+#[derive(Clone, Copy)]
+pub struct ImageMetrics {
+    /// Mean squared error over all the channels
+    pub mse: f64,
+    /// Largest absolute error of a channel
+    pub max_diff: u8
+}
+
 
-use ocl::{ProQue, Buffer};
+impl ImageMetrics {
 
-use rhai::{Engine, Dynamic, Scope, AST, Map};
 
-use image::RgbImage;
+    /// Peak signal to noise ratio in dB, infinite for identical images
+    pub fn psnr(&self) -> f64 {
+        10.0 * (255.0 * 255.0 / self.mse).log10()
+    }
+}
 
 
 pub struct CInstance {
     rhai_eng: Engine,
     rhai_ast: AST,
-    scope: CScope
+    /// set up by `scope()` when the device is first needed
+    scope: OnceCell<CScope>,
+    setup: RefCell<Option<DeviceSetup>>,
+    max_size: (usize, usize),
+    /// configuration given to the pipeline, before the class overrides
+    config: Map,
+    /// class of the images being processed, when the source is split in class folders
+    class: Option<String>,
+    /// pauses the processing while the GPU is over its limits
+    thermal: Option<ThermalGuard>,
+    /// inputs and outputs declared by the pipeline
+    declaration: Declaration,
+    /// copies the inputs to the outputs instead of running the pipeline
+    passthrough: Option<Passthrough>,
+    /// constants given to the pipeline on the command line
+    params: Vec<(String, String)>,
+    /// whether the pipeline names its outputs with `out_name(IMG_NAME, record)`
+    names_outputs: bool,
+    /// kernels run in place of a rhai script
+    passes: Option<Vec<Pass>>,
+    /// time spent in each stage of the runs, when measured
+    timings: Option<StageTimings>,
+    /// images registered by `add_output` in the last run, for each of its images in order
+    named_outputs: VecDeque<Vec<(String, DynamicImage)>>,
+    /// outputs of the last run saved in another pixel format than 8-bit rgb, for each of its images in order
+    native_outputs: VecDeque<Option<DynamicImage>>,
+    /// metrics emitted by the last run, for each of its images in order
+    metrics: VecDeque<Map>,
+    /// hash of the program, pipeline and configuration
+    version: String,
+    /// whether the next run is printed by the script debugger, set until the first run with `--debug-script`
+    debug_run: Option<Rc<Cell<bool>>>,
+    /// transparency of the next input, given to the rgba pipelines in `input_rgba`
+    input_alpha: Option<GrayImage>,
+    /// package the pipeline is read from, whose files are read until the device is set up
+    package: Option<Package>
 }
 
 
-impl CInstance {
+/// Time spent uploading the inputs, running the pipeline and downloading the outputs
+#[derive(Clone, Copy, Default)]
+pub struct StageTimings {
+    pub upload: Duration,
+    pub compute: Duration,
+    pub download: Duration,
+    /// number of runs of the pipeline
+    pub runs: usize
+}
 
 
-    pub fn init(verbose: bool, ocl_prog: String, pipeline: String, 
-            pipeline_config: String, size: (usize, usize)) -> Self 
-    {
-        if verbose {
-            println!("* Initializing compute environment");
-            println!("** Reading opencl source");
+/// Reads the source of an opencl program
+fn read_program(ocl_prog: &str) -> Result<String, String> {
+    std::fs::read_to_string(ocl_prog).map_err(|e| format!("Could not read file {}: {}", ocl_prog, e))
+}
+
+
+/// Builds an opencl program and the built-in kernels on the first of `devices` that accepts it
+fn build_program(ocl_src: String, size: (usize, usize), devices: &[(Platform, Device)], verbose: bool) -> Result<(ProQue, Program), String> {
+    let prog_queue = if devices.is_empty() {
+        ProQue::builder().src(ocl_src).dims(size).build().map_err(|e| format!("Could not create the OpenCL queue: {}", e))?
+    } else {
+        let mut last_error = None;
+        let mut built = None;
+        for &(platform, device) in devices {
+            match ProQue::builder().src(ocl_src.clone()).dims(size).platform(platform).device(device).build() {
+                Ok(queue) => {
+                    built = Some(queue);
+                    break;
+                }
+                Err(e) => {
+                    if verbose {
+                        println!("** Could not use device `{}`, falling back to the next one: {}",
+                            device.name().unwrap_or_default(), e);
+                    }
+                    last_error = Some(e);
+                }
+            }
         }
+        built.ok_or_else(|| format!("Could not create the OpenCL queue: {}", last_error.unwrap()))?
+    };
+
+    if verbose {
+        println!("** Using device `{}`", prog_queue.device().name().unwrap_or_default());
+    }
+
+    let builtins = Program::builder()
+        .src(builtins::BUILTINS_SRC)
+        .devices(prog_queue.device())
+        .build(prog_queue.context())
+        .map_err(|e| format!("Could not build the built-in kernels: {}", e))?;
+
+    Ok((prog_queue, builtins))
+}
+
+
+/// Failure of the setup of the device
+#[derive(Debug)]
+pub enum InitError {
+    /// The opencl program could not be built on any device
+    Device(String),
+    /// The `init()` of the pipeline failed
+    Pipeline(String)
+}
 
-        let mut ocl_src = String::new();
-        {
-            use std::io::{BufReader, Read};
-            use std::fs::File;
 
-            let mut f = BufReader::new(File::open(&ocl_prog).expect(
-                format!("Could not read file {}", ocl_prog).as_str()
-            ));
-            f.read_to_string(&mut ocl_src).unwrap();
+impl std::fmt::Display for InitError {
+
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Device(e) | Self::Pipeline(e) => f.write_str(e)
         }
+    }
+}
+
+
+/// Device side of a compute instance, waiting for its program to be built
+struct DeviceSetup {
+    build: JoinHandle<Result<(ProQue, Program), String>>,
+    opts: ComputeOptions,
+    size: (usize, usize),
+    asset_dir: PathBuf,
+    init_cache: Option<initcache::InitCache>,
+    sandbox: Sandbox
+}
+
+
+impl DeviceSetup {
+
+
+    /// Creates the io buffers and runs `init()` of the pipeline once the program is built
+    fn finish(self, rhai_ast: &AST, pipeline_config: &Map, declaration: &Declaration, passes: Option<&[Pass]>) -> Result<CScope, InitError> {
+        let Self { build, opts, size, asset_dir, init_cache, sandbox } = self;
+        let verbose = opts.verbose;
+
+        let (prog_queue, builtins) = build.join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            .map_err(InitError::Device)?;
+        let mut cscope = CScope::init(HashMap::new(), prog_queue, builtins);
+
 
         if verbose {
-            println!("** Creating queue");
+            println!("** Creating io buffers");
         }
 
-        let prog_queue = ProQue::builder()
-            .src(ocl_src)
-            .dims(size)
-            .build()
-            .expect("Could not create the OpenCL queue.");
+        cscope.realloc_buffers = opts.realloc_buffers;
+        cscope.rng.set(opts.seed);
+        cscope.backgrounds = Rc::new(opts.backgrounds.clone());
+        cscope.throttle = opts.throttle;
+        cscope.float_mapping = opts.float_mapping.unwrap_or((FloatMapping::Clip, 255.0));
+        cscope.dynimg_alloc = if opts.realloc_buffers { (1, 1) } else { size };
+        cscope.dynimg_size = size;
+        cscope.create_dynimage("input".into());
+        cscope.create_dynimage("output".into());
+        if declaration.format.channels == 4 {
+            cscope.create_rgba_image("input_rgba".into());
+        }
+        cscope.config = pipeline_config.clone();
+        cscope.trace_kernels = opts.trace_kernels;
+        cscope.asset_dir = asset_dir;
+        cscope.allow_debug_writes = sandbox.allow_debug_writes;
+        cscope.init_cache = init_cache.map(Rc::new);
+
+
+        if let Some(passes) = passes {
+            cscope.setup_passes(passes);
+        } else { // script initialization
+            if verbose {
+                println!("** Running initializing code");
+            }
+
+            let mut init_eng = Engine::new();
+            let mut init_scope = Scope::new();
+
+            apply_sandbox(&mut init_eng, &sandbox);
+            route_messages(&mut init_eng);
+
+            init_eng.register_type_with_name::<CScope>("Ocl")
+                .register_fn("create_int_buffer", CScope::create_int_buffer)
+                .register_fn("create_float_buffer", CScope::create_float_buffer)
+                .register_fn("create_int_buffer_of_size", CScope::create_int_buffer_of_size)
+                .register_fn("create_float_buffer_of_size", CScope::create_float_buffer_of_size)
+                .register_fn("create_dynimage", CScope::create_dynimage)
+                .register_fn("create_gray_image", CScope::create_gray_image)
+                .register_fn("create_rgba_image", CScope::create_rgba_image)
+                .register_fn("set_layout", CScope::set_layout)
+                .register_fn("load_image_asset", CScope::load_image_asset)
+                .register_fn("load_image_asset", CScope::load_named_image_asset)
+                .register_fn("load_csv", CScope::load_csv);
+            random::register(&mut init_eng);
+            clock::register(&mut init_eng);
+            math::register(&mut init_eng);
+            initcache::register(&mut init_eng);
+
+            push_params(&mut init_scope, &opts.params);
+            init_scope.push("ocl", cscope.clone())
+                .push("config", pipeline_config.clone())
+                .push_constant("IMG_WIDTH", size.0 as i32)
+                .push_constant("IMG_HEIGHT", size.1 as i32);
+
+            let _result: () = init_eng.call_fn(&mut init_scope, rhai_ast, "init", ())
+                .map_err(|e| InitError::Pipeline(format!("The initialization of the pipeline failed: {}", e)))?;
+        }
+
+        if let Some(name) = declaration.buffers.iter().find(|b| !cscope.get_buffers().contains_key(b.as_str())) {
+            return Err(InitError::Pipeline(format!("The buffer `{}` declared by the pipeline was not created by init()", name)));
+        }
 
 
         if verbose {
-            println!("** Creating io buffers");
+            println!("Finished initialization.");
         }
+        Ok(cscope)
+    }
+}
 
-        let mut buffers = HashMap::new();
 
+impl CInstance {
 
-        buffers.insert("input".into(), Buff::DynImage(Buffer::<u8>::builder()
-            .queue(prog_queue.queue().clone())
-            .len(size.0 * size.1 * 3)
-            .build()
-            .expect("Could not allocate buffer")));
 
+    /// Compiles the pipeline, building the opencl program in the background.
+    /// Returns an error when the program cannot be read or the pipeline compiled.
+    pub fn init(opts: &ComputeOptions, ocl_prog: String, pipeline: Option<String>, 
+            pipeline_config: String, size: (usize, usize)) -> Result<Self, String> 
+    {
+        let verbose = opts.verbose;
+
+        if verbose {
+            println!("* Initializing compute environment");
+            println!("** Reading opencl source");
+            println!("** Building the opencl program in the background");
+        }
+
+        // the device is only waited for when the first image needs it, the program
+        // building meanwhile so that it overlaps the compilation of the pipeline
+        let ocl_src = read_program(&ocl_prog)?;
+        let version = pipeline_version(&ocl_src, pipeline.as_deref(), &pipeline_config);
+        let build = {
+            let devices = opts.devices.clone();
+            std::thread::spawn(move || build_program(ocl_src, size, &devices, verbose))
+        };
 
-        buffers.insert("output".into(), Buff::DynImage(Buffer::<u8>::builder()
-            .queue(prog_queue.queue().clone())
-            .len(size.0 * size.1 * 3)
-            .build()
-            .expect("Could not allocate buffer")));
-        
 
         if verbose {
             println!("* Initializing pipeline");
@@ -101,16 +459,48 @@ impl CInstance {
         }
 
 
+        // the scripts measure their time from the creation of the first instance
+        clock::run_start_ms();
         let mut rhai_eng = Engine::new();
 
         rhai_eng.set_max_expr_depths(64, 64);
 
-        let pipeline_config = rhai_eng.parse_json(pipeline_config, true).expect("Invalid pipeline configuration");
-        let mut cscope = CScope::init(buffers, pipeline_config.clone(), prog_queue);
-        cscope.set_image_size(size);
+        let mut pipeline_config = rhai_eng.parse_json(pipeline_config, true)
+            .map_err(|e| format!("Invalid pipeline configuration: {}", e))?;
+        crate::expand::expand_map(&mut pipeline_config)
+            .map_err(|e| format!("Invalid pipeline configuration: {}", e))?;
+        let init_cache = opts.init_cache.as_deref()
+            .map(|dir| initcache::InitCache::new(dir, &version, &pipeline_config, &opts.params, size));
+        let sandbox = if opts.package_config {
+            if pipeline_config.contains_key("sandbox") {
+                eprintln!("Warning: the `sandbox` of the package configuration is ignored, give it with --config");
+            }
+            Sandbox::default()
+        } else {
+            Sandbox::from_config(&pipeline_config).map_err(|e| format!("Invalid pipeline configuration: {}", e))?
+        };
+        apply_sandbox(&mut rhai_eng, &sandbox);
+        route_messages(&mut rhai_eng);
+        let asset_dir = Path::new(pipeline.as_ref().unwrap_or(&ocl_prog)).parent().map(Path::to_path_buf).unwrap_or_default();
 
         rhai_eng.register_type_with_name::<CScope>("Ocl")
-            .register_fn("call_kernel", CScope::call_kernel);
+            .register_fn("call_kernel", CScope::call_kernel)
+            .register_fn("set_output_size", CScope::set_output_size);
+        builtins::register(&mut rhai_eng);
+        random::register(&mut rhai_eng);
+        clock::register(&mut rhai_eng);
+        math::register(&mut rhai_eng);
+        cv::register(&mut rhai_eng);
+        depth::register(&mut rhai_eng);
+        outputs::register(&mut rhai_eng);
+        metrics::register(&mut rhai_eng);
+        gray::register(&mut rhai_eng);
+        rgba::register(&mut rhai_eng);
+        let debug_run = opts.debug_script.then(|| Rc::new(Cell::new(true)));
+        if let Some(active) = &debug_run {
+            let source = pipeline.as_ref().and_then(|p| std::fs::read_to_string(p).ok());
+            debug::register(&mut rhai_eng, source, active.clone());
+        }
 
         rhai_eng.register_type_with_name::<BufferRhaiRef>("Buffer")
             .register_fn("len", BufferRhaiRef::len);
@@ -123,57 +513,702 @@ impl CInstance {
             println!("** Compiling rhai code");
         }
 
-        let rhai_ast = rhai_eng.compile_file(pipeline.into()).unwrap();
+        // without a script, the pipeline is the list of passes of the configuration
+        let (rhai_ast, passes) = match pipeline {
+            Some(pipeline) => {
+                let ast = rhai_eng.compile_file(pipeline.clone().into())
+                    .map_err(|e| format!("Could not compile `{}`: {}", pipeline, e))?;
+                (ast, None)
+            }
+            None => match passes::parse(&pipeline_config).map_err(|e| format!("Invalid pipeline configuration: {}", e))? {
+                Some(passes) => (AST::empty(), Some(passes)),
+                None => return Err(String::from("Provide a rhai pipeline, or `passes` in the configuration."))
+            }
+        };
 
+        let declaration = if rhai_ast.iter_functions().any(|f| f.name == "declare" && f.params.is_empty()) {
+            let mut declare_eng = Engine::new();
+            apply_sandbox(&mut declare_eng, &sandbox);
+            route_messages(&mut declare_eng);
+            let declared: Map = declare_eng.call_fn(&mut Scope::new(), &rhai_ast, "declare", ())
+                .map_err(|e| format!("Invalid pipeline declaration: {}", e))?;
+            Declaration::parse(&declared).map_err(|e| format!("Invalid pipeline declaration: {}", e))?
+        } else {
+            Declaration::default()
+        };
+        if let Some(key) = declaration.config.iter().find(|k| !pipeline_config.contains_key(k.as_str())) {
+            return Err(format!("The pipeline configuration has no `{}`, required by the pipeline", key));
+        }
 
-        if verbose {
-            println!("** Running initializing code");
+
+        let names_outputs = rhai_ast.iter_functions().any(|f| f.name == "out_name" && f.params.len() == 2);
+
+        Ok(Self {
+            rhai_eng: rhai_eng,
+            rhai_ast: rhai_ast,
+            scope: OnceCell::new(),
+            setup: RefCell::new(Some(DeviceSetup {
+                build,
+                opts: opts.clone(),
+                size,
+                asset_dir,
+                init_cache,
+                sandbox
+            })),
+            max_size: size,
+            config: pipeline_config,
+            class: None,
+            thermal: if opts.thermal.max_temp.is_some() || opts.thermal.max_power.is_some() {
+                ThermalGuard::new(opts.thermal)
+            } else {
+                None
+            },
+            declaration,
+            passthrough: opts.passthrough,
+            params: opts.params.clone(),
+            names_outputs,
+            passes,
+            timings: None,
+            named_outputs: VecDeque::new(),
+            native_outputs: VecDeque::new(),
+            metrics: VecDeque::new(),
+            version,
+            debug_run,
+            input_alpha: None,
+            package: None
+        })
+    }
+
+
+    /// The scope of the device, set up the first time it is needed
+    fn scope(&self) -> &CScope {
+        self.try_scope().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+
+    fn try_scope(&self) -> Result<&CScope, InitError> {
+        if let Some(scope) = self.scope.get() {
+            return Ok(scope);
         }
 
-        { // script initialization
-            let mut init_eng = Engine::new();
-            let mut init_scope = Scope::new();
+        let setup = self.setup.borrow_mut().take()
+            .ok_or_else(|| InitError::Device(String::from("The device setup failed previously")))?;
+        let scope = setup.finish(&self.rhai_ast, &self.config, &self.declaration, self.passes.as_deref())?;
+        Ok(self.scope.get_or_init(|| scope))
+    }
 
-            init_eng.register_type_with_name::<CScope>("Ocl")
-                .register_fn("create_int_buffer", CScope::create_int_buffer)
-                .register_fn("create_float_buffer", CScope::create_float_buffer)
-                .register_fn("create_int_buffer_of_size", CScope::create_int_buffer_of_size)
-                .register_fn("create_float_buffer_of_size", CScope::create_float_buffer_of_size)
-                .register_fn("create_dynimage", CScope::create_dynimage);
 
-            init_scope.push("ocl", cscope.clone())
-                .push("config", pipeline_config)
-                .push_constant("IMG_WIDTH", size.0 as i32)
-                .push_constant("IMG_HEIGHT", size.1 as i32);
+    fn scope_mut(&mut self) -> &mut CScope {
+        self.scope();
+        self.scope.get_mut().unwrap()
+    }
+
+
+    /// Sets up the device now instead of when the first image is processed,
+    /// running the `init()` of the pipeline
+    pub fn init_device(&self) -> Result<(), InitError> {
+        self.try_scope().map(|_| ())
+    }
+
+
+    /// Images registered by `add_output` for the next output of the last run, by name
+    pub fn take_named_outputs(&mut self) -> Vec<(String, DynamicImage)> {
+        self.named_outputs.pop_front().unwrap_or_default()
+    }
+
+
+    /// The next output of the last run in the pixel format it is saved in, when it is not 8-bit rgb
+    /// (such as a gray output). The output returned by the run then holds its pixels converted to rgb.
+    pub fn take_native_output(&mut self) -> Option<DynamicImage> {
+        self.native_outputs.pop_front().flatten()
+    }
+
+
+    /// Metrics emitted by `emit_metric` for the next output of the last run, empty when there are none
+    pub fn take_metrics(&mut self) -> Map {
+        self.metrics.pop_front().unwrap_or_default()
+    }
+
+
+    /// Measures the time spent in each stage of the next runs, waiting for the device between them
+    pub fn time_stages(&mut self) {
+        self.timings = Some(StageTimings::default());
+    }
+
+
+    /// Time spent in each stage of the runs since `time_stages` was called
+    pub fn stage_timings(&self) -> Option<StageTimings> {
+        self.timings
+    }
+
+
+    /// Hash of the opencl program, the rhai pipeline and its configuration, identifying their version
+    pub fn pipeline_version(&self) -> &str {
+        &self.version
+    }
+
+
+    /// Name and driver version of the device, setting it up
+    pub fn device_identity(&self) -> String {
+        use ocl::enums::DeviceInfo;
+
+        let device = self.scope().prog_queue.device();
+        format!("{} (driver {})", device.name().unwrap_or_default(),
+            device.info(DeviceInfo::DriverVersion).map(|v| v.to_string()).unwrap_or_default())
+    }
+
+
+    /// The inputs and outputs declared by the pipeline
+    pub fn declaration(&self) -> &Declaration {
+        &self.declaration
+    }
+
+
+    /// The maximum dimentions of the images the pipeline can process
+    pub fn max_size(&self) -> (usize, usize) {
+        self.max_size
+    }
+
+
+    /// Absolute difference between two images of the same dimentions, computed on the device
+    /// and normalized so that the largest difference is white
+    pub fn diff(&self, a: &RgbImage, b: &RgbImage) -> RgbImage {
+        self.scope().diff(a, b)
+    }
+
+
+    /// Resets the random generator of the pipeline to `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.scope().rng.set(seed);
+    }
+
+
+    /// Keeps the package the pipeline is read from, the `init()` of the script reading its assets
+    /// when the first image is processed
+    pub fn keep_package(&mut self, package: Package) {
+        self.package = Some(package);
+    }
+
+
+    /// Sets whether the next runs leave out the files the pipeline writes (`save_image`, `export_npy`)
+    /// and the trace of `--debug-script`, for the runs whose outputs are not kept
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.scope().dry_run.set(dry_run);
+    }
+
+
+    /// Error metrics between two images of the same dimentions, computed on the device
+    pub fn compare(&self, a: &RgbImage, b: &RgbImage) -> ImageMetrics {
+        self.scope().compare(a, b)
+    }
 
-            let _result: () = init_eng.call_fn(&mut init_scope, &rhai_ast, "init", ()).unwrap();
+
+    /// Downscales an image on the device, keeping its aspect ratio, so that it fits in `bounds`
+    pub fn resize_to_fit(&self, img: &RgbImage, bounds: (usize, usize)) -> RgbImage {
+        let size = crate::decode::fit_size((img.width() as usize, img.height() as usize), bounds);
+        self.scope().resize_image(img, size)
+    }
+
+
+    /// Sets the class of the next processed images.
+    /// The `config` seen by `run()` is the pipeline configuration with the entries of
+    /// `config.classes[class]` replacing the top level ones.
+    pub fn set_class(&mut self, class: Option<&str>) {
+        let mut config = self.config.clone();
+
+        if let Some(class) = class {
+            let overrides = self.config.get("classes")
+                .and_then(|classes| classes.read_lock::<Map>())
+                .and_then(|classes| classes.get(class).cloned())
+                .and_then(|overrides| overrides.try_cast::<Map>());
+            if let Some(overrides) = overrides {
+                config.extend(overrides);
+            }
         }
 
+        self.scope_mut().config = config;
+        self.class = class.map(String::from);
+    }
 
-        if verbose {
-            println!("Finished initialization.");
+
+    /// Sets the transparency of the next input, given with it in `input_rgba` to the pipelines
+    /// declaring an rgba format. The input is opaque without it.
+    pub fn set_input_alpha(&mut self, alpha: Option<GrayImage>) {
+        self.input_alpha = alpha;
+    }
+
+
+    pub fn compute(&mut self, img: &RgbImage) -> RgbImage {
+        self.scope_mut().set_batch(&[0, img.width() as i32, img.height() as i32]);
+        self.run_pipeline(img, 1).0
+    }
+
+
+    /// Processes several images in a single run of the pipeline.
+    /// The images are stacked vertically in the input buffer, the `batch` buffer holding
+    /// the `(y offset, width, height)` of each of them so kernels can stay within an image.
+    /// The images must fit in the maximum dimentions once stacked.
+    /// Each output comes with the record of its mix with another image of the batch, if any.
+    pub fn compute_batch(&mut self, imgs: &[RgbImage]) -> Vec<(RgbImage, Option<Map>)> {
+        use image::imageops;
+
+        let width = imgs.iter().map(|img| img.width()).max().unwrap_or(0);
+        let height = imgs.iter().map(|img| img.height()).sum();
+
+        let mut packed = RgbImage::new(width, height);
+        let mut offsets = Vec::with_capacity(imgs.len() * 3);
+        let mut y = 0;
+        for img in imgs {
+            imageops::replace(&mut packed, img, 0, y as i64);
+            offsets.extend([y as i32, img.width() as i32, img.height() as i32]);
+            y += img.height();
         }
-        Self {
-            rhai_eng: rhai_eng,
-            rhai_ast: rhai_ast,
-            scope: cscope
+
+        self.scope_mut().set_batch(&offsets);
+        let (out, _) = self.run_pipeline(&packed, imgs.len() as i32);
+        if self.scope().output_size.get().is_some() {
+            panic!("The pipelines setting the size of their output cannot process batches");
+        }
+
+        // the named and native outputs of the batch are cut like the output
+        let named = self.named_outputs.pop_front().unwrap_or_default();
+        let native = self.native_outputs.pop_front().flatten();
+        for o in offsets.chunks(3) {
+            self.named_outputs.push_back(named.iter().map(|(name, img)| {
+                if (img.width(), img.height()) != out.dimensions() {
+                    panic!("The output `{}` of a batch should have the dimentions of the output", name);
+                }
+                (name.clone(), img.crop_imm(0, o[0] as u32, o[1] as u32, o[2] as u32))
+            }).collect());
+            self.native_outputs.push_back(native.as_ref().map(|img| img.crop_imm(0, o[0] as u32, o[1] as u32, o[2] as u32)));
         }
+
+        let records = self.scope().mix_records.take();
+        offsets.chunks(3)
+            .map(|o| imageops::crop_imm(&out, 0, o[0] as u32, o[1] as u32, o[2] as u32).to_image())
+            .zip(records)
+            .collect()
     }
 
 
-    pub fn compute(&mut self, img: &RgbImage) -> RgbImage {
-        self.scope.set_image_size((img.width() as usize, img.height() as usize));
-        self.scope.set_input(img);
-        let mut scope = self.scope.create_rhai_scope();
-        scope.push("ocl", self.scope.clone());
+    /// Generates the `index`-th image of a synthetic dataset, calling `run()` on a black input
+    /// of the maximum dimentions. The random generator is seeded from `seed` and `index`.
+    /// The map returned by `run()`, if any, is the label record of the image.
+    pub fn generate(&mut self, seed: u64, index: u64) -> (RgbImage, Option<Map>) {
+        self.scope().rng.set(random::seed_for(seed, index));
+        let (w, h) = self.max_size;
+        self.scope_mut().set_batch(&[0, w as i32, h as i32]);
+
+        let blank = RgbImage::new(self.max_size.0 as u32, self.max_size.1 as u32);
+        let (img, result) = self.run_pipeline(&blank, 1);
+        (img, result.try_cast::<Map>())
+    }
+
+
+    /// Output file of the `index`-th output of the run, named by the `out_name(IMG_NAME, record)`
+    /// function of the pipeline when it has one. `IMG_NAME` is the default file name and the record
+    /// holds the `class`, `width`, `height`, `index` and the `hash` (sha256) of the pixels of the output.
+    /// The returned name replaces the default one, in the same directory, and may hold subdirectories.
+    /// Without an extension, the extension of the default name is kept.
+    pub fn out_name(&self, out_file: &Path, output: &RgbImage, index: usize) -> Result<PathBuf, String> {
+        use sha2::{Sha256, Digest};
+
+        let default_name = match out_file.file_name() {
+            Some(name) if self.names_outputs => name.to_string_lossy().to_string(),
+            _ => return Ok(out_file.to_path_buf())
+        };
+
+        let mut record = Map::new();
+        record.insert("class".into(), self.class.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT));
+        record.insert("width".into(), (output.width() as rhai::INT).into());
+        record.insert("height".into(), (output.height() as rhai::INT).into());
+        record.insert("index".into(), (index as rhai::INT).into());
+        record.insert("hash".into(), format!("{:x}", Sha256::digest(output.as_raw())).into());
+
+        let name: String = self.rhai_eng.call_fn(&mut Scope::new(), &self.rhai_ast, "out_name", (default_name, record))
+            .map_err(|e| format!("out_name() failed for `{}`: {}", out_file.display(), e))?;
+
+        let name = Path::new(&name);
+        let valid = !name.as_os_str().is_empty()
+            && name.components().all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !valid {
+            return Err(format!("out_name() returned `{}` for `{}`, expected a relative path without `..`",
+                name.display(), out_file.display()));
+        }
+
+        let mut file = out_file.with_file_name(name);
+        if file.extension().is_none() {
+            if let Some(ext) = out_file.extension() {
+                file.set_extension(ext);
+            }
+        }
+        Ok(file)
+    }
+
+
+    /// Runs the pipeline on an image, returning the output and the value returned by `run()`
+    fn run_pipeline(&mut self, img: &RgbImage, batch_size: i32) -> (RgbImage, Dynamic) {
+        if let Some(thermal) = &mut self.thermal {
+            thermal.wait();
+        }
+
+        let timed = self.timings.is_some();
+        // the queue is finished after each stage to time it alone
+        let finish = |cscope: &CScope, start: Instant| {
+            if timed {
+                cscope.prog_queue.finish().expect("Could not wait for the device");
+            }
+            start.elapsed()
+        };
+
+        let start = Instant::now();
+        // the output size, float and named outputs are set again by each run
+        self.scope().output_size.set(None);
+        self.scope().float_output.replace(None);
+        self.scope().named_outputs.replace(Vec::new());
+        self.scope().gray_output.replace(None);
+        self.scope().rgba_output.replace(None);
+        self.scope().deep_output.replace(None);
+        self.scope().metrics.replace(vec![Map::new(); batch_size.max(1) as usize]);
+        self.scope_mut().set_image_size((img.width() as usize, img.height() as usize));
+        let format = self.declaration.format;
+        let channels = if format.native_gray && self.passthrough.is_none() && (format.channels == 1 || gray::is_gray(img)) { 1 } else { 3 };
+        self.scope().input_channels.set(channels);
+        self.scope_mut().set_input(img);
+        let alpha = self.input_alpha.take();
+        if self.scope().get_buffers().contains_key("input_rgba") {
+            self.scope().set_rgba_input(img, alpha.as_ref());
+        }
+        // the kernels are traced along with the script, for this run only
+        let debugging = self.debug_run.as_ref().map(|d| d.get()).unwrap_or(false) && !self.scope().dry_run.get();
+        let trace_kernels = self.scope().trace_kernels;
+        if debugging {
+            println!("{}Debugging the run of a {}x{} image{}", GREEN, img.width(), img.height(), CLEAR);
+            self.scope_mut().trace_kernels = true;
+        }
+        let cscope = self.scope();
+        let upload = finish(cscope, start);
+        let mut scope = cscope.create_rhai_scope();
+        // before the built-in constants, which shadow them
+        push_params(&mut scope, &self.params);
+        scope.push("ocl", cscope.clone());
         scope.push_constant("IMG_WIDTH", img.width()  as i32)
-            .push_constant("IMG_HEIGTH", img.height() as i32);
+            .push_constant("IMG_HEIGTH", img.height() as i32)
+            .push_constant("IMG_CHANNELS", channels as i32)
+            .push_constant("BATCH_SIZE", batch_size)
+            .push_constant("CLASS", self.class.clone().unwrap_or_default())
+            .push_constant("SEED", (cscope.next_random() >> 33) as i32);
+
+        let start = Instant::now();
+        let result: Dynamic = match (self.passthrough, &self.passes) {
+            (Some(mode), _) => {
+                cscope.passthrough(mode);
+                Dynamic::UNIT
+            }
+            (None, Some(passes)) => {
+                cscope.run_passes(passes);
+                Dynamic::UNIT
+            }
+            (None, None) => self.rhai_eng.call_fn(&mut scope, &self.rhai_ast, "run", ()).unwrap()
+        };
+        let compute = finish(cscope, start);
+
+        let start = Instant::now();
+        let native = cscope.read_gray_output().map(DynamicImage::ImageLuma8)
+            .or_else(|| cscope.read_rgba_output().map(DynamicImage::ImageRgba8));
+        let (native, output) = match (native, cscope.read_deep_output()) {
+            (Some(native), _) => {
+                let output = native.to_rgb8();
+                (Some(native), output)
+            }
+            (None, Some((deep, preview))) => (Some(deep), preview),
+            (None, None) => (None, cscope.get_output())
+        };
+        let named = cscope.read_named_outputs();
+        let metrics = cscope.take_metrics();
+        let download = start.elapsed();
+        self.named_outputs = VecDeque::from([named]);
+        self.native_outputs = VecDeque::from([native]);
+        self.metrics = metrics.into();
+        if debugging {
+            self.debug_run.as_ref().unwrap().set(false);
+            self.scope_mut().trace_kernels = trace_kernels;
+        }
+
+        if let Some(timings) = &mut self.timings {
+            timings.upload += upload;
+            timings.compute += compute;
+            timings.download += download;
+            timings.runs += 1;
+        }
+        (output, result)
+    }
+
+}
+
+
+/// Runs single kernels of a program on the `input` and `output` images, outside of any pipeline
+pub struct KernelRunner {
+    scope: CScope,
+    /// buffers declared by the arguments of the last run, for the kernel to write to
+    output_buffers: Vec<String>
+}
+
+
+impl KernelRunner {
+
+
+    pub fn init(ocl_prog: &str, size: (usize, usize), devices: &[(Platform, Device)]) -> Self {
+        Self::from_source(read_program(ocl_prog).unwrap_or_else(|e| panic!("{}", e)), size, devices)
+    }
+
+
+    /// Runner of a program given by its source
+    pub fn from_source(ocl_src: String, size: (usize, usize), devices: &[(Platform, Device)]) -> Self {
+        let mut scope = CScope::from_source(ocl_src, size, devices, false);
+        scope.dynimg_size = size;
+        scope.dynimg_alloc = size;
+        scope.create_dynimage("input".into());
+        scope.create_dynimage("output".into());
+
+        Self {
+            scope,
+            output_buffers: Vec::new()
+        }
+    }
+
+
+    pub fn set_trace_kernels(&mut self, trace_kernels: bool) {
+        self.scope.trace_kernels = trace_kernels;
+    }
+
+
+    /// Runs `kernel` on `input` and returns the `output` image, which is cleared beforehand.
+    /// The strings `"input"` and `"output"` in `args` stand for the images, arrays are uploaded
+    /// to int or float buffers and numbers are passed as `int` or `float`.
+    /// `{"float_buffer": len}` and `{"int_buffer": len}` declare zeroed buffers the kernel writes to,
+    /// read back with `read_output_buffers()`.
+    pub fn run(&mut self, kernel: &str, input: &RgbImage, args: &Array) -> Result<RgbImage, String> {
+        let (w, h) = self.scope.dynimg_size;
+        if (input.width() as usize, input.height() as usize) != (w, h) {
+            return Err(format!("The input is {}x{} but the kernels run on {}x{} images",
+                input.width(), input.height(), w, h));
+        }
+
+        self.output_buffers.clear();
+        let mut kernel_args = Vec::with_capacity(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            let arg = if let Ok(name) = arg.clone().into_string() {
+                if name != "input" && name != "output" {
+                    return Err(format!("Unknown image `{}` (expected input or output)", name));
+                }
+                Dynamic::from(ImageRhaiRef{name, width: w as i32, height: h as i32})
+            } else if let Ok(v) = arg.as_int() {
+                Dynamic::from(v as i32)
+            } else if let Ok(v) = arg.as_float() {
+                Dynamic::from(v as f32)
+            } else if let Some(values) = arg.read_lock::<Array>() {
+                let name = format!("arg{}", i);
+                if values.iter().all(|v| v.is::<rhai::INT>()) {
+                    let data = values.iter().map(|v| Dynamic::from(v.as_int().unwrap() as i32)).collect();
+                    Dynamic::from(self.scope.create_int_buffer(name, data))
+                } else {
+                    let mut data = Vec::with_capacity(values.len());
+                    for v in values.iter() {
+                        let v = v.as_float().or_else(|_| v.as_int().map(|v| v as rhai::FLOAT))
+                            .map_err(|t| format!("Unsupported buffer value of type {}", t))?;
+                        data.push(Dynamic::from(v as f32));
+                    }
+                    Dynamic::from(self.scope.create_float_buffer(name, data))
+                }
+            } else if let Some(decl) = arg.read_lock::<Map>() {
+                let name = format!("arg{}", i);
+                let len = |key: &str| decl.get(key).and_then(|v| v.as_int().ok());
+                let buff = match (len("float_buffer"), len("int_buffer")) {
+                    (Some(len), _) => self.scope.create_float_buffer_of_size(name.clone(), len as i32),
+                    (_, Some(len)) => self.scope.create_int_buffer_of_size(name.clone(), len as i32),
+                    _ => return Err("Expected {\"float_buffer\": len} or {\"int_buffer\": len}".into())
+                };
+                match &self.scope.get_buffers()[&name] {
+                    Buff::IntBuffer(b) => b.cmd().fill(0, None).enq().unwrap(),
+                    Buff::FloatBuffer(b) => b.cmd().fill(0.0, None).enq().unwrap(),
+                    _ => ()
+                }
+                self.output_buffers.push(name);
+                Dynamic::from(buff)
+            } else {
+                return Err(format!("Unsupported kernel argument of type {}", arg.type_name()));
+            };
+            kernel_args.push(arg);
+        }
+
+        self.scope.set_input(input);
+        if let Buff::DynImage(buff) = &self.scope.get_buffers()["output"] {
+            buff.cmd().fill(0u8, None).enq().unwrap();
+        }
+
+        self.scope.call_kernel(kernel.into(), kernel_args);
+        Ok(self.scope.get_output())
+    }
+
+
+    /// Contents of the buffers declared by the arguments of the last run
+    pub fn read_output_buffers(&self) -> Vec<(String, Vec<f64>)> {
+        let buffers = self.scope.get_buffers();
+        self.output_buffers.iter().filter_map(|name| {
+            let values = match buffers.get(name)? {
+                Buff::IntBuffer(b) => {
+                    let mut values = vec![0; b.len()];
+                    b.read(&mut values).enq().unwrap();
+                    values.into_iter().map(|v| v as f64).collect()
+                }
+                Buff::FloatBuffer(b) => {
+                    let mut values = vec![0.0; b.len()];
+                    b.read(&mut values).enq().unwrap();
+                    values.into_iter().map(|v| v as f64).collect()
+                }
+                _ => return None
+            };
+            Some((name.clone(), values))
+        }).collect()
+    }
+}
+
+
+/// Short sha256 of the sources and configuration of a pipeline
+fn pipeline_version(ocl_src: &str, pipeline: Option<&str>, config: &str) -> String {
+    use sha2::{Sha256, Digest};
+
+    let mut hasher = Sha256::new();
+    hasher.update(ocl_src.as_bytes());
+    if let Some(pipeline) = pipeline {
+        hasher.update(std::fs::read(pipeline).unwrap_or_default());
+    }
+    hasher.update(config.as_bytes());
+    format!("{:x}", hasher.finalize())[..12].to_string()
+}
+
+
+/// What a pipeline script is allowed to do
+#[derive(Clone, Copy)]
+struct Sandbox {
+    /// bounds of the resources used by a single call to `init` or `run`
+    max_operations: u64,
+    max_call_levels: usize,
+    max_array_size: usize,
+    /// `eval` and loading modules from the filesystem
+    allow_eval: bool,
+    allow_import: bool,
+    /// writing files with `save_image` and `export_npy`
+    allow_debug_writes: bool
+}
+
+
+impl Default for Sandbox {
+
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000_000,
+            max_call_levels: 64,
+            max_array_size: 1 << 24,
+            allow_eval: false,
+            allow_import: false,
+            allow_debug_writes: false
+        }
+    }
+}
+
+
+impl Sandbox {
+
+
+    /// Sandbox of the `sandbox` map of a pipeline configuration, the missing settings keeping
+    /// their default, except for the files written by the pipeline which the user's own pipelines
+    /// may write
+    fn from_config(config: &Map) -> Result<Self, String> {
+        let mut sandbox = Self { allow_debug_writes: true, ..Self::default() };
+        let map = match config.get("sandbox") {
+            Some(map) => map.read_lock::<Map>().map(|m| m.clone())
+                .ok_or_else(|| String::from("`sandbox` is not a map"))?,
+            None => return Ok(sandbox)
+        };
+
+        let get_limit = |key: &str| match map.get(key).map(|v| v.as_int()) {
+            None => Ok(None),
+            Some(Ok(limit)) if limit > 0 => Ok(Some(limit)),
+            Some(_) => Err(format!("`sandbox.{}` must be a positive integer", key))
+        };
+        let get_bool = |key: &str, default: bool| match map.get(key).map(|v| v.as_bool()) {
+            None => Ok(default),
+            Some(Ok(b)) => Ok(b),
+            Some(Err(_)) => Err(format!("`sandbox.{}` must be a boolean", key))
+        };
+
+        if let Some(ops) = get_limit("max_operations")? {
+            sandbox.max_operations = ops as u64;
+        }
+        if let Some(levels) = get_limit("max_call_levels")? {
+            sandbox.max_call_levels = levels as usize;
+        }
+        if let Some(size) = get_limit("max_array_size")? {
+            sandbox.max_array_size = size as usize;
+        }
+        sandbox.allow_eval = get_bool("allow_eval", false)?;
+        sandbox.allow_import = get_bool("allow_import", false)?;
+        sandbox.allow_debug_writes = get_bool("allow_debug_writes", true)?;
+        Ok(sandbox)
+    }
+}
+
 
-        let _result: () = self.rhai_eng.call_fn(&mut scope, &self.rhai_ast, "run", ()).unwrap();
+/// Restricts what a pipeline script is allowed to do.
+/// The limits are read from the `sandbox` map of the pipeline configuration given by the user, never
+/// from the one of a package: `max_operations`, `max_call_levels` and `max_array_size` bound the
+/// resources used by a single call to `init` or `run`, while `allow_eval` and `allow_import`
+/// re-enable `eval` and loading modules from the filesystem (both disabled by default).
+/// `allow_debug_writes` (disabled for packages) is applied by the `Ocl` functions that write files.
+fn apply_sandbox(eng: &mut Engine, sandbox: &Sandbox) {
+    eng.set_max_operations(sandbox.max_operations);
+    eng.set_max_call_levels(sandbox.max_call_levels);
+    eng.set_max_array_size(sandbox.max_array_size);
 
-        return self.scope.get_output();
+    if !sandbox.allow_eval {
+        eng.disable_symbol("eval");
     }
+    if !sandbox.allow_import {
+        eng.set_module_resolver(DummyModuleResolver::new());
+    }
+}
 
+
+/// Value of a parameter given as text: a boolean, an integer, a float, or else the string itself
+fn param_value(value: &str) -> Dynamic {
+    if let Ok(b) = value.parse::<bool>() {
+        b.into()
+    } else if let Ok(i) = value.parse::<rhai::INT>() {
+        i.into()
+    } else if let Ok(f) = value.parse::<rhai::FLOAT>() {
+        f.into()
+    } else {
+        value.into()
+    }
+}
+
+
+fn push_params(scope: &mut Scope, params: &[(String, String)]) {
+    for (name, value) in params {
+        scope.push_constant_dynamic(name.as_str(), param_value(value));
+    }
+}
+
+
+/// Prints the `print` and `debug` messages of the scripts with the images being processed
+fn route_messages(eng: &mut Engine) {
+    eng.on_print(crate::log::script_message)
+        .on_debug(|text, _source, pos| match pos.line() {
+            Some(line) => crate::log::script_message(&format!("(line {}) {}", line, text)),
+            None => crate::log::script_message(text)
+        });
 }
 
 
@@ -182,10 +1217,70 @@ struct CScope {
     buffers: Rc<RefCell<HashMap<String, Buff>>>,
     config: Map,
     prog_queue: ProQue,
-    dynimg_size: (usize, usize)
+    builtins: Program,
+    dynimg_size: (usize, usize),
+    /// Dimentions the dynamic images are allocated for
+    dynimg_alloc: (usize, usize),
+    realloc_buffers: bool,
+    trace_kernels: bool,
+    /// Directory relative to which the pipeline assets are resolved
+    asset_dir: PathBuf,
+    /// Mean of `flat - dark` for each (dark, flat) pair used by `flat_field`
+    flat_field_means: Rc<RefCell<FlatFieldMeans>>,
+    /// Memory layout of the input and output images expected by the pipeline
+    layout: Rc<Cell<Layout>>,
+    /// Dimentions of the output of the current run when set by `set_output_size`, those of the input otherwise
+    output_size: Rc<Cell<Option<(usize, usize)>>>,
+    /// Device buffers used by the host for intermediate copies, not visible to the pipeline
+    scratch: Rc<RefCell<Vec<Buffer<u8>>>>,
+    /// State of the random generator of the pipeline
+    rng: Rc<Cell<u64>>,
+    /// Images `random_background` picks from
+    backgrounds: Rc<Vec<PathBuf>>,
+    /// L*a*b* statistics of the images loaded in `init()` used as `color_transfer` references
+    lab_stats_cache: Rc<RefCell<HashMap<String, [f32; 6]>>>,
+    /// `(y offset, width, height)` of each image of the current batch
+    batch: Rc<RefCell<Vec<i32>>>,
+    /// Mixing records of the images of the current batch, set by `mixup` and `cutmix`
+    mix_records: Rc<RefCell<Vec<Option<Map>>>>,
+    /// Share of the time the device may be busy, in percent
+    throttle: Option<u8>,
+    /// Float buffer the output of the current run is read from, set by `set_float_output`
+    float_output: Rc<RefCell<Option<depth::FloatOutput>>>,
+    /// Images saved along the output of the current run, by name, set by `add_output`
+    named_outputs: Rc<RefCell<Vec<(String, ImageRhaiRef)>>>,
+    /// Gray image the output of the current run is read from, set by `set_gray_output`
+    gray_output: Rc<RefCell<Option<String>>>,
+    /// Rgba image the output of the current run is read from, set by `set_rgba_output`
+    rgba_output: Rc<RefCell<Option<String>>>,
+    /// Channels of the input of the current run, 1 for the gray inputs of the pipelines declaring `native_gray`
+    input_channels: Rc<Cell<u8>>,
+    /// Buffer the output of the current run is read from with 16 bits or floats per channel, set by `set_deep_output`
+    deep_output: Rc<RefCell<Option<depth::DeepOutput>>>,
+    /// Metrics emitted by the current run with `emit_metric`, for each of its images
+    metrics: Rc<RefCell<Vec<Map>>>,
+    /// Whether the runs leave out the files written by the pipeline, while sampling a run before it
+    dry_run: Rc<Cell<bool>>,
+    /// Whether the pipeline may write files with `save_image` and `export_npy`
+    allow_debug_writes: bool,
+    /// Default mapping of the float outputs, with the scale of the values
+    float_mapping: (FloatMapping, f32),
+    /// Directory of the buffers cached by `init()`, with `--init-cache`
+    init_cache: Option<Rc<initcache::InitCache>>
+}
+
+
+/// Order in which the pixels of the input and output images are stored
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    RowMajor,
+    ColumnMajor
 }
 
 
+type FlatFieldMeans = HashMap<(String, String), [f32; 3]>;
+
+
 /// Differenciate between general buffers and images.
 /// In the code, general buffers will be sent to opencl as is,
 /// but images will be sent with their dimentions (they take three arguments)
@@ -194,7 +1289,11 @@ enum Buff {
     IntBuffer(Buffer<i32>),
     FloatBuffer(Buffer<f32>),
     DynImage(Buffer<u8>),
-    Image(Buffer<u8>, i32, i32)
+    Image(Buffer<u8>, i32, i32),
+    /// one byte per pixel, of the dimentions of the dynamic images
+    GrayImage(Buffer<u8>),
+    /// four bytes per pixel, of the dimentions of the dynamic images
+    RgbaImage(Buffer<u8>)
 }
 
 
@@ -239,23 +1338,80 @@ impl ImageRhaiRef {
 impl CScope {
 
 
-    fn init(buffers: HashMap<String, Buff>, config: Map, prog_queue: ProQue) -> Self {
+    fn init(buffers: HashMap<String, Buff>, prog_queue: ProQue, builtins: Program) -> Self {
         Self {
             buffers: Rc::new(RefCell::new(buffers)),
-            config: config,
+            config: Map::new(),
             prog_queue: prog_queue,
-            dynimg_size: (0, 0)
+            builtins,
+            dynimg_size: (0, 0),
+            dynimg_alloc: (0, 0),
+            realloc_buffers: false,
+            trace_kernels: false,
+            asset_dir: PathBuf::new(),
+            flat_field_means: Rc::new(RefCell::new(HashMap::new())),
+            layout: Rc::new(Cell::new(Layout::RowMajor)),
+            output_size: Rc::new(Cell::new(None)),
+            scratch: Rc::new(RefCell::new(Vec::new())),
+            rng: Rc::new(Cell::new(0)),
+            backgrounds: Rc::new(Vec::new()),
+            lab_stats_cache: Rc::new(RefCell::new(HashMap::new())),
+            batch: Rc::new(RefCell::new(Vec::new())),
+            mix_records: Rc::new(RefCell::new(Vec::new())),
+            throttle: None,
+            float_output: Rc::new(RefCell::new(None)),
+            named_outputs: Rc::new(RefCell::new(Vec::new())),
+            gray_output: Rc::new(RefCell::new(None)),
+            rgba_output: Rc::new(RefCell::new(None)),
+            input_channels: Rc::new(Cell::new(3)),
+            deep_output: Rc::new(RefCell::new(None)),
+            metrics: Rc::new(RefCell::new(Vec::new())),
+            dry_run: Rc::new(Cell::new(false)),
+            allow_debug_writes: false,
+            float_mapping: (FloatMapping::Clip, 255.0),
+            init_cache: None
         }
     }
 
 
+    /// Builds the opencl program and the built-in kernels, with a work size of `size`
+    /// The queue is built on the first of `devices` where the program builds, reporting the failures
+    /// in `verbose` mode
+    fn from_source(ocl_src: String, size: (usize, usize), devices: &[(Platform, Device)], verbose: bool) -> Self {
+        let (prog_queue, builtins) = build_program(ocl_src, size, devices, verbose).unwrap_or_else(|e| panic!("{}", e));
+        Self::init(HashMap::new(), prog_queue, builtins)
+    }
+
+
+    /// Whether the device supports double precision
+    fn has_fp64(&self) -> bool {
+        use ocl::enums::DeviceInfo;
+
+        self.prog_queue.device().info(DeviceInfo::Extensions)
+            .map(|e| e.to_string().contains("cl_khr_fp64"))
+            .unwrap_or(true)
+    }
+
+
     fn call_kernel(&mut self, name: String, args: Vec<Dynamic>) {
         let mut ker = self.prog_queue.kernel_builder(&name);
+        // human readable description of each resolved argument, for --trace-kernels
+        let mut trace = Vec::new();
 
         for arg in args {
+            if arg.is::<f64>() && !self.has_fp64() {
+                warnings::report("fp64", None, &format!("the kernel `{}` receives a double on a device without \
+                    double precision (cl_khr_fp64), pass it as a float", name));
+            }
+
             macro_rules! add_arg {
                 (type $t:ty) => {
-                    if arg.is::<$t>() { ker.arg(arg.cast::<$t>()); continue; }
+                    if arg.is::<$t>() {
+                        let val = arg.cast::<$t>();
+                        trace.push(format!("{} {}", stringify!($t), val));
+                        ker.arg(val);
+                        continue;
+                    }
                 };
                 (vect $t:ty) => { // TODO: use when it works
                     add_arg!(type $t);
@@ -285,9 +1441,11 @@ impl CScope {
                 
                 match &self.get_buffers()[&buff.name] {
                     Buff::IntBuffer(b) => {
+                        trace.push(format!("int buffer `{}` (len {})", buff.name, b.len()));
                         ker.arg(b.clone());
                     }
                     Buff::FloatBuffer(b) => {
+                        trace.push(format!("float buffer `{}` (len {})", buff.name, b.len()));
                         ker.arg(b.clone());
                     }
                     _ => { panic!("There is no buffer named {}", buff.name); }
@@ -305,9 +1463,33 @@ impl CScope {
 
                 match &self.get_buffers()[&img.name] {
                     Buff::Image(b, _, _) => {
+                        trace.push(format!("image `{}` ({}x{}, {} bytes)", img.name, img.width, img.height, b.len()));
+                        trace.push(format!("i32 {} (width of `{}`)", img.width, img.name));
+                        trace.push(format!("i32 {} (height of `{}`)", img.height, img.name));
                         ker.arg(b.clone()).arg(img.width).arg(img.height);
                     },
-                    Buff::DynImage(b) => {
+                    // an output of its own dimentions is sent like an image
+                    Buff::DynImage(b) => match self.output_size.get().filter(|_| img.name == "output") {
+                        Some((w, h)) => {
+                            trace.push(format!("output `{}` ({}x{}, {} bytes)", img.name, w, h, b.len()));
+                            trace.push(format!("i32 {} (width of `{}`)", w, img.name));
+                            trace.push(format!("i32 {} (height of `{}`)", h, img.name));
+                            ker.arg(b.clone()).arg(w as i32).arg(h as i32);
+                        }
+                        None => {
+                            trace.push(format!("dynimage `{}` ({}x{}, {} bytes)", img.name,
+                                self.dynimg_size.0, self.dynimg_size.1, b.len()));
+                            ker.arg(b.clone());
+                        }
+                    },
+                    Buff::GrayImage(b) => {
+                        trace.push(format!("gray image `{}` ({}x{}, {} bytes)", img.name,
+                            self.dynimg_size.0, self.dynimg_size.1, b.len()));
+                        ker.arg(b.clone());
+                    }
+                    Buff::RgbaImage(b) => {
+                        trace.push(format!("rgba image `{}` ({}x{}, {} bytes)", img.name,
+                            self.dynimg_size.0, self.dynimg_size.1, b.len()));
                         ker.arg(b.clone());
                     }
                     _ => { panic!("There is no image named {}", img.name); }
@@ -315,6 +1497,12 @@ impl CScope {
 
                 continue;
             }
+
+            trace.push(format!("{}unsupported argument of type {}{}", RED, arg.type_name(), CLEAR));
+        }
+
+        if self.trace_kernels {
+            self.print_kernel_trace(&name, &trace);
         }
 
         let ker = ker.arg(self.dynimg_size.0 as i32)
@@ -323,9 +1511,38 @@ impl CScope {
             .expect("Could not build kernel.");
 
 
+        let start = Instant::now();
         unsafe {
             ker.enq().expect("Could not run kernel.");
         }
+        self.pace(start);
+    }
+
+
+    /// With a throttle, waits for the kernel started at `start` and sleeps so that the device
+    /// is only busy for the allowed share of the time
+    fn pace(&self, start: Instant) {
+        if let Some(percent) = self.throttle.filter(|&p| p < 100) {
+            self.prog_queue.finish().expect("Could not wait for the kernel");
+            let busy = start.elapsed();
+            std::thread::sleep(busy * (100 - percent as u32) / percent as u32);
+        }
+    }
+
+
+    /// Prints a kernel launch with its resolved arguments and work size
+    fn print_kernel_trace(&self, name: &str, args: &[String]) {
+        let work_size = match self.prog_queue.dims().to_lens() {
+            Ok([x, y, _]) => format!("{}x{}", x, y),
+            Err(_) => String::from("unspecified")
+        };
+
+        println!("{}kernel{} {} (global work size {})", GREEN, CLEAR, name, work_size);
+        for (i, arg) in args.iter().enumerate() {
+            println!("  {}: {}", i, arg);
+        }
+        println!("  {}: i32 {} (img_w)", args.len(), self.dynimg_size.0);
+        println!("  {}: i32 {} (img_h)", args.len() + 1, self.dynimg_size.1);
     }
 
 
@@ -340,27 +1557,153 @@ impl CScope {
 
     fn set_image_size(&mut self, size: (usize, usize)) {
         self.dynimg_size = size;
+
+        if self.realloc_buffers && size != self.dynimg_alloc {
+            self.dynimg_alloc = size;
+
+            let names: Vec<(String, Buff)> = self.get_buffers().iter()
+                .filter(|(_, b)| matches!(b, Buff::DynImage(_) | Buff::GrayImage(_) | Buff::RgbaImage(_)))
+                .map(|(name, b)| (name.clone(), b.clone()))
+                .collect();
+            for (name, buff) in names {
+                match buff {
+                    Buff::GrayImage(_) => self.create_gray_image(name),
+                    Buff::RgbaImage(_) => self.create_rgba_image(name),
+                    _ => self.create_dynimage(name)
+                }
+            }
+        }
     }
 
 
     // TODO: more error checks with set and get image
     fn set_input(&mut self, img: &RgbImage) {
         if let Buff::DynImage(buff) = &self.get_buffers()["input".into()] {
-            buff.write(img.as_raw()).enq().unwrap();
+            if self.input_channels.get() == 1 {
+                // transposed on the host like the gray images
+                let (w, h) = self.dynimg_size;
+                let pixels = img.pixels().map(|p| p[0]).collect();
+                buff.write(&depth::row_major(pixels, (h, w), 1, self.layout.get())).enq().unwrap();
+            } else if self.layout.get() == Layout::RowMajor {
+                buff.write(img.as_raw()).enq().unwrap();
+            } else {
+                let staging = self.staging_buffer();
+                staging.write(img.as_raw()).enq().unwrap();
+                self.transpose(&staging, buff, self.dynimg_size, true);
+            }
+        }
+    }
+
+
+    /// Declares the memory layout of the input and output images (`row_major` or `column_major`).
+    /// The images are transposed on the device when the pipeline does not use the row-major layout.
+    fn set_layout(&mut self, layout: String) {
+        self.layout.set(match layout.as_str() {
+            "row_major" => Layout::RowMajor,
+            "column_major" => Layout::ColumnMajor,
+            _ => panic!("Unknown layout `{}` (expected row_major or column_major)", layout)
+        });
+    }
+
+
+    /// Returns a buffer large enough to hold a copy of the current dynamic images
+    fn staging_buffer(&self) -> Buffer<u8> {
+        self.scratch_buffer(0, self.dynimg_size.0 * self.dynimg_size.1 * 3)
+    }
+
+
+    /// Returns the scratch buffer `slot`, (re)allocating it when it is smaller than `len`
+    fn scratch_buffer(&self, slot: usize, len: usize) -> Buffer<u8> {
+        let mut scratch = self.scratch.borrow_mut();
+
+        while scratch.len() <= slot || scratch[slot].len() < len {
+            let buff = Buffer::<u8>::builder()
+                .queue(self.prog_queue.queue().clone())
+                .len(len.max(1))
+                .build()
+                .expect("Could not allocate buffer");
+
+            if scratch.len() <= slot {
+                scratch.push(buff);
+            } else {
+                scratch[slot] = buff;
+            }
+        }
+
+        scratch[slot].clone()
+    }
+
+
+    /// Writes the batch offsets to the `batch` buffer, growing it when needed
+    fn set_batch(&mut self, offsets: &[i32]) {
+        let fits = matches!(self.get_buffers().get("batch"), Some(Buff::IntBuffer(b)) if b.len() >= offsets.len());
+        if !fits {
+            self.create_int_buffer_of_size("batch".into(), offsets.len() as i32);
+        }
+
+        if let Some(Buff::IntBuffer(buff)) = self.get_buffers().get("batch") {
+            buff.write(offsets).enq().unwrap();
         }
+
+        *self.batch.borrow_mut() = offsets.to_vec();
+        *self.mix_records.borrow_mut() = vec![None; offsets.len() / 3];
     }
 
 
     fn get_output(&self) -> RgbImage {
-        let mut pixels = vec![0u8; self.dynimg_size.0 * self.dynimg_size.1 * 3];
+        let (w, h) = self.out_size();
+        if let Some(pixels) = self.read_float_output() {
+            return RgbImage::from_raw(w as u32, h as u32, pixels).unwrap();
+        }
+
+        let mut pixels = vec![0u8; w * h * 3];
         if let Buff::DynImage(buff) = &self.get_buffers()["output".into()] {
-            buff.read(&mut pixels).enq().unwrap(); // TODO: pixels having the wrong dimentions due to direct call to read
+            if self.layout.get() == Layout::RowMajor {
+                buff.read(&mut pixels).enq().unwrap();
+            } else {
+                let staging = self.scratch_buffer(0, pixels.len());
+                self.transpose(buff, &staging, (w, h), false);
+                staging.read(&mut pixels).enq().unwrap();
+            }
         }
-        let rgb_image = RgbImage::from_raw(self.dynimg_size.0 as u32, self.dynimg_size.1 as u32, pixels).unwrap();
+        let rgb_image = RgbImage::from_raw(w as u32, h as u32, pixels).unwrap();
         return rgb_image;
     }
 
 
+    /// Dimentions of the output of the current run
+    fn out_size(&self) -> (usize, usize) {
+        self.output_size.get().unwrap_or(self.dynimg_size)
+    }
+
+
+    /// Sets the dimentions of the output of the current run, for the pipelines whose output is not
+    /// of the dimentions of their input. The output is then sent to the kernels like an image, with
+    /// its dimentions. Returns the output image.
+    fn set_output_size(&mut self, width: INT, height: INT) -> ImageRhaiRef {
+        if width <= 0 || height <= 0 {
+            panic!("Invalid output size {}x{}", width, height);
+        }
+        let len = width as usize * height as usize * 3;
+        let too_small = matches!(&self.get_buffers()["output"], Buff::DynImage(b) if b.len() < len);
+        if too_small {
+            let buff = Buffer::<u8>::builder()
+                .queue(self.prog_queue.queue().clone())
+                .len(len)
+                .build()
+                .expect("Could not allocate buffer");
+            self.get_buffers_mut().insert("output".into(), Buff::DynImage(buff));
+        }
+
+        self.output_size.set(Some((width as usize, height as usize)));
+        ImageRhaiRef {
+            name: "output".into(),
+            width: width as i32,
+            height: height as i32
+        }
+    }
+
+
     fn create_rhai_scope(&self) -> Scope {
         let mut scope = Scope::new();
 
@@ -372,7 +1715,7 @@ impl CScope {
                 Buff::FloatBuffer(b) => {
                     scope.push(name, BufferRhaiRef{name: name.clone(), size: b.len() as i32});
                 }
-                Buff::DynImage(_) => {
+                Buff::DynImage(_) | Buff::GrayImage(_) | Buff::RgbaImage(_) => {
                     scope.push(name, ImageRhaiRef{name: name.clone(), width: self.dynimg_size.0 as i32, height: self.dynimg_size.1 as i32});
                 }
                 Buff::Image(_, w, h) => {
@@ -460,7 +1803,7 @@ impl CScope {
 
     fn create_dynimage(&mut self, name: String) {
         let queue = self.prog_queue.queue().clone();
-        let size = self.dynimg_size.0 * self.dynimg_size.1 * 3;
+        let size = self.dynimg_alloc.0 * self.dynimg_alloc.1 * 3;
         self.get_buffers_mut().insert(name, Buff::DynImage(Buffer::<u8>::builder()
             .queue(queue)
             .len(size)
@@ -482,4 +1825,65 @@ impl CScope {
             height: height as i32
         };
     }
-}
+
+
+    /// Path of an asset, relative to the pipeline, which cannot read outside of its directory
+    fn asset_path(&self, path: &str) -> PathBuf {
+        let rel = Path::new(path);
+        if !rel.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            panic!("Cannot read the asset `{}`: expected a path relative to the pipeline without `..`", path);
+        }
+        self.asset_dir.join(rel)
+    }
+
+
+    /// Loads an image asset (relative to the pipeline) into an image buffer named after the file
+    fn load_image_asset(&mut self, path: String) -> ImageRhaiRef {
+        let name = Path::new(&path).file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_else(|| panic!("Invalid asset path `{}`", path))
+            .to_string();
+        self.load_named_image_asset(name, path)
+    }
+
+
+    /// Loads an image asset (relative to the pipeline) into the image buffer `name`
+    fn load_named_image_asset(&mut self, name: String, path: String) -> ImageRhaiRef {
+        let full_path = self.asset_path(&path);
+        let img = image::open(&full_path)
+            .unwrap_or_else(|e| panic!("Could not read image asset `{}`: {}", full_path.display(), e))
+            .into_rgb8();
+
+        let img_ref = self.create_image(name.clone(), img.width() as usize, img.height() as usize);
+        if let Buff::Image(buff, _, _) = &self.get_buffers()[&name] {
+            buff.write(img.as_raw()).enq().unwrap();
+        }
+        img_ref
+    }
+
+
+    /// Reads a csv file (relative to the pipeline) as an array of rows.
+    /// Cells are converted to integers or floats when possible, and kept as strings otherwise.
+    fn load_csv(&mut self, path: String) -> Array {
+        let full_path = self.asset_path(&path);
+        let content = std::fs::read_to_string(&full_path)
+            .unwrap_or_else(|e| panic!("Could not read csv asset `{}`: {}", full_path.display(), e));
+
+        content.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let row: Array = line.split(',').map(|cell| {
+                    let cell = cell.trim();
+                    if let Ok(i) = cell.parse::<i64>() {
+                        Dynamic::from(i)
+                    } else if let Ok(f) = cell.parse::<f64>() {
+                        Dynamic::from(f)
+                    } else {
+                        Dynamic::from(cell.to_string())
+                    }
+                }).collect();
+                Dynamic::from(row)
+            })
+            .collect()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,572 @@
+// Built-in kernels, compiled separately from the user program.
+// Images are tightly packed rgb buffers of `w * h * 3` bytes.
+
+
+#define BUILTIN_INIT(w, h)                  \
+const int x = get_global_id(0);             \
+const int y = get_global_id(1);             \
+if (x >= w || y >= h) {                     \
+    return;                                 \
+}                                           \
+const int idx = (x + y * w) * 3;
+
+
+// Scientific camera correction: `(img - dark) * mean(flat - dark) / (flat - dark)`
+__kernel void flat_field(__global const uchar* img, __global const uchar* dark,
+    __global const uchar* flat, __global uchar* dst,
+    const float mean_r, const float mean_g, const float mean_b,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float mean[3] = {mean_r, mean_g, mean_b};
+
+    for (int c = 0; c < 3; c++) {
+        const float gain = (float) flat[idx + c] - (float) dark[idx + c];
+        const float val = (float) img[idx + c] - (float) dark[idx + c];
+        dst[idx + c] = convert_uchar_sat_rte(gain > 0.0f ? val * mean[c] / gain : val);
+    }
+}
+
+
+// Copies an image from the row-major to the column-major layout, or back
+__kernel void transpose(__global const uchar* src, __global uchar* dst,
+    const int to_column_major, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const int col_idx = (y + x * h) * 3;
+
+    for (int c = 0; c < 3; c++) {
+        if (to_column_major) {
+            dst[col_idx + c] = src[idx + c];
+        } else {
+            dst[idx + c] = src[col_idx + c];
+        }
+    }
+}
+
+
+// Absolute difference of two images, written to the first one
+__kernel void diff_images(__global uchar* a, __global const uchar* b, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    for (int c = 0; c < 3; c++) {
+        a[idx + c] = abs_diff(a[idx + c], b[idx + c]);
+    }
+}
+
+
+// Per pixel error between two images: sum of the squared errors and largest absolute error of the channels
+__kernel void pair_error(__global const uchar* a, __global const uchar* b, __global float* err, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    float squared = 0.0f;
+    float largest = 0.0f;
+    for (int c = 0; c < 3; c++) {
+        const float d = (float) a[idx + c] - (float) b[idx + c];
+        squared += d * d;
+        largest = fmax(largest, fabs(d));
+    }
+    err[2 * (x + y * w)] = squared;
+    err[2 * (x + y * w) + 1] = largest;
+}
+
+
+// Bilinear resize of `src` (`src_w * src_h`) to `dst` (`w * h`)
+__kernel void resize(__global const uchar* src, __global uchar* dst,
+    const int src_w, const int src_h, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float sx = clamp((x + 0.5f) * src_w / w - 0.5f, 0.0f, (float) (src_w - 1));
+    const float sy = clamp((y + 0.5f) * src_h / h - 0.5f, 0.0f, (float) (src_h - 1));
+    const int x0 = (int) sx;
+    const int y0 = (int) sy;
+    const int x1 = min(x0 + 1, src_w - 1);
+    const int y1 = min(y0 + 1, src_h - 1);
+
+    for (int c = 0; c < 3; c++) {
+        const float top = mix((float) src[(x0 + y0 * src_w) * 3 + c], (float) src[(x1 + y0 * src_w) * 3 + c], sx - x0);
+        const float bottom = mix((float) src[(x0 + y1 * src_w) * 3 + c], (float) src[(x1 + y1 * src_w) * 3 + c], sx - x0);
+        dst[idx + c] = convert_uchar_sat_rte(mix(top, bottom, sy - y0));
+    }
+}
+
+
+// Weight of a resampling filter at the distance `x`: triangle (0), cubic (1) of parameter `a`
+// or Lanczos (2) of `a` lobes
+float resample_weight(const int filter, const float a, float x)
+{
+    x = fabs(x);
+    if (filter == 0) {
+        return x < 1.0f ? 1.0f - x : 0.0f;
+    }
+    if (filter == 2) {
+        if (x >= a) {
+            return 0.0f;
+        }
+        return x < 1e-6f ? 1.0f : a * sinpi(x) * sinpi(x / a) / (M_PI_F * M_PI_F * x * x);
+    }
+    if (x < 1.0f) {
+        return ((a + 2.0f) * x - (a + 3.0f)) * x * x + 1.0f;
+    }
+    if (x < 2.0f) {
+        return (((x - 5.0f) * x + 8.0f) * x - 4.0f) * a;
+    }
+    return 0.0f;
+}
+
+
+// One pass of the antialiased resize of Pillow, along the rows (`horizontal`) or the columns.
+// `dst` (`w * h`) has the height (or width) of `src`, whose length along the pass is `src_len`.
+// The filter is stretched by the reduction factor, the window being cut at the borders.
+__kernel void resample_pass(__global const uchar* src, __global uchar* dst, const int src_len,
+    const int horizontal, const int filter, const float a, const float support,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const int pos = horizontal ? x : y;
+    const float scale = (float) src_len / (horizontal ? w : h);
+    const float filterscale = fmax(scale, 1.0f);
+    const float center = (pos + 0.5f) * scale;
+    const float reach = support * filterscale;
+    const int start = max((int) (center - reach + 0.5f), 0);
+    const int end = min((int) (center + reach + 0.5f), src_len);
+
+    float sum[3] = {0.0f, 0.0f, 0.0f};
+    float total = 0.0f;
+    for (int i = start; i < end; i++) {
+        const float weight = resample_weight(filter, a, (i - center + 0.5f) / filterscale);
+        const int s = (horizontal ? i + y * src_len : x + i * w) * 3;
+        total += weight;
+        for (int c = 0; c < 3; c++) {
+            sum[c] += weight * src[s + c];
+        }
+    }
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat(floor(sum[c] / total + 0.5f));
+    }
+}
+
+
+// Bicubic resize of OpenCV (`INTER_CUBIC`): 4x4 taps around the center of the pixel, replicating
+// the borders, with `a` = -0.75 and no antialiasing
+__kernel void resize_cubic(__global const uchar* src, __global uchar* dst,
+    const int src_w, const int src_h, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float fx = (x + 0.5f) * src_w / w - 0.5f;
+    const float fy = (y + 0.5f) * src_h / h - 0.5f;
+    const int ix = (int) floor(fx);
+    const int iy = (int) floor(fy);
+
+    float sum[3] = {0.0f, 0.0f, 0.0f};
+    for (int j = iy - 1; j <= iy + 2; j++) {
+        const float wy = resample_weight(1, -0.75f, fy - j);
+        const int sy = clamp(j, 0, src_h - 1);
+        for (int i = ix - 1; i <= ix + 2; i++) {
+            const float weight = wy * resample_weight(1, -0.75f, fx - i);
+            const int s = (clamp(i, 0, src_w - 1) + sy * src_w) * 3;
+            for (int c = 0; c < 3; c++) {
+                sum[c] += weight * src[s + c];
+            }
+        }
+    }
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(sum[c]);
+    }
+}
+
+
+// Area resize (`INTER_AREA` of OpenCV): each pixel is the average of the source pixels it covers,
+// weighted by the covered fraction of each
+__kernel void resize_area(__global const uchar* src, __global uchar* dst,
+    const int src_w, const int src_h, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float sx = (float) src_w / w;
+    const float sy = (float) src_h / h;
+    const float x0 = x * sx, x1 = (x + 1) * sx;
+    const float y0 = y * sy, y1 = (y + 1) * sy;
+
+    float sum[3] = {0.0f, 0.0f, 0.0f};
+    for (int j = (int) y0; j < min((int) ceil(y1), src_h); j++) {
+        const float wy = fmin(y1, j + 1.0f) - fmax(y0, (float) j);
+        for (int i = (int) x0; i < min((int) ceil(x1), src_w); i++) {
+            const float weight = wy * (fmin(x1, i + 1.0f) - fmax(x0, (float) i));
+            const int s = (i + j * src_w) * 3;
+            for (int c = 0; c < 3; c++) {
+                sum[c] += weight * src[s + c];
+            }
+        }
+    }
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(sum[c] / (sx * sy));
+    }
+}
+
+
+// Composites the foreground of a green (or any color) screen capture over a background.
+// The key is matched on chroma only, `tolerance` and `softness` being distances in [0, 1].
+__kernel void chroma_key(__global const uchar* src, __global const uchar* bg, __global uchar* dst,
+    const float key_cb, const float key_cr, const float tolerance, const float softness,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float r = src[idx] / 255.0f;
+    const float g = src[idx + 1] / 255.0f;
+    const float b = src[idx + 2] / 255.0f;
+
+    const float cb = -0.168736f * r - 0.331264f * g + 0.5f * b;
+    const float cr = 0.5f * r - 0.418688f * g - 0.081312f * b;
+    const float dist = hypot(cb - key_cb, cr - key_cr);
+    const float alpha = clamp((dist - tolerance) / fmax(softness, 1e-6f), 0.0f, 1.0f);
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(mix((float) bg[idx + c], (float) src[idx + c], alpha));
+    }
+}
+
+
+// Poisson blending: the pixels of `src` selected by `mask` are solved so that their gradients
+// match the ones of `src` while matching `dst` on the border of the mask.
+// `f` holds the current solution in the coordinates of `src`, which is placed at (x0, y0) in `dst`.
+
+// Whether a pixel of `src` is solved for
+#define SEAMLESS_INSIDE(sx, sy)                                                     \
+    ((sx) >= 0 && (sy) >= 0 && (sx) < w && (sy) < h && mask[((sx) + (sy) * w) * 3] > 127 \
+    && (sx) + x0 >= 0 && (sy) + y0 >= 0 && (sx) + x0 < dst_w && (sy) + y0 < dst_h)
+
+
+__kernel void seamless_init(__global const uchar* src, __global float* f, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    for (int c = 0; c < 3; c++) {
+        f[idx + c] = src[idx + c];
+    }
+}
+
+
+// One Jacobi iteration
+__kernel void seamless_step(__global const uchar* src, __global const uchar* dst,
+    __global const uchar* mask, __global const float* f_in, __global float* f_out,
+    const int x0, const int y0, const int dst_w, const int dst_h, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    if (!SEAMLESS_INSIDE(x, y)) {
+        return;
+    }
+
+    const int nx[4] = {x - 1, x + 1, x, x};
+    const int ny[4] = {y, y, y - 1, y + 1};
+
+    for (int c = 0; c < 3; c++) {
+        float sum = 0.0f;
+
+        for (int n = 0; n < 4; n++) {
+            const int sx = clamp(nx[n], 0, w - 1);
+            const int sy = clamp(ny[n], 0, h - 1);
+            sum += (float) src[idx + c] - (float) src[(sx + sy * w) * 3 + c];
+
+            if (SEAMLESS_INSIDE(nx[n], ny[n])) {
+                sum += f_in[(nx[n] + ny[n] * w) * 3 + c];
+            } else {
+                const int dx = clamp(nx[n] + x0, 0, dst_w - 1);
+                const int dy = clamp(ny[n] + y0, 0, dst_h - 1);
+                sum += dst[(dx + dy * dst_w) * 3 + c];
+            }
+        }
+
+        f_out[idx + c] = sum / 4.0f;
+    }
+}
+
+
+__kernel void seamless_write(__global const float* f, __global const uchar* mask, __global uchar* dst,
+    const int x0, const int y0, const int dst_w, const int dst_h, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    if (SEAMLESS_INSIDE(x, y)) {
+        const int dst_idx = (x + x0 + (y + y0) * dst_w) * 3;
+        for (int c = 0; c < 3; c++) {
+            dst[dst_idx + c] = convert_uchar_sat_rte(f[idx + c]);
+        }
+    }
+}
+
+
+// sRGB (0-255) to CIE L*a*b* (D65)
+void srgb_to_lab(const float* rgb, float* lab)
+{
+    float c[3];
+    for (int i = 0; i < 3; i++) {
+        c[i] = rgb[i] / 255.0f;
+        c[i] = c[i] <= 0.04045f ? c[i] / 12.92f : pow((c[i] + 0.055f) / 1.055f, 2.4f);
+    }
+
+    float xyz[3] = {
+        (0.4124564f * c[0] + 0.3575761f * c[1] + 0.1804375f * c[2]) / 0.95047f,
+        0.2126729f * c[0] + 0.7151522f * c[1] + 0.0721750f * c[2],
+        (0.0193339f * c[0] + 0.1191920f * c[1] + 0.9503041f * c[2]) / 1.08883f
+    };
+    for (int i = 0; i < 3; i++) {
+        xyz[i] = xyz[i] > 0.008856f ? cbrt(xyz[i]) : 7.787f * xyz[i] + 16.0f / 116.0f;
+    }
+
+    lab[0] = 116.0f * xyz[1] - 16.0f;
+    lab[1] = 500.0f * (xyz[0] - xyz[1]);
+    lab[2] = 200.0f * (xyz[1] - xyz[2]);
+}
+
+
+// CIE L*a*b* (D65) to sRGB (0-255)
+void lab_to_srgb(const float* lab, float* rgb)
+{
+    const float fy = (lab[0] + 16.0f) / 116.0f;
+    float f[3] = {fy + lab[1] / 500.0f, fy, fy - lab[2] / 200.0f};
+    for (int i = 0; i < 3; i++) {
+        f[i] = f[i] > 0.206893f ? f[i] * f[i] * f[i] : (f[i] - 16.0f / 116.0f) / 7.787f;
+    }
+    f[0] *= 0.95047f;
+    f[2] *= 1.08883f;
+
+    const float c[3] = {
+        3.2404542f * f[0] - 1.5371385f * f[1] - 0.4985314f * f[2],
+        -0.9692660f * f[0] + 1.8760108f * f[1] + 0.0415560f * f[2],
+        0.0556434f * f[0] - 0.2040259f * f[1] + 1.0572252f * f[2]
+    };
+    for (int i = 0; i < 3; i++) {
+        const float v = clamp(c[i], 0.0f, 1.0f);
+        rgb[i] = (v <= 0.0031308f ? 12.92f * v : 1.055f * pow(v, 1.0f / 2.4f) - 0.055f) * 255.0f;
+    }
+}
+
+
+__kernel void to_lab(__global const uchar* src, __global float* lab, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float rgb[3] = {src[idx], src[idx + 1], src[idx + 2]};
+    float l[3];
+    srgb_to_lab(rgb, l);
+
+    for (int c = 0; c < 3; c++) {
+        lab[idx + c] = l[c];
+    }
+}
+
+
+// Reinhard color transfer, `stats` holding the mean and standard deviation of each L*a*b* channel
+// of the source then of the reference
+__kernel void color_transfer(__global const uchar* src, __global uchar* dst, __constant float* stats,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float rgb[3] = {src[idx], src[idx + 1], src[idx + 2]};
+    float lab[3];
+    srgb_to_lab(rgb, lab);
+
+    for (int c = 0; c < 3; c++) {
+        lab[c] = (lab[c] - stats[c]) * stats[9 + c] / fmax(stats[3 + c], 1e-4f) + stats[6 + c];
+    }
+
+    float out[3];
+    lab_to_srgb(lab, out);
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(out[c]);
+    }
+}
+
+
+// Uniform random number in (0, 1) from a pixel position and a seed
+float hash_uniform(uint x, uint y, uint c, uint seed)
+{
+    uint v = x * 1973u + y * 9277u + c * 26699u + seed * 0x9E3779B9u;
+    v = v * 747796405u + 2891336453u;
+    v = ((v >> ((v >> 28u) + 4u)) ^ v) * 277803737u;
+    v = (v >> 22u) ^ v;
+    return ((float) (v >> 8) + 0.5f) / 16777216.0f;
+}
+
+
+// Poisson-Gaussian sensor noise: gaussian noise of variance `shot * value + read` on [0, 1] values
+__kernel void sensor_noise(__global const uchar* src, __global uchar* dst,
+    const float shot, const float read, const uint seed, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    for (int c = 0; c < 3; c++) {
+        const float value = src[idx + c] / 255.0f;
+        // Box-Muller transform
+        const float u1 = hash_uniform(x, y, c, seed);
+        const float u2 = hash_uniform(x, y, c + 3, seed);
+        const float n = sqrt(-2.0f * log(u1)) * cos(2.0f * M_PI_F * u2);
+
+        dst[idx + c] = convert_uchar_sat_rte((value + n * sqrt(shot * value + read)) * 255.0f);
+    }
+}
+
+
+// Linear motion blur of `length` pixels along the direction of `angle` radians
+__kernel void motion_blur(__global const uchar* src, __global uchar* dst,
+    const float length, const float angle, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const int samples = max((int) length, 1);
+    const float dx = cos(angle);
+    const float dy = sin(angle);
+
+    float sum[3] = {0.0f, 0.0f, 0.0f};
+    for (int i = 0; i < samples; i++) {
+        const float t = samples > 1 ? (float) i / (samples - 1) * length - length / 2.0f : 0.0f;
+        const int sx = clamp((int) floor(x + t * dx + 0.5f), 0, w - 1);
+        const int sy = clamp((int) floor(y + t * dy + 0.5f), 0, h - 1);
+        for (int c = 0; c < 3; c++) {
+            sum[c] += src[(sx + sy * w) * 3 + c];
+        }
+    }
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(sum[c] / samples);
+    }
+}
+
+
+// Rain streaks of `length` pixels in the direction (dx, dy), starting at random pixels with probability `density`
+__kernel void rain(__global const uchar* src, __global uchar* dst,
+    const float density, const float length, const float dx, const float dy, const float brightness,
+    const uint seed, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    // the pixel is covered if a streak started up to `length` pixels before it
+    float streak = 0.0f;
+    for (int i = 0; i < (int) length; i++) {
+        const int sx = (int) floor(x - i * dx + 0.5f);
+        const int sy = (int) floor(y - i * dy + 0.5f);
+        if (sx < 0 || sx >= w || sy < 0 || sy >= h) break;
+        if (hash_uniform(sx, sy, 0, seed) < density) {
+            streak = fmax(streak, 1.0f - (float) i / length);
+        }
+    }
+
+    const float alpha = streak * brightness;
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(mix((float) src[idx + c], 220.0f, alpha));
+    }
+}
+
+
+// Uniform haze of the given color, denser at the top of the image by `gradient`
+__kernel void fog(__global const uchar* src, __global uchar* dst,
+    const float density, const float gradient, const float r, const float g, const float b,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float color[3] = {r, g, b};
+
+    const float depth = 1.0f - gradient * (float) y / h;
+    const float transmission = exp(-density * depth);
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(src[idx + c] * transmission + color[c] * (1.0f - transmission));
+    }
+}
+
+
+// Glow around the light source (lx, ly) and ghosts along the line through the center of the image
+__kernel void lens_flare(__global const uchar* src, __global uchar* dst,
+    const float lx, const float ly, const float radius, const float intensity,
+    const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+    const float cx = w / 2.0f;
+    const float cy = h / 2.0f;
+
+    const float d = hypot(x - lx, y - ly) / radius;
+    float light = exp(-d * d);
+
+    // ghosts mirrored through the center, with tints of decreasing size
+    const float ghost_pos[3] = {0.5f, 1.2f, 1.8f};
+    const float ghost_size[3] = {0.4f, 0.25f, 0.6f};
+    float tint[3] = {light, light, light};
+    for (int i = 0; i < 3; i++) {
+        const float gx = lx + (cx - lx) * 2.0f * ghost_pos[i];
+        const float gy = ly + (cy - ly) * 2.0f * ghost_pos[i];
+        const float gd = hypot(x - gx, y - gy) / (radius * ghost_size[i]);
+        const float ghost = gd < 1.0f ? 0.25f * (1.0f - gd * gd) : 0.0f;
+        tint[i] += ghost;
+        tint[(i + 1) % 3] += 0.5f * ghost;
+    }
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(src[idx + c] + 255.0f * intensity * tint[c]);
+    }
+}
+
+
+// Darkens the image by up to `strength` from `radius` (relative to the half diagonal) to the corners
+__kernel void vignette(__global const uchar* src, __global uchar* dst,
+    const float strength, const float radius, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    const float r = hypot(x - w / 2.0f, y - h / 2.0f) / hypot(w / 2.0f, h / 2.0f);
+    const float t = clamp((r - radius) / fmax(1.0f - radius, 1e-3f), 0.0f, 1.0f);
+    const float factor = 1.0f - strength * t * t * (3.0f - 2.0f * t);
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(src[idx + c] * factor);
+    }
+}
+
+
+// Copies the image unchanged, for checking the I/O path of the pipelines
+__kernel void identity(__global const uchar* src, __global uchar* dst, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = src[idx + c];
+    }
+}
+
+
+// Mixes each image of the batch with a partner image, inside a box.
+// `params` holds the (partner, weight of the image, box x0, y0, x1, y1) of each image, the box
+// being relative to the image dimentions and the partner being sampled at the same relative position.
+__kernel void batch_mix(__global const uchar* src, __global uchar* dst,
+    __global const int* batch, __global const float* params, const int count, const int w, const int h)
+{
+    BUILTIN_INIT(w, h);
+
+    int k = -1;
+    for (int i = 0; i < count; i++) {
+        if (y >= batch[3 * i] && y < batch[3 * i] + batch[3 * i + 2] && x < batch[3 * i + 1]) {
+            k = i;
+        }
+    }
+
+    const float u = k < 0 ? 0.0f : (x + 0.5f) / batch[3 * k + 1];
+    const float v = k < 0 ? 0.0f : (y - batch[3 * k] + 0.5f) / batch[3 * k + 2];
+    const bool inside = k >= 0
+        && u >= params[6 * k + 2] && v >= params[6 * k + 3] && u < params[6 * k + 4] && v < params[6 * k + 5];
+    if (!inside) {
+        for (int c = 0; c < 3; c++) {
+            dst[idx + c] = src[idx + c];
+        }
+        return;
+    }
+
+    const int j = (int) params[6 * k];
+    const float weight = params[6 * k + 1];
+    const int px = min((int) (u * batch[3 * j + 1]), batch[3 * j + 1] - 1);
+    const int py = batch[3 * j] + min((int) (v * batch[3 * j + 2]), batch[3 * j + 2] - 1);
+    const int pidx = (px + py * w) * 3;
+    for (int c = 0; c < 3; c++) {
+        dst[idx + c] = convert_uchar_sat_rte(mix((float) src[pidx + c], (float) src[idx + c], weight));
+    }
+}
@@ -0,0 +1,842 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Built-in operations, callable from any pipeline without writing opencl code.
+
+
+use std::time::Instant;
+
+use ocl::{Buffer, Kernel};
+use ocl::builders::KernelBuilder;
+
+use rhai::{Engine, Map, Array, INT, FLOAT};
+
+use image::{RgbImage, imageops};
+
+use super::{CScope, Buff, ImageRhaiRef, ImageMetrics, Passthrough};
+
+use crate::{GREEN, CLEAR};
+
+
+pub const BUILTINS_SRC: &str = include_str!("builtins.cl");
+
+
+/// Registers the built-in operations on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("flat_field", CScope::flat_field)
+        .register_fn("resize", CScope::resize)
+        .register_fn("resize", CScope::resize_mode)
+        .register_fn("chroma_key", CScope::chroma_key)
+        .register_fn("random_background", CScope::random_background)
+        .register_fn("seamless_clone", CScope::seamless_clone)
+        .register_fn("seamless_clone", CScope::seamless_clone_iterations)
+        .register_fn("color_transfer", CScope::color_transfer)
+        .register_fn("sensor_noise", CScope::sensor_noise)
+        .register_fn("jpeg_artifacts", CScope::jpeg_artifacts)
+        .register_fn("motion_blur", CScope::motion_blur)
+        .register_fn("rain", CScope::rain)
+        .register_fn("fog", CScope::fog)
+        .register_fn("lens_flare", CScope::lens_flare)
+        .register_fn("vignette", CScope::vignette)
+        .register_fn("mixup", CScope::mixup)
+        .register_fn("cutmix", CScope::cutmix);
+}
+
+
+/// Interpolation conventions of `resize`, matching the libraries the training code may use
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ResizeMode {
+    /// half-pixel centers without antialiasing, like `INTER_LINEAR` of OpenCV
+    Bilinear,
+    /// `INTER_CUBIC` of OpenCV
+    OpencvBicubic,
+    /// `Image.BILINEAR` of Pillow, antialiased when downscaling
+    PillowBilinear,
+    /// `Image.BICUBIC` of Pillow, antialiased when downscaling
+    PillowBicubic,
+    /// average of the covered pixels, like `INTER_AREA` of OpenCV
+    Area,
+    /// `Image.LANCZOS` of Pillow (3 lobes), antialiased when downscaling
+    Lanczos3
+}
+
+
+impl ResizeMode {
+
+
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "bilinear" | "opencv_bilinear" => Ok(Self::Bilinear),
+            "opencv_bicubic" => Ok(Self::OpencvBicubic),
+            "pillow_bilinear" => Ok(Self::PillowBilinear),
+            "pillow_bicubic" => Ok(Self::PillowBicubic),
+            "area" => Ok(Self::Area),
+            "lanczos3" => Ok(Self::Lanczos3),
+            _ => Err(format!("unknown mode `{}` (expected bilinear, opencv_bilinear, opencv_bicubic, \
+                pillow_bilinear, pillow_bicubic, area or lanczos3)", name))
+        }
+    }
+}
+
+
+/// Reads the number `key` of the options of the built-in `name`, or `default` if it is not set
+fn opt_float(opts: &Map, name: &str, key: &str, default: f32) -> f32 {
+    opts.get(key)
+        .map(|v| v.as_float().ok().or_else(|| v.as_int().ok().map(|v| v as FLOAT))
+            .unwrap_or_else(|| panic!("{}: `{}` should be a number", name, key)) as f32)
+        .unwrap_or(default)
+}
+
+
+impl CScope {
+
+
+    /// Returns the device buffer of an image with its current dimentions
+    pub(super) fn image_buffer(&self, img: &ImageRhaiRef) -> (Buffer<u8>, usize, usize) {
+        match self.get_buffers().get(&img.name) {
+            Some(Buff::DynImage(b)) if img.name == "output" => (b.clone(), self.out_size().0, self.out_size().1),
+            Some(Buff::DynImage(b)) => (b.clone(), self.dynimg_size.0, self.dynimg_size.1),
+            Some(Buff::Image(b, w, h)) => (b.clone(), *w as usize, *h as usize),
+            Some(Buff::GrayImage(_)) => panic!("{} is a gray image, which the built-ins do not take", img.name),
+            Some(Buff::RgbaImage(_)) => panic!("{} is an rgba image, which the built-ins do not take", img.name),
+            _ => panic!("There is no image named {}", img.name)
+        }
+    }
+
+
+    /// Returns the device buffer of an image, checking that it has the expected dimentions
+    fn image_buffer_sized(&self, img: &ImageRhaiRef, size: (usize, usize)) -> Buffer<u8> {
+        let (buff, w, h) = self.image_buffer(img);
+        if (w, h) != size {
+            panic!("Image {} is {}x{} but {}x{} was expected", img.name, w, h, size.0, size.1);
+        }
+        buff
+    }
+
+
+    /// Runs the built-in kernel `name` over an image of dimentions `size`.
+    /// `set_args` adds the kernel arguments, except for the image dimentions which are added last.
+    fn enq_builtin<F>(&self, name: &str, size: (usize, usize), set_args: F)
+        where F: FnOnce(&mut KernelBuilder)
+    {
+        if self.trace_kernels {
+            println!("{}builtin{} {} (global work size {}x{})", GREEN, CLEAR, name, size.0, size.1);
+        }
+
+        let mut builder = Kernel::builder();
+        builder.program(&self.builtins)
+            .name(name)
+            .queue(self.prog_queue.queue().clone())
+            .global_work_size(size);
+        set_args(&mut builder);
+
+        let ker = builder.arg(size.0 as i32)
+            .arg(size.1 as i32)
+            .build()
+            .expect("Could not build built-in kernel.");
+
+        let start = Instant::now();
+        unsafe {
+            ker.enq().expect("Could not run built-in kernel.");
+        }
+        self.pace(start);
+    }
+
+
+    /// Reads back an image buffer to the host
+    pub(super) fn read_image_buffer(&self, img: &ImageRhaiRef) -> Vec<u8> {
+        let (buff, w, h) = self.image_buffer(img);
+        let mut pixels = vec![0u8; w * h * 3];
+        buff.read(&mut pixels).enq().unwrap();
+        pixels
+    }
+
+
+    /// Copies an image between the row-major and column-major layouts
+    pub(super) fn transpose(&self, src: &Buffer<u8>, dst: &Buffer<u8>, size: (usize, usize), to_column_major: bool) {
+        self.enq_builtin("transpose", size, |ker| {
+            ker.arg(src.clone())
+                .arg(dst.clone())
+                .arg(to_column_major as i32);
+        });
+    }
+
+
+    /// Copies the input image to the output one instead of running the pipeline
+    pub(super) fn passthrough(&self, mode: Passthrough) {
+        let buffers = self.get_buffers();
+        let (input, output) = match (&buffers["input"], &buffers["output"]) {
+            (Buff::DynImage(input), Buff::DynImage(output)) => (input.clone(), output.clone()),
+            _ => panic!("The input and output images are not dynamic images")
+        };
+        drop(buffers);
+
+        match mode {
+            Passthrough::Copy => input.copy(&output, None, None).enq().expect("Could not copy the input image."),
+            Passthrough::Identity => self.enq_builtin("identity", self.dynimg_size, |ker| {
+                ker.arg(input)
+                    .arg(output);
+            })
+        }
+    }
+
+
+    /// Normalized absolute difference of two images of the same dimentions
+    pub(super) fn diff(&self, a: &RgbImage, b: &RgbImage) -> RgbImage {
+        let size = (a.width() as usize, a.height() as usize);
+        let len = size.0 * size.1 * 3;
+
+        let a_buff = self.scratch_buffer(1, len);
+        let b_buff = self.scratch_buffer(2, len);
+        a_buff.write(a.as_raw()).enq().unwrap();
+        b_buff.write(b.as_raw()).enq().unwrap();
+
+        self.enq_builtin("diff_images", size, |ker| {
+            ker.arg(a_buff.clone())
+                .arg(b_buff.clone());
+        });
+
+        let mut pixels = vec![0u8; len];
+        a_buff.read(&mut pixels).enq().unwrap();
+
+        let max = pixels.iter().copied().max().unwrap_or(0);
+        if max > 0 {
+            for px in pixels.iter_mut() {
+                *px = (*px as u32 * 255 / max as u32) as u8;
+            }
+        }
+
+        RgbImage::from_raw(a.width(), a.height(), pixels).unwrap()
+    }
+
+
+    /// Error metrics between two images of the same dimentions, the per pixel errors being computed on the device
+    pub(super) fn compare(&self, a: &RgbImage, b: &RgbImage) -> ImageMetrics {
+        let size = (a.width() as usize, a.height() as usize);
+        let len = size.0 * size.1 * 3;
+
+        let a_buff = self.scratch_buffer(1, len);
+        let b_buff = self.scratch_buffer(2, len);
+        a_buff.write(a.as_raw()).enq().unwrap();
+        b_buff.write(b.as_raw()).enq().unwrap();
+        let err_buff = Buffer::<f32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len((size.0 * size.1 * 2).max(1))
+            .build()
+            .expect("Could not allocate buffer");
+
+        self.enq_builtin("pair_error", size, |ker| {
+            ker.arg(a_buff.clone())
+                .arg(b_buff.clone())
+                .arg(err_buff.clone());
+        });
+
+        let mut err = vec![0f32; err_buff.len()];
+        err_buff.read(&mut err).enq().unwrap();
+
+        let mut squared = 0f64;
+        let mut max_diff = 0f32;
+        for px in err.chunks(2).take(size.0 * size.1) {
+            squared += px[0] as f64;
+            max_diff = max_diff.max(px[1]);
+        }
+        ImageMetrics {
+            mse: if len > 0 { squared / len as f64 } else { 0.0 },
+            max_diff: max_diff as u8
+        }
+    }
+
+
+    fn enq_resize(&self, src: &Buffer<u8>, src_size: (usize, usize), dst: &Buffer<u8>, size: (usize, usize)) {
+        self.enq_resize_mode(src, src_size, dst, size, ResizeMode::Bilinear);
+    }
+
+
+    fn enq_resize_mode(&self, src: &Buffer<u8>, src_size: (usize, usize), dst: &Buffer<u8>, size: (usize, usize), mode: ResizeMode) {
+        let kernel = match mode {
+            ResizeMode::Bilinear => "resize",
+            ResizeMode::OpencvBicubic => "resize_cubic",
+            ResizeMode::Area => "resize_area",
+            ResizeMode::PillowBilinear | ResizeMode::PillowBicubic | ResizeMode::Lanczos3 => {
+                // (filter, a, support) of the separable passes
+                let filter = match mode {
+                    ResizeMode::PillowBilinear => (0, 0.0f32, 1.0f32),
+                    ResizeMode::PillowBicubic => (1, -0.5, 2.0),
+                    _ => (2, 3.0, 3.0)
+                };
+                return self.enq_resample(src, src_size, dst, size, filter);
+            }
+        };
+
+        self.enq_builtin(kernel, size, |ker| {
+            ker.arg(src.clone())
+                .arg(dst.clone())
+                .arg(src_size.0 as i32)
+                .arg(src_size.1 as i32);
+        });
+    }
+
+
+    /// Separable resize of Pillow, the rows being resampled first and the intermediate image rounded
+    /// to 8 bits like Pillow does. A dimention that does not change is not resampled.
+    fn enq_resample(&self, src: &Buffer<u8>, src_size: (usize, usize), dst: &Buffer<u8>, size: (usize, usize),
+            (filter, a, support): (i32, f32, f32)) {
+        let pass = |from: &Buffer<u8>, to: &Buffer<u8>, src_len: usize, horizontal: bool, pass_size: (usize, usize)| {
+            self.enq_builtin("resample_pass", pass_size, |ker| {
+                ker.arg(from.clone())
+                    .arg(to.clone())
+                    .arg(src_len as i32)
+                    .arg(horizontal as i32)
+                    .arg(filter)
+                    .arg(a)
+                    .arg(support);
+            });
+        };
+
+        match (src_size.0 != size.0, src_size.1 != size.1) {
+            (true, true) => {
+                let rows = self.scratch_buffer(3, size.0 * src_size.1 * 3);
+                pass(src, &rows, src_size.0, true, (size.0, src_size.1));
+                pass(&rows, dst, src_size.1, false, size);
+            }
+            (true, false) => pass(src, dst, src_size.0, true, size),
+            (false, true) => pass(src, dst, src_size.1, false, size),
+            (false, false) => src.copy(dst, None, Some(size.0 * size.1 * 3)).enq().unwrap()
+        }
+    }
+
+
+    /// Bilinear resize of a host image to `size`
+    pub(super) fn resize_image(&self, img: &RgbImage, size: (usize, usize)) -> RgbImage {
+        let src_size = (img.width() as usize, img.height() as usize);
+        let len = size.0 * size.1 * 3;
+
+        let src_buff = self.scratch_buffer(1, src_size.0 * src_size.1 * 3);
+        let dst_buff = self.scratch_buffer(2, len);
+        src_buff.write(img.as_raw()).enq().unwrap();
+
+        self.enq_resize(&src_buff, src_size, &dst_buff, size);
+
+        let mut pixels = vec![0u8; len];
+        dst_buff.read(&mut pixels).enq().unwrap();
+
+        RgbImage::from_raw(size.0 as u32, size.1 as u32, pixels).unwrap()
+    }
+
+
+    /// Bilinear resize of `src` to the dimentions of `dst`
+    fn resize(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef) {
+        self.resize_mode(src, dst, "bilinear");
+    }
+
+
+    /// Resize of `src` to the dimentions of `dst` following the conventions of `mode` (see `ResizeMode`)
+    fn resize_mode(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, mode: &str) {
+        let mode = ResizeMode::parse(mode).unwrap_or_else(|e| panic!("resize: {}", e));
+        let (src_buff, src_w, src_h) = self.image_buffer(&src);
+        let (dst_buff, w, h) = self.image_buffer(&dst);
+        self.enq_resize_mode(&src_buff, (src_w, src_h), &dst_buff, (w, h), mode);
+    }
+
+
+    /// Flat-field and dark-frame correction of `img` into `dst`.
+    /// The calibration frames should be loaded once in `init()`, the mean of `flat - dark`
+    /// being computed the first time they are used.
+    fn flat_field(&mut self, img: ImageRhaiRef, dark: ImageRhaiRef, flat: ImageRhaiRef, dst: ImageRhaiRef) {
+        let (img_buff, w, h) = self.image_buffer(&img);
+        let dark_buff = self.image_buffer_sized(&dark, (w, h));
+        let flat_buff = self.image_buffer_sized(&flat, (w, h));
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let key = (dark.name.clone(), flat.name.clone());
+        let cached = self.flat_field_means.borrow().get(&key).copied();
+        let mean = match cached {
+            Some(mean) => mean,
+            None => {
+                let dark_px = self.read_image_buffer(&dark);
+                let flat_px = self.read_image_buffer(&flat);
+
+                let mut mean = [0f32; 3];
+                for (i, (f, d)) in flat_px.iter().zip(dark_px.iter()).enumerate() {
+                    mean[i % 3] += *f as f32 - *d as f32;
+                }
+                for m in mean.iter_mut() {
+                    *m /= (w * h) as f32;
+                }
+
+                self.flat_field_means.borrow_mut().insert(key, mean);
+                mean
+            }
+        };
+
+        self.enq_builtin("flat_field", (w, h), |ker| {
+            ker.arg(img_buff)
+                .arg(dark_buff)
+                .arg(flat_buff)
+                .arg(dst_buff)
+                .arg(mean[0])
+                .arg(mean[1])
+                .arg(mean[2]);
+        });
+    }
+
+
+    /// Composites the foreground of the chroma key capture `src` over `bg` into `dst`.
+    /// `opts` may set the `key` color (`[r, g, b]`, green by default), and the `tolerance` and
+    /// `softness` of the matte as chroma distances in [0, 1].
+    fn chroma_key(&mut self, src: ImageRhaiRef, bg: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let bg_buff = self.image_buffer_sized(&bg, (w, h));
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let key = match opts.get("key") {
+            Some(key) => {
+                let key = key.read_lock::<Array>().map(|k| k.clone()).unwrap_or_default();
+                let channels: Vec<f32> = key.iter().filter_map(|c| c.as_int().ok()).map(|c| c as f32 / 255.0).collect();
+                if channels.len() != 3 {
+                    panic!("chroma_key: `key` should be [r, g, b]");
+                }
+                [channels[0], channels[1], channels[2]]
+            }
+            None => [0.0, 1.0, 0.0]
+        };
+
+        let key_cb = -0.168736 * key[0] - 0.331264 * key[1] + 0.5 * key[2];
+        let key_cr = 0.5 * key[0] - 0.418688 * key[1] - 0.081312 * key[2];
+
+        self.enq_builtin("chroma_key", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(bg_buff)
+                .arg(dst_buff)
+                .arg(key_cb)
+                .arg(key_cr)
+                .arg(opt_float(&opts, "chroma_key", "tolerance", 0.15))
+                .arg(opt_float(&opts, "chroma_key", "softness", 0.1));
+        });
+    }
+
+
+    /// Fills `dst` with an image of the backgrounds directory (`--backgrounds`), picked with the
+    /// random generator of the pipeline and scaled to cover the image
+    fn random_background(&mut self, dst: ImageRhaiRef) {
+        let (dst_buff, w, h) = self.image_buffer(&dst);
+        if self.backgrounds.is_empty() {
+            panic!("There are no backgrounds (use --backgrounds)");
+        }
+
+        let path = &self.backgrounds[(self.next_random() % self.backgrounds.len() as u64) as usize];
+        let bg = crate::decode::decode_image(path, None).unwrap_or_else(|e| panic!("{}", e));
+
+        // largest region of the background with the aspect ratio of the image
+        let scale = (bg.width() as f32 / w as f32).min(bg.height() as f32 / h as f32);
+        let (crop_w, crop_h) = (((w as f32 * scale) as u32).max(1), ((h as f32 * scale) as u32).max(1));
+        let bg = imageops::crop_imm(&bg, (bg.width() - crop_w) / 2, (bg.height() - crop_h) / 2, crop_w, crop_h).to_image();
+
+        let src_buff = self.scratch_buffer(1, bg.as_raw().len());
+        src_buff.write(bg.as_raw()).enq().unwrap();
+        self.enq_resize(&src_buff, (crop_w as usize, crop_h as usize), &dst_buff, (w, h));
+    }
+
+
+    fn seamless_clone(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, mask: ImageRhaiRef, x: INT, y: INT) {
+        self.seamless_clone_iterations(src, dst, mask, x, y, 500);
+    }
+
+
+    /// Poisson blending of the pixels of `src` selected by `mask` (red channel over 127) into `dst`,
+    /// the top left corner of `src` being placed at (x, y). The blending is solved with `iterations`
+    /// Jacobi iterations, 500 by default.
+    fn seamless_clone_iterations(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, mask: ImageRhaiRef,
+        x: INT, y: INT, iterations: INT)
+    {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let mask_buff = self.image_buffer_sized(&mask, (w, h));
+        let (dst_buff, dst_w, dst_h) = self.image_buffer(&dst);
+
+        let solution = || Buffer::<f32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len(w * h * 3)
+            .build()
+            .expect("Could not allocate buffer");
+        let (mut f_in, mut f_out) = (solution(), solution());
+
+        self.enq_builtin("seamless_init", (w, h), |ker| {
+            ker.arg(src_buff.clone())
+                .arg(f_in.clone());
+        });
+        f_in.copy(&f_out, None, None).enq().unwrap();
+
+        for _ in 0..iterations {
+            self.enq_builtin("seamless_step", (w, h), |ker| {
+                ker.arg(src_buff.clone())
+                    .arg(dst_buff.clone())
+                    .arg(mask_buff.clone())
+                    .arg(f_in.clone())
+                    .arg(f_out.clone())
+                    .arg(x as i32)
+                    .arg(y as i32)
+                    .arg(dst_w as i32)
+                    .arg(dst_h as i32);
+            });
+            std::mem::swap(&mut f_in, &mut f_out);
+        }
+
+        self.enq_builtin("seamless_write", (w, h), |ker| {
+            ker.arg(f_in.clone())
+                .arg(mask_buff.clone())
+                .arg(dst_buff.clone())
+                .arg(x as i32)
+                .arg(y as i32)
+                .arg(dst_w as i32)
+                .arg(dst_h as i32);
+        });
+    }
+
+
+    /// Mean and standard deviation of each L*a*b* channel of an image
+    fn lab_stats(&self, img: &ImageRhaiRef) -> [f32; 6] {
+        let (buff, w, h) = self.image_buffer(img);
+        let lab = Buffer::<f32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len(w * h * 3)
+            .build()
+            .expect("Could not allocate buffer");
+
+        self.enq_builtin("to_lab", (w, h), |ker| {
+            ker.arg(buff)
+                .arg(lab.clone());
+        });
+
+        let mut values = vec![0f32; w * h * 3];
+        lab.read(&mut values).enq().unwrap();
+
+        let mut sum = [0f64; 3];
+        let mut sum_sq = [0f64; 3];
+        for (i, v) in values.iter().enumerate() {
+            sum[i % 3] += *v as f64;
+            sum_sq[i % 3] += *v as f64 * *v as f64;
+        }
+
+        let n = (w * h) as f64;
+        let mut stats = [0f32; 6];
+        for c in 0..3 {
+            let mean = sum[c] / n;
+            stats[c] = mean as f32;
+            stats[3 + c] = (sum_sq[c] / n - mean * mean).max(0.0).sqrt() as f32;
+        }
+        stats
+    }
+
+
+    /// Reinhard color transfer: matches the mean and standard deviation of `src` in L*a*b* to the
+    /// ones of `reference`, writing the result to `dst`.
+    /// The statistics of a reference loaded in `init()` are only computed once.
+    fn color_transfer(&mut self, src: ImageRhaiRef, reference: ImageRhaiRef, dst: ImageRhaiRef) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let is_asset = matches!(self.get_buffers().get(&reference.name), Some(Buff::Image(..)));
+        let cached = self.lab_stats_cache.borrow().get(&reference.name).copied();
+        let ref_stats = match cached {
+            Some(stats) if is_asset => stats,
+            _ => {
+                let stats = self.lab_stats(&reference);
+                if is_asset {
+                    self.lab_stats_cache.borrow_mut().insert(reference.name.clone(), stats);
+                }
+                stats
+            }
+        };
+
+        let mut stats = self.lab_stats(&src).to_vec();
+        stats.extend(ref_stats);
+        let stats_buff = Buffer::<f32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len(stats.len())
+            .copy_host_slice(&stats)
+            .build()
+            .expect("Could not allocate buffer");
+
+        self.enq_builtin("color_transfer", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(stats_buff);
+        });
+    }
+
+
+    /// Adds Poisson-Gaussian sensor noise to `src` into `dst`, seeded by the random generator of the pipeline.
+    /// `opts` may set the `iso` sensitivity (400 by default), or directly the `shot` noise gain and the `read`
+    /// noise standard deviation, for values in [0, 1].
+    fn sensor_noise(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let iso = opt_float(&opts, "sensor_noise", "iso", 400.0) / 100.0;
+        let shot = opt_float(&opts, "sensor_noise", "shot", 0.0025 * iso);
+        let read = opt_float(&opts, "sensor_noise", "read", 0.002 * iso);
+        let seed = (self.next_random() >> 32) as u32;
+
+        self.enq_builtin("sensor_noise", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(shot)
+                .arg(read * read)
+                .arg(seed);
+        });
+    }
+
+
+    /// Simulates compression artifacts by encoding `src` as a JPEG of the given `quality` (1 to 100)
+    /// and decoding it back into `dst`
+    fn jpeg_artifacts(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, quality: INT) {
+        let (_, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let pixels = self.read_image_buffer(&src);
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1, 100) as u8)
+            .encode(&pixels, w as u32, h as u32, image::ColorType::Rgb8)
+            .unwrap_or_else(|e| panic!("jpeg_artifacts: {}", e));
+        let decoded = image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg)
+            .unwrap_or_else(|e| panic!("jpeg_artifacts: {}", e))
+            .into_rgb8();
+
+        dst_buff.write(decoded.as_raw()).enq().unwrap();
+    }
+
+
+    /// Linear motion blur of `src` into `dst`, over `length` pixels in the direction of `angle` degrees
+    fn motion_blur(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, length: FLOAT, angle: FLOAT) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        self.enq_builtin("motion_blur", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(length.max(1.0) as f32)
+                .arg(angle.to_radians() as f32);
+        });
+    }
+
+
+    /// Reads the number `key` of the options of the built-in `name`, or picks it at random in [lo, hi)
+    fn opt_random(&self, opts: &Map, name: &str, key: &str, lo: f32, hi: f32) -> f32 {
+        if opts.contains_key(key) {
+            opt_float(opts, name, key, lo)
+        } else {
+            self.random_range(lo, hi)
+        }
+    }
+
+
+    /// Random float in [lo, hi)
+    fn random_range(&self, lo: f32, hi: f32) -> f32 {
+        lo + (hi - lo) * ((self.next_random() >> 40) as f32 / (1u64 << 24) as f32)
+    }
+
+
+    /// Adds rain streaks to `src` into `dst`. `opts` may set the `density` of the streaks, their `length`
+    /// in pixels, their `angle` from the vertical in degrees and their `brightness` in [0, 1],
+    /// which are otherwise random.
+    fn rain(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let density = self.opt_random(&opts, "rain", "density", 0.001, 0.005);
+        let length = self.opt_random(&opts, "rain", "length", 10.0, 30.0).max(1.0);
+        let angle = self.opt_random(&opts, "rain", "angle", -20.0, 20.0).to_radians();
+        let brightness = self.opt_random(&opts, "rain", "brightness", 0.3, 0.7);
+        let seed = (self.next_random() >> 32) as u32;
+
+        self.enq_builtin("rain", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(density)
+                .arg(length)
+                .arg(angle.sin())
+                .arg(angle.cos())
+                .arg(brightness)
+                .arg(seed);
+        });
+    }
+
+
+    /// Adds haze to `src` into `dst`. `opts` may set its `density`, its vertical `gradient` in [0, 1]
+    /// (denser at the top) and its `color` (`[r, g, b]`), which are otherwise random.
+    fn fog(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let density = self.opt_random(&opts, "fog", "density", 0.2, 1.2);
+        let gradient = self.opt_random(&opts, "fog", "gradient", 0.0, 0.6);
+        let color = match opts.get("color") {
+            Some(color) => {
+                let color = color.read_lock::<Array>().map(|c| c.clone()).unwrap_or_default();
+                let channels: Vec<f32> = color.iter().filter_map(|c| c.as_int().ok()).map(|c| c as f32).collect();
+                if channels.len() != 3 {
+                    panic!("fog: `color` should be [r, g, b]");
+                }
+                [channels[0], channels[1], channels[2]]
+            }
+            None => {
+                let gray = self.random_range(190.0, 240.0);
+                [gray, gray, gray]
+            }
+        };
+
+        self.enq_builtin("fog", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(density)
+                .arg(gradient)
+                .arg(color[0])
+                .arg(color[1])
+                .arg(color[2]);
+        });
+    }
+
+
+    /// Adds a lens flare to `src` into `dst`. `opts` may set the position `x` and `y` of the light source,
+    /// the `radius` of its glow relative to the largest dimension of the image and its `intensity`, which are otherwise random.
+    fn lens_flare(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let x = self.opt_random(&opts, "lens_flare", "x", 0.0, w as f32);
+        let y = self.opt_random(&opts, "lens_flare", "y", 0.0, h as f32 / 2.0);
+        let radius = self.opt_random(&opts, "lens_flare", "radius", 0.05, 0.15) * w.max(h) as f32;
+        let intensity = self.opt_random(&opts, "lens_flare", "intensity", 0.4, 0.9);
+
+        self.enq_builtin("lens_flare", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(x)
+                .arg(y)
+                .arg(radius.max(1.0))
+                .arg(intensity);
+        });
+    }
+
+
+    /// Darkens the corners of `src` into `dst`. `opts` may set the `strength` of the vignette in [0, 1]
+    /// and the `radius` where it starts, relative to the half diagonal, which are otherwise random.
+    fn vignette(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, opts: Map) {
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let strength = self.opt_random(&opts, "vignette", "strength", 0.2, 0.6);
+        let radius = self.opt_random(&opts, "vignette", "radius", 0.3, 0.7);
+
+        self.enq_builtin("vignette", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(strength)
+                .arg(radius);
+        });
+    }
+
+
+    /// MixUp: blends each image of the batch of `src` with another image of the batch into `dst`,
+    /// the weight of the image following a Beta(`alpha`, `alpha`) distribution
+    fn mixup(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, alpha: FLOAT) {
+        self.batch_mix(src, dst, alpha, "mixup");
+    }
+
+
+    /// CutMix: pastes a box of another image of the batch of `src` over each image into `dst`,
+    /// the area of the image left following a Beta(`alpha`, `alpha`) distribution
+    fn cutmix(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, alpha: FLOAT) {
+        self.batch_mix(src, dst, alpha, "cutmix");
+    }
+
+
+    /// Pairs the images of the batch at random and mixes them with the `mixup` or `cutmix` method,
+    /// recording the partner and the mixing coefficient of each image
+    fn batch_mix(&mut self, src: ImageRhaiRef, dst: ImageRhaiRef, alpha: FLOAT, method: &str) {
+        if src.name == dst.name {
+            panic!("{}: the source and destination images should differ", method);
+        }
+        if alpha <= 0.0 {
+            panic!("{}: `alpha` should be positive", method);
+        }
+
+        let (src_buff, w, h) = self.image_buffer(&src);
+        let dst_buff = self.image_buffer_sized(&dst, (w, h));
+
+        let batch = self.batch.borrow().clone();
+        let count = batch.len() / 3;
+        let partners = self.random_permutation(count);
+
+        let mut params = Vec::with_capacity(count * 6);
+        let mut records = Vec::with_capacity(count);
+        for &partner in &partners {
+            let lambda = self.random_beta(alpha) as f32;
+            let (weight, area) = if method == "cutmix" {
+                // box of area 1 - lambda, clipped to the image
+                let cut = (1.0 - lambda).sqrt();
+                let (cx, cy) = (self.random_range(0.0, 1.0), self.random_range(0.0, 1.0));
+                let (x0, y0) = ((cx - cut / 2.0).max(0.0), (cy - cut / 2.0).max(0.0));
+                let (x1, y1) = ((cx + cut / 2.0).min(1.0), (cy + cut / 2.0).min(1.0));
+                (0.0, [x0, y0, x1, y1])
+            } else {
+                (lambda, [0.0, 0.0, 1.0, 1.0])
+            };
+
+            // share of the image in the output
+            let lambda = if method == "cutmix" { 1.0 - (area[2] - area[0]) * (area[3] - area[1]) } else { lambda };
+            params.extend([partner as f32, weight]);
+            params.extend(area);
+
+            let mut record = Map::new();
+            record.insert("mix".into(), method.into());
+            record.insert("partner".into(), (partner as INT).into());
+            record.insert("lambda".into(), (lambda as FLOAT).into());
+            records.push(Some(record));
+        }
+        *self.mix_records.borrow_mut() = records;
+
+        let batch_buff = Buffer::<i32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len(batch.len())
+            .copy_host_slice(&batch)
+            .build()
+            .expect("Could not allocate buffer");
+        let params_buff = Buffer::<f32>::builder()
+            .queue(self.prog_queue.queue().clone())
+            .len(params.len())
+            .copy_host_slice(&params)
+            .build()
+            .expect("Could not allocate buffer");
+
+        self.enq_builtin("batch_mix", (w, h), |ker| {
+            ker.arg(src_buff)
+                .arg(dst_buff)
+                .arg(batch_buff)
+                .arg(params_buff)
+                .arg(count as i32);
+        });
+    }
+}
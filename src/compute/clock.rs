@@ -0,0 +1,115 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Time in the pipeline scripts, for budgets, periodic snapshots or time buckets of the outputs: the
+// wall-clock time in milliseconds since the Unix epoch, the time the run started at, and UTC dates.
+
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rhai::{Engine, Map, INT};
+
+
+static RUN_START: OnceLock<INT> = OnceLock::new();
+
+
+/// Registers the time functions
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("now_ms", now_ms)
+        .register_fn("run_start_ms", run_start_ms)
+        .register_fn("elapsed_ms", elapsed_ms)
+        .register_fn("run_elapsed_ms", run_elapsed_ms)
+        .register_fn("utc_date", utc_date)
+        .register_fn("iso_date", iso_date);
+}
+
+
+/// Milliseconds since the Unix epoch
+pub fn now_ms() -> INT {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as INT).unwrap_or_default()
+}
+
+
+/// Time the run started at, fixed by the first call
+pub fn run_start_ms() -> INT {
+    *RUN_START.get_or_init(now_ms)
+}
+
+
+/// Milliseconds since `since`, a time given by `now_ms`
+fn elapsed_ms(since: INT) -> INT {
+    now_ms() - since
+}
+
+
+/// Milliseconds since the start of the run
+fn run_elapsed_ms() -> INT {
+    now_ms() - run_start_ms()
+}
+
+
+/// Year, month, day, hour, minute, second and millisecond of a time in UTC
+fn utc_date(ms: INT) -> Map {
+    let (year, month, day, secs) = civil(ms);
+    let mut date = Map::new();
+    date.insert("year".into(), year.into());
+    date.insert("month".into(), month.into());
+    date.insert("day".into(), day.into());
+    date.insert("hour".into(), (secs / 3600).into());
+    date.insert("minute".into(), (secs / 60 % 60).into());
+    date.insert("second".into(), (secs % 60).into());
+    date.insert("millisecond".into(), ms.rem_euclid(1000).into());
+    date
+}
+
+
+/// Time in UTC as `YYYY-MM-DDTHH:MM:SSZ`
+fn iso_date(ms: INT) -> String {
+    let (year, month, day, secs) = civil(ms);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+
+/// Date of a time since the Unix epoch, with the seconds since midnight
+fn civil(ms: INT) -> (INT, INT, INT, INT) {
+    let secs = ms.div_euclid(1000);
+    let days = secs.div_euclid(86400);
+
+    // days to proleptic Gregorian dates, by eras of 400 years starting on March 1st
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs.rem_euclid(86400))
+}
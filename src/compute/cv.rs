@@ -0,0 +1,148 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// OpenCV interop (with the `opencv` feature): conversions between the images of the tool and
+// `opencv::core::Mat`, and the `cv(img, name, args)` escape hatch running an OpenCV operation
+// on the host, for the operations that are not built-ins yet.
+//
+// The mats are 8-bit, 3 channels, in the RGB order of the tool (not the BGR order of OpenCV).
+
+
+#[cfg(feature = "opencv")]
+use opencv::core::{Mat, Size, Scalar, CV_8UC3};
+#[cfg(feature = "opencv")]
+use opencv::imgproc;
+#[cfg(feature = "opencv")]
+use opencv::prelude::*;
+
+use rhai::{Engine, Array};
+#[cfg(feature = "opencv")]
+use rhai::FLOAT;
+
+use super::{CScope, ImageRhaiRef};
+
+
+/// Registers `cv` on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("cv", CScope::cv);
+}
+
+
+/// Copies RGB pixels (such as those of an image buffer or of an `RgbImage`) into a new mat
+#[cfg(feature = "opencv")]
+pub fn pixels_to_mat(pixels: &[u8], width: usize, height: usize) -> opencv::Result<Mat> {
+    let mut mat = Mat::new_rows_cols_with_default(height as i32, width as i32, CV_8UC3, Scalar::all(0.0))?;
+    mat.data_bytes_mut()?.copy_from_slice(pixels);
+    Ok(mat)
+}
+
+
+/// Copies the pixels of an 8-bit mat of 3 channels, with its width and height
+#[cfg(feature = "opencv")]
+pub fn mat_to_pixels(mat: &Mat) -> opencv::Result<(Vec<u8>, usize, usize)> {
+    if mat.typ() != CV_8UC3 {
+        return Err(opencv::Error::new(opencv::core::StsUnsupportedFormat, "expected an 8-bit mat of 3 channels"));
+    }
+    // a region of another mat is not continuous, unlike its copy
+    let copy;
+    let mat = if mat.is_continuous() {
+        mat
+    } else {
+        copy = mat.try_clone()?;
+        &copy
+    };
+    Ok((mat.data_bytes()?.to_vec(), mat.cols() as usize, mat.rows() as usize))
+}
+
+
+/// Reads the argument `i` of the operation `name` as a number
+#[cfg(feature = "opencv")]
+fn arg(args: &Array, name: &str, i: usize) -> f64 {
+    args.get(i)
+        .and_then(|v| v.as_float().ok().or_else(|| v.as_int().ok().map(|v| v as FLOAT)))
+        .unwrap_or_else(|| panic!("cv: `{}` expects a number as its argument {}", name, i + 1))
+}
+
+
+/// Runs the OpenCV operation `name` with its numeric `args`, from `src` into `dst`
+#[cfg(feature = "opencv")]
+fn apply(src: &Mat, dst: &mut Mat, name: &str, args: &Array) -> opencv::Result<()> {
+    let square = |i| Size::new(arg(args, name, i) as i32, arg(args, name, i) as i32);
+
+    match name {
+        "medianBlur" => imgproc::median_blur(src, dst, arg(args, name, 0) as i32),
+        "blur" => imgproc::blur_def(src, dst, square(0)),
+        "GaussianBlur" => imgproc::gaussian_blur_def(src, dst, square(0), arg(args, name, 1)),
+        "bilateralFilter" => imgproc::bilateral_filter_def(src, dst,
+            arg(args, name, 0) as i32, arg(args, name, 1), arg(args, name, 2)),
+        "erode" | "dilate" => {
+            let kernel = imgproc::get_structuring_element_def(imgproc::MORPH_RECT, square(0))?;
+            if name == "erode" {
+                imgproc::erode_def(src, dst, &kernel)
+            } else {
+                imgproc::dilate_def(src, dst, &kernel)
+            }
+        }
+        "threshold" => imgproc::threshold(src, dst, arg(args, name, 0), arg(args, name, 1), imgproc::THRESH_BINARY).map(|_| ()),
+        "Canny" => {
+            let mut edges = Mat::default();
+            imgproc::canny_def(src, &mut edges, arg(args, name, 0), arg(args, name, 1))?;
+            imgproc::cvt_color_def(&edges, dst, imgproc::COLOR_GRAY2RGB)
+        }
+        _ => panic!("cv: unsupported operation `{}` (supported: medianBlur, blur, GaussianBlur, \
+            bilateralFilter, erode, dilate, threshold, Canny)", name)
+    }
+}
+
+
+impl CScope {
+
+
+    /// Runs the OpenCV operation `name` on `img` in place, such as `cv(img, "medianBlur", [5])`
+    #[cfg(feature = "opencv")]
+    fn cv(&mut self, img: ImageRhaiRef, name: &str, args: Array) {
+        let (buff, w, h) = self.image_buffer(&img);
+        let src = pixels_to_mat(&self.read_image_buffer(&img), w, h)
+            .unwrap_or_else(|e| panic!("cv: {}", e));
+
+        let mut dst = Mat::default();
+        apply(&src, &mut dst, name, &args).unwrap_or_else(|e| panic!("cv: {}: {}", name, e));
+
+        let (pixels, dst_w, dst_h) = mat_to_pixels(&dst).unwrap_or_else(|e| panic!("cv: {}: {}", name, e));
+        if (dst_w, dst_h) != (w, h) {
+            panic!("cv: `{}` changed the dimentions of {} to {}x{}", name, img.name, dst_w, dst_h);
+        }
+        buff.write(&pixels).enq().unwrap();
+    }
+
+
+    #[cfg(not(feature = "opencv"))]
+    fn cv(&mut self, _img: ImageRhaiRef, name: &str, _args: Array) {
+        panic!("cv: running `{}` requires building with the `opencv` feature", name);
+    }
+}
@@ -0,0 +1,121 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Script debugging: with `--debug-script`, the run of the first image prints each statement of
+// the pipeline as it executes, the variables it changes and the kernels it calls.
+
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use rhai::{Engine, Dynamic, Scope, Position};
+use rhai::{ASTNode, Stmt};
+use rhai::debugger::{DebuggerCommand, DebuggerEvent};
+
+use super::{BufferRhaiRef, ImageRhaiRef};
+use crate::{GREEN, CLEAR};
+
+
+/// Longest printed value of a variable
+const MAX_VALUE_LEN: usize = 60;
+
+
+/// Registers the debugger printing the statements of the pipeline while `active` is set,
+/// `source` being the text of the pipeline for showing the statements
+pub fn register(eng: &mut Engine, source: Option<String>, active: Rc<Cell<bool>>) {
+    let lines: Vec<String> = source.map(|s| s.lines().map(str::to_owned).collect()).unwrap_or_default();
+    // values of the variables in the scope of each function call level, as printed, at the previous statement
+    let known = RefCell::new(Vec::<Vec<(String, String)>>::new());
+
+    #[allow(deprecated)] // volatile, but not deprecated
+    eng.register_debugger(|| Dynamic::UNIT, move |context, event, node, _source, pos| {
+        if !active.get() {
+            return Ok(DebuggerCommand::Continue);
+        }
+
+        let mut known = known.borrow_mut();
+        // each function call has a scope of its own
+        known.truncate(context.call_level() + 1);
+        known.resize_with(context.call_level() + 1, Vec::new);
+        // the variables pushed by the host are not changes of the script
+        if let DebuggerEvent::Start = event {
+            *known.last_mut().unwrap() = snapshot(context.scope());
+        }
+
+        // expressions are stepped into for the statements of the functions they call
+        match node {
+            ASTNode::Stmt(Stmt::Block(..) | Stmt::Noop(..)) => return Ok(DebuggerCommand::StepInto),
+            ASTNode::Stmt(_) => print_changes(context.scope(), known.last_mut().unwrap()),
+            _ => return Ok(DebuggerCommand::StepInto)
+        }
+        print_statement(&lines, pos);
+        Ok(DebuggerCommand::StepInto)
+    });
+}
+
+
+fn print_statement(lines: &[String], pos: Position) {
+    match pos.line() {
+        Some(line) => println!("{}line {:>4}{}  {}", GREEN, line, CLEAR,
+            lines.get(line - 1).map(|l| l.trim()).unwrap_or_default()),
+        None => println!("{}statement{}", GREEN, CLEAR)
+    }
+}
+
+
+fn snapshot(scope: &Scope) -> Vec<(String, String)> {
+    scope.iter_raw().map(|(name, _, value)| (name.to_owned(), describe(value))).collect()
+}
+
+
+/// Prints the variables declared or assigned since the previous statement
+fn print_changes(scope: &Scope, known: &mut Vec<(String, String)>) {
+    let current = snapshot(scope);
+    for (i, var) in current.iter().enumerate() {
+        if known.get(i) != Some(var) {
+            println!("  {} = {}", var.0, var.1);
+        }
+    }
+    *known = current;
+}
+
+
+/// Short description of a value, buffers and images by their name
+fn describe(value: &Dynamic) -> String {
+    if let Some(buffer) = value.read_lock::<BufferRhaiRef>() {
+        return format!("buffer `{}`", buffer.name);
+    }
+    if let Some(img) = value.read_lock::<ImageRhaiRef>() {
+        return format!("image `{}` ({}x{})", img.name, img.width, img.height);
+    }
+    let text = if value.is::<String>() { format!("{:?}", value.to_string()) } else { value.to_string() };
+    match text.char_indices().nth(MAX_VALUE_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text
+    }
+}
@@ -0,0 +1,177 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Declaration of what a pipeline expects, returned as a map by its optional `declare()` function:
+//
+//     fn declare() {
+//         #{
+//             source: "classes",      // "image" (default), "directory", "classes" or "none"
+//             batch: true,            // the pipeline mixes the images of a batch
+//             buffers: ["weights"],   // buffers and images init() creates
+//             config: ["strength"],   // keys the configuration has to give
+//             format: #{ channels: 1, color: "linear" }   // pixel format (see negotiate.rs), 4 channels for rgba
+//                                                         // and native_gray: true for one-channel gray inputs
+//         }
+//     }
+//
+// The buffers and the configuration are checked once init() ran, the source by the runner before
+// starting.
+
+
+use rhai::{Map, Array, Dynamic};
+
+use crate::negotiate::PipelineFormat;
+
+
+/// Kind of source of the images
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceKind {
+    /// any source of images
+    Image,
+    /// several images: a directory or a source with a scheme
+    Directory,
+    /// a directory of class folders, processed with --classes
+    Classes,
+    /// no source, the images are generated
+    None
+}
+
+
+impl SourceKind {
+
+
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "image" => Ok(SourceKind::Image),
+            "directory" => Ok(SourceKind::Directory),
+            "classes" => Ok(SourceKind::Classes),
+            "none" => Ok(SourceKind::None),
+            _ => Err(format!("unknown source `{}` (expected image, directory, classes or none)", name))
+        }
+    }
+
+
+    /// Whether the provided source can be given to a pipeline declaring `self`
+    fn accepts(self, provided: SourceKind) -> bool {
+        match self {
+            SourceKind::Image => provided != SourceKind::None,
+            SourceKind::Directory => provided == SourceKind::Directory || provided == SourceKind::Classes,
+            SourceKind::Classes => provided == SourceKind::Classes,
+            SourceKind::None => provided == SourceKind::None
+        }
+    }
+
+
+    fn describe(self) -> &'static str {
+        match self {
+            SourceKind::Image => "an image source",
+            SourceKind::Directory => "a directory",
+            SourceKind::Classes => "a directory of class folders (--classes)",
+            SourceKind::None => "no source (--generate)"
+        }
+    }
+}
+
+
+/// Inputs and outputs declared by a pipeline, everything being accepted without a declaration
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub source: SourceKind,
+    pub batch: bool,
+    pub buffers: Vec<String>,
+    pub config: Vec<String>,
+    pub format: PipelineFormat
+}
+
+
+impl Default for Declaration {
+
+    fn default() -> Self {
+        Self {
+            source: SourceKind::Image,
+            batch: false,
+            buffers: Vec::new(),
+            config: Vec::new(),
+            format: PipelineFormat::default()
+        }
+    }
+}
+
+
+impl Declaration {
+
+
+    /// Reads the map returned by `declare()`
+    pub fn parse(map: &Map) -> Result<Self, String> {
+        let mut declaration = Self::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "source" => {
+                    let name = value.clone().into_string().map_err(|t| format!("`source` should be a string, not {}", t))?;
+                    declaration.source = SourceKind::parse(&name)?;
+                }
+                "batch" => {
+                    declaration.batch = value.as_bool().map_err(|t| format!("`batch` should be a bool, not {}", t))?;
+                }
+                "buffers" => declaration.buffers = string_list(value, "buffers")?,
+                "config" => declaration.config = string_list(value, "config")?,
+                "format" => {
+                    let format = value.read_lock::<Map>().ok_or_else(|| String::from("`format` should be a map"))?;
+                    declaration.format = PipelineFormat::parse(&format)?;
+                }
+                _ => return Err(format!("unknown key `{}` (expected source, batch, buffers, config or format)", key))
+            }
+        }
+        Ok(declaration)
+    }
+
+
+    /// Checks the source and the batching given by the runner against the declaration
+    pub fn check(&self, source: SourceKind, batch: bool) -> Result<(), String> {
+        if !self.source.accepts(source) {
+            return Err(format!("The pipeline expects {}, but is given {}", self.source.describe(), source.describe()));
+        }
+        if self.batch && !batch {
+            return Err(String::from("The pipeline processes batches of images, run it with --batch"));
+        }
+        // the transparency is given for a single input
+        if self.format.channels == 4 && batch {
+            return Err(String::from("The rgba pipelines cannot process batches of images"));
+        }
+        Ok(())
+    }
+}
+
+
+fn string_list(value: &Dynamic, key: &str) -> Result<Vec<String>, String> {
+    let error = || format!("`{}` should be an array of strings", key);
+    value.read_lock::<Array>()
+        .ok_or_else(error)?
+        .iter()
+        .map(|v| v.clone().into_string().map_err(|_| error()))
+        .collect()
+}
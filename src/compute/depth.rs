@@ -0,0 +1,266 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Float outputs: a pipeline can compute its output in a float buffer, mapped to the 8-bit output
+// image when it is read back, by clipping, normalizing or dithering the values. A deep output
+// (`ocl.set_deep_output(buffer, depth)`) is saved with 16 bits or as floats per channel instead.
+
+
+use clap::ValueEnum;
+use image::{DynamicImage, ImageBuffer, RgbImage};
+use rhai::{Engine, FLOAT};
+
+use super::{CScope, Buff, BufferRhaiRef, Layout};
+
+
+/// Mapping of the float outputs to 8-bit values
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum FloatMapping {
+    /// Scale the values and clip them to 0-255
+    Clip,
+    /// Stretch the range of the values of each output to 0-255
+    Normalize,
+    /// Scale the values and add an ordered dither before rounding them, against banding
+    Dither
+}
+
+
+/// Float buffer holding the output of the current run, with its mapping and scale
+#[derive(Clone)]
+pub(super) struct FloatOutput {
+    buffer: String,
+    mapping: FloatMapping,
+    scale: f32
+}
+
+
+/// Pixel format of a deep output
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Depth {
+    U16,
+    F32
+}
+
+
+/// Buffer holding the deep output of the current run, with its depth and the scale of its values
+#[derive(Clone)]
+pub(super) struct DeepOutput {
+    buffer: String,
+    depth: Depth,
+    scale: Option<f32>
+}
+
+
+/// Thresholds of the 4x4 Bayer matrix, in sixteenths
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0]
+];
+
+
+/// Registers the float output functions on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("set_float_output", CScope::set_float_output)
+        .register_fn("set_float_output", CScope::set_float_output_mapping)
+        .register_fn("set_float_output", CScope::set_float_output_scaled)
+        .register_fn("set_deep_output", CScope::set_deep_output)
+        .register_fn("set_deep_output", CScope::set_deep_output_scaled);
+}
+
+
+fn parse_depth(depth: &str) -> Depth {
+    match depth {
+        "u16" => Depth::U16,
+        "f32" => Depth::F32,
+        _ => panic!("Unknown output depth `{}` (expected u16 or f32)", depth)
+    }
+}
+
+
+/// The `channels` values of the pixels of a `size` image in row-major order, from the layout of the pipeline
+pub(super) fn row_major<T: Copy>(values: Vec<T>, size: (usize, usize), channels: usize, layout: Layout) -> Vec<T> {
+    if layout == Layout::RowMajor {
+        return values;
+    }
+    let (w, h) = size;
+    let mut rows = values.clone();
+    for x in 0..w {
+        for y in 0..h {
+            let (src, dst) = ((x * h + y) * channels, (y * w + x) * channels);
+            rows[dst..dst + channels].copy_from_slice(&values[src..src + channels]);
+        }
+    }
+    rows
+}
+
+
+fn parse_mapping(mapping: &str) -> FloatMapping {
+    FloatMapping::from_str(mapping, false)
+        .unwrap_or_else(|_| panic!("Unknown float mapping `{}` (expected clip, normalize or dither)", mapping))
+}
+
+
+/// Maps the float values of a `width` wide image to 8-bit values
+fn map_values(values: &[f32], width: usize, mapping: FloatMapping, scale: f32) -> Vec<u8> {
+    match mapping {
+        FloatMapping::Clip => values.iter().map(|v| (v * scale).round().clamp(0.0, 255.0) as u8).collect(),
+        FloatMapping::Normalize => {
+            let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let range = if max > min { max - min } else { 1.0 };
+            values.iter().map(|v| ((v - min) / range * 255.0).round() as u8).collect()
+        }
+        FloatMapping::Dither => values.iter().enumerate().map(|(i, v)| {
+            let (x, y) = (i / 3 % width, i / 3 / width);
+            let threshold = (BAYER_4X4[y % 4][x % 4] + 0.5) / 16.0 - 0.5;
+            (v * scale + threshold).round().clamp(0.0, 255.0) as u8
+        }).collect()
+    }
+}
+
+
+impl CScope {
+
+
+    /// Takes the output of the current run from a float buffer of `width * height * 3` values,
+    /// mapped as set on the command line
+    fn set_float_output(&mut self, buffer: BufferRhaiRef) {
+        let (mapping, scale) = self.float_mapping;
+        self.float_output_from(buffer, mapping, scale);
+    }
+
+
+    /// Takes the output of the current run from a float buffer, mapped with `mapping`
+    /// (`clip`, `normalize` or `dither`)
+    fn set_float_output_mapping(&mut self, buffer: BufferRhaiRef, mapping: &str) {
+        let scale = self.float_mapping.1;
+        self.float_output_from(buffer, parse_mapping(mapping), scale);
+    }
+
+
+    /// Takes the output of the current run from a float buffer, mapped with `mapping` after
+    /// multiplying the values by `scale`
+    fn set_float_output_scaled(&mut self, buffer: BufferRhaiRef, mapping: &str, scale: FLOAT) {
+        self.float_output_from(buffer, parse_mapping(mapping), scale as f32);
+    }
+
+
+    fn float_output_from(&mut self, buffer: BufferRhaiRef, mapping: FloatMapping, scale: f32) {
+        let len = self.out_size().0 * self.out_size().1 * 3;
+        if (buffer.size as usize) < len {
+            panic!("The float output `{}` holds {} values but the output has {}", buffer.name, buffer.size, len);
+        }
+        *self.float_output.borrow_mut() = Some(FloatOutput {
+            buffer: buffer.name,
+            mapping,
+            scale
+        });
+    }
+
+
+    /// Reads the float output of the current run as 8-bit pixels in row-major order, if there is one
+    pub(super) fn read_float_output(&self) -> Option<Vec<u8>> {
+        let output = self.float_output.borrow().clone()?;
+        let (w, h) = self.out_size();
+        let mut values = vec![0.0f32; w * h * 3];
+        match &self.get_buffers()[&output.buffer] {
+            Buff::FloatBuffer(b) => b.read(&mut values).enq().unwrap(),
+            _ => panic!("`{}` is not a float buffer", output.buffer)
+        }
+
+        let values = row_major(values, (w, h), 3, self.layout.get());
+        Some(map_values(&values, w, output.mapping, output.scale))
+    }
+
+
+    /// Takes the output of the current run from a buffer of `width * height * 3` values, saved with
+    /// 16 bits (`u16`) or as floats (`f32`) per channel. The values of a float buffer are multiplied
+    /// by 65535 for `u16`, 0 to 1 spanning the 16 bits, and kept as they are otherwise.
+    fn set_deep_output(&mut self, buffer: BufferRhaiRef, depth: &str) {
+        self.deep_output_from(buffer, parse_depth(depth), None);
+    }
+
+
+    /// Like `set_deep_output`, multiplying the values by `scale`
+    fn set_deep_output_scaled(&mut self, buffer: BufferRhaiRef, depth: &str, scale: FLOAT) {
+        self.deep_output_from(buffer, parse_depth(depth), Some(scale as f32));
+    }
+
+
+    fn deep_output_from(&mut self, buffer: BufferRhaiRef, depth: Depth, scale: Option<f32>) {
+        let len = self.out_size().0 * self.out_size().1 * 3;
+        if (buffer.size as usize) < len {
+            panic!("The deep output `{}` holds {} values but the output has {}", buffer.name, buffer.size, len);
+        }
+        match (self.get_buffers().get(&buffer.name), depth) {
+            (Some(Buff::FloatBuffer(_)), _) | (Some(Buff::IntBuffer(_)), Depth::U16) => (),
+            (Some(Buff::IntBuffer(_)), Depth::F32) => panic!("The f32 output `{}` should be a float buffer", buffer.name),
+            _ => panic!("There is no buffer named {}", buffer.name)
+        }
+        *self.deep_output.borrow_mut() = Some(DeepOutput {
+            buffer: buffer.name,
+            depth,
+            scale
+        });
+    }
+
+
+    /// Reads the deep output of the current run in row-major order, if there is one, with its values
+    /// normalized to 8 bits for the uses of the output on the host (thumbnails, comparisons)
+    pub(super) fn read_deep_output(&self) -> Option<(DynamicImage, RgbImage)> {
+        let output = self.deep_output.borrow().clone()?;
+        let (w, h) = self.out_size();
+        let (values, scale) = match &self.get_buffers()[&output.buffer] {
+            Buff::FloatBuffer(b) => {
+                let mut values = vec![0.0f32; w * h * 3];
+                b.read(&mut values).enq().unwrap();
+                (values, if output.depth == Depth::U16 { 65535.0 } else { 1.0 })
+            }
+            Buff::IntBuffer(b) => {
+                let mut values = vec![0i32; w * h * 3];
+                b.read(&mut values).enq().unwrap();
+                (values.into_iter().map(|v| v as f32).collect(), 1.0)
+            }
+            _ => panic!("`{}` is not a buffer", output.buffer)
+        };
+        let values = row_major(values, (w, h), 3, self.layout.get());
+        let scale = output.scale.unwrap_or(scale);
+
+        let (w32, h32) = (w as u32, h as u32);
+        let img = match output.depth {
+            Depth::U16 => DynamicImage::ImageRgb16(ImageBuffer::from_raw(w32, h32,
+                values.iter().map(|v| (v * scale).round().clamp(0.0, 65535.0) as u16).collect()).unwrap()),
+            Depth::F32 => DynamicImage::ImageRgb32F(ImageBuffer::from_raw(w32, h32,
+                values.iter().map(|v| v * scale).collect()).unwrap())
+        };
+        let preview = RgbImage::from_raw(w32, h32, map_values(&values, w, FloatMapping::Normalize, 1.0)).unwrap();
+        Some((img, preview))
+    }
+}
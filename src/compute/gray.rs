@@ -0,0 +1,98 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Gray images: images of one byte per pixel of the dimentions of the dynamic images, for the
+// masks and edge maps. A gray output (`ocl.set_gray_output(image)`) is saved as a one-channel file.
+
+
+use image::{GrayImage, RgbImage};
+use rhai::Engine;
+
+use ocl::Buffer;
+
+use super::{CScope, Buff, ImageRhaiRef, depth};
+
+
+/// Registers the gray output function on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("set_gray_output", CScope::set_gray_output);
+}
+
+
+/// Whether every pixel of an image is gray, as the gray sources are once decoded to rgb. The gray
+/// inputs are uploaded with one channel to the pipelines declaring `native_gray`.
+pub fn is_gray(img: &RgbImage) -> bool {
+    img.pixels().all(|p| p[0] == p[1] && p[1] == p[2])
+}
+
+
+impl CScope {
+
+
+    /// Creates a gray image of the dimentions of the dynamic images, sent to the kernels as its buffer alone
+    pub(super) fn create_gray_image(&mut self, name: String) {
+        let queue = self.prog_queue.queue().clone();
+        let len = self.dynimg_alloc.0 * self.dynimg_alloc.1;
+        self.get_buffers_mut().insert(name, Buff::GrayImage(Buffer::<u8>::builder()
+            .queue(queue)
+            .len(len)
+            .build()
+            .expect("Could not allocate buffer")));
+    }
+
+
+    /// Takes the output of the current run from a gray image, saved as a one-channel file
+    fn set_gray_output(&mut self, img: ImageRhaiRef) {
+        if !matches!(self.get_buffers().get(&img.name), Some(Buff::GrayImage(_))) {
+            panic!("`{}` is not a gray image", img.name);
+        }
+        *self.gray_output.borrow_mut() = Some(img.name);
+    }
+
+
+    /// Reads back a gray image in row-major order
+    pub(super) fn read_gray_image(&self, name: &str) -> GrayImage {
+        let (w, h) = self.dynimg_size;
+        let mut pixels = vec![0u8; w * h];
+        match &self.get_buffers()[name] {
+            Buff::GrayImage(b) => b.read(&mut pixels).enq().unwrap(),
+            _ => panic!("`{}` is not a gray image", name)
+        }
+
+        // the built-in transpose kernel works on rgb pixels
+        let pixels = depth::row_major(pixels, (w, h), 1, self.layout.get());
+        GrayImage::from_raw(w as u32, h as u32, pixels).unwrap()
+    }
+
+
+    /// Reads the gray output of the current run, if there is one
+    pub(super) fn read_gray_output(&self) -> Option<GrayImage> {
+        let name = self.gray_output.borrow().clone()?;
+        Some(self.read_gray_image(&name))
+    }
+}
@@ -0,0 +1,173 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Caching of the buffers computed by `init()`: `ocl.cache_buffer(buffer)` writes a buffer to the
+// directory of `--init-cache`, and `ocl.load_cached(name)` creates it back from there on the next
+// runs, the files being keyed by a hash of the program, the pipeline, its configuration after the
+// expansion of the variables, its parameters and the image dimentions. The assets read by `init()`
+// are not part of the key.
+
+
+use std::path::{Path, PathBuf};
+
+use ocl::Buffer;
+use rhai::{Engine, Map};
+use sha2::{Sha256, Digest};
+
+use super::{CScope, Buff, BufferRhaiRef};
+
+
+/// Directory of the cached buffers, with the key of the pipeline
+pub(super) struct InitCache {
+    dir: PathBuf,
+    key: String
+}
+
+
+impl InitCache {
+
+
+    /// Cache of the pipeline of `version` (the hash of its program, script and configuration text),
+    /// `config` being its configuration once expanded
+    pub fn new(dir: &Path, version: &str, config: &Map, params: &[(String, String)], size: (usize, usize)) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(version.as_bytes());
+        // the maps are ordered by key
+        hasher.update(format!("{:?}", config).as_bytes());
+        for (name, value) in params {
+            hasher.update(format!("\0{}={}", name, value).as_bytes());
+        }
+        hasher.update((size.0 as u64).to_le_bytes());
+        hasher.update((size.1 as u64).to_le_bytes());
+
+        let key = format!("{:x}", hasher.finalize());
+        Self { dir: dir.to_path_buf(), key: key[..16].to_string() }
+    }
+
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}.buf", name, self.key))
+    }
+}
+
+
+/// Registers the caching functions on the `Ocl` type of the `init()` engine
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("load_cached", CScope::load_cached)
+        .register_fn("cache_buffer", CScope::cache_buffer);
+}
+
+
+/// Checks that a buffer name can be used in a file name
+fn check_name(name: &str) {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        panic!("The buffer `{}` cannot be cached (expected letters, digits, `_` and `-` in its name)", name);
+    }
+}
+
+
+impl CScope {
+
+
+    /// Creates the buffer `name` from the cache, returning whether it was there.
+    /// Always false without `--init-cache`.
+    fn load_cached(&mut self, name: String) -> bool {
+        check_name(&name);
+        let path = match &self.init_cache {
+            Some(cache) => cache.path(&name),
+            None => return false
+        };
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => return false
+        };
+        if bytes.len() < 5 || (bytes.len() - 1) % 4 != 0 {
+            eprintln!("Warning: ignoring the cached buffer `{}`: the file is truncated", path.display());
+            return false;
+        }
+
+        let words = bytes[1..].chunks_exact(4).map(|w| [w[0], w[1], w[2], w[3]]);
+        let queue = self.prog_queue.queue().clone();
+        let buff = match bytes[0] {
+            b'i' => {
+                let data: Vec<i32> = words.map(i32::from_le_bytes).collect();
+                Buff::IntBuffer(Buffer::builder().queue(queue).len(data.len()).copy_host_slice(&data)
+                    .build().expect("Could not allocate buffer"))
+            }
+            b'f' => {
+                let data: Vec<f32> = words.map(f32::from_le_bytes).collect();
+                Buff::FloatBuffer(Buffer::builder().queue(queue).len(data.len()).copy_host_slice(&data)
+                    .build().expect("Could not allocate buffer"))
+            }
+            _ => {
+                eprintln!("Warning: ignoring the cached buffer `{}`: unknown buffer type", path.display());
+                return false;
+            }
+        };
+        self.get_buffers_mut().insert(name, buff);
+        true
+    }
+
+
+    /// Writes `buffer` to the cache, for `load_cached` to create it back on the next runs.
+    /// Does nothing without `--init-cache`.
+    fn cache_buffer(&mut self, buffer: BufferRhaiRef) {
+        check_name(&buffer.name);
+        let cache = match &self.init_cache {
+            Some(cache) => cache,
+            None => return
+        };
+
+        let mut bytes = Vec::with_capacity(1 + buffer.size.max(0) as usize * 4);
+        match &self.get_buffers()[&buffer.name] {
+            Buff::IntBuffer(b) => {
+                let mut values = vec![0i32; b.len()];
+                b.read(&mut values).enq().unwrap();
+                bytes.push(b'i');
+                bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            }
+            Buff::FloatBuffer(b) => {
+                let mut values = vec![0f32; b.len()];
+                b.read(&mut values).enq().unwrap();
+                bytes.push(b'f');
+                bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            }
+            _ => panic!("Only the int and float buffers can be cached, `{}` is an image", buffer.name)
+        }
+
+        // written aside then renamed, so that an interrupted run leaves no partial buffer
+        let path = cache.path(&buffer.name);
+        let part = path.with_extension("part");
+        let written = std::fs::create_dir_all(&cache.dir)
+            .and_then(|_| std::fs::write(&part, &bytes))
+            .and_then(|_| std::fs::rename(&part, &path));
+        if let Err(e) = written {
+            eprintln!("Warning: could not cache the buffer `{}` in `{}`: {}", buffer.name, cache.dir.display(), e);
+        }
+    }
+}
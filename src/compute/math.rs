@@ -0,0 +1,199 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Numeric helpers for the scripts computing kernel parameters on the host: clamping and
+// interpolation, 3x3 matrices given as arrays of 9 numbers in row-major order, and statistics
+// over arrays of numbers.
+
+
+use rhai::{Engine, Array, Dynamic, FLOAT, INT};
+
+
+/// Registers the math functions
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("clamp", clamp)
+        .register_fn("clamp", clamp_int)
+        .register_fn("lerp", lerp)
+        .register_fn("smoothstep", smoothstep)
+        .register_fn("mat3_mul", mat3_mul)
+        .register_fn("mat3_apply", mat3_apply)
+        .register_fn("mat3_invert", mat3_invert)
+        .register_fn("sum", sum)
+        .register_fn("mean", mean)
+        .register_fn("variance", variance)
+        .register_fn("std_dev", std_dev)
+        .register_fn("median", median)
+        .register_fn("min", min)
+        .register_fn("max", max);
+}
+
+
+fn clamp(x: FLOAT, lo: FLOAT, hi: FLOAT) -> FLOAT {
+    x.max(lo).min(hi)
+}
+
+
+fn clamp_int(x: INT, lo: INT, hi: INT) -> INT {
+    x.max(lo).min(hi)
+}
+
+
+/// `a` for `t = 0` and `b` for `t = 1`
+fn lerp(a: FLOAT, b: FLOAT, t: FLOAT) -> FLOAT {
+    a + (b - a) * t
+}
+
+
+/// Smooth transition from 0 below `edge0` to 1 above `edge1`
+fn smoothstep(edge0: FLOAT, edge1: FLOAT, x: FLOAT) -> FLOAT {
+    let t = clamp((x - edge0) / (edge1 - edge0), 0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+/// Product of two 3x3 matrices
+fn mat3_mul(a: Array, b: Array) -> Array {
+    let (a, b) = (matrix(&a), matrix(&b));
+    let mut m = [0.0; 9];
+    for i in 0..3 {
+        for j in 0..3 {
+            m[3 * i + j] = (0..3).map(|k| a[3 * i + k] * b[3 * k + j]).sum();
+        }
+    }
+    to_array(&m)
+}
+
+
+/// Product of a 3x3 matrix and a vector of 3 numbers
+fn mat3_apply(m: Array, v: Array) -> Array {
+    let m = matrix(&m);
+    let v = numbers(&v);
+    if v.len() != 3 {
+        panic!("Expected a vector of 3 numbers, got {}", v.len());
+    }
+    to_array(&[0, 1, 2].map(|i| m[3 * i] * v[0] + m[3 * i + 1] * v[1] + m[3 * i + 2] * v[2]))
+}
+
+
+/// Inverse of a 3x3 matrix
+fn mat3_invert(m: Array) -> Array {
+    let m = matrix(&m);
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[3 * r0 + c0] * m[3 * r1 + c1] - m[3 * r0 + c1] * m[3 * r1 + c0]
+    };
+    let det = m[0] * cofactor(0, 0) + m[1] * cofactor(0, 1) + m[2] * cofactor(0, 2);
+    if det.abs() < FLOAT::EPSILON {
+        panic!("The matrix {:?} cannot be inverted", m);
+    }
+
+    // the inverse is the transposed matrix of the cofactors over the determinant
+    let mut inv = [0.0; 9];
+    for i in 0..3 {
+        for j in 0..3 {
+            inv[3 * j + i] = cofactor(i, j) / det;
+        }
+    }
+    to_array(&inv)
+}
+
+
+fn sum(values: Array) -> FLOAT {
+    numbers(&values).iter().sum()
+}
+
+
+fn mean(values: Array) -> FLOAT {
+    let values = non_empty(&values);
+    values.iter().sum::<FLOAT>() / values.len() as FLOAT
+}
+
+
+/// Population variance
+fn variance(values: Array) -> FLOAT {
+    let values = non_empty(&values);
+    let mean = values.iter().sum::<FLOAT>() / values.len() as FLOAT;
+    values.iter().map(|v| (v - mean) * (v - mean)).sum::<FLOAT>() / values.len() as FLOAT
+}
+
+
+fn std_dev(values: Array) -> FLOAT {
+    variance(values).sqrt()
+}
+
+
+fn median(values: Array) -> FLOAT {
+    let mut values = non_empty(&values);
+    values.sort_by(FLOAT::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] }
+}
+
+
+fn min(values: Array) -> FLOAT {
+    non_empty(&values).into_iter().fold(FLOAT::INFINITY, FLOAT::min)
+}
+
+
+fn max(values: Array) -> FLOAT {
+    non_empty(&values).into_iter().fold(FLOAT::NEG_INFINITY, FLOAT::max)
+}
+
+
+/// Values of an array of ints and floats
+fn numbers(values: &Array) -> Vec<FLOAT> {
+    values.iter().map(number).collect()
+}
+
+
+fn number(value: &Dynamic) -> FLOAT {
+    value.as_float()
+        .or_else(|_| value.as_int().map(|i| i as FLOAT))
+        .unwrap_or_else(|t| panic!("Expected a number, got a value of type {}", t))
+}
+
+
+fn non_empty(values: &Array) -> Vec<FLOAT> {
+    if values.is_empty() {
+        panic!("Expected a non-empty array of numbers");
+    }
+    numbers(values)
+}
+
+
+/// Values of a 3x3 matrix given as an array of 9 numbers
+fn matrix(values: &Array) -> [FLOAT; 9] {
+    numbers(values).try_into()
+        .unwrap_or_else(|v: Vec<FLOAT>| panic!("Expected a 3x3 matrix as an array of 9 numbers, got {}", v.len()))
+}
+
+
+fn to_array(values: &[FLOAT]) -> Array {
+    values.iter().map(|&v| v.into()).collect()
+}
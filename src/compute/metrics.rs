@@ -0,0 +1,71 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Metrics of the outputs: a run can emit values with `ocl.emit_metric(name, value)`, written by
+// the host in a json sidecar next to each output, for checking datasets rather than producing them.
+
+
+use rhai::{Engine, Dynamic, Map, INT};
+
+use super::CScope;
+
+
+/// Registers the metric functions on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("emit_metric", CScope::emit_metric)
+        .register_fn("emit_metric", CScope::emit_batch_metric);
+}
+
+
+impl CScope {
+
+
+    /// Adds a metric to the outputs of the current run, every image of a batch getting it
+    fn emit_metric(&mut self, name: &str, value: Dynamic) {
+        for metrics in self.metrics.borrow_mut().iter_mut() {
+            metrics.insert(name.into(), value.clone());
+        }
+    }
+
+
+    /// Adds a metric to the output of the `index`-th image of the batch
+    fn emit_batch_metric(&mut self, name: &str, value: Dynamic, index: INT) {
+        let mut metrics = self.metrics.borrow_mut();
+        let count = metrics.len();
+        match metrics.get_mut(index as usize).filter(|_| index >= 0) {
+            Some(metrics) => { metrics.insert(name.into(), value); }
+            None => panic!("Invalid batch index {} for the metric `{}` (the batch has {} images)", index, name, count)
+        }
+    }
+
+
+    /// Takes the metrics emitted during the current run, for each of its images
+    pub(super) fn take_metrics(&self) -> Vec<Map> {
+        self.metrics.take()
+    }
+}
@@ -0,0 +1,271 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Named outputs: besides `output`, a run can register other images (such as a mask) with
+// `ocl.add_output(image, name)`, read back after the run and saved with their name as suffix.
+// For debugging, `ocl.save_image(image, path)` writes any image right away, and
+// `ocl.export_npy(buffer, path)` any buffer or image as a NumPy array, keeping the float values.
+// These files are written below `imgproc-debug` in the working directory, unless the sandbox of the
+// configuration disables them with `allow_debug_writes`, as it does for packages.
+
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf, Component};
+
+use image::{RgbImage, DynamicImage, ImageFormat, ImageOutputFormat};
+use rhai::{Engine, Array};
+
+use crate::sink::{ImageSink, FileTree};
+use crate::warnings;
+
+use super::{CScope, Buff, BufferRhaiRef, ImageRhaiRef, Layout};
+
+
+/// Registers the named output functions on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("add_output", CScope::add_output)
+        .register_fn("save_image", CScope::save_image)
+        .register_fn("export_npy", CScope::export_npy)
+        .register_fn("export_npy", CScope::export_npy_shaped)
+        .register_fn("export_npy", CScope::export_image_npy);
+}
+
+
+/// Directory of the files written by the pipeline, in the working directory
+const DEBUG_DIR: &str = "imgproc-debug";
+
+
+/// Path of a file written by the pipeline, which cannot write outside of the debug directory
+fn debug_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        panic!("Cannot write to `{}`: expected a relative path without `..`", path.display());
+    }
+    Path::new(DEBUG_DIR).join(path)
+}
+
+
+/// Contents of a `.npy` file (format version 1.0) of the little-endian values `data`,
+/// of the numpy type `descr` and of dimentions `shape`
+fn npy_bytes(descr: &str, shape: &[usize], data: &[u8]) -> Vec<u8> {
+    let shape = match shape {
+        [len] => format!("({},)", len),
+        _ => format!("({})", shape.iter().map(usize::to_string).collect::<Vec<_>>().join(", "))
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    // the header is padded with spaces so that the data is aligned on 64 bytes
+    let len = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - len % 64) % 64));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(10 + header.len() + data.len());
+    bytes.extend(b"\x93NUMPY\x01\x00");
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+
+impl CScope {
+
+
+    /// Saves `img` as an output of the current run, saved next to the main output as `<output>_<name>`
+    fn add_output(&mut self, img: ImageRhaiRef, name: &str) {
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            panic!("Invalid output name `{}` (expected letters, digits, `_` and `-`)", name);
+        }
+        if !self.get_buffers().contains_key(&img.name) {
+            panic!("There is no image named {}", img.name);
+        }
+
+        let mut outputs = self.named_outputs.borrow_mut();
+        outputs.retain(|(n, _)| n != name);
+        outputs.push((name.to_string(), img));
+    }
+
+
+    /// Reads back the images registered by `add_output` during the current run, with their name
+    pub(super) fn read_named_outputs(&self) -> Vec<(String, DynamicImage)> {
+        let outputs = self.named_outputs.take();
+        outputs.into_iter().map(|(name, img)| (name, self.read_image(&img))).collect()
+    }
+
+
+    /// Reads back an image in row-major order, gray images as one-channel images and rgba ones with their alpha
+    fn read_image(&self, img: &ImageRhaiRef) -> DynamicImage {
+        match self.get_buffers().get(&img.name) {
+            Some(Buff::GrayImage(_)) => return DynamicImage::ImageLuma8(self.read_gray_image(&img.name)),
+            Some(Buff::RgbaImage(_)) => return DynamicImage::ImageRgba8(self.read_rgba_image(&img.name)),
+            _ => ()
+        }
+
+        let (buff, w, h) = self.image_buffer(img);
+        let pixels = match &self.get_buffers()[&img.name] {
+            // the dynamic images share the layout of the output
+            Buff::DynImage(_) if self.layout.get() == Layout::ColumnMajor => {
+                let staging = self.scratch_buffer(0, w * h * 3);
+                self.transpose(&buff, &staging, (w, h), false);
+                let mut pixels = vec![0u8; w * h * 3];
+                staging.read(&mut pixels).enq().unwrap();
+                pixels
+            }
+            _ => self.read_image_buffer(img)
+        };
+        RgbImage::from_raw(w as u32, h as u32, pixels).unwrap().into()
+    }
+
+
+    /// Writes `img` as it is at this point of the run to `path`, relative to the working directory,
+    /// Whether the files of the pipeline are written: not while sampling a run, nor when the
+    /// sandbox disables them
+    fn debug_writes(&self) -> bool {
+        if !self.allow_debug_writes {
+            warnings::report("debug-writes", None, "the files written by the pipeline are left out (enable them with `allow_debug_writes` in the sandbox)");
+            return false;
+        }
+        !self.dry_run.get()
+    }
+
+
+    /// in the format of its extension. Meant for debugging the intermediate images of a pipeline.
+    fn save_image(&mut self, img: ImageRhaiRef, path: &str) {
+        let path = debug_path(path);
+        if !self.get_buffers().contains_key(&img.name) {
+            panic!("There is no image named {}", img.name);
+        }
+        if !self.debug_writes() {
+            return;
+        }
+
+        // in the format of the extension, like the outputs of the run
+        let mut bytes = Vec::new();
+        let saved = ImageFormat::from_path(&path)
+            .and_then(|format| self.read_image(&img).write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::from(format)))
+            .map_err(|e| format!("Could not encode `{}`: {}", path.display(), e))
+            .and_then(|_| FileTree.write_file(&path, &bytes));
+        if let Err(e) = saved {
+            eprintln!("Warning: could not save the image `{}`: {}", img.name, e);
+        }
+    }
+
+
+    /// Writes an int or float buffer to `path` as a one-dimentional NumPy array
+    /// (`int32` or `float32`), as it is at this point of the run
+    fn export_npy(&mut self, buffer: BufferRhaiRef, path: &str) {
+        self.export_buffer_npy(&buffer, path, None);
+    }
+
+
+    /// Writes an int or float buffer to `path` as a NumPy array of dimentions `shape`
+    /// (such as `[3, IMG_HEIGHT, IMG_WIDTH]`), which must hold as many values as the buffer
+    fn export_npy_shaped(&mut self, buffer: BufferRhaiRef, path: &str, shape: Array) {
+        let shape: Vec<usize> = shape.into_iter()
+            .map(|d| match d.as_int() {
+                Ok(d) if d > 0 => d as usize,
+                _ => panic!("Invalid npy shape: expected positive integers, got {}", d)
+            })
+            .collect();
+        self.export_buffer_npy(&buffer, path, Some(shape));
+    }
+
+
+    fn export_buffer_npy(&self, buffer: &BufferRhaiRef, path: &str, shape: Option<Vec<usize>>) {
+        let path = debug_path(path);
+        let (descr, len, data) = match self.get_buffers().get(&buffer.name) {
+            Some(Buff::IntBuffer(b)) => {
+                let mut values = vec![0i32; b.len()];
+                b.read(&mut values).enq().unwrap();
+                ("<i4", b.len(), values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())
+            }
+            Some(Buff::FloatBuffer(b)) => {
+                let mut values = vec![0f32; b.len()];
+                b.read(&mut values).enq().unwrap();
+                ("<f4", b.len(), values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())
+            }
+            _ => panic!("There is no buffer named {}", buffer.name)
+        };
+        let shape = shape.unwrap_or_else(|| vec![len]);
+        if shape.iter().product::<usize>() != len {
+            panic!("Cannot export `{}` as a {:?} array: it holds {} values", buffer.name, shape, len);
+        }
+        if !self.debug_writes() {
+            return;
+        }
+
+        if let Err(e) = FileTree.write_file(&path, &npy_bytes(descr, &shape, &data)) {
+            eprintln!("Warning: could not export the buffer `{}`: {}", buffer.name, e);
+        }
+    }
+
+
+    /// Writes an image to `path` as a NumPy array of bytes (`uint8`) of dimentions (height, width, 3),
+    /// (height, width) for a gray image or (height, width, 4) for an rgba image
+    fn export_image_npy(&mut self, img: ImageRhaiRef, path: &str) {
+        let path = debug_path(path);
+        if !self.get_buffers().contains_key(&img.name) {
+            panic!("There is no image named {}", img.name);
+        }
+        if !self.debug_writes() {
+            return;
+        }
+
+        let img_data = self.read_image(&img);
+        let mut shape = vec![img_data.height() as usize, img_data.width() as usize];
+        if img_data.color().channel_count() > 1 {
+            shape.push(img_data.color().channel_count() as usize);
+        }
+        if let Err(e) = FileTree.write_file(&path, &npy_bytes("|u1", &shape, img_data.as_bytes())) {
+            eprintln!("Warning: could not export the image `{}`: {}", img.name, e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn npy_bytes_aligns_the_data_on_64_bytes() {
+        for (shape, text) in [(vec![3], "(3,)"), (vec![2, 3, 4], "(2, 3, 4)")] {
+            let data: Vec<u8> = (0..shape.iter().product::<usize>() * 4).map(|i| i as u8).collect();
+            let bytes = npy_bytes("<f4", &shape, &data);
+
+            assert_eq!(bytes[..8], *b"\x93NUMPY\x01\x00");
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            assert_eq!((10 + header_len) % 64, 0);
+            let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+            assert_eq!(header.trim_end(), format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", text));
+            assert!(header.ends_with('\n'));
+            assert_eq!(bytes[10 + header_len..], data[..]);
+        }
+    }
+}
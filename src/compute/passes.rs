@@ -0,0 +1,175 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Declarative pipeline given by the `passes` list of the configuration, run without a rhai script:
+//
+//     "passes": [
+//         {"kernel": "apply_kernel", "args": [[0.0625, 0.125, 0.0625, 0.125, 0.25, 0.125, 0.0625, 0.125, 0.0625], 3, 3]},
+//         {"kernel": "threshold", "args": [0.5]}
+//     ]
+//
+// Each kernel reads the output of the previous one, the first one reading the input image and the
+// last one writing the output image, and receives its `args` after these two images. Integers and
+// floats are passed as `int` and `float`, and arrays are uploaded to int or float buffers.
+
+
+use rhai::{Dynamic, Map, Array, INT};
+
+use super::{CScope, Buff, ImageRhaiRef, BufferRhaiRef};
+
+
+/// Argument of a kernel of a pass
+#[derive(Clone)]
+enum PassArg {
+    Int(i32),
+    Float(f32),
+    IntBuffer(Vec<i32>),
+    FloatBuffer(Vec<f32>)
+}
+
+
+/// A kernel of a declarative pipeline
+#[derive(Clone)]
+pub struct Pass {
+    kernel: String,
+    args: Vec<PassArg>
+}
+
+
+/// Intermediate images of the passes, alternately written
+const STAGES: [&str; 2] = ["pass_a", "pass_b"];
+
+
+/// The passes of the configuration, if it has some
+pub fn parse(config: &Map) -> Result<Option<Vec<Pass>>, String> {
+    let passes = match config.get("passes") {
+        Some(passes) => passes.read_lock::<Array>().map(|p| p.clone()).ok_or("`passes` should be a list")?,
+        None => return Ok(None)
+    };
+    if passes.is_empty() {
+        return Err(String::from("`passes` is empty"));
+    }
+
+    passes.iter().enumerate().map(|(i, pass)| {
+        let pass = pass.read_lock::<Map>().ok_or_else(|| format!("Pass {} is not an object", i))?;
+        let kernel = pass.get("kernel").and_then(|k| k.clone().into_string().ok())
+            .ok_or_else(|| format!("Pass {} has no `kernel`", i))?;
+        let args = match pass.get("args") {
+            Some(args) => args.read_lock::<Array>().ok_or_else(|| format!("The `args` of pass {} should be a list", i))?
+                .iter()
+                .map(parse_arg)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Pass {} ({}): {}", i, kernel, e))?,
+            None => Vec::new()
+        };
+        Ok(Pass { kernel, args })
+    }).collect::<Result<Vec<_>, String>>().map(Some)
+}
+
+
+fn parse_arg(arg: &Dynamic) -> Result<PassArg, String> {
+    if let Ok(v) = arg.as_int() {
+        Ok(PassArg::Int(v as i32))
+    } else if let Ok(v) = arg.as_float() {
+        Ok(PassArg::Float(v as f32))
+    } else if let Ok(v) = arg.as_bool() {
+        Ok(PassArg::Int(v as i32))
+    } else if let Some(values) = arg.read_lock::<Array>() {
+        if values.iter().all(|v| v.is::<INT>()) {
+            Ok(PassArg::IntBuffer(values.iter().map(|v| v.as_int().unwrap() as i32).collect()))
+        } else {
+            values.iter()
+                .map(|v| v.as_float().or_else(|_| v.as_int().map(|v| v as rhai::FLOAT)).map(|v| v as f32))
+                .collect::<Result<Vec<_>, _>>()
+                .map(PassArg::FloatBuffer)
+                .map_err(|t| format!("Unsupported buffer value of type {}", t))
+        }
+    } else {
+        Err(format!("Unsupported argument of type {}", arg.type_name()))
+    }
+}
+
+
+impl CScope {
+
+
+    /// Creates the intermediate images and uploads the buffer arguments of the passes
+    pub(super) fn setup_passes(&mut self, passes: &[Pass]) {
+        for name in STAGES.iter().take(passes.len().saturating_sub(1).min(2)) {
+            self.create_dynimage(name.to_string());
+        }
+
+        for (i, pass) in passes.iter().enumerate() {
+            for (j, arg) in pass.args.iter().enumerate() {
+                let name = format!("pass{}_arg{}", i, j);
+                match arg {
+                    PassArg::IntBuffer(data) => {
+                        self.create_int_buffer(name, data.iter().map(|&v| Dynamic::from(v)).collect());
+                    }
+                    PassArg::FloatBuffer(data) => {
+                        self.create_float_buffer(name, data.iter().map(|&v| Dynamic::from(v)).collect());
+                    }
+                    _ => ()
+                }
+            }
+        }
+    }
+
+
+    /// Runs the passes from the input image to the output image
+    pub(super) fn run_passes(&self, passes: &[Pass]) {
+        let image = |name: &str| Dynamic::from(ImageRhaiRef {
+            name: name.to_string(),
+            width: self.dynimg_size.0 as i32,
+            height: self.dynimg_size.1 as i32
+        });
+
+        for (i, pass) in passes.iter().enumerate() {
+            let src = if i == 0 { "input" } else { STAGES[(i - 1) % 2] };
+            let dst = if i + 1 == passes.len() { "output" } else { STAGES[i % 2] };
+
+            let mut args = vec![image(src), image(dst)];
+            for (j, arg) in pass.args.iter().enumerate() {
+                args.push(match arg {
+                    PassArg::Int(v) => Dynamic::from(*v),
+                    PassArg::Float(v) => Dynamic::from(*v),
+                    PassArg::IntBuffer(_) | PassArg::FloatBuffer(_) => {
+                        let name = format!("pass{}_arg{}", i, j);
+                        let size = match &self.get_buffers()[&name] {
+                            Buff::IntBuffer(b) => b.len(),
+                            Buff::FloatBuffer(b) => b.len(),
+                            _ => 0
+                        };
+                        Dynamic::from(BufferRhaiRef { name, size: size as i32 })
+                    }
+                });
+            }
+            self.clone().call_kernel(pass.kernel.clone(), args);
+        }
+    }
+}
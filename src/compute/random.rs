@@ -0,0 +1,156 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Seeded random numbers for the pipelines, so that runs can be reproduced.
+
+
+use rhai::{Engine, INT, FLOAT};
+
+use super::CScope;
+
+
+/// Registers the random functions on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("rand", CScope::rand)
+        .register_fn("rand_int", CScope::rand_int);
+}
+
+
+/// Seed of the `index`-th generated image, independent of the images generated before it
+pub fn seed_for(seed: u64, index: u64) -> u64 {
+    let mut state = seed ^ index.wrapping_mul(0x9E3779B97F4A7C15);
+    splitmix64(&mut state)
+}
+
+
+/// `count` distinct indices in 0..len picked at random from `seed`, in increasing order
+pub fn sample_indices(len: usize, count: usize, seed: u64) -> Vec<usize> {
+    let mut state = seed;
+    let mut indices: Vec<usize> = (0..len).collect();
+    let count = count.min(len);
+    for i in 0..count {
+        let j = i + (splitmix64(&mut state) % (len - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(count);
+    indices.sort_unstable();
+    indices
+}
+
+
+/// Whether the `i`-th item of a list is part of a random sample of `fraction` of it picked from `seed`
+pub fn sampled(i: usize, fraction: f64, seed: u64) -> bool {
+    // the i-th number of the sequence of `seed`
+    let mut state = seed.wrapping_add((i as u64).wrapping_mul(0x9E3779B97F4A7C15));
+    (splitmix64(&mut state) as f64 / u64::MAX as f64) < fraction
+}
+
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+
+impl CScope {
+
+
+    /// Next random value of the pipeline generator
+    pub(super) fn next_random(&self) -> u64 {
+        let mut state = self.rng.get();
+        let value = splitmix64(&mut state);
+        self.rng.set(state);
+        value
+    }
+
+
+    /// Random float in [0, 1)
+    fn rand(&mut self) -> FLOAT {
+        (self.next_random() >> 11) as FLOAT / (1u64 << 53) as FLOAT
+    }
+
+
+    /// Random float following the normal distribution
+    fn random_normal(&self) -> f64 {
+        // Box-Muller transform, with u1 in (0, 1]
+        let u1 = ((self.next_random() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        let u2 = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+
+    /// Random float following the gamma distribution of shape `shape` (Marsaglia and Tsang's method)
+    fn random_gamma(&self, shape: f64) -> f64 {
+        if shape < 1.0 {
+            let u = ((self.next_random() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+            return self.random_gamma(shape + 1.0) * u.powf(1.0 / shape);
+        }
+
+        let d = shape - 1.0 / 3.0;
+        let c = 1.0 / (9.0 * d).sqrt();
+        loop {
+            let x = self.random_normal();
+            let v = (1.0 + c * x).powi(3);
+            if v <= 0.0 {
+                continue;
+            }
+            let u = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+            if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+                return d * v;
+            }
+        }
+    }
+
+
+    /// Random float following the beta distribution of parameters (`alpha`, `alpha`)
+    pub(super) fn random_beta(&self, alpha: f64) -> f64 {
+        let x = self.random_gamma(alpha);
+        let y = self.random_gamma(alpha);
+        if x + y > 0.0 { x / (x + y) } else { 0.5 }
+    }
+
+
+    /// Random permutation of 0..len
+    pub(super) fn random_permutation(&self, len: usize) -> Vec<usize> {
+        let mut perm: Vec<usize> = (0..len).collect();
+        for i in (1..len).rev() {
+            perm.swap(i, (self.next_random() % (i as u64 + 1)) as usize);
+        }
+        perm
+    }
+
+
+    /// Random integer in [lo, hi)
+    fn rand_int(&mut self, lo: INT, hi: INT) -> INT {
+        if hi <= lo {
+            panic!("Empty range {}..{}", lo, hi);
+        }
+        lo + (self.next_random() % (hi - lo) as u64) as INT
+    }
+}
@@ -0,0 +1,120 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// RGBA images: images of four bytes per pixel of the dimentions of the dynamic images, for the
+// pipelines declaring `format: #{ channels: 4 }` to keep the transparency of their inputs. The input
+// is also given with its alpha in `input_rgba`, and an rgba output (`ocl.set_rgba_output(image)`)
+// is saved with its transparency.
+
+
+use std::borrow::Cow;
+
+use image::{GrayImage, RgbImage, RgbaImage, imageops};
+use rhai::Engine;
+
+use ocl::Buffer;
+
+use super::{CScope, Buff, ImageRhaiRef, depth};
+
+
+/// Registers the rgba output function on the `Ocl` type
+pub fn register(eng: &mut Engine) {
+    eng.register_fn("set_rgba_output", CScope::set_rgba_output);
+}
+
+
+impl CScope {
+
+
+    /// Creates an rgba image of the dimentions of the dynamic images, sent to the kernels as its buffer alone
+    pub(super) fn create_rgba_image(&mut self, name: String) {
+        let queue = self.prog_queue.queue().clone();
+        let len = self.dynimg_alloc.0 * self.dynimg_alloc.1 * 4;
+        self.get_buffers_mut().insert(name, Buff::RgbaImage(Buffer::<u8>::builder()
+            .queue(queue)
+            .len(len)
+            .build()
+            .expect("Could not allocate buffer")));
+    }
+
+
+    /// Uploads the input with its transparency to `input_rgba`, opaque without `alpha`
+    pub(super) fn set_rgba_input(&self, img: &RgbImage, alpha: Option<&GrayImage>) {
+        let (w, h) = img.dimensions();
+        // the alpha follows the input when it is resized to fit
+        let alpha = alpha.map(|a| if a.dimensions() == (w, h) {
+            Cow::Borrowed(a)
+        } else {
+            Cow::Owned(imageops::resize(a, w, h, imageops::FilterType::Triangle))
+        });
+
+        let mut pixels = Vec::with_capacity(w as usize * h as usize * 4);
+        for (i, p) in img.pixels().enumerate() {
+            pixels.extend_from_slice(&p.0);
+            pixels.push(alpha.as_ref().map(|a| a.as_raw()[i]).unwrap_or(255));
+        }
+        // transposed on the host like the gray images, `row_major` of the transposed dimentions
+        // giving the column-major order
+        let pixels = depth::row_major(pixels, (h as usize, w as usize), 4, self.layout.get());
+
+        match &self.get_buffers()["input_rgba"] {
+            Buff::RgbaImage(b) => b.write(&pixels).enq().unwrap(),
+            _ => panic!("`input_rgba` is not an rgba image")
+        }
+    }
+
+
+    /// Takes the output of the current run from an rgba image, saved with its transparency
+    fn set_rgba_output(&mut self, img: ImageRhaiRef) {
+        if !matches!(self.get_buffers().get(&img.name), Some(Buff::RgbaImage(_))) {
+            panic!("`{}` is not an rgba image", img.name);
+        }
+        *self.rgba_output.borrow_mut() = Some(img.name);
+    }
+
+
+    /// Reads back an rgba image in row-major order
+    pub(super) fn read_rgba_image(&self, name: &str) -> RgbaImage {
+        let (w, h) = self.dynimg_size;
+        let mut pixels = vec![0u8; w * h * 4];
+        match &self.get_buffers()[name] {
+            Buff::RgbaImage(b) => b.read(&mut pixels).enq().unwrap(),
+            _ => panic!("`{}` is not an rgba image", name)
+        }
+
+        let pixels = depth::row_major(pixels, (w, h), 4, self.layout.get());
+        RgbaImage::from_raw(w as u32, h as u32, pixels).unwrap()
+    }
+
+
+    /// Reads the rgba output of the current run, if there is one
+    pub(super) fn read_rgba_output(&self) -> Option<RgbaImage> {
+        let name = self.rgba_output.borrow().clone()?;
+        Some(self.read_rgba_image(&name))
+    }
+}
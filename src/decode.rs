@@ -0,0 +1,481 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use std::fs::File;
+use std::io::BufReader;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::SystemTime;
+
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, RgbImage};
+use image::codecs::jpeg::JpegDecoder;
+use image::io::Reader as ImageReader;
+
+use crate::warnings;
+
+
+static APPLY_ORIENTATION: AtomicBool = AtomicBool::new(true);
+
+
+/// Sets whether the jpeg images are turned upright as their EXIF orientation tells, which
+/// they are by default, the outputs being written upright and without the tag
+pub fn set_apply_orientation(apply: bool) {
+    APPLY_ORIENTATION.store(apply, Ordering::Relaxed);
+}
+
+
+/// Decodes an image file as an rgb image, see `decode_dynamic`
+pub fn decode_image(path: &Path, max_size: Option<(usize, usize)>) -> Result<RgbImage, String> {
+    decode_dynamic(path, max_size).map(DynamicImage::into_rgb8)
+}
+
+
+/// Decodes an image file in its own pixel format.
+/// When `max_size` is given, jpeg images larger than it are downscaled while decoding (DCT scaling),
+/// which keeps them at least as large as what fits in `max_size`.
+/// Truncated png files are decoded up to the missing data, with a warning.
+/// Jpeg images are rotated and flipped as their EXIF orientation tells, unless disabled.
+pub fn decode_dynamic(path: &Path, max_size: Option<(usize, usize)>) -> Result<DynamicImage, String> {
+    let reader = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| format!("Could not read file `{}`: {}", path.display(), e))?;
+
+    match reader.format() {
+        Some(ImageFormat::Jpeg) => {
+            let orientation = if APPLY_ORIENTATION.load(Ordering::Relaxed) { read_orientation(path) } else { 1 };
+            // the bounds of the image as stored, for the DCT scaling
+            let max_size = max_size.map(|(w, h)| if orientation >= 5 { (h, w) } else { (w, h) });
+            decode_jpeg(path, max_size).map(|img| orient(img, orientation))
+        }
+        format => match reader.decode() {
+            Ok(img) => Ok(img),
+            Err(e) if format == Some(ImageFormat::Png) => {
+                let img = decode_truncated_png(path)
+                    .map_err(|_| format!("Could not read image at `{}`: {}", path.display(), e))?;
+                warnings::report("truncated", Some(path), &format!("is truncated or damaged, the unreadable rows are left black ({})", e));
+                Ok(DynamicImage::ImageRgb8(img))
+            }
+            Err(e) => Err(format!("Could not read image at `{}`: {}", path.display(), e))
+        }
+    }
+}
+
+
+/// Decodes an image file read in memory in its own pixel format, turning the jpeg images upright
+/// like `decode_dynamic`
+pub fn decode_dynamic_from_memory(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
+    let jpeg = image::guess_format(bytes).ok() == Some(ImageFormat::Jpeg);
+    if jpeg && APPLY_ORIENTATION.load(Ordering::Relaxed) {
+        Ok(orient(img, orientation(bytes)))
+    } else {
+        Ok(img)
+    }
+}
+
+
+/// File, decoding bounds and modification time of a decoded image
+type CacheKey = (PathBuf, Option<(usize, usize)>, Option<SystemTime>);
+
+
+/// Decoded images kept in memory for the inputs processed several times in a run,
+/// the least recently used ones being dropped to stay within a memory budget
+pub struct DecodeCache {
+    budget: usize,
+    used: usize,
+    images: HashMap<CacheKey, DynamicImage>,
+    /// least recently used first
+    order: VecDeque<CacheKey>
+}
+
+
+impl DecodeCache {
+
+
+    /// Cache of at most `budget` bytes of pixels
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            images: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+
+    /// Decodes an image file like `decode_dynamic`, or returns its cached copy
+    pub fn decode(&mut self, path: &Path, max_size: Option<(usize, usize)>) -> Result<DynamicImage, String> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let key = (path.to_path_buf(), max_size, modified);
+
+        if let Some(img) = self.images.get(&key) {
+            if let Some(pos) = self.order.iter().position(|k| *k == key) {
+                let key = self.order.remove(pos).unwrap();
+                self.order.push_back(key);
+            }
+            return Ok(img.clone());
+        }
+
+        let img = decode_dynamic(path, max_size)?;
+        let size = img.as_bytes().len();
+        if size <= self.budget {
+            while self.used + size > self.budget {
+                match self.order.pop_front().and_then(|k| self.images.remove(&k)) {
+                    Some(old) => self.used -= old.as_bytes().len(),
+                    None => break
+                }
+            }
+            self.used += size;
+            self.images.insert(key.clone(), img.clone());
+            self.order.push_back(key);
+        }
+        Ok(img)
+    }
+}
+
+
+/// Number of images each prefetching thread can decode ahead of the processing
+const PREFETCH_AHEAD: usize = 2;
+
+
+/// Progress of the prefetching threads
+struct PrefetchState {
+    /// index of the next file to decode
+    next: usize,
+    /// index of the next file to be taken
+    taken: usize,
+    decoded: HashMap<usize, Result<DynamicImage, String>>,
+    stopped: bool
+}
+
+
+/// Decodes a list of files on worker threads, ahead of the processing taking them in order
+pub struct Prefetch {
+    state: Arc<(Mutex<PrefetchState>, Condvar)>
+}
+
+
+impl Prefetch {
+
+
+    /// Starts decoding `files` like `decode_dynamic` on `threads` threads
+    pub fn start(files: Vec<PathBuf>, max_size: Option<(usize, usize)>, threads: usize) -> Self {
+        let state = Arc::new((Mutex::new(PrefetchState {
+            next: 0,
+            taken: 0,
+            decoded: HashMap::new(),
+            stopped: false
+        }), Condvar::new()));
+
+        let files = Arc::new(files);
+        for _ in 0..threads {
+            let state = state.clone();
+            let files = files.clone();
+            thread::spawn(move || loop {
+                let (lock, cvar) = &*state;
+                let i = {
+                    let mut st = lock.lock().unwrap();
+                    while !st.stopped && st.next < files.len() && st.next >= st.taken + PREFETCH_AHEAD * threads {
+                        st = cvar.wait(st).unwrap();
+                    }
+                    if st.stopped || st.next >= files.len() {
+                        return;
+                    }
+                    st.next += 1;
+                    st.next - 1
+                };
+
+                let img = decode_dynamic(&files[i], max_size);
+                lock.lock().unwrap().decoded.insert(i, img);
+                cvar.notify_all();
+            });
+        }
+
+        Self { state }
+    }
+
+
+    /// Waits for the file of index `i` to be decoded, the files being taken in order
+    pub fn take(&self, i: usize) -> Result<DynamicImage, String> {
+        let (lock, cvar) = &*self.state;
+        let mut st = lock.lock().unwrap();
+        loop {
+            if let Some(img) = st.decoded.remove(&i) {
+                st.taken = i + 1;
+                cvar.notify_all();
+                return img;
+            }
+            st = cvar.wait(st).unwrap();
+        }
+    }
+}
+
+
+impl Drop for Prefetch {
+
+    // stops the threads, also when the processing panics
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        if let Ok(mut st) = lock.lock() {
+            st.stopped = true;
+        }
+        cvar.notify_all();
+    }
+}
+
+
+/// Largest dimentions of the same aspect ratio as `size` fitting in `bounds`
+pub fn fit_size(size: (usize, usize), bounds: (usize, usize)) -> (usize, usize) {
+    let scale = (bounds.0 as f64 / size.0 as f64).min(bounds.1 as f64 / size.1 as f64).min(1.0);
+    (((size.0 as f64 * scale).round() as usize).max(1), ((size.1 as f64 * scale).round() as usize).max(1))
+}
+
+
+fn decode_jpeg(path: &Path, max_size: Option<(usize, usize)>) -> Result<DynamicImage, String> {
+    let err = |e: image::ImageError| format!("Could not read image at `{}`: {}", path.display(), e);
+
+    let file = File::open(path).map_err(|e| format!("Could not read file `{}`: {}", path.display(), e))?;
+    let mut decoder = JpegDecoder::new(BufReader::new(file)).map_err(err)?;
+
+    if let Some(bounds) = max_size {
+        let (w, h) = decoder.dimensions();
+        let (fit_w, fit_h) = fit_size((w as usize, h as usize), bounds);
+        if (fit_w, fit_h) != (w as usize, h as usize) {
+            decoder.scale(fit_w.min(u16::MAX as usize) as u16, fit_h.min(u16::MAX as usize) as u16).map_err(err)?;
+        }
+    }
+
+    DynamicImage::from_decoder(decoder).map_err(err)
+}
+
+
+/// Decodes the rows of a non-interlaced png up to the first error
+fn decode_truncated_png(path: &Path) -> Result<RgbImage, String> {
+    use png::{ColorType, Transformations};
+
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+
+    let (width, height) = (reader.info().width, reader.info().height);
+    if reader.info().interlaced {
+        return Err("interlaced".into());
+    }
+
+    let (color_type, _) = reader.output_color_type();
+    let mut pixels = vec![0u8; reader.output_buffer_size()];
+    let line_size = reader.output_line_size(width);
+    let mut y = 0;
+    while let Ok(Some(row)) = reader.next_row() {
+        pixels[y * line_size..(y + 1) * line_size].copy_from_slice(row.data());
+        y += 1;
+    }
+
+    let img = match color_type {
+        ColorType::Grayscale => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        ColorType::GrayscaleAlpha => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        ColorType::Rgb => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        ColorType::Rgba => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        ColorType::Indexed => None
+    };
+    img.map(|img| img.into_rgb8()).ok_or_else(|| String::from("unsupported color type"))
+}
+
+
+/// ICC profile embedded in a png or jpeg file
+pub fn read_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    let format = ImageReader::open(path).ok()?.with_guessed_format().ok()?.format()?;
+    icc_profile(format, BufReader::new(File::open(path).ok()?))
+}
+
+
+/// ICC profile embedded in a png or jpeg file read in memory
+pub fn icc_profile_from_memory(bytes: &[u8]) -> Option<Vec<u8>> {
+    icc_profile(image::guess_format(bytes).ok()?, bytes)
+}
+
+
+fn icc_profile<R: std::io::Read>(format: ImageFormat, reader: R) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(reader).read_info().ok()?;
+            reader.info().icc_profile.as_ref().map(|icc| icc.to_vec())
+        }
+        ImageFormat::Jpeg => {
+            let mut decoder = jpeg_decoder::Decoder::new(reader);
+            decoder.read_info().ok()?;
+            decoder.icc_profile()
+        }
+        _ => None
+    }
+}
+
+
+/// EXIF orientation of a jpeg file, from 1 (upright) to 8, 1 when it has none
+fn read_orientation(path: &Path) -> u8 {
+    match File::open(path) {
+        Ok(file) => orientation(BufReader::new(file)),
+        Err(_) => 1
+    }
+}
+
+
+fn orientation<R: std::io::Read>(reader: R) -> u8 {
+    let mut decoder = jpeg_decoder::Decoder::new(reader);
+    if decoder.read_info().is_err() {
+        return 1;
+    }
+    decoder.exif_data().and_then(exif_orientation).unwrap_or(1)
+}
+
+
+/// Value of the orientation tag (0x0112) in the first directory of EXIF data
+fn exif_orientation(exif: &[u8]) -> Option<u8> {
+    let exif = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+    let big_endian = match exif.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None
+    };
+    let u16_at = |pos: usize| exif.get(pos..pos + 2).map(|b| {
+        if big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) }
+    });
+    let u32_at = |pos: usize| exif.get(pos..pos + 4).map(|b| {
+        if big_endian { u32::from_be_bytes([b[0], b[1], b[2], b[3]]) } else { u32::from_le_bytes([b[0], b[1], b[2], b[3]]) }
+    });
+
+    // directory entries of 12 bytes: tag, type, count and value
+    let dir = u32_at(4)? as usize;
+    let count = u16_at(dir)? as usize;
+    (0..count).map(|i| dir + 2 + 12 * i)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|o| (1..=8).contains(o))
+        .map(|o| o as u8)
+}
+
+
+/// Turns an image upright from its EXIF orientation
+fn orient(img: DynamicImage, orientation: u8) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img
+    }
+}
+
+
+/// Frames of an animated gif, png or webp file, none when the file is not animated
+pub fn decode_animation(path: &Path) -> Result<Option<Vec<DynamicImage>>, String> {
+    use image::{AnimationDecoder, Frames};
+    use image::codecs::gif::GifDecoder;
+    use image::codecs::png::PngDecoder;
+    use image::codecs::webp::WebPDecoder;
+
+    let err = |e: image::ImageError| format!("Could not read image at `{}`: {}", path.display(), e);
+
+    let format = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| format!("Could not read file `{}`: {}", path.display(), e))?
+        .format();
+    let file = BufReader::new(File::open(path).map_err(|e| format!("Could not read file `{}`: {}", path.display(), e))?);
+
+    let frames: Frames = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(file).map_err(err)?.into_frames(),
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(file).map_err(err)?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        Some(ImageFormat::WebP) => WebPDecoder::new(file).map_err(err)?.into_frames(),
+        _ => return Ok(None)
+    };
+
+    let frames: Vec<DynamicImage> = frames
+        .map(|frame| frame.map(|f| DynamicImage::ImageRgba8(f.into_buffer())))
+        .collect::<Result<_, _>>()
+        .map_err(err)?;
+
+    Ok(if frames.len() > 1 { Some(frames) } else { None })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// EXIF data of a single directory holding the orientation `value`
+    fn exif(big_endian: bool, value: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let u32_bytes = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend(if big_endian { b"MM\0*" } else { b"II*\0" });
+        exif.extend(u32_bytes(8));
+        exif.extend(u16_bytes(2));
+        // an entry before the orientation, of type SHORT
+        exif.extend(u16_bytes(0x0100));
+        exif.extend(u16_bytes(3));
+        exif.extend(u32_bytes(1));
+        exif.extend(u32_bytes(640));
+        exif.extend(u16_bytes(0x0112));
+        exif.extend(u16_bytes(3));
+        exif.extend(u32_bytes(1));
+        exif.extend(u16_bytes(value));
+        exif.extend([0, 0]);
+        exif.extend(u32_bytes(0));
+        exif
+    }
+
+
+    #[test]
+    fn exif_orientation_reads_both_byte_orders() {
+        assert_eq!(exif_orientation(&exif(false, 6)), Some(6));
+        assert_eq!(exif_orientation(&exif(true, 8)), Some(8));
+        assert_eq!(exif_orientation(&exif(true, 3)[6..]), Some(3));
+    }
+
+
+    #[test]
+    fn exif_orientation_rejects_invalid_data() {
+        assert_eq!(exif_orientation(&exif(false, 9)), None);
+        assert_eq!(exif_orientation(&exif(false, 6)[..20]), None);
+        assert_eq!(exif_orientation(b"Exif\0\0XX*\0"), None);
+        assert_eq!(exif_orientation(&[]), None);
+    }
+}
@@ -0,0 +1,120 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Free space of the output filesystem: checked against the extrapolated size of the outputs before
+// a run, and watched during the run, which pauses while the space is low.
+
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Minimum time between two checks of the free space while processing
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time between two checks of the free space while paused
+const PAUSE_INTERVAL: Duration = Duration::from_secs(30);
+
+
+/// Free space in bytes of the filesystem holding `path`, or of its closest existing ancestor when
+/// it does not exist yet. `None` when it cannot be read.
+pub fn free_bytes(path: &Path) -> Option<u64> {
+    let path = path.ancestors()
+        .map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p })
+        .find(|p| p.exists())?;
+    statvfs_free(path)
+}
+
+
+#[cfg(unix)]
+fn statvfs_free(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+
+#[cfg(not(unix))]
+fn statvfs_free(_path: &Path) -> Option<u64> {
+    None
+}
+
+
+/// Pauses the processing while the output filesystem has less than a minimum of free space
+pub struct DiskGuard {
+    dir: PathBuf,
+    min_free: u64,
+    last_check: Option<Instant>
+}
+
+
+impl DiskGuard {
+
+
+    /// Watches the filesystem of `dir`, returning `None` with a warning when its free space cannot be read
+    pub fn new(dir: &Path, min_free: u64) -> Option<Self> {
+        if free_bytes(dir).is_none() {
+            eprintln!("Warning: the free space of `{}` cannot be read, it is not watched", dir.display());
+            return None;
+        }
+        Some(Self { dir: dir.to_path_buf(), min_free, last_check: None })
+    }
+
+
+    /// Blocks while the free space is under the minimum, checking it at most every few seconds
+    pub fn wait(&mut self) {
+        if self.last_check.map(|t| t.elapsed() < POLL_INTERVAL).unwrap_or(false) {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+
+        let free = free_bytes(&self.dir).unwrap_or(u64::MAX);
+        if free >= self.min_free {
+            return;
+        }
+
+        println!("Only {:.1} MB left on the filesystem of `{}` (less than --min-free-space), pausing until space is freed",
+            free as f64 / 1e6, self.dir.display());
+        let start = Instant::now();
+        loop {
+            thread::sleep(PAUSE_INTERVAL);
+            let free = free_bytes(&self.dir).unwrap_or(u64::MAX);
+            if free >= self.min_free {
+                println!("{:.1} MB free after {}s, resuming", free as f64 / 1e6, start.elapsed().as_secs());
+                break;
+            }
+        }
+        self.last_check = Some(Instant::now());
+    }
+}
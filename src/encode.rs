@@ -0,0 +1,505 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, ImageOutputFormat, DynamicImage, RgbImage, ImageEncoder};
+use image::codecs::png::{PngEncoder, CompressionType, FilterType};
+
+use crate::color::OutputProfile;
+use crate::warnings;
+
+
+/// PNG compression level
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum PngCompression {
+    Default,
+    Fast,
+    Best
+}
+
+
+/// PNG filter strategy
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive
+}
+
+
+/// Format of the outputs, replacing the extension of their inputs
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutFormat {
+    Png,
+    Jpeg,
+    /// requires the `webp` feature
+    Webp,
+    Bmp,
+    Tiff,
+    Exr
+}
+
+
+impl OutFormat {
+
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutFormat::Png => "png",
+            OutFormat::Jpeg => "jpg",
+            OutFormat::Webp => "webp",
+            OutFormat::Bmp => "bmp",
+            OutFormat::Tiff => "tiff",
+            OutFormat::Exr => "exr"
+        }
+    }
+}
+
+
+/// JPEG chroma subsampling
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum JpegSubsampling {
+    #[clap(name = "444")]
+    S444,
+    #[clap(name = "422")]
+    S422,
+    #[clap(name = "420")]
+    S420
+}
+
+
+/// Handling of the outputs larger than the maximum size
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Oversized {
+    /// Fail to save them
+    Reject,
+    /// Save them as jpeg files of the highest quality fitting in the maximum size
+    Recompress
+}
+
+
+/// Lowest jpeg quality an oversized output is recompressed to
+const MIN_RECOMPRESS_QUALITY: u8 = 30;
+
+
+/// Settings of the output encoders
+#[derive(Clone)]
+pub struct EncodeOptions {
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+    pub jpeg_quality: u8,
+    /// only supported by the mozjpeg encoder, the default one always uses 4:2:2
+    pub jpeg_subsampling: Option<JpegSubsampling>,
+    pub webp_quality: u8,
+    /// format of every output, instead of the one of their input
+    pub format: Option<OutFormat>,
+    /// formats each output is also saved in, next to it
+    pub also_save: Vec<OutFormat>,
+    /// color profile to tag the png and jpeg outputs with
+    pub output_profile: Option<OutputProfile>,
+    /// maximum size of an encoded output
+    pub max_bytes: Option<usize>,
+    pub oversized: Oversized,
+    /// trace of the run, written in the metadata of the png and jpeg outputs
+    pub trace: Option<String>
+}
+
+
+impl EncodeOptions {
+
+
+    /// Checks that the selected encoders support the options
+    pub fn validate(&self) -> Result<(), String> {
+        if self.jpeg_quality == 0 || self.jpeg_quality > 100 {
+            return Err(format!("Invalid jpeg quality {} (expected 1 to 100)", self.jpeg_quality));
+        }
+        if self.jpeg_subsampling.is_some() && !cfg!(feature = "mozjpeg") {
+            return Err("Setting the jpeg subsampling requires building with the `mozjpeg` feature".into());
+        }
+        if self.webp_quality > 100 {
+            return Err(format!("Invalid webp quality {} (expected 0 to 100)", self.webp_quality));
+        }
+        if (self.format == Some(OutFormat::Webp) || self.also_save.contains(&OutFormat::Webp)) && !cfg!(feature = "webp") {
+            return Err("Saving webp outputs requires building with the `webp` feature".into());
+        }
+        if let Some(OutputProfile::Icc(icc)) = &self.output_profile {
+            // the jpeg segments of a profile are numbered on one byte
+            let jpeg = matches!(self.format, None | Some(OutFormat::Jpeg)) || self.also_save.contains(&OutFormat::Jpeg);
+            if jpeg && icc.len() > 255 * MAX_ICC_SEGMENT_DATA {
+                return Err(format!("The color profile takes {} bytes, more than the {} a jpeg file can hold",
+                    icc.len(), 255 * MAX_ICC_SEGMENT_DATA));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// The pixels of an image as 8-bit RGB, for the formats and sinks that only take those
+pub fn as_rgb8(img: &DynamicImage) -> Cow<'_, RgbImage> {
+    match img {
+        DynamicImage::ImageRgb8(rgb) => Cow::Borrowed(rgb),
+        _ => Cow::Owned(img.to_rgb8())
+    }
+}
+
+
+/// The image in a pixel format that `format` can hold, reporting the precision lost in the conversion.
+/// Png and tiff files hold 16 bits per channel and transparency, exr files hold floats and the others
+/// 8-bit rgb or gray pixels.
+fn fit_depth<'a>(img: &'a DynamicImage, format: ImageFormat, path: &Path) -> Cow<'a, DynamicImage> {
+    match (format, img) {
+        (ImageFormat::OpenExr, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) => Cow::Borrowed(img),
+        (ImageFormat::OpenExr, _) if img.color().has_alpha() => Cow::Owned(DynamicImage::ImageRgba32F(img.to_rgba32f())),
+        (ImageFormat::OpenExr, _) => Cow::Owned(DynamicImage::ImageRgb32F(img.to_rgb32f())),
+        (ImageFormat::Png | ImageFormat::Tiff, DynamicImage::ImageRgb32F(_)) => {
+            warnings::report("bit depth", Some(path), "saved with 16 bits per channel, its float values clipped to 0-1");
+            Cow::Owned(DynamicImage::ImageRgb16(img.to_rgb16()))
+        }
+        (ImageFormat::Png | ImageFormat::Tiff, _) => Cow::Borrowed(img),
+        (_, DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgb32F(_)) => {
+            warnings::report("bit depth", Some(path), "saved with 8 bits per channel, the most its format holds");
+            Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8()))
+        }
+        (_, DynamicImage::ImageRgba8(_)) => {
+            warnings::report("transparency", Some(path), "saved without its transparency, which its format cannot hold");
+            Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8()))
+        }
+        _ => Cow::Borrowed(img)
+    }
+}
+
+
+/// Encodes an image in the format given by the extension of `path`
+pub fn encode_image(img: &DynamicImage, path: &Path, opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let format = ImageFormat::from_path(path).map_err(|e| e.to_string())?;
+    let img = fit_depth(img, format, path);
+    let img = img.as_ref();
+    let mut bytes = Vec::new();
+
+    match format {
+        ImageFormat::Png => {
+            let compression = match opts.png_compression {
+                PngCompression::Default => CompressionType::Default,
+                PngCompression::Fast => CompressionType::Fast,
+                PngCompression::Best => CompressionType::Best
+            };
+            let filter = match opts.png_filter {
+                PngFilter::None => FilterType::NoFilter,
+                PngFilter::Sub => FilterType::Sub,
+                PngFilter::Up => FilterType::Up,
+                PngFilter::Avg => FilterType::Avg,
+                PngFilter::Paeth => FilterType::Paeth,
+                PngFilter::Adaptive => FilterType::Adaptive
+            };
+            PngEncoder::new_with_quality(&mut bytes, compression, filter)
+                .write_image(img.as_bytes(), img.width(), img.height(), img.color())
+                .map_err(|e| e.to_string())?;
+            if let Some(profile) = &opts.output_profile {
+                tag_png(&mut bytes, profile);
+            }
+            if let Some(trace) = &opts.trace {
+                // signature (8 bytes) and IHDR chunk (25 bytes)
+                let mut data = b"Trace\0".to_vec();
+                data.extend(trace.as_bytes());
+                bytes.splice(33..33, png_chunk(b"tEXt", &data));
+            }
+        }
+        ImageFormat::Jpeg => {
+            bytes = encode_jpeg(img, opts)?;
+            if let Some(OutputProfile::Icc(icc)) = &opts.output_profile {
+                // untagged jpeg files are read as sRGB
+                tag_jpeg(&mut bytes, icc);
+            }
+            if let Some(trace) = &opts.trace {
+                comment_jpeg(&mut bytes, trace);
+            }
+        }
+        ImageFormat::WebP => bytes = encode_webp(img, opts)?,
+        _ => {
+            img.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::from(format))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+
+/// Encodes an output like `encode_image`, within the maximum size of the options. An oversized output
+/// is recompressed as a jpeg file if allowed, returning the path of the output with its new extension.
+pub fn encode_output(img: &DynamicImage, path: &Path, opts: &EncodeOptions) -> Result<(PathBuf, Vec<u8>), String> {
+    let bytes = encode_image(img, path, opts)?;
+    let max_bytes = match opts.max_bytes {
+        Some(max_bytes) if bytes.len() > max_bytes => max_bytes,
+        _ => return Ok((path.to_path_buf(), bytes))
+    };
+
+    if opts.oversized == Oversized::Recompress {
+        let jpeg = match ImageFormat::from_path(path) {
+            Ok(ImageFormat::Jpeg) => path.to_path_buf(),
+            _ => path.with_extension("jpg")
+        };
+        // converted once, not to report the lost bit depth at every quality
+        let img = fit_depth(img, ImageFormat::Jpeg, path);
+        let qualities = (MIN_RECOMPRESS_QUALITY..=opts.jpeg_quality).rev().step_by(10);
+        for jpeg_quality in qualities.filter(|&q| jpeg != path || q < opts.jpeg_quality) {
+            let bytes = encode_image(&img, &jpeg, &EncodeOptions { jpeg_quality, ..opts.clone() })?;
+            if bytes.len() <= max_bytes {
+                warnings::report("recompressed", Some(path), &format!(
+                    "recompressed to a jpeg file of quality {} to fit in {} bytes", jpeg_quality, max_bytes));
+                return Ok((jpeg, bytes));
+            }
+        }
+    }
+
+    Err(format!("The output takes {} bytes, more than the maximum of {}", bytes.len(), max_bytes))
+}
+
+
+/// Adds a `sRGB` or `iCCP` chunk after the header of an encoded png
+fn tag_png(bytes: &mut Vec<u8>, profile: &OutputProfile) {
+    let (name, data) = match profile {
+        OutputProfile::Srgb => (b"sRGB", vec![0]), // perceptual rendering intent
+        OutputProfile::Icc(icc) => {
+            let mut data = b"ICC profile\0\0".to_vec(); // name and compression method
+            data.extend(miniz_oxide::deflate::compress_to_vec_zlib(icc, 6));
+            (b"iCCP", data)
+        }
+    };
+
+    // signature (8 bytes) and IHDR chunk (25 bytes)
+    bytes.splice(33..33, png_chunk(name, &data));
+}
+
+
+/// A png chunk, with its length and checksum
+fn png_chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(name);
+    chunk.extend(data);
+    chunk.extend(crc32fast::hash(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+
+/// Position of an encoded jpeg after its start of image marker and application segments
+fn after_app_segments(bytes: &[u8]) -> usize {
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() && bytes[pos] == 0xFF && (0xE0..=0xEF).contains(&bytes[pos + 1]) {
+        pos += 2 + u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+    }
+    pos.min(bytes.len())
+}
+
+
+/// Adds a `COM` comment segment after the application segments of an encoded jpeg
+fn comment_jpeg(bytes: &mut Vec<u8>, comment: &str) {
+    let pos = after_app_segments(bytes);
+    let text = &comment.as_bytes()[..comment.len().min(65533)];
+    let mut segment = Vec::with_capacity(text.len() + 4);
+    segment.extend([0xFF, 0xFE]);
+    segment.extend(((text.len() + 2) as u16).to_be_bytes());
+    segment.extend(text);
+    bytes.splice(pos..pos, segment);
+}
+
+
+/// Largest ICC profile data of an `APP2` segment of a jpeg file
+const MAX_ICC_SEGMENT_DATA: usize = 65519;
+
+
+/// Adds `APP2` ICC profile segments after the application segments of an encoded jpeg, the `APP0`
+/// JFIF header staying first. The profile is left out when it takes more than 255 segments.
+fn tag_jpeg(bytes: &mut Vec<u8>, icc: &[u8]) {
+    let count = icc.len().div_ceil(MAX_ICC_SEGMENT_DATA);
+    if count > 255 {
+        return;
+    }
+    let mut segments = Vec::with_capacity(icc.len() + count * 18);
+    for (i, data) in icc.chunks(MAX_ICC_SEGMENT_DATA).enumerate() {
+        segments.extend([0xFF, 0xE2]);
+        segments.extend(((data.len() + 16) as u16).to_be_bytes());
+        segments.extend(b"ICC_PROFILE\0");
+        segments.extend([i as u8 + 1, count as u8]);
+        segments.extend(data);
+    }
+
+    let pos = after_app_segments(bytes);
+    bytes.splice(pos..pos, segments);
+}
+
+
+#[cfg(feature = "webp")]
+fn encode_webp(img: &DynamicImage, opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let img = as_rgb8(img);
+    let encoder = webp::Encoder::from_rgb(img.as_raw(), img.width(), img.height());
+    Ok(encoder.encode(opts.webp_quality as f32).to_vec())
+}
+
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_img: &DynamicImage, _opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+    Err("Saving webp files requires building with the `webp` feature".into())
+}
+
+
+#[cfg(not(feature = "mozjpeg"))]
+fn encode_jpeg(img: &DynamicImage, opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+    use image::codecs::jpeg::JpegEncoder;
+
+    let mut bytes = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut bytes, opts.jpeg_quality);
+    match img {
+        DynamicImage::ImageLuma8(gray) => encoder.encode_image(gray),
+        _ => encoder.encode_image(as_rgb8(img).as_ref())
+    }.map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+
+#[cfg(feature = "mozjpeg")]
+fn encode_jpeg(img: &DynamicImage, opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let (color_space, pixels) = match img {
+        DynamicImage::ImageLuma8(gray) => (mozjpeg::ColorSpace::JCS_GRAYSCALE, Cow::Borrowed(gray.as_raw())),
+        _ => (mozjpeg::ColorSpace::JCS_RGB, match as_rgb8(img) {
+            Cow::Borrowed(rgb) => Cow::Borrowed(rgb.as_raw()),
+            Cow::Owned(rgb) => Cow::Owned(rgb.into_raw())
+        })
+    };
+    let mut comp = mozjpeg::Compress::new(color_space);
+    comp.set_size(img.width() as usize, img.height() as usize);
+    comp.set_quality(opts.jpeg_quality as f32);
+
+    match opts.jpeg_subsampling {
+        Some(JpegSubsampling::S444) => comp.set_chroma_sampling_pixel_sizes((1, 1), (1, 1)),
+        Some(JpegSubsampling::S422) => comp.set_chroma_sampling_pixel_sizes((2, 1), (2, 1)),
+        Some(JpegSubsampling::S420) => comp.set_chroma_sampling_pixel_sizes((2, 2), (2, 2)),
+        None => ()
+    }
+
+    let mut comp = comp.start_compress(Vec::new()).map_err(|e| e.to_string())?;
+    comp.write_scanlines(&pixels).map_err(|e| e.to_string())?;
+    comp.finish().map_err(|e| e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// Small jpeg of the `image` encoder, which starts with a JFIF `APP0` segment
+    fn jpeg() -> Vec<u8> {
+        let img = RgbImage::from_pixel(16, 16, image::Rgb([200, 100, 50]));
+        let mut bytes = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut bytes).encode_image(&img).unwrap();
+        bytes
+    }
+
+
+    fn png() -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, image::Rgb([200, 100, 50])));
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png).unwrap();
+        bytes
+    }
+
+
+    #[test]
+    fn tag_jpeg_embeds_the_profile_after_the_jfif_header() {
+        // split over two segments
+        let icc: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut bytes = jpeg();
+        tag_jpeg(&mut bytes, &icc);
+
+        assert_eq!(bytes[2..4], [0xFF, 0xE0]);
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(&bytes));
+        decoder.decode().unwrap();
+        assert_eq!(decoder.icc_profile(), Some(icc));
+    }
+
+
+    #[test]
+    fn tag_jpeg_leaves_out_the_profiles_over_255_segments() {
+        let mut bytes = jpeg();
+        let untagged = bytes.clone();
+        tag_jpeg(&mut bytes, &vec![0; 255 * MAX_ICC_SEGMENT_DATA + 1]);
+        assert_eq!(bytes, untagged);
+    }
+
+
+    #[test]
+    fn comment_jpeg_adds_a_segment_after_the_application_segments() {
+        let mut bytes = jpeg();
+        tag_jpeg(&mut bytes, b"profile");
+        let pos = after_app_segments(&bytes);
+        comment_jpeg(&mut bytes, "trace");
+
+        assert_eq!(bytes[pos..pos + 9], *b"\xFF\xFE\x00\x07trace");
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(&bytes));
+        decoder.decode().unwrap();
+        assert_eq!(decoder.icc_profile(), Some(b"profile".to_vec()));
+    }
+
+
+    #[test]
+    fn tag_png_embeds_the_profile() {
+        let icc: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut bytes = png();
+        tag_png(&mut bytes, &OutputProfile::Icc(icc.clone()));
+
+        let mut reader = png::Decoder::new(Cursor::new(&bytes)).read_info().unwrap();
+        assert!(reader.info().icc_profile.is_some());
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+
+        // the png decoder of this version loses the end of the profiles, which are read here
+        let len = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]) as usize;
+        assert_eq!(bytes[37..41], *b"iCCP");
+        let chunk = &bytes[37..41 + len];
+        assert_eq!(bytes[41 + len..45 + len], crc32fast::hash(chunk).to_be_bytes());
+        let data = chunk[4..].strip_prefix(b"ICC profile\0\0").unwrap();
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec_zlib(data).unwrap(), icc);
+    }
+
+
+    #[test]
+    fn tag_png_marks_srgb() {
+        let mut bytes = png();
+        tag_png(&mut bytes, &OutputProfile::Srgb);
+
+        let reader = png::Decoder::new(Cursor::new(&bytes)).read_info().unwrap();
+        assert!(reader.info().srgb.is_some());
+    }
+}
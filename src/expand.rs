@@ -0,0 +1,163 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Environment variable expansion in the configuration values, so that the same
+// configuration can be used on machines storing the data at different places.
+//
+// `${VAR}` is replaced by the value of the variable and `${VAR:-default}` falls back to `default`
+// when the variable is unset or empty. `$$` is a literal `$`.
+// The variables of a `.env` file in the working directory are loaded at startup, without
+// overriding the variables of the environment.
+
+
+use std::path::Path;
+
+use rhai::{Map, Array};
+
+
+/// Name of the file of the default environment variables
+pub const DOTENV_FILE: &str = ".env";
+
+
+/// Sets the variables of a `.env` file (`KEY=value` lines) that are not already set
+pub fn load_dotenv(path: &Path) -> Result<(), String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Could not read `{}`: {}", path.display(), e))
+    };
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=')
+            .ok_or_else(|| format!("`{}` line {}: expected `KEY=value`", path.display(), i + 1))?;
+        let key = key.trim();
+        let value = value.trim();
+        let value = if value.len() >= 2 && (value.starts_with('"') && value.ends_with('"')
+                || value.starts_with('\'') && value.ends_with('\'')) {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+
+/// Expands the `${VAR}` and `${VAR:-default}` references of a string
+pub fn expand(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| format!("Unclosed `${{` in `{}`", s))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None)
+            };
+
+            match (std::env::var(name).ok().filter(|v| !v.is_empty()), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => return Err(format!("Environment variable `{}` is not set (in `{}`)", name, s))
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+
+/// Expands the strings of a configuration, recursively
+pub fn expand_map(map: &mut Map) -> Result<(), String> {
+    for value in map.values_mut() {
+        if value.is::<String>() {
+            *value = expand(&value.clone().into_string().unwrap())?.into();
+        } else if value.is::<Map>() {
+            expand_map(&mut value.write_lock::<Map>().unwrap())?;
+        } else if value.is::<Array>() {
+            for item in value.write_lock::<Array>().unwrap().iter_mut() {
+                if item.is::<String>() {
+                    *item = expand(&item.clone().into_string().unwrap())?.into();
+                } else if let Some(mut map) = item.write_lock::<Map>() {
+                    expand_map(&mut map)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn expand_replaces_the_variables() {
+        std::env::set_var("IMGPROC_TEST_DATA", "/data");
+        assert_eq!(expand("${IMGPROC_TEST_DATA}/images").unwrap(), "/data/images");
+        assert_eq!(expand("${IMGPROC_TEST_DATA:-/tmp}").unwrap(), "/data");
+        assert_eq!(expand("${IMGPROC_TEST_UNSET:-/tmp}/x").unwrap(), "/tmp/x");
+    }
+
+
+    #[test]
+    fn expand_keeps_the_literal_dollars() {
+        assert_eq!(expand("$${IMGPROC_TEST_UNSET}").unwrap(), "${IMGPROC_TEST_UNSET}");
+        assert_eq!(expand("cost $5").unwrap(), "cost $5");
+    }
+
+
+    #[test]
+    fn expand_rejects_the_unset_variables() {
+        assert!(expand("${IMGPROC_TEST_UNSET}").is_err());
+        assert!(expand("${IMGPROC_TEST_DATA").is_err());
+    }
+}
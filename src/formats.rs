@@ -23,7 +23,83 @@ SOFTWARE.
 */
 
 
-use crate::{RED, GREEN, CLEAR};
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// When to write the color escape sequences
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorMode {
+    /// When the output is a terminal and `NO_COLOR` is not set
+    Auto,
+    Always,
+    Never
+}
+
+
+static COLORED: AtomicBool = AtomicBool::new(true);
+
+
+/// Escape sequence, written only when the output is colored
+pub struct Escape(&'static str);
+
+
+impl fmt::Display for Escape {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if colored() {
+            f.write_str(self.0)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+pub const RED:   Escape = Escape("\x1b[38;2;255;0;0m");
+pub const GREEN: Escape = Escape("\x1b[38;2;0;255;0m");
+pub const CLEAR: Escape = Escape("\x1b[m");
+
+
+pub fn set_color_mode(mode: ColorMode) {
+    let colored = match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => std::env::var_os("NO_COLOR").unwrap_or_default().is_empty()
+            && std::io::stdout().is_terminal()
+            && std::io::stderr().is_terminal()
+    };
+    COLORED.store(colored, Ordering::Relaxed);
+}
+
+
+/// Whether the output is colored, and can be rewritten with escape sequences
+pub fn colored() -> bool {
+    COLORED.load(Ordering::Relaxed)
+}
+
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+
+/// Suppresses the progress and information output, keeping the warnings and errors
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+
+/// Message of a panic, from its payload
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown error"))
+}
 
 
 pub fn format_bool(b: bool) -> String {
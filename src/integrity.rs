@@ -0,0 +1,254 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Decodes every image of a source without running the pipeline, to find the files to clean from a
+// dataset before the GPU pass: empty files, files that are not images, truncated or corrupt images,
+// and images of unusual dimentions.
+
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageError};
+
+use rhai::{Map, Array, INT};
+
+use crate::json;
+use crate::source::ImageSource;
+use crate::{RED, GREEN, CLEAR};
+
+
+/// Images smaller than this on a side are reported
+const MIN_SIDE: u32 = 16;
+/// Images whose longer side is more than this many times their shorter side are reported
+const MAX_ASPECT: u32 = 10;
+/// Images of more pixels than this are reported
+const MAX_PIXELS: u64 = 100_000_000;
+
+
+/// Problem found in a source file
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IssueKind {
+    Empty,
+    NotImage,
+    Truncated,
+    Corrupt,
+    UnusualSize
+}
+
+
+impl IssueKind {
+
+
+    const ALL: [IssueKind; 5] = [Self::Empty, Self::NotImage, Self::Truncated, Self::Corrupt, Self::UnusualSize];
+
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::NotImage => "not an image",
+            Self::Truncated => "truncated",
+            Self::Corrupt => "corrupt",
+            Self::UnusualSize => "unusual dimentions"
+        }
+    }
+}
+
+
+struct Issue {
+    file: PathBuf,
+    kind: IssueKind,
+    detail: String
+}
+
+
+/// Checks every file of `src` (a file, or a directory searched recursively) on `threads` threads,
+/// printing the problems found and writing them as json to `report`.
+/// Returns whether every file is a usable image.
+pub fn validate_images(src: &Path, threads: usize, report: Option<&Path>) -> bool {
+    let mut files = Vec::new();
+    if let Err(e) = list_files(src, &mut files, &mut HashSet::new()) {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return false;
+    }
+    files.sort();
+    validate_files(&files, threads, report)
+}
+
+
+/// Checks the files of a list, see `validate_images`
+pub fn validate_files(files: &[PathBuf], threads: usize, report: Option<&Path>) -> bool {
+    let threads = threads.clamp(1, files.len().max(1));
+    let mut issues: Vec<(usize, Issue)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|t| {
+            scope.spawn(move || files.iter().enumerate()
+                .skip(t)
+                .step_by(threads)
+                .filter_map(|(i, file)| check_file(file).map(|issue| (i, issue)))
+                .collect::<Vec<_>>())
+        }).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
+    issues.sort_by_key(|(i, _)| *i);
+    let issues: Vec<Issue> = issues.into_iter().map(|(_, issue)| issue).collect();
+    summarize(files.len(), &issues, report)
+}
+
+
+/// Decodes every image of the source of the `scheme://location` string `src`, see `validate_images`
+pub fn validate_source(src: &str, source: Box<dyn ImageSource>, report: Option<&Path>) -> bool {
+    let mut checked = 0;
+    let mut issues = Vec::new();
+    for (i, item) in source.enumerate() {
+        checked += 1;
+        match item {
+            Ok(item) => issues.extend(check_size(Path::new(&item.id), &item.image)),
+            // the images that cannot be read have no id, only their position in the source
+            Err(e) => issues.push(Issue { file: PathBuf::from(format!("{}#{}", src, i)), kind: IssueKind::Corrupt, detail: e })
+        }
+    }
+    summarize(checked, &issues, report)
+}
+
+
+/// Prints the problems found in the `checked` files and writes their report, returning whether
+/// there are none
+fn summarize(checked: usize, issues: &[Issue], report: Option<&Path>) -> bool {
+    for issue in issues {
+        println!("{}{}{}  {}: {}", RED, issue.kind.name(), CLEAR, issue.file.display(), issue.detail);
+    }
+    let counts: Vec<String> = IssueKind::ALL.iter()
+        .map(|&kind| (kind, issues.iter().filter(|i| i.kind == kind).count()))
+        .filter(|(_, count)| *count > 0)
+        .map(|(kind, count)| format!("{} {}", count, kind.name()))
+        .collect();
+    if counts.is_empty() {
+        println!("{}Checked {} files, all valid{}", GREEN, checked, CLEAR);
+    } else {
+        println!("Checked {} files: {}", checked, counts.join(", "));
+    }
+
+    if let Some(report) = report {
+        let json = json::format_map(&report_map(checked, issues));
+        if let Err(e) = std::fs::write(report, json) {
+            eprintln!("{}Could not write `{}`: {}{}", RED, report.display(), e, CLEAR);
+            return false;
+        }
+    }
+    issues.is_empty()
+}
+
+
+/// Files of `path`, searched recursively, the directories already in `visited` (by their canonical
+/// path) being left out so that the symbolic links cannot loop
+fn list_files(path: &Path, files: &mut Vec<PathBuf>, visited: &mut HashSet<PathBuf>) -> Result<(), String> {
+    let meta = std::fs::metadata(path).map_err(|e| format!("Could not read `{}`: {}", path.display(), e))?;
+    if !meta.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let canonical = path.canonicalize().map_err(|e| format!("Could not read `{}`: {}", path.display(), e))?;
+    if !visited.insert(canonical) {
+        return Ok(());
+    }
+
+    let entries = std::fs::read_dir(path).map_err(|e| format!("Could not read files in `{}`: {}", path.display(), e))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        list_files(&entry.path(), files, visited)?;
+    }
+    Ok(())
+}
+
+
+/// Decodes a file, returning its problem if any
+fn check_file(file: &Path) -> Option<Issue> {
+    let issue = |kind, detail: String| Some(Issue { file: file.to_path_buf(), kind, detail });
+
+    match std::fs::metadata(file) {
+        Ok(meta) if meta.len() == 0 => return issue(IssueKind::Empty, String::from("zero bytes")),
+        Ok(_) => (),
+        Err(e) => return issue(IssueKind::Corrupt, e.to_string())
+    }
+
+    let reader = match ImageReader::open(file).and_then(|r| r.with_guessed_format()) {
+        Ok(reader) => reader,
+        Err(e) => return issue(IssueKind::Corrupt, e.to_string())
+    };
+    if reader.format().is_none() {
+        return issue(IssueKind::NotImage, String::from("unknown format"));
+    }
+
+    let img = match reader.decode() {
+        Ok(img) => img,
+        Err(ImageError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return issue(IssueKind::Truncated, e.to_string());
+        }
+        Err(e) => {
+            // the decoders report missing data in their own errors
+            let message = e.to_string();
+            let lower = message.to_lowercase();
+            let kind = if lower.contains("eof") || lower.contains("end of") || lower.contains("truncat") {
+                IssueKind::Truncated
+            } else {
+                IssueKind::Corrupt
+            };
+            return issue(kind, message);
+        }
+    };
+
+    check_size(file, &img)
+}
+
+
+/// Reports an image of unusual dimentions
+fn check_size(file: &Path, img: &DynamicImage) -> Option<Issue> {
+    let (w, h) = (img.width(), img.height());
+    if w.min(h) < MIN_SIDE || w.max(h) / w.min(h).max(1) > MAX_ASPECT || w as u64 * h as u64 > MAX_PIXELS {
+        return Some(Issue { file: file.to_path_buf(), kind: IssueKind::UnusualSize, detail: format!("{}x{}", w, h) });
+    }
+    None
+}
+
+
+/// Json report of the problems found
+fn report_map(checked: usize, issues: &[Issue]) -> Map {
+    let files: Array = issues.iter().map(|issue| {
+        let mut entry = Map::new();
+        entry.insert("file".into(), issue.file.display().to_string().into());
+        entry.insert("issue".into(), issue.kind.name().into());
+        entry.insert("detail".into(), issue.detail.clone().into());
+        entry.into()
+    }).collect();
+
+    let mut report = Map::new();
+    report.insert("checked".into(), (checked as INT).into());
+    report.insert("valid".into(), ((checked - issues.len()) as INT).into());
+    report.insert("issues".into(), files.into());
+    report
+}
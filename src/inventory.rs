@@ -0,0 +1,232 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Inventory of the OpenCL platforms and devices of the machine, printed by the `list` command as
+// text or as json for the scripts picking a device.
+
+
+use ocl::{Platform, Device, enums::{DeviceInfo, DeviceInfoResult as DIR, DeviceMemCacheType, DeviceLocalMemType}};
+use ocl::flags::{DEVICE_TYPE_CPU, DEVICE_TYPE_GPU, DEVICE_TYPE_ACCELERATOR,
+                DEVICE_TYPE_CUSTOM, DEVICE_TYPE_DEFAULT};
+
+use rhai::{Map, Array, Dynamic, INT};
+
+use crate::json;
+
+
+/// Information about a platform, the fields that could not be queried being `None`
+pub struct PlatformInfo {
+    pub name: Option<String>,
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    /// `None` when the devices could not be listed
+    pub devices: Option<Vec<DeviceSummary>>
+}
+
+
+/// Information about a device, the fields that could not be queried being `None`
+pub struct DeviceSummary {
+    pub name: Option<String>,
+    pub types: Option<Vec<&'static str>>,
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub driver_version: Option<String>,
+    pub available: Option<bool>,
+
+    pub max_compute_units: Option<u32>,
+    pub max_work_item_dimensions: Option<u32>,
+    pub max_workgroup_size: Option<usize>,
+    /// in MHz
+    pub max_clock_frequency: Option<u32>,
+    pub max_mem_alloc_size: Option<u64>,
+    pub max_parameter_size: Option<usize>,
+    pub max_samplers: Option<u32>,
+
+    pub image_support: Option<bool>,
+    pub image2d_max: Option<(usize, usize)>,
+    pub image3d_max: Option<(usize, usize, usize)>,
+
+    pub global_mem_size: Option<u64>,
+    pub global_mem_cache: Option<&'static str>,
+    pub global_mem_cache_size: Option<u64>,
+
+    pub local_mem_size: Option<u64>,
+    pub local_mem_type: Option<&'static str>,
+
+    pub max_constant_buffer_size: Option<u64>,
+    pub max_constant_args: Option<u32>
+}
+
+
+/// Value of a device info, if it could be queried
+macro_rules! info {
+    ($device:expr, $info:ident) => {
+        match $device.info(DeviceInfo::$info) {
+            Ok(DIR::$info(value)) => Some(value),
+            _ => None
+        }
+    };
+}
+
+
+/// Queries the platforms and devices of the machine, none when the platforms cannot be listed
+pub fn collect() -> Vec<PlatformInfo> {
+    let platforms = ocl::core::get_platform_ids().unwrap_or_default();
+    platforms.into_iter().map(Platform::new).map(|p| PlatformInfo {
+        name: p.name().ok(),
+        vendor: p.vendor().ok(),
+        version: p.version().ok(),
+        devices: Device::list(p, None).ok().map(|devices| devices.iter().map(DeviceSummary::query).collect())
+    }).collect()
+}
+
+
+impl DeviceSummary {
+
+
+    fn query(d: &Device) -> Self {
+        let types = info!(d, Type).map(|tpe| {
+            [(DEVICE_TYPE_DEFAULT, "default"), (DEVICE_TYPE_CPU, "CPU"), (DEVICE_TYPE_GPU, "GPU"),
+             (DEVICE_TYPE_ACCELERATOR, "accelerator"), (DEVICE_TYPE_CUSTOM, "custom")]
+                .into_iter()
+                .filter(|(flag, _)| tpe.contains(*flag))
+                .map(|(_, name)| name)
+                .collect()
+        });
+
+        let image2d_max = info!(d, Image2dMaxWidth).zip(info!(d, Image2dMaxHeight));
+        let image3d_max = match (info!(d, Image3dMaxWidth), info!(d, Image3dMaxHeight), info!(d, Image3dMaxDepth)) {
+            (Some(w), Some(h), Some(depth)) => Some((w, h, depth)),
+            _ => None
+        };
+
+        Self {
+            name: d.name().ok(),
+            types,
+            vendor: d.vendor().ok(),
+            version: d.version().ok().map(|v| v.to_string()),
+            driver_version: info!(d, DriverVersion),
+            available: d.is_available().ok(),
+
+            max_compute_units: info!(d, MaxComputeUnits),
+            max_work_item_dimensions: info!(d, MaxWorkItemDimensions),
+            max_workgroup_size: d.max_wg_size().ok(),
+            max_clock_frequency: info!(d, MaxClockFrequency),
+            max_mem_alloc_size: info!(d, MaxMemAllocSize),
+            max_parameter_size: info!(d, MaxParameterSize),
+            max_samplers: info!(d, MaxSamplers),
+
+            image_support: info!(d, ImageSupport),
+            image2d_max,
+            image3d_max,
+
+            global_mem_size: info!(d, GlobalMemSize),
+            global_mem_cache: info!(d, GlobalMemCacheType).map(|tpe| match tpe {
+                DeviceMemCacheType::None => "none",
+                DeviceMemCacheType::ReadOnlyCache => "read only",
+                DeviceMemCacheType::ReadWriteCache => "read write"
+            }),
+            global_mem_cache_size: info!(d, GlobalMemCacheSize),
+
+            local_mem_size: info!(d, LocalMemSize),
+            local_mem_type: info!(d, LocalMemType).map(|tpe| match tpe {
+                DeviceLocalMemType::None => "none",
+                DeviceLocalMemType::Local => "local",
+                DeviceLocalMemType::Global => "global"
+            }),
+
+            max_constant_buffer_size: info!(d, MaxConstantBufferSize),
+            max_constant_args: info!(d, MaxConstantArgs)
+        }
+    }
+
+
+    fn to_map(&self) -> Map {
+        let mut map = Map::new();
+        let mut set = |key: &str, value: Option<Dynamic>| {
+            map.insert(key.into(), value.unwrap_or(Dynamic::UNIT));
+        };
+        let int = |v: u64| Dynamic::from(v as INT);
+
+        set("name", self.name.clone().map(Into::into));
+        set("type", self.types.as_ref().map(|t| t.iter().map(|&s| Dynamic::from(s)).collect::<Array>().into()));
+        set("vendor", self.vendor.clone().map(Into::into));
+        set("version", self.version.clone().map(Into::into));
+        set("driver_version", self.driver_version.clone().map(Into::into));
+        set("available", self.available.map(Into::into));
+
+        set("max_compute_units", self.max_compute_units.map(|v| int(v as u64)));
+        set("max_work_item_dimensions", self.max_work_item_dimensions.map(|v| int(v as u64)));
+        set("max_workgroup_size", self.max_workgroup_size.map(|v| int(v as u64)));
+        set("max_clock_frequency_mhz", self.max_clock_frequency.map(|v| int(v as u64)));
+        set("max_mem_alloc_size", self.max_mem_alloc_size.map(int));
+        set("max_parameter_size", self.max_parameter_size.map(|v| int(v as u64)));
+        set("max_samplers", self.max_samplers.map(|v| int(v as u64)));
+
+        set("image_support", self.image_support.map(Into::into));
+        set("image2d_max", self.image2d_max.map(|(w, h)| vec![int(w as u64), int(h as u64)].into()));
+        set("image3d_max", self.image3d_max.map(|(w, h, d)| vec![int(w as u64), int(h as u64), int(d as u64)].into()));
+
+        set("global_mem_size", self.global_mem_size.map(int));
+        set("global_mem_cache", self.global_mem_cache.map(Into::into));
+        set("global_mem_cache_size", self.global_mem_cache_size.map(int));
+
+        set("local_mem_size", self.local_mem_size.map(int));
+        set("local_mem_type", self.local_mem_type.map(Into::into));
+
+        set("max_constant_buffer_size", self.max_constant_buffer_size.map(int));
+        set("max_constant_args", self.max_constant_args.map(|v| int(v as u64)));
+        map
+    }
+}
+
+
+impl PlatformInfo {
+
+
+    fn to_map(&self) -> Map {
+        let mut map = Map::new();
+        let string = |s: &Option<String>| s.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT);
+        map.insert("name".into(), string(&self.name));
+        map.insert("vendor".into(), string(&self.vendor));
+        map.insert("version".into(), string(&self.version));
+        map.insert("devices".into(), match &self.devices {
+            Some(devices) => devices.iter().map(|d| Dynamic::from(d.to_map())).collect::<Array>().into(),
+            None => Dynamic::UNIT
+        });
+        map
+    }
+}
+
+
+/// The inventory as json: `{"platforms": [...]}`, the sizes in bytes and the fields that could not
+/// be queried being `null`
+pub fn to_json(platforms: &[PlatformInfo]) -> String {
+    let mut map = Map::new();
+    map.insert("platforms".into(), platforms.iter().map(|p| Dynamic::from(p.to_map())).collect::<Array>().into());
+    json::format_map(&map)
+}
@@ -0,0 +1,231 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Coordination of several processes working on the same source and output directories (on shared
+// storage), so that nodes can be added to a running job.
+//
+// Each input file is claimed by creating its lock file in `<output>/.imgproc/claims`, which only
+// one process can do. The claims and the completed files are appended to `<output>/.imgproc/journal`
+// as `claim <node> <file>` and `done <node> <file>` lines. A process restarted with the same node
+// name takes its unfinished claims back and skips the files it already completed. The process
+// running a node is recorded in `<output>/.imgproc/nodes`, so that a second process of the same
+// host cannot take the name (the host name by default) while the first one runs.
+//
+// The device of each run is recorded as `device <node> <name and driver version>`, so that a resumed
+// run can tell when it continues on another device, whose outputs may differ slightly.
+
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use sha2::{Sha256, Digest};
+
+
+/// Directory of the coordination files, in the output directory
+const JOURNAL_DIR: &str = ".imgproc";
+
+
+/// Claims the input files of a run, and records the ones completed
+#[derive(Clone)]
+pub struct Journal {
+    /// Append-only journal file
+    file: PathBuf,
+    claims_dir: PathBuf,
+    /// Source directory the journaled paths are relative to
+    src_root: PathBuf,
+    node: String,
+    /// Files completed by this node in a previous run
+    done: HashSet<String>,
+    /// Device of the last run of this node
+    device: Option<String>
+}
+
+
+impl Journal {
+
+
+    pub fn open(src_root: &Path, out_dir: &Path, node: &str) -> Result<Self, String> {
+        let dir = out_dir.join(JOURNAL_DIR);
+        let claims_dir = dir.join("claims");
+        fs::create_dir_all(&claims_dir)
+            .map_err(|e| format!("Could not create directory `{}`: {}", claims_dir.display(), e))?;
+
+        let file = dir.join("journal");
+        let node = node.replace(' ', "_");
+        register_node(&dir.join("nodes"), &node)?;
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Could not read `{}`: {}", file.display(), e))
+        };
+
+        let mut done = HashSet::new();
+        let mut device = None;
+        for line in content.lines() {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("done"), Some(n), Some(path)) if n == node => { done.insert(path.to_string()); }
+                (Some("device"), Some(n), Some(name)) if n == node => device = Some(name.to_string()),
+                _ => ()
+            }
+        }
+
+        Ok(Self {
+            file,
+            claims_dir,
+            src_root: src_root.to_path_buf(),
+            node,
+            done,
+            device
+        })
+    }
+
+
+    /// Tries to claim an input file, returning whether this process should process it
+    pub fn claim(&self, in_file: &Path) -> bool {
+        let rel = self.relative(in_file);
+        if self.done.contains(&rel) {
+            return false;
+        }
+
+        let lock = self.claims_dir.join(format!("{:x}", Sha256::digest(rel.as_bytes())));
+        match OpenOptions::new().write(true).create_new(true).open(&lock) {
+            Ok(mut f) => {
+                f.write_all(self.node.as_bytes())
+                    .unwrap_or_else(|e| panic!("Could not write `{}`: {}", lock.display(), e));
+                self.append("claim", &rel);
+                true
+            }
+            // claimed before, by this node if it was restarted
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                fs::read_to_string(&lock).map(|owner| owner == self.node).unwrap_or(false)
+            }
+            Err(e) => panic!("Could not create `{}`: {}", lock.display(), e)
+        }
+    }
+
+
+    /// Whether an input file is left to this process: neither completed by it in a previous run nor
+    /// claimed by another node. Unlike `claim`, this does not claim it.
+    pub fn pending(&self, in_file: &Path) -> bool {
+        let rel = self.relative(in_file);
+        if self.done.contains(&rel) {
+            return false;
+        }
+        let lock = self.claims_dir.join(format!("{:x}", Sha256::digest(rel.as_bytes())));
+        fs::read_to_string(&lock).map(|owner| owner == self.node).unwrap_or(true)
+    }
+
+
+    /// Records that the outputs of an input file are written
+    pub fn done(&self, in_file: &Path) {
+        self.append("done", &self.relative(in_file));
+    }
+
+
+    /// Records the device this run computes on. When the last run of this node used another
+    /// device, this is an error if `require_same`, and a warning otherwise.
+    pub fn pin_device(&mut self, device: &str, require_same: bool) -> Result<(), String> {
+        match &self.device {
+            Some(previous) if previous == device => return Ok(()),
+            Some(previous) if require_same => {
+                return Err(format!("The previous run of `{}` computed on `{}` but this one would compute on `{}`",
+                    self.node, previous, device));
+            }
+            Some(previous) => eprintln!("Warning: the previous run of `{}` computed on `{}`, the outputs of `{}` may differ slightly",
+                self.node, previous, device),
+            None => ()
+        }
+
+        self.append("device", device);
+        self.device = Some(device.to_string());
+        Ok(())
+    }
+
+
+    fn relative(&self, in_file: &Path) -> String {
+        in_file.strip_prefix(&self.src_root).unwrap_or(in_file).display().to_string()
+    }
+
+
+    fn append(&self, event: &str, rel: &str) {
+        let line = format!("{} {} {}\n", event, self.node, rel);
+        OpenOptions::new().create(true).append(true).open(&self.file)
+            // a single write per line, so that the lines of the processes do not interleave
+            .and_then(|mut f| f.write_all(line.as_bytes()))
+            .unwrap_or_else(|e| panic!("Could not write `{}`: {}", self.file.display(), e));
+    }
+}
+
+
+/// Records this process as the one running `node`, unless a running process of this host already is
+fn register_node(nodes_dir: &Path, node: &str) -> Result<(), String> {
+    fs::create_dir_all(nodes_dir)
+        .map_err(|e| format!("Could not create directory `{}`: {}", nodes_dir.display(), e))?;
+    let file = nodes_dir.join(format!("{:x}", Sha256::digest(node.as_bytes())));
+
+    let host = default_node_name();
+    if let Ok(owner) = fs::read_to_string(&file) {
+        // the processes of other hosts cannot be checked
+        let running = owner.trim().rsplit_once(' ')
+            .filter(|(owner_host, _)| *owner_host == host)
+            .and_then(|(_, pid)| pid.parse::<u32>().ok())
+            .filter(|&pid| pid != std::process::id() && process_running(pid));
+        if let Some(pid) = running {
+            return Err(format!("The node `{}` of the journal is run by the process {} of this host, give each process its own --node-name",
+                node, pid));
+        }
+    }
+    fs::write(&file, format!("{} {}", host, std::process::id()))
+        .map_err(|e| format!("Could not write `{}`: {}", file.display(), e))
+}
+
+
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    // the signal 0 only checks the process, which may belong to another user
+    let signaled = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    signaled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+
+#[cfg(not(unix))]
+fn process_running(_pid: u32) -> bool {
+    false
+}
+
+
+/// Default node name: the host name
+pub fn default_node_name() -> String {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
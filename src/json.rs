@@ -0,0 +1,104 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Json output of rhai values. `rhai::format_map_as_json` only handles maps nested in maps, the
+// arrays being written in rhai syntax.
+
+
+use std::fmt::Write;
+
+use rhai::{Dynamic, Map, Array, FLOAT, INT};
+
+
+/// A map as a json object
+pub fn format_map(map: &Map) -> String {
+    let mut json = String::new();
+    write_map(&mut json, map);
+    json
+}
+
+
+fn write_value(json: &mut String, value: &Dynamic) {
+    if let Some(map) = value.read_lock::<Map>() {
+        write_map(json, &map);
+    } else if let Some(array) = value.read_lock::<Array>() {
+        json.push('[');
+        for (i, item) in array.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write_value(json, item);
+        }
+        json.push(']');
+    } else if let Ok(f) = value.as_float() {
+        // json has no infinite numbers
+        if f.is_finite() {
+            write!(json, "{:?}", f as FLOAT).unwrap();
+        } else {
+            json.push_str("null");
+        }
+    } else if let Ok(i) = value.as_int() {
+        write!(json, "{}", i as INT).unwrap();
+    } else if let Ok(b) = value.as_bool() {
+        write!(json, "{}", b).unwrap();
+    } else if value.is::<()>() {
+        json.push_str("null");
+    } else {
+        write_string(json, &value.to_string());
+    }
+}
+
+
+fn write_map(json: &mut String, map: &Map) {
+    json.push('{');
+    for (i, (key, value)) in map.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_string(json, key);
+        json.push(':');
+        write_value(json, value);
+    }
+    json.push('}');
+}
+
+
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+}
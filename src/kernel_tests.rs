@@ -0,0 +1,279 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Runs kernels in isolation against synthetic inputs, checking invariants of their output.
+//
+// The test spec is a json file:
+// {
+//     "width": 64, "height": 64,
+//     "tests": [
+//         {
+//             "name": "gaussian blur",
+//             "kernel": "apply_kernel",
+//             "input": "impulse",
+//             "args": ["input", "output", [0.0625, 0.125, 0.0625, 0.125, 0.25, 0.125, 0.0625, 0.125, 0.0625], 3, 3],
+//             "energy": 0.02,
+//             "range": [0, 255]
+//         }
+//     ]
+// }
+//
+// `input` is one of `gradient`, `impulse`, `noise` (with an optional `seed`) or `constant`
+// (with an optional `value`). The checks are:
+//  - `range`: every output channel is within [min, max]
+//  - `energy`: the sum of the output differs from the sum of the input by at most this ratio
+//  - `max_diff`: no output channel differs from the input by more than this value
+//  - `snapshot`: the buffers declared with `{"float_buffer": len}` or `{"int_buffer": len}` in `args`
+//    match the golden values saved in this file (relative to the spec), within the relative `epsilon`
+//    (1e-5 by default). The file is written when it does not exist or with `--update-snapshots`.
+
+
+use std::path::{Path, PathBuf};
+
+use image::RgbImage;
+
+use ocl::{Platform, Device};
+
+use rhai::{Engine, Dynamic, Map, Array};
+
+use crate::compute::KernelRunner;
+use crate::{RED, GREEN, CLEAR};
+
+
+/// Where the golden buffers of the tests are
+struct Snapshots {
+    dir: PathBuf,
+    update: bool
+}
+
+
+/// Runs the kernel tests of `spec` on `program`, returning whether they all passed
+pub fn run_kernel_tests(program: &str, spec_file: &Path, devices: &[(Platform, Device)],
+        trace_kernels: bool, update_snapshots: bool) -> bool {
+    let snapshots = Snapshots {
+        dir: spec_file.parent().map(Path::to_path_buf).unwrap_or_default(),
+        update: update_snapshots
+    };
+
+    let spec = match read_json(spec_file) {
+        Ok(spec) => spec,
+        Err(e) => {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            return false;
+        }
+    };
+
+    let get_dim = |key: &str| spec.get(key).and_then(|v| v.as_int().ok()).unwrap_or(64) as usize;
+    let size = (get_dim("width"), get_dim("height"));
+
+    let tests = spec.get("tests").and_then(|t| t.read_lock::<Array>().map(|t| t.clone())).unwrap_or_default();
+
+    let mut runner = KernelRunner::init(program, size, devices);
+    runner.set_trace_kernels(trace_kernels);
+
+    let mut failed = 0;
+    for (i, test) in tests.iter().enumerate() {
+        let test = match test.read_lock::<Map>() {
+            Some(test) => test.clone(),
+            None => {
+                eprintln!("{}Test {} is not an object{}", RED, i, CLEAR);
+                failed += 1;
+                continue;
+            }
+        };
+        let name = get_str(&test, "name").or_else(|| get_str(&test, "kernel")).unwrap_or_else(|| format!("test {}", i));
+
+        match run_test(&mut runner, &test, size, &snapshots) {
+            Ok(()) => println!("{}ok{}      {}", GREEN, CLEAR, name),
+            Err(e) => {
+                println!("{}FAILED{}  {}: {}", RED, CLEAR, name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    failed == 0
+}
+
+
+fn read_json(file: &Path) -> Result<Map, String> {
+    let json = std::fs::read_to_string(file)
+        .map_err(|e| format!("Could not read `{}`: {}", file.display(), e))?;
+    Engine::new().parse_json(json, true)
+        .map_err(|e| format!("Invalid json in `{}`: {}", file.display(), e))
+}
+
+
+fn get_str(map: &Map, key: &str) -> Option<String> {
+    map.get(key).and_then(|v| v.clone().into_string().ok())
+}
+
+
+fn get_num(map: &Map, key: &str) -> Option<f64> {
+    map.get(key).and_then(|v| v.as_float().ok().or_else(|| v.as_int().ok().map(|v| v as f64)))
+}
+
+
+fn run_test(runner: &mut KernelRunner, test: &Map, size: (usize, usize), snapshots: &Snapshots) -> Result<(), String> {
+    let kernel = get_str(test, "kernel").ok_or("No kernel to test")?;
+    let input = synthetic_input(test, size)?;
+    let args = match test.get("args") {
+        Some(args) => args.read_lock::<Array>().map(|a| a.clone()).ok_or("`args` is not an array")?,
+        None => vec![Dynamic::from("input"), Dynamic::from("output")]
+    };
+
+    let output = runner.run(&kernel, &input, &args)?;
+
+    if let Some(range) = test.get("range") {
+        let range = range.read_lock::<Array>().map(|a| a.clone()).unwrap_or_default();
+        let bound = |i: usize| range.get(i).and_then(|v| v.as_int().ok());
+        let (min, max) = match (bound(0), bound(1)) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Err("`range` should be [min, max]".into())
+        };
+        if let Some(px) = output.as_raw().iter().find(|px| (**px as i64) < min || (**px as i64) > max) {
+            return Err(format!("output value {} is out of [{}, {}]", px, min, max));
+        }
+    }
+
+    if let Some(tolerance) = get_num(test, "energy") {
+        let energy = |img: &RgbImage| img.as_raw().iter().map(|px| *px as f64).sum::<f64>();
+        let (in_energy, out_energy) = (energy(&input), energy(&output));
+        let ratio = (out_energy - in_energy).abs() / in_energy.max(1.0);
+        if ratio > tolerance {
+            return Err(format!("energy changed by {:.2}% (input {}, output {})", ratio * 100.0, in_energy, out_energy));
+        }
+    }
+
+    if let Some(max_diff) = get_num(test, "max_diff") {
+        let diff = input.as_raw().iter().zip(output.as_raw())
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .max()
+            .unwrap_or(0);
+        if diff as f64 > max_diff {
+            return Err(format!("output differs from the input by {} (at most {} expected)", diff, max_diff));
+        }
+    }
+
+    if let Some(snapshot) = get_str(test, "snapshot") {
+        let epsilon = get_num(test, "epsilon").unwrap_or(1e-5);
+        check_snapshot(&runner.read_output_buffers(), &snapshots.dir.join(snapshot), epsilon, snapshots.update)?;
+    }
+
+    Ok(())
+}
+
+
+/// Compares the buffers to their golden values, saving them instead when there are none yet
+fn check_snapshot(buffers: &[(String, Vec<f64>)], file: &Path, epsilon: f64, update: bool) -> Result<(), String> {
+    if update || !file.exists() {
+        return write_snapshot(buffers, file);
+    }
+
+    let golden = read_json(file)?;
+    if let Some(name) = golden.keys().find(|name| !buffers.iter().any(|(b, _)| b == name.as_str())) {
+        return Err(format!("the snapshot has a buffer `{}` the test does not declare", name));
+    }
+
+    for (name, values) in buffers {
+        let expected = golden.get(name.as_str())
+            .and_then(|v| v.read_lock::<Array>().map(|a| a.clone()))
+            .ok_or_else(|| format!("buffer `{}` is not in the snapshot", name))?;
+        if expected.len() != values.len() {
+            return Err(format!("buffer `{}` has {} values but the snapshot has {}", name, values.len(), expected.len()));
+        }
+
+        for (i, (value, expected)) in values.iter().zip(expected.iter()).enumerate() {
+            // non finite values are saved as null
+            let expected = expected.as_float().ok().or_else(|| expected.as_int().ok().map(|v| v as f64));
+            let matches = match expected {
+                Some(expected) => (value - expected).abs() <= epsilon * value.abs().max(expected.abs()),
+                None => !value.is_finite()
+            };
+            if !matches {
+                let expected = expected.map(|v| v.to_string()).unwrap_or_else(|| String::from("not finite"));
+                return Err(format!("`{}`[{}] is {} but the snapshot has {}", name, i, value, expected));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+fn write_snapshot(buffers: &[(String, Vec<f64>)], file: &Path) -> Result<(), String> {
+    let mut json = String::from("{\n");
+    for (i, (name, values)) in buffers.iter().enumerate() {
+        let values: Vec<String> = values.iter()
+            .map(|v| if v.is_finite() { format!("{:?}", v) } else { String::from("null") })
+            .collect();
+        let sep = if i + 1 < buffers.len() { "," } else { "" };
+        json.push_str(&format!("    \"{}\": [{}]{}\n", name, values.join(", "), sep));
+    }
+    json.push_str("}\n");
+
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Could not create `{}`: {}", dir.display(), e))?;
+    }
+    std::fs::write(file, json).map_err(|e| format!("Could not write snapshot `{}`: {}", file.display(), e))?;
+    println!("  wrote snapshot `{}`", file.display());
+    Ok(())
+}
+
+
+/// Generates the input image of a test
+fn synthetic_input(test: &Map, size: (usize, usize)) -> Result<RgbImage, String> {
+    let (w, h) = (size.0 as u32, size.1 as u32);
+    let input = get_str(test, "input").unwrap_or_else(|| String::from("gradient"));
+
+    match input.as_str() {
+        "gradient" => Ok(RgbImage::from_fn(w, h, |x, y| {
+            let r = (x * 255 / (w - 1).max(1)) as u8;
+            let g = (y * 255 / (h - 1).max(1)) as u8;
+            image::Rgb([r, g, ((r as u32 + g as u32) / 2) as u8])
+        })),
+        "impulse" => Ok(RgbImage::from_fn(w, h, |x, y| {
+            if x == w / 2 && y == h / 2 { image::Rgb([255; 3]) } else { image::Rgb([0; 3]) }
+        })),
+        "noise" => {
+            // xorshift, so that the inputs are the same on every run
+            let mut state = get_num(test, "seed").unwrap_or(1.0) as u32 | 1;
+            let mut next = move || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            };
+            Ok(RgbImage::from_fn(w, h, |_, _| image::Rgb([next(), next(), next()])))
+        }
+        "constant" => {
+            let value = get_num(test, "value").unwrap_or(128.0) as u8;
+            Ok(RgbImage::from_pixel(w, h, image::Rgb([value; 3])))
+        }
+        _ => Err(format!("Unknown synthetic input `{}` (expected gradient, impulse, noise or constant)", input))
+    }
+}
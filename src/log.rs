@@ -0,0 +1,84 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Messages of the pipeline scripts (`print` and `debug`), printed with the image being processed
+// as context, above the progress bar instead of over it.
+
+
+use std::sync::Mutex;
+
+use crate::formats;
+
+
+/// Images the messages are about, `None` outside of the runs of the pipeline
+static CONTEXT: Mutex<Option<String>> = Mutex::new(None);
+/// Last line of the terminal when it is a progress bar rewritten in place, drawn again after the messages
+static PROGRESS: Mutex<Option<String>> = Mutex::new(None);
+
+
+/// Sets the images of the next messages, the file of an image or a batch of images
+pub fn set_context(context: Option<String>) {
+    *CONTEXT.lock().unwrap() = context;
+}
+
+
+/// Prints a new progress bar
+pub fn start_progress(line: &str) {
+    println!("{}", line);
+    if formats::colored() {
+        *PROGRESS.lock().unwrap() = Some(line.to_string());
+    }
+}
+
+
+/// Replaces the progress bar, which is left as is by the messages once `finished`. Without escape
+/// sequences, the new bar is printed on a new line.
+pub fn update_progress(line: &str, finished: bool) {
+    let mut progress = PROGRESS.lock().unwrap();
+    if progress.is_some() {
+        print!("\x1b[A\r\x1b[K");
+    }
+    println!("{}", line);
+    *progress = (formats::colored() && !finished).then(|| line.to_string());
+}
+
+
+/// Prints a message of a script
+pub fn script_message(text: &str) {
+    let progress = PROGRESS.lock().unwrap();
+    if progress.is_some() {
+        print!("\x1b[A\r\x1b[K");
+    }
+    match &*CONTEXT.lock().unwrap() {
+        Some(context) => println!("{}{}: {}", crate::trace::log_prefix(), context, text),
+        None => println!("{}{}", crate::trace::log_prefix(), text)
+    }
+    if let Some(line) = &*progress {
+        println!("{}", line);
+    }
+}
@@ -29,252 +29,1190 @@ extern crate rhai;
 
 mod formats;
 mod compute;
+mod package;
+mod animation;
+mod process;
+mod encode;
+mod decode;
+mod color;
+mod kernel_tests;
+mod abtest;
+mod expand;
+mod journal;
+mod thermal;
+mod source;
+mod sink;
+mod json;
+mod inventory;
+mod negotiate;
+mod bench;
+mod selftest;
+mod integrity;
+mod warnings;
+mod diskspace;
+mod report;
+mod trace;
+mod log;
+
+use clap::{Parser, Subcommand, CommandFactory};
+use clap_complete::Shell;
+
+use compute::{CInstance, ComputeOptions, DeviceType, FloatMapping, Passthrough, InitError};
+use package::Package;
+use thermal::ThermalLimits;
+use process::{process_src, ExistingOutput};
+use encode::{EncodeOptions, OutFormat, PngCompression, PngFilter, JpegSubsampling, Oversized};
+use formats::ColorMode;
+
+use std::path::{Path, PathBuf};
+
+use ocl::{Platform, Device};
+
+
+pub use formats::{RED, GREEN, CLEAR};
+
+
+/// Exit status of the process, by kind of failure
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Failure {
+    /// The arguments, or the files they give, are invalid
+    Arguments = 2,
+    /// The OpenCL device could not be set up
+    Device = 3,
+    /// The pipeline could not be compiled or initialized
+    Pipeline = 4,
+    /// Some images could not be processed or written
+    Images = 5
+}
+
+
+impl Failure {
 
-use clap::Parser;
 
-use compute::CInstance;
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
 
-use image::RgbImage;
-use image::io::Reader as ImageReader;
+impl From<InitError> for Failure {
 
-use std::path::Path;
+    fn from(e: InitError) -> Self {
+        match e {
+            InitError::Device(_) => Self::Device,
+            InitError::Pipeline(_) => Self::Pipeline
+        }
+    }
+}
 
 
-pub const RED:   &str = "\x1b[38;2;255;0;0m";
-pub const GREEN: &str = "\x1b[38;2;0;255;0m";
-pub const CLEAR: &str = "\x1b[m";
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0  success
+    1  a check (selftest, test-kernels, abtest, bench) failed
+    2  invalid arguments
+    3  the OpenCL device could not be set up
+    4  the pipeline could not be compiled or initialized
+    5  some images could not be processed or written, or are invalid with --validate-only";
 
 
 /// An image processing program for use in AI image recognition
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_STATUS_HELP)]
 struct Args {
-    /// Source data
-    #[clap(value_parser)]
-    src: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+
+    /// Log every kernel launch with its resolved arguments before running it
+    #[clap(long, action, global = true)]
+    trace_kernels: bool,
+
+    /// Index of the OpenCL platform to use (see `list`), the default platform otherwise
+    #[clap(long, value_parser, global = true)]
+    platform: Option<usize>,
+
+    /// Index of the device to use on the platform (see `list`), the first one otherwise
+    #[clap(long, value_parser, global = true)]
+    device: Option<usize>,
+
+    /// Use the first device whose name matches this regex, ignoring case (see `list`)
+    #[clap(long, value_parser, global = true, value_name = "PATTERN", conflicts_with = "device")]
+    device_name: Option<String>,
+
+    /// Kind of device to use, by default a GPU falling back to the CPU when there is none
+    #[clap(long, value_enum, global = true, conflicts_with_all = &["device", "device-name"])]
+    device_type: Option<DeviceType>,
+
+    /// Decode the jpeg images as stored, without turning them upright as their EXIF orientation tells
+    #[clap(long, action, global = true)]
+    ignore_orientation: bool,
+
+    /// When to color the output
+    #[clap(long, value_enum, global = true, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    #[clap(short, long, action, global = true)]
+    verbose: bool,
+
+    /// Do not print the progress and information messages, only the warnings and errors
+    #[clap(short, long, action, global = true, conflicts_with = "verbose")]
+    quiet: bool
+}
+
+
+#[derive(Subcommand)]
+enum Command {
+    /// Process source images with a pipeline, or generate images with `--generate`
+    Run {
+        /// Source data: an image, a directory, a glob pattern such as `photos/**/*.jpg` (quoted, so that
+        /// the shell does not expand it), or a `dir://`, `list://` or `zip://` source
+        #[clap(value_parser, required_unless_present_any = &["generate", "files-from"])]
+        src: Option<String>,
+
+        /// Process the image files listed in FILE (`-` for the standard input), one path per line,
+        /// the outputs keeping their paths relative to the deepest directory holding them all
+        #[clap(long, value_parser, value_name = "FILE", conflicts_with_all = &["src", "generate", "validate-only"])]
+        files_from: Option<String>,
+
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+
+        /// Generate N images without any source, `run()` returning the label record of each image
+        #[clap(long, value_parser, value_name = "N", conflicts_with = "src")]
+        generate: Option<u64>,
+
+        #[clap(flatten)]
+        process: Box<ProcessArgs>
+    },
+    /// List all available platforms and devices
+    List {
+        /// Output format, json including every field of the verbose listing
+        #[clap(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat
+    },
+    /// Compile and initialize a pipeline without processing anything, checking its declaration
+    /// against a source when one is given
+    Validate {
+        /// Source data the pipeline is meant to process
+        #[clap(value_parser)]
+        src: Option<String>,
+
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+
+        /// The source directory holds one folder per class (see `run --classes`)
+        #[clap(long, action, requires = "src")]
+        classes: bool,
+
+        /// Images packed into a single launch (see `run --batch`)
+        #[clap(long, value_parser)]
+        batch: Option<usize>
+    },
+    /// Time the pipeline on an image, or on a synthetic image of the maximum dimentions
+    Bench {
+        /// Image to process
+        #[clap(value_parser)]
+        src: Option<String>,
+
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+
+        /// Number of timed runs
+        #[clap(long, value_parser, default_value_t = 20)]
+        iterations: usize,
+
+        /// Number of runs before the timed ones
+        #[clap(long, value_parser, default_value_t = 3)]
+        warmup: usize,
+
+        /// Seed of the random numbers of the pipeline
+        #[clap(long, value_parser, default_value_t = 0)]
+        seed: u64
+    },
+    /// Print the completion script of a shell, the device names being completed with the devices
+    /// of this machine
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell
+    },
+    /// Check that OpenCL works on each device (or the selected ones) by running a small built-in kernel
+    Selftest,
+    /// Run each kernel of a program on synthetic inputs, checking the invariants of a test spec
+    TestKernels {
+        /// Opencl program to test
+        #[clap(value_parser)]
+        program: String,
+        /// Test spec (json)
+        #[clap(value_parser)]
+        spec: String,
+        /// Overwrite the golden buffers of the tests with the current results
+        #[clap(long, action)]
+        update_snapshots: bool
+    },
+    /// Run two pipelines on the same source images and compare their outputs
+    Abtest {
+        /// Source data
+        #[clap(value_parser)]
+        src: String,
+
+        // pipeline A, whose dimentions, configuration and parameters pipeline B shares
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+
+        /// Rhai script pipeline B, compared to the pipeline A of --pipeline or --package
+        #[clap(long, value_parser)]
+        pipeline_b: String,
+        /// Opencl program of pipeline B, when it differs from the one of pipeline A
+        #[clap(long, value_parser)]
+        program_b: Option<String>,
+
+        /// Seed of the random numbers of both pipelines
+        #[clap(long, value_parser, default_value_t = 0)]
+        seed: u64,
+        /// Also write the metrics of every image and the aggregate statistics to a json file
+        #[clap(long, value_parser, value_name = "FILE")]
+        report: Option<String>
+    }
+}
+
+
+/// Pipeline to run: a package, or an opencl program and a rhai script
+#[derive(clap::Args, Clone)]
+struct PipelineArgs {
+    /// Pipeline package (.aipack)
+    #[clap(long, value_parser, conflicts_with_all = &["program", "pipeline"],
+        required_unless_present = "program")]
+    package: Option<String>,
+
     /// Opencl program to be used
-    #[clap(value_parser)]
+    #[clap(long, value_parser)]
     program: Option<String>,
-    /// Rhai script pipeline
-    #[clap(value_parser)]
+
+    /// Rhai script pipeline (without it, the `passes` of the configuration are run)
+    #[clap(long, value_parser, requires = "program")]
     pipeline: Option<String>,
 
-    #[clap(value_parser)]
-    /// The maximum width of the images to process
+    /// The maximum width of the images to process (defaults to the package manifest)
+    #[clap(long, value_parser, requires = "height")]
     width: Option<usize>,
-    #[clap(value_parser)]
-    /// The maximum height of the images to process
+
+    /// The maximum height of the images to process (defaults to the package manifest)
+    #[clap(long, value_parser, requires = "width")]
     height: Option<usize>,
 
+    /// rhai script configuration (defaults to the package configuration when running a package)
+    #[clap(short, long, value_parser)]
+    config: Option<String>,
+
+    /// Give the constant KEY to the pipeline scripts, the value being a boolean, an integer, a float
+    /// or else a string (repeatable)
+    #[clap(long = "set", value_parser = parse_param, value_name = "KEY=VALUE")]
+    params: Vec<(String, String)>
+}
+
+
+/// Options shared by every mode processing source images
+#[derive(clap::Args, Clone)]
+pub struct ProcessArgs {
     #[clap(short, long, value_parser, default_value_t = String::from("out"))]
-    /// Output file or directory
-    output: String,
+    /// Output file or directory, or a sink: `zip://out.zip`, `pack://out.bin`, `http://host:port/path`
+    /// or `null://` (statistics only)
+    pub output: String,
+
+    /// Pack up to N images into a single launch when they fit in the maximum dimentions.
+    /// The pipeline receives the `batch` buffer of (y offset, width, height) for each image.
+    #[clap(long, value_parser)]
+    pub batch: Option<usize>,
+
+    /// Allocate the images at the dimentions of each processed image instead of the maximum
+    /// dimentions, for devices that cannot hold the full size buffers
+    #[clap(long, action)]
+    pub realloc_buffers: bool,
+
+    /// Also assemble the outputs of a directory, in file name order, into an animation (gif or apng)
+    #[clap(long, value_parser)]
+    pub animate: Option<String>,
+
+    /// Delay between two frames of the animation in milliseconds
+    #[clap(long, value_parser, default_value_t = 100)]
+    pub frame_delay: u32,
+
+    /// Also save the normalized difference between each output and its input (`<name>_diff`)
+    #[clap(long, action)]
+    pub emit_diff: bool,
+
+    /// Also save a thumbnail of each output, fitting in SIZE by SIZE pixels, to `<output>_thumbnails`
+    #[clap(long, value_parser, value_name = "SIZE")]
+    pub thumbnails: Option<u32>,
+
+    /// The source directory holds one folder per class, the outputs being saved in the same folders.
+    /// The pipeline receives the class as `CLASS` and the overrides of `config.classes[CLASS]` in `config`
+    #[clap(long, action)]
+    pub classes: bool,
+
+    /// Also process the images of the nested folders of the source directory, recreating
+    /// their relative paths under the output
+    #[clap(long, action)]
+    pub recursive: bool,
+
+    /// How the pipelines computing their output in a float buffer (`ocl.set_float_output(buffer)`)
+    /// have it mapped to 8-bit values, unless they give the mapping themselves
+    #[clap(long, value_enum, value_name = "MAPPING", default_value_t = FloatMapping::Clip)]
+    pub float_mapping: FloatMapping,
+
+    /// Factor of the float output values before they are clipped or dithered
+    #[clap(long, value_parser, value_name = "FACTOR", default_value_t = 255.0)]
+    pub float_scale: f32,
+
+    /// Directory where `init()` caches its expensive buffers (`ocl.cache_buffer(buffer)`) for
+    /// the next runs to load them back (`ocl.load_cached(name)`) instead of computing them again
+    #[clap(long, value_parser, value_name = "DIR")]
+    pub init_cache: Option<PathBuf>,
+
+    /// Print each statement of the pipeline as it runs on the first image, with the variables
+    /// it changes and the kernels it calls
+    #[clap(long, action)]
+    pub debug_script: bool,
+
+    /// Format of the outputs, which take its extension, instead of the format of their input
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub out_format: Option<OutFormat>,
+
+    /// Formats each output is also saved in, next to it with their extension, encoding the image
+    /// read back once (e.g. `--also-save jpeg,webp`)
+    #[clap(long, value_enum, value_delimiter = ',', value_name = "FORMATS")]
+    pub also_save: Vec<OutFormat>,
+
+    /// Compression level of the png outputs
+    #[clap(long, value_enum, default_value_t = PngCompression::Default)]
+    pub png_compression: PngCompression,
+
+    /// Filter strategy of the png outputs
+    #[clap(long, value_enum, default_value_t = PngFilter::Adaptive)]
+    pub png_filter: PngFilter,
+
+    /// Quality of the jpeg outputs, from 1 to 100
+    #[clap(long, value_parser, default_value_t = 90)]
+    pub jpeg_quality: u8,
+
+    /// Quality of the lossy webp outputs, from 0 to 100 (requires the `webp` feature)
+    #[clap(long, value_parser, default_value_t = 90)]
+    pub webp_quality: u8,
+
+    /// Chroma subsampling of the jpeg outputs (requires the `mozjpeg` feature)
+    #[clap(long, value_enum)]
+    pub jpeg_subsampling: Option<JpegSubsampling>,
+
+    /// Maximum size of an output file, the larger outputs being handled as set by --oversized
+    #[clap(long, value_parser, value_name = "BYTES")]
+    pub max_output_bytes: Option<usize>,
+
+    /// What to do with the outputs larger than --max-output-bytes: fail to save them, or recompress
+    /// them to jpeg files of decreasing quality (the file taking the `.jpg` extension)
+    #[clap(long, value_enum, default_value_t = Oversized::Reject, requires = "max-output-bytes")]
+    pub oversized: Oversized,
+
+    /// Downscale the images larger than the maximum dimentions to fit in them, using the
+    /// jpeg DCT scaling while decoding when possible
+    #[clap(long, action)]
+    pub downscale: bool,
+
+    /// Convert the images with an embedded ICC profile to the working space before processing them
+    #[clap(long, action)]
+    pub color_manage: bool,
+
+    /// ICC profile of the working space (defaults to sRGB)
+    #[clap(long, value_parser, value_name = "ICC")]
+    pub working_space: Option<String>,
+
+    /// Tag the png and jpeg outputs with the working space color profile
+    #[clap(long, action)]
+    pub tag_outputs: bool,
+
+    /// How to process animated gif, png and webp inputs
+    #[clap(long, value_enum, default_value_t = AnimatedInput::First)]
+    pub animated: AnimatedInput,
+
+    /// Seed of the random numbers of the pipeline (`ocl.rand()`, `ocl.rand_int()` and `SEED`)
+    /// and of the files picked by --sample
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Directory of the images the `random_background` built-in picks from
+    #[clap(long, value_parser, value_name = "DIR")]
+    pub backgrounds: Option<String>,
+
+    /// Keep MB megabytes free on the output filesystem: before the run, the size of the outputs is
+    /// extrapolated from a few files and checked against the space above it, and the run pauses
+    /// while less is free
+    #[clap(long, value_parser, value_name = "MB")]
+    pub min_free_space: Option<u64>,
+
+    /// Go on with the next files when a file cannot be decoded, processed or saved, listing the
+    /// failed files at the end of the run (which still exits with an error)
+    #[clap(long, action)]
+    pub keep_going: bool,
+
+    /// Overwrite the output files that already exist (the default)
+    #[clap(long, action, conflicts_with_all = &["no-clobber", "backup"])]
+    pub overwrite: bool,
+
+    /// Keep the output files that already exist instead of overwriting them
+    #[clap(long, action, conflicts_with = "backup")]
+    pub no_clobber: bool,
+
+    /// Rename the output files that already exist to `<name>~` before writing the new ones
+    #[clap(long, action)]
+    pub backup: bool,
+
+    /// Skip the inputs whose output already exists and is newer than them, to resume an interrupted
+    /// run by running it again (the outputs named by `out_name()` are not looked for)
+    #[clap(long, action)]
+    pub skip_existing: bool,
+
+    /// Write a json report of the run to FILE (`report.json` in the output directory by default), with
+    /// the count of failed files, the time spent on each input and in each stage of the pipeline
+    #[clap(long, value_parser, value_name = "FILE", min_values = 0, max_values = 1, require_equals = true)]
+    pub report: Option<Option<String>>,
+
+    /// Trace the run with the identifier ID (generated when not given): the identifier, the version of
+    /// the pipeline and the device go in the logs, the records, the report and the metadata of the outputs
+    #[clap(long, value_parser, value_name = "ID", min_values = 0, max_values = 1, require_equals = true)]
+    pub trace_id: Option<Option<String>>,
+
+    /// Write the sha256 of every output file to a `SHA256SUMS` manifest in the output directory
+    #[clap(long, action)]
+    pub checksums: bool,
+
+    /// Only process a random FRACTION (from 0 to 1) of the files of each source directory, picked from --seed
+    #[clap(long, value_parser, value_name = "FRACTION", conflicts_with = "generate")]
+    pub sample: Option<f64>,
+
+    /// Leave out the first N files of each source directory (after --sample)
+    #[clap(long, value_parser, value_name = "N", default_value_t = 0, conflicts_with = "generate")]
+    pub skip: usize,
+
+    /// Only process the first N files of each source directory (after --sample and --skip)
+    #[clap(long, value_parser, value_name = "N", conflicts_with = "generate")]
+    pub limit: Option<usize>,
+
+    /// Only process the I-th of the shards of the inputs (from 0), for splitting a dataset across jobs
+    #[clap(long, value_parser, value_name = "I", requires = "shard-count")]
+    pub shard_index: Option<usize>,
+
+    /// Number of shards the inputs are split into, the files of each directory being dealt in name order
+    #[clap(long, value_parser, value_name = "N", requires = "shard-index")]
+    pub shard_count: Option<usize>,
+
+    /// Split the inputs across these devices of the platform (see `list`), each one running its own
+    /// instance of the pipeline on a shard of the files and writing its own manifests
+    #[clap(long, value_parser, value_delimiter = ',', value_name = "I,J,...",
+        conflicts_with_all = &["device", "device-name", "device-type", "generate", "journal", "animate", "estimate"])]
+    pub devices: Vec<usize>,
+
+    /// Suffix of the manifests of the run, set for each device with --devices
+    #[clap(skip)]
+    pub manifest_suffix: Option<String>,
+
+    /// Coordinate with the other processes using the same source directory and output directory
+    /// through a journal in the output, each file being processed by the first process claiming it
+    #[clap(long, action)]
+    pub journal: bool,
+
+    /// Name of this process in the journal (defaults to the host name). A process restarted with
+    /// the same name resumes its unfinished files; the processes running at once need their own names
+    #[clap(long, value_parser, value_name = "NAME", requires = "journal")]
+    pub node_name: Option<String>,
+
+    /// Refuse to resume a journaled run on another device (name and driver version) than the one
+    /// of the previous run of this node, instead of warning
+    #[clap(long, action, requires = "journal")]
+    pub require_same_device: bool,
+
+    /// Keep the device busy at most PERCENT of the time, sleeping between kernels, and lower the
+    /// priority of the process, to run in the background of a workstation in use
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=100), value_name = "PERCENT")]
+    pub throttle: Option<u8>,
+
+    /// Pause the processing while the GPU is hotter than CELSIUS degrees (read through NVML with
+    /// the `nvml` feature, or the hwmon sysfs interface)
+    #[clap(long, value_parser, value_name = "CELSIUS")]
+    pub max_gpu_temp: Option<f32>,
+
+    /// Pause the processing while the GPU draws more than WATTS
+    #[clap(long, value_parser, value_name = "WATTS")]
+    pub max_gpu_power: Option<f32>,
+
+    /// Process a random sample of K files of the source directory first, and ask for confirmation
+    /// after printing the run time and output size extrapolated to the files of the run
+    #[clap(long, value_parser, value_name = "K")]
+    pub estimate: Option<usize>,
+
+    /// Run after printing the estimate without asking for confirmation, for the runs without a terminal
+    #[clap(long, action, requires = "estimate")]
+    pub yes: bool,
+
+    /// Keep up to MB megabytes of decoded images in memory, for the inputs read several times in a run
+    #[clap(long, value_parser, value_name = "MB", default_value_t = 0)]
+    pub decode_cache: usize,
+
+    /// Copy each input to its output instead of running the pipeline, going through the decoding,
+    /// upload, download and encoding alone, to tell I/O issues from pipeline ones
+    #[clap(long, value_enum, value_name = "MODE", min_values = 0, max_values = 1, require_equals = true, default_missing_value = "copy")]
+    pub passthrough: Option<Passthrough>,
+
+    /// Only decode every image of the source, reporting the empty, truncated or corrupt files and
+    /// the unusual dimentions, without running the pipeline
+    #[clap(long, action, conflicts_with = "generate")]
+    pub validate_only: bool,
+
+    /// Number of threads decoding the upcoming source images and encoding the outputs while the device
+    /// computes (the animated inputs and those of --decode-cache being decoded on the main thread)
+    #[clap(long, value_parser, value_name = "N", default_value_t = 1)]
+    pub threads: usize,
+
+    /// Unit of the progress bar: files, or input bytes for the directories whose images have very
+    /// different sizes
+    #[clap(long, value_enum, value_name = "UNIT", default_value_t = ProgressUnit::Files)]
+    pub progress: ProgressUnit,
+
+    /// Number of threads decoding the images with --validate-only
+    #[clap(long, value_parser, value_name = "N", default_value_t = 1, requires = "validate-only")]
+    pub validate_threads: usize,
+
+    /// Also write the problems found by --validate-only to FILE as json
+    #[clap(long, value_parser, value_name = "FILE", requires = "validate-only")]
+    pub validation_report: Option<String>
+}
 
-    /// List all available platforms and devices
-    #[clap(short = 'l', long, action)]
-    list_platform: bool,
 
-    /// rhai script configuration
-    #[clap(short, long, value_parser)]
-    config: Option<String>,
+/// Processing of the animated inputs
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnimatedInput {
+    /// Only process the first frame
+    First,
+    /// Process every frame, as `<name>_<frame>`
+    Frames,
+    /// Do not process animated inputs
+    Skip
+}
+
 
-    #[clap(short, long, action)]
-    verbose: bool
+/// Unit of the progress of a run
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressUnit {
+    Files,
+    Bytes
 }
 
 
-// TODO: select device from command line (with default)
+/// Output format of the platform listing
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ListFormat {
+    Text,
+    Json
+}
 
 
-fn main() {
-    let args = Args::parse();
+impl PipelineArgs {
 
-    if args.list_platform {
-        list_platform(args.verbose);
-    } else {
 
-        let src = match args.src {
-            None => {
-                eprintln!("{}Provide source image or directory to process.{}", RED, CLEAR);
-                eprintln!("To print help use --help.");
-                return;
-            },
-            Some(s) => s
+    /// Compiles the pipeline, returning the kind of failure with the error
+    fn init(&self, opts: &ComputeOptions) -> Result<CInstance, (Failure, String)> {
+        let arguments = |e| (Failure::Arguments, e);
+        let pack = match &self.package {
+            Some(package) => Some(Package::open(Path::new(package)).map_err(arguments)?),
+            None => None
         };
 
-        let program = match args.program {
-            None => {
-                eprintln!("{}Provide the opencl program.{}", RED, CLEAR);
-                eprintln!("To print help use --help.");
-                return;
-            },
-            Some(s) => s
+        let size = match (self.width, self.height, pack.as_ref().and_then(|p| p.size)) {
+            (Some(w), Some(h), _) => (w, h),
+            (_, _, Some(size)) => size,
+            _ => return Err(arguments(String::from("Provide the maximum image dimentions (--width and --height).")))
         };
 
-        let pipeline = match args.pipeline {
-            None => {
-                eprintln!("{}Provide a pipepline to follow.{}", RED, CLEAR);
-                eprintln!("To print help use --help.");
-                return;
-            },
-            Some(s) => s
-        };
+        let config = self.config.clone()
+            .or_else(|| pack.as_ref().and_then(|p| p.config.clone()))
+            .unwrap_or_else(|| String::from("{}"));
 
+        // the program or the script of a package are only replaced by `with_pipeline`
+        let (program, pipeline) = match (&pack, &self.program, &self.pipeline) {
+            (Some(pack), program, pipeline) => (program.clone().unwrap_or_else(|| pack.program()),
+                pipeline.clone().or_else(|| Some(pack.pipeline()))),
+            (None, Some(program), pipeline) => (program.clone(), pipeline.clone()),
+            _ => return Err(arguments(String::from("Provide the opencl program, or a package.")))
+        };
 
-        let size = match (args.width, args.height) {
-            (Some(w), Some(h)) => (w, h),
-            _ => {
-                eprintln!("{}Provide the maximum image dimentions.{}", RED, CLEAR);
-                eprintln!("To print help use --help.");
-                return;
-            }
+        let opts = ComputeOptions {
+            params: self.params.clone(),
+            package_config: self.config.is_none() && pack.is_some(),
+            ..opts.clone()
         };
+        let mut compute = CInstance::init(&opts, program, pipeline, config, size).map_err(|e| (Failure::Pipeline, e))?;
+        if let Some(pack) = pack {
+            compute.keep_package(pack);
+        }
+        Ok(compute)
+    }
 
 
-        let config = match args.config {
-            Some(c) => c,
-            None => String::from("{}")
-        };
+    /// The same pipeline arguments with another rhai script, and another opencl program if given
+    fn with_pipeline(&self, pipeline: String, program: Option<String>) -> PipelineArgs {
+        PipelineArgs {
+            program: program.or_else(|| self.program.clone()),
+            pipeline: Some(pipeline),
+            ..self.clone()
+        }
+    }
+}
+
+
+/// Parses a `KEY=VALUE` parameter, the key being a rhai identifier
+fn parse_param(param: &str) -> Result<(String, String), String> {
+    let (key, value) = param.split_once('=').ok_or_else(|| format!("Expected KEY=VALUE, got `{}`", param))?;
+    let valid = key.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false)
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("`{}` is not a valid variable name", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
 
-        let mut compute = CInstance::init(args.verbose, program, pipeline, config, size);
 
-        use std::fs::metadata;
+/// Files of the source given by a glob pattern or by `--files-from`, relative to the directory
+/// returned with them
+fn source_matches(src: Option<&str>, files_from: Option<&str>) -> Result<Option<(String, Vec<PathBuf>)>, String> {
+    match (src, files_from) {
+        (Some(src), _) => expand_glob(src),
+        (None, Some(list)) => read_file_list(list).map(Some),
+        (None, None) => Ok(None)
+    }
+}
 
-        let src_meta = metadata(format!("{}", &src)).expect(format!("File `{}` does not exist", src).as_str());
 
-        if src_meta.is_dir() {
-            process_dir(&mut compute, Path::new(&src), Path::new(&args.output));
-        } else if src_meta.is_file() {
-            process_file(&mut compute, Path::new(&src), Path::new(&args.output));
+/// Expands a source that is a glob pattern rather than an existing path, returning the directory
+/// before the first wildcard and the matching files relative to it
+fn expand_glob(src: &str) -> Result<Option<(String, Vec<PathBuf>)>, String> {
+    let is_pattern = src.contains(['*', '?', '[']);
+    if !is_pattern || source::has_scheme(src) || Path::new(src).exists() {
+        return Ok(None);
+    }
+
+    let root: PathBuf = Path::new(src).components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect();
+    let root = if root.as_os_str().is_empty() { PathBuf::from(".") } else { root };
+
+    let paths = glob::glob(src).map_err(|e| format!("Invalid glob pattern `{}`: {}", src, e))?;
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| format!("Could not read `{}`: {}", e.path().display(), e.error()))?;
+        if path.is_file() {
+            files.push(path.strip_prefix(&root).map(Path::to_path_buf).unwrap_or(path));
         }
     }
+    if files.is_empty() {
+        return Err(format!("No file matches `{}`", src));
+    }
+    files.sort();
+    Ok(Some((root.to_string_lossy().into_owned(), files)))
 }
 
 
-/// Applies the compute pipeline to the input file, saving it to out_file
-fn process_file(compute: &mut CInstance, in_file: &Path, out_file: &Path) {
-    let img = ImageReader::open(in_file)
-        .expect(format!("Could not read file `{}`", in_file.to_str().unwrap()).as_str()).decode()
-        .expect(format!("Could not read image at `{}`", in_file.to_str().unwrap()).as_str());
-    let image: RgbImage = img.into_rgb8();
+/// Reads the list of files of `--files-from`, returning the deepest directory holding them all
+/// and the files relative to it. The listed files must all exist.
+fn read_file_list(list: &str) -> Result<(String, Vec<PathBuf>), String> {
+    use std::io::Read;
+
+    let mut content = String::new();
+    let read = if list == "-" {
+        std::io::stdin().read_to_string(&mut content).map(|_| ())
+    } else {
+        std::fs::File::open(list).and_then(|mut f| f.read_to_string(&mut content)).map(|_| ())
+    };
+    read.map_err(|e| format!("Could not read the list of files `{}`: {}", list, e))?;
+
+    let mut paths = Vec::new();
+    let mut invalid = Vec::new();
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match Path::new(line).canonicalize() {
+            Ok(path) if path.is_file() => paths.push(path),
+            Ok(_) => invalid.push(format!("`{}`: not a file", line)),
+            Err(e) => invalid.push(format!("`{}`: {}", line, e))
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(format!("The list `{}` has {} files that cannot be read:\n  {}", list, invalid.len(), invalid.join("\n  ")));
+    }
+    if paths.is_empty() {
+        return Err(format!("The list `{}` has no file to process", list));
+    }
 
-    let out = compute.compute(&image);
-    out.save(out_file)
-        .expect(format!("Could not save image to `{}`", out_file.to_str().unwrap()).as_str());
+    let mut root = paths[0].parent().map(Path::to_path_buf).unwrap_or_default();
+    for path in &paths {
+        while !path.starts_with(&root) {
+            root = root.parent().map(Path::to_path_buf).unwrap_or_default();
+        }
+    }
+    let files = paths.iter()
+        .map(|p| p.strip_prefix(&root).expect("the root holds every file").to_path_buf())
+        .collect();
+    Ok((root.to_string_lossy().into_owned(), files))
 }
 
 
-fn process_dir(compute: &mut CInstance, in_dir: &Path, out_dir: &Path) {
-    use std::fs;
+impl ProcessArgs {
 
-    let file_count = fs::read_dir(in_dir)
-        .expect(format!("Could not read files in `{}`", in_dir.to_str().unwrap()).as_str())
-        .count();
-    
-    let mut i = 0;
 
-    println!("<----------------------------------------> 0.00%");
+    pub fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            png_compression: self.png_compression,
+            png_filter: self.png_filter,
+            jpeg_quality: self.jpeg_quality,
+            jpeg_subsampling: self.jpeg_subsampling,
+            webp_quality: self.webp_quality,
+            format: self.out_format,
+            also_save: self.also_save.clone(),
+            output_profile: None,
+            max_bytes: self.max_output_bytes,
+            oversized: self.oversized,
+            trace: None
+        }
+    }
+
 
-    for file in fs::read_dir(in_dir).unwrap() {
-        match file {
-            Ok(file) => {
-                if file.file_type().unwrap().is_file() {
-                    let mut in_file = in_dir.to_path_buf();
-                    in_file.push(file.file_name());
+    /// Checks the shard and selection options
+    pub fn validate_selection(&self) -> Result<(), String> {
+        if let Some(sample) = self.sample.filter(|s| !(0.0..=1.0).contains(s)) {
+            return Err(format!("The sampled fraction should be between 0 and 1, not {}", sample));
+        }
+        match (self.shard_index, self.shard_count) {
+            (_, Some(0)) => Err(String::from("The shard count should be at least 1")),
+            (Some(i), Some(n)) if i >= n => Err(format!("The shard index should be less than the shard count ({})", n)),
+            _ => Ok(())
+        }
+    }
 
-                    let mut out_file = out_dir.to_path_buf();
-                    out_file.push(file.file_name());
 
-                    process_file(compute, in_file.as_path(), out_file.as_path());
+    /// Path of the output of an image at `path`, with the extension of --out-format
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        match self.out_format {
+            Some(format) => path.with_extension(format.extension()),
+            None => path.to_path_buf()
+        }
+    }
+
+
+    /// What to do with the output files that already exist
+    pub fn existing_outputs(&self) -> ExistingOutput {
+        if self.no_clobber {
+            ExistingOutput::Keep
+        } else if self.backup {
+            ExistingOutput::Backup
+        } else {
+            ExistingOutput::Overwrite
+        }
+    }
+
+
+    /// Whether --sample, --skip or --limit select some of the inputs
+    pub fn selects(&self) -> bool {
+        self.sample.is_some() || self.skip > 0 || self.limit.is_some()
+    }
+
+
+    /// The inputs of a list selected by --sample, --skip and --limit, before the sharding
+    pub fn select<I: Iterator>(&self, items: I) -> impl Iterator<Item = I::Item> {
+        let (sample, seed) = (self.sample, self.seed);
+        items.enumerate()
+            .filter(move |(i, _)| sample.map(|s| compute::sampled(*i, s, seed)).unwrap_or(true))
+            .map(|(_, item)| item)
+            .skip(self.skip)
+            .take(self.limit.unwrap_or(usize::MAX))
+    }
+
+
+    /// Whether the `i`-th input of a list belongs to the shard to process
+    pub fn in_shard(&self, i: usize) -> bool {
+        match (self.shard_index, self.shard_count) {
+            (Some(index), Some(count)) => i % count == index,
+            _ => true
+        }
+    }
+}
+
+
+fn main() {
+    let args = Args::parse();
+    formats::set_color_mode(args.color);
+    formats::set_quiet(args.quiet);
+    decode::set_apply_orientation(!args.ignore_orientation);
+
+    // the panics are failures of the processing, printed without the location meant for debugging
+    if !args.verbose && std::env::var_os("RUST_BACKTRACE").is_none() {
+        std::panic::set_hook(Box::new(|info| {
+            eprintln!("{}{}{}", RED, formats::panic_message(info.payload()), CLEAR);
+        }));
+    }
+
+    if let Err(e) = expand::load_dotenv(Path::new(expand::DOTENV_FILE)) {
+        eprintln!("Warning: {}", e);
+    }
+
+    if let Command::Run { process, .. } = &args.command {
+        if process.throttle.is_some() {
+            lower_priority();
+        }
+    }
+
+    if let Command::List { format } = args.command {
+        list_platform(args.verbose, format);
+        return;
+    }
+
+    if let Command::Completions { shell } = args.command {
+        print_completions(shell);
+        return;
+    }
+
+    if let Command::Selftest = args.command {
+        match compute::device_candidates(args.platform, args.device, args.device_name.as_deref(), args.device_type) {
+            Ok(devices) if selftest::run_selftest(&devices) => return,
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                Failure::Device.exit();
+            }
+        }
+    }
+
+    // the validation only decodes the sources, without the pipeline or the device
+    if let Command::Run { src, files_from, process, .. } = &args.command {
+        if process.validate_only && (src.is_some() || files_from.is_some()) {
+            let report = process.validation_report.as_deref().map(Path::new);
+            let matches = source_matches(src.as_deref(), files_from.as_deref()).unwrap_or_else(|e| {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                Failure::Arguments.exit();
+            });
+            let valid = match (src, matches) {
+                (_, Some((root, files))) => {
+                    let files: Vec<PathBuf> = files.iter().map(|f| Path::new(&root).join(f)).collect();
+                    integrity::validate_files(&files, process.validate_threads, report)
                 }
+                (Some(src), None) if source::has_scheme(src) => match source::open_source(src, false) {
+                    Ok(source) => integrity::validate_source(src, source, report),
+                    Err(e) => {
+                        eprintln!("{}{}{}", RED, e, CLEAR);
+                        Failure::Arguments.exit();
+                    }
+                },
+                (Some(src), None) => integrity::validate_images(Path::new(src), process.validate_threads, report),
+                (None, None) => unreachable!("the list of files gives matches")
+            };
+            if !valid {
+                Failure::Images.exit();
+            }
+            return;
+        }
+    }
+
+    let devices = match compute::device_candidates(args.platform, args.device, args.device_name.as_deref(), args.device_type) {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            Failure::Device.exit();
+        }
+    };
+
+    let init = |pipeline: &PipelineArgs, opts: &ComputeOptions| match pipeline.init(opts) {
+        Ok(compute) => compute,
+        Err((failure, e)) => {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            if failure == Failure::Arguments {
+                eprintln!("To print help use --help.");
             }
-            _ => {}
+            failure.exit();
         }
+    };
+    let init_device = |compute: &CInstance| if let Err(e) = compute.init_device() {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        Failure::from(e).exit();
+    };
+
+    match args.command {
+        Command::Run { src, files_from, pipeline, generate, process } => {
+            if let Some(id) = &process.trace_id {
+                let id = id.clone().unwrap_or_else(trace::generate_id);
+                if !formats::quiet() {
+                    println!("Trace id: {}", id);
+                }
+                trace::set_run_id(id);
+            }
+            let matches = match source_matches(src.as_deref(), files_from.as_deref()) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    eprintln!("{}{}{}", RED, e, CLEAR);
+                    Failure::Arguments.exit();
+                }
+            };
+            if !process.devices.is_empty() {
+                let src = match &matches {
+                    Some((root, _)) => root.clone(),
+                    None => src.expect("clap requires the source without --generate")
+                };
+                let selected = process.devices.iter()
+                    .map(|&i| compute::device_candidates(args.platform, Some(i), None, None).map(|mut d| d.remove(0)))
+                    .collect::<Result<Vec<_>, _>>();
+                let selected = match selected {
+                    Ok(selected) => selected,
+                    Err(e) => {
+                        eprintln!("{}{}{}", RED, e, CLEAR);
+                        Failure::Device.exit();
+                    }
+                };
+                let files = matches.map(|(_, files)| files);
+                let result = process_on_devices(&pipeline, &src, files, &process, selected, args.verbose, args.trace_kernels);
+                warnings::print_summary();
+                if let Err(failure) = result {
+                    failure.exit();
+                }
+                return;
+            }
 
-        i += 1;
-        let progress_percent = (i as f32 / file_count as f32) * 100.0;
-        let progress = ((i as f32 / file_count as f32) * 40.0) as i32;
-        print!("\x1b[A\r<");
-        for _ in 0..progress {
-            print!("=");
+            let opts = compute_options(args.verbose, args.trace_kernels, devices, &process);
+            let mut compute = init(&pipeline, &opts);
+            // the panics of the processing are the failures of single images
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match (generate, src, matches) {
+                (Some(count), _, _) => process::generate(&mut compute, count, &process),
+                (None, _, Some((root, files))) => process_src(&mut compute, &root, Some(files), &process),
+                (None, Some(src), None) => process_src(&mut compute, &src, None, &process),
+                (None, None, None) => unreachable!("clap requires the source without --generate")
+            }));
+            warnings::print_summary();
+            match result {
+                Ok(Ok(())) => (),
+                Ok(Err(failure)) => failure.exit(),
+                Err(_) => Failure::Images.exit()
+            }
+        }
+        Command::List { .. } | Command::Completions { .. } | Command::Selftest => unreachable!("handled before selecting the device"),
+        Command::Validate { src, pipeline, classes, batch } => {
+            let opts = ComputeOptions { verbose: args.verbose, trace_kernels: args.trace_kernels, devices, ..Default::default() };
+            let compute = init(&pipeline, &opts);
+            let declared = match src.as_deref() {
+                Some(src) => process::source_kind(src, classes)
+                    .and_then(|kind| compute.declaration().check(kind, batch.unwrap_or(1) > 1)),
+                None => Ok(())
+            };
+            match declared {
+                Ok(()) => {
+                    // the program and init() are only checked once the declarations are
+                    init_device(&compute);
+                    println!("{}The pipeline is valid.{}", GREEN, CLEAR);
+                }
+                Err(e) => {
+                    eprintln!("{}{}{}", RED, e, CLEAR);
+                    Failure::Arguments.exit();
+                }
+            }
+        }
+        Command::Bench { src, pipeline, iterations, warmup, seed } => {
+            let opts = ComputeOptions { verbose: args.verbose, trace_kernels: args.trace_kernels, seed, devices, ..Default::default() };
+            let mut compute = init(&pipeline, &opts);
+            // the setup of the device is not part of the measures
+            init_device(&compute);
+            if !bench::run_bench(&mut compute, src.as_deref().map(Path::new), iterations, warmup) {
+                std::process::exit(1);
+            }
         }
-        for _ in progress..40 {
-            print!("-");
+        Command::TestKernels { program, spec, update_snapshots } => {
+            if !kernel_tests::run_kernel_tests(&program, Path::new(&spec), &devices, args.trace_kernels, update_snapshots) {
+                std::process::exit(1);
+            }
         }
-        println!("> {:.2}%", progress_percent);
+        Command::Abtest { src, pipeline, pipeline_b, program_b, seed, report } => {
+            let opts = ComputeOptions {
+                verbose: args.verbose,
+                trace_kernels: args.trace_kernels,
+                seed,
+                devices,
+                ..Default::default()
+            };
+
+            let mut compute_a = init(&pipeline, &opts);
+            let mut compute_b = init(&pipeline.with_pipeline(pipeline_b, program_b), &opts);
+            if !abtest::run_abtest(&mut compute_a, &mut compute_b, Path::new(&src), report.as_deref().map(Path::new)) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+
+/// Processes the source on several devices at once, each one running its own instance of the
+/// pipeline on its share of the files, returning the first failure of the devices
+fn process_on_devices(pipeline: &PipelineArgs, src: &str, files: Option<Vec<PathBuf>>, process: &ProcessArgs,
+        devices: Vec<(Platform, Device)>, verbose: bool, trace_kernels: bool) -> Result<(), Failure> {
+    // the devices would write the same archive or stream
+    if process.output.contains("://") {
+        eprintln!("{}Splitting the inputs across devices (--devices) requires an output directory{}", RED, CLEAR);
+        return Err(Failure::Arguments);
     }
+
+    // the shards of the devices subdivide the shard of the process
+    let (index, count) = match (process.shard_index, process.shard_count) {
+        (Some(index), Some(count)) => (index, count),
+        _ => (0, 1)
+    };
+    let device_count = devices.len();
+
+    let results: Vec<Result<(), Failure>> = std::thread::scope(|s| {
+        let threads: Vec<_> = devices.into_iter().enumerate().map(|(k, device)| {
+            let files = files.clone();
+            s.spawn(move || {
+                let process = ProcessArgs {
+                    shard_index: Some(index + count * k),
+                    shard_count: Some(count * device_count),
+                    manifest_suffix: Some(format!("_device{}", k)),
+                    ..process.clone()
+                };
+                let opts = compute_options(verbose, trace_kernels, vec![device], &process);
+                let mut compute = pipeline.init(&opts).map_err(|(failure, e)| {
+                    eprintln!("{}{}{}", RED, e, CLEAR);
+                    failure
+                })?;
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process_src(&mut compute, src, files, &process)))
+                    .unwrap_or(Err(Failure::Images))
+            })
+        }).collect();
+        threads.into_iter().map(|t| t.join().unwrap_or(Err(Failure::Images))).collect()
+    });
+    results.into_iter().find_map(Result::err).map_or(Ok(()), Err)
+}
+
+
+fn compute_options(verbose: bool, trace_kernels: bool, devices: Vec<(Platform, Device)>,
+        process: &ProcessArgs) -> ComputeOptions {
+    ComputeOptions {
+        verbose,
+        trace_kernels,
+        devices,
+        realloc_buffers: process.realloc_buffers,
+        seed: process.seed,
+        backgrounds: process.backgrounds.as_deref().map(list_images).unwrap_or_default(),
+        throttle: process.throttle,
+        thermal: ThermalLimits {
+            max_temp: process.max_gpu_temp,
+            max_power: process.max_gpu_power
+        },
+        passthrough: process.passthrough,
+        float_mapping: Some((process.float_mapping, process.float_scale)),
+        init_cache: process.init_cache.clone(),
+        debug_script: process.debug_script,
+        ..Default::default()
+    }
+}
+
+
+/// Lowers the scheduling priority of the process, so that it yields to interactive programs
+fn lower_priority() {
+    #[cfg(unix)]
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 10) } != 0 {
+        eprintln!("Warning: could not lower the priority of the process");
+    }
+    #[cfg(not(unix))]
+    eprintln!("Warning: lowering the priority of the process is not supported on this platform");
+}
+
+
+/// Writes the completion script of `shell` to the standard output
+fn print_completions(shell: Shell) {
+    // device name patterns matching the devices of this machine, the spaces becoming any character
+    // so that the shells complete them as single words
+    let names: Vec<&'static str> = inventory::collect().iter()
+        .flat_map(|p| p.devices.iter().flatten())
+        .filter_map(|d| d.name.as_deref())
+        .map(|name| {
+            let pattern = regex::escape(name.trim()).split_whitespace().collect::<Vec<_>>().join(".");
+            // the command holds `'static` strings, and is only built once
+            &*Box::leak(pattern.into_boxed_str())
+        })
+        .collect();
+
+    let mut cmd = Args::command();
+    if !names.is_empty() {
+        cmd = cmd.mut_arg("device-name", |arg| arg.possible_values(names));
+    }
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+}
+
+
+/// Files of a directory, in name order
+fn list_images(dir: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Could not read files in `{}`: {}", dir, e))
+        .filter_map(|f| f.ok())
+        .filter(|f| f.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|f| f.path())
+        .collect();
+    files.sort();
+    files
 }
 
 
 /// Lists all available platforms in a comprehensible way
-fn list_platform(verbose: bool) {
+fn list_platform(verbose: bool, format: ListFormat) {
     use formats::*;
 
-    use ocl::{Platform, Device, enums::{DeviceInfo, DeviceInfoResult as DIR, DeviceMemCacheType, DeviceLocalMemType}};
-    use ocl::flags::{DEVICE_TYPE_CPU, DEVICE_TYPE_GPU, DEVICE_TYPE_ACCELERATOR,
-                    DEVICE_TYPE_CUSTOM, DEVICE_TYPE_DEFAULT};
+    let platforms = inventory::collect();
 
-    let platforms = Platform::list();
+    if format == ListFormat::Json {
+        println!("{}", inventory::to_json(&platforms));
+        return;
+    }
 
     if platforms.len() == 0 {
         println!("{}No platforms found on this machine. \nTry to install opencl packages.{}", RED, CLEAR);
     }
 
-    for p in platforms {
-        // println!("platform: {}{:?}{}", GREEN, p.as_core(), CLEAR);
-        if let Ok(name) = p.name() {
-            println!("name: {}", name);
+    for (i, p) in platforms.into_iter().enumerate() {
+        if let Some(name) = &p.name {
+            println!("[{}] name: {}", i, name);
         } else {
             println!("  {}Could not get platform name.{}", RED, CLEAR);
         }
-        if let Ok(vendor) = p.vendor() {
+        if let Some(vendor) = &p.vendor {
             println!("  vendor: {}", vendor);
         }
-        if let Ok(version) = p.version() {
+        if let Some(version) = &p.version {
             println!("  version: {}", version);
         }
 
-        if let Ok(devices) = Device::list(p, None) {
+        if let Some(devices) = &p.devices {
             if devices.len() == 0 {
                 println!("    {}No devices found on this platform.{}", RED, CLEAR);
             }
 
-            for d in devices {
+            for (j, d) in devices.iter().enumerate() {
                 println!();
-                if let Ok(name) = d.name() {
-                    println!("  device name: {}", name);
+                if let Some(name) = &d.name {
+                    println!("  [{}] device name: {}", j, name);
                 } else {
                     println!("  {}Could not get device name.{}", RED, CLEAR);
                 }
-                if let Ok(DIR::Type(tpe)) = d.info(DeviceInfo::Type) {
-                    print!("  type: ");
-                    if tpe.contains(DEVICE_TYPE_DEFAULT) {
-                        print!("default ");
-                    }
-                    if tpe.contains(DEVICE_TYPE_CPU) {
-                        print!("CPU ");
-                    }
-                    if tpe.contains(DEVICE_TYPE_GPU) {
-                        print!("GPU ");
-                    }
-                    if tpe.contains(DEVICE_TYPE_ACCELERATOR) {
-                        print!("accelerator ");
-                    }
-                    if tpe.contains(DEVICE_TYPE_CUSTOM) {
-                        print!("custom ")
-                    }
-                    println!();
+                if let Some(types) = &d.types {
+                    println!("  type: {} ", types.join(" "));
                 }
-                if let Ok(vendor) = d.vendor() {
+                if let Some(vendor) = &d.vendor {
                     println!("    vendor: {}", vendor);
                 }
-                if let Ok(version) = d.version() {
+                if let Some(version) = &d.version {
                     println!("    opencl version: {}", version);
                 }
-                if let Ok(DIR::DriverVersion(version)) = d.info(DeviceInfo::DriverVersion) {
+                if let Some(version) = &d.driver_version {
                     println!("    driver version: {}", version);
                 }
-                if let Ok(available) = d.is_available() {
+                if let Some(available) = d.available {
                     println!("    available: {}", format_bool(available));
                 }
 
@@ -282,79 +1220,67 @@ fn list_platform(verbose: bool) {
                 if verbose {
 
                     // general information about the device
-                    if let Ok(DIR::MaxComputeUnits(mx)) = d.info(DeviceInfo::MaxComputeUnits) {
+                    if let Some(mx) = d.max_compute_units {
                         println!("    max compute units: {}", mx);
                     }
-                    if let Ok(DIR::MaxWorkItemDimensions(mx)) = d.info(DeviceInfo::MaxWorkItemDimensions) {
+                    if let Some(mx) = d.max_work_item_dimensions {
                         println!("    max work item dimensions: {}", mx);
                     }
-                    if let Ok(max_wg_size) = d.max_wg_size() {
+                    if let Some(max_wg_size) = d.max_workgroup_size {
                         println!("    max workgroup size: {}", max_wg_size);
                     }
-                    if let Ok(DIR::MaxClockFrequency(mx)) = d.info(DeviceInfo::MaxClockFrequency) {
+                    if let Some(mx) = d.max_clock_frequency {
                         println!("    max clock frequency: {}", format_freq(mx as f32));
                     }
-                    if let Ok(DIR::MaxMemAllocSize(mx)) = d.info(DeviceInfo::MaxMemAllocSize) {
+                    if let Some(mx) = d.max_mem_alloc_size {
                         println!("    max memory alloc size: {}", format_mem(mx));
                     }
-                    if let Ok(DIR::MaxParameterSize(mx)) = d.info(DeviceInfo::MaxParameterSize) {
+                    if let Some(mx) = d.max_parameter_size {
                         println!("    max parameter size: {}", mx);
                     }
-                    if let Ok(DIR::MaxSamplers(mx)) = d.info(DeviceInfo::MaxSamplers) {
+                    if let Some(mx) = d.max_samplers {
                         println!("    max samplers: {}", mx);
                     }
                     
 
                     // images
-                    if let Ok(DIR::ImageSupport(b)) = d.info(DeviceInfo::ImageSupport) {
+                    if let Some(b) = d.image_support {
                         println!("    image support: {}", format_bool(b));
                     }
-                    if let (Ok(DIR::Image2dMaxWidth(w)), Ok(DIR::Image2dMaxHeight(h)))
-                            = (d.info(DeviceInfo::Image2dMaxWidth), d.info(DeviceInfo::Image2dMaxHeight)) {
+                    if let Some((w, h)) = d.image2d_max {
                         println!("    max image2D dim: {}x{}", w, h);
                     }
-                    if let (Ok(DIR::Image3dMaxWidth(w)), Ok(DIR::Image3dMaxHeight(h)), Ok(DIR::Image3dMaxDepth(d)))
-                            = (d.info(DeviceInfo::Image3dMaxWidth), d.info(DeviceInfo::Image3dMaxHeight), d.info(DeviceInfo::Image3dMaxDepth)) {
+                    if let Some((w, h, d)) = d.image3d_max {
                         println!("    max image3D dim: {}x{}x{}", w, h, d);
                     }
 
 
                     // global memory
-                    if let Ok(DIR::GlobalMemSize(size)) = d.info(DeviceInfo::GlobalMemSize) {
+                    if let Some(size) = d.global_mem_size {
                         println!("    global memory size: {}", format_mem(size));
                     }
-                    if let Ok(DIR::GlobalMemCacheType(tpe)) = d.info(DeviceInfo::GlobalMemCacheType) {
-                        print!("    global memory cache: ");
-                        match tpe {
-                            DeviceMemCacheType::None => println!("none"),
-                            DeviceMemCacheType::ReadOnlyCache => println!("read only"),
-                            DeviceMemCacheType::ReadWriteCache => println!("read write")
-                        }
+                    if let Some(cache) = d.global_mem_cache {
+                        println!("    global memory cache: {}", cache);
                     }
-                    if let Ok(DIR::GlobalMemCacheSize(size)) = d.info(DeviceInfo::GlobalMemCacheSize) {
+                    if let Some(size) = d.global_mem_cache_size {
                         println!("    global memory cache size: {}", format_mem(size));
                     }
 
 
                     // local memory
-                    if let Ok(DIR::LocalMemSize(size)) = d.info(DeviceInfo::LocalMemSize) {
+                    if let Some(size) = d.local_mem_size {
                         println!("    global memory size: {}", format_mem(size));
                     }
-                    if let Ok(DIR::LocalMemType(tpe)) = d.info(DeviceInfo::LocalMemType) {
-                        print!("    global memory cache: ");
-                        match tpe {
-                            DeviceLocalMemType::None => println!("none"),
-                            DeviceLocalMemType::Local => println!("local"),
-                            DeviceLocalMemType::Global => println!("global")
-                        }
+                    if let Some(tpe) = d.local_mem_type {
+                        println!("    global memory cache: {}", tpe);
                     }
 
 
                     // constant buffers
-                    if let Ok(DIR::MaxConstantBufferSize(size)) = d.info(DeviceInfo::MaxConstantBufferSize) {
+                    if let Some(size) = d.max_constant_buffer_size {
                         println!("    max constant buffer size: {}", format_mem(size));
                     }
-                    if let Ok(DIR::MaxConstantArgs(n)) = d.info(DeviceInfo::MaxConstantArgs) {
+                    if let Some(n) = d.max_constant_args {
                         println!("    max constant buffers argument: {}", n);
                     }
                 }
@@ -0,0 +1,189 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Conversions between the pixel formats of the sources, the pipeline and the outputs.
+// The device buffers hold 8 bit rgb pixels, in which a pipeline can declare to work in grayscale
+// (the luma in the three channels) or on linear values:
+//
+//     fn declare() {
+//         #{ format: #{ channels: 1, color: "linear" } }
+//     }
+//
+// A pipeline declaring 4 channels also gets the transparency of its inputs, in `input_rgba`, and
+// one declaring `native_gray: true` gets its gray inputs with one byte per pixel in `input`, the
+// `IMG_CHANNELS` constant of the run telling the kernels whether they index one or three channels.
+//
+// The decoded images are converted to 8 bit rgb, then to the format of the pipeline, and the outputs
+// back to srgb before being encoded. The lossy conversions are reported as pitfalls of the run.
+
+
+use std::path::Path;
+
+use image::{DynamicImage, GrayImage, RgbImage, imageops};
+
+use rhai::Map;
+
+use crate::warnings;
+
+
+/// Format of the pixels the pipeline works on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PipelineFormat {
+    /// 1 for grayscale, 3 for rgb, 4 for rgba
+    pub channels: u8,
+    /// linear values instead of srgb encoded ones
+    pub linear: bool,
+    /// gray inputs uploaded with one channel instead of three
+    pub native_gray: bool
+}
+
+
+impl Default for PipelineFormat {
+
+    fn default() -> Self {
+        Self { channels: 3, linear: false, native_gray: false }
+    }
+}
+
+
+impl PipelineFormat {
+
+
+    /// Reads the `format` map of a declaration
+    pub fn parse(map: &Map) -> Result<Self, String> {
+        let mut format = Self::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "channels" => format.channels = match value.as_int() {
+                    Ok(1) => 1,
+                    Ok(3) => 3,
+                    Ok(4) => 4,
+                    _ => return Err(format!("`format.channels` should be 1, 3 or 4, not {}", value))
+                },
+                "depth" => if value.as_int() != Ok(8) {
+                    return Err(format!("`format.depth` can only be 8, not {}", value));
+                },
+                "color" => format.linear = match value.clone().into_string().as_deref() {
+                    Ok("srgb") => false,
+                    Ok("linear") => true,
+                    _ => return Err(format!("`format.color` should be \"srgb\" or \"linear\", not {}", value))
+                },
+                "native_gray" => format.native_gray = value.as_bool()
+                    .map_err(|t| format!("`format.native_gray` should be a bool, not {}", t))?,
+                _ => return Err(format!("unknown key `format.{}` (expected channels, depth, color or native_gray)", key))
+            }
+        }
+        Ok(format)
+    }
+
+
+    /// Converts an srgb image to this format
+    pub fn convert_srgb(&self, img: RgbImage) -> RgbImage {
+        let img = if self.channels == 1 {
+            DynamicImage::ImageLuma8(imageops::grayscale(&img)).into_rgb8()
+        } else {
+            img
+        };
+        if self.linear { map_values(img, srgb_to_linear) } else { img }
+    }
+
+
+    /// Converts an image in this format to srgb
+    pub fn to_srgb(self, img: RgbImage) -> RgbImage {
+        if self.linear { map_values(img, linear_to_srgb) } else { img }
+    }
+}
+
+
+/// Converts the decoded images to the format of the pipeline
+pub struct Negotiator {
+    format: PipelineFormat
+}
+
+
+impl Negotiator {
+
+
+    pub fn new(format: PipelineFormat) -> Self {
+        Self { format }
+    }
+
+
+    /// Converts a decoded image to 8 bit rgb, in which the source and pipeline formats are negotiated
+    pub fn to_rgb8(&self, img: DynamicImage, file: &Path) -> RgbImage {
+        let color = img.color();
+        if color.bytes_per_pixel() > color.channel_count() {
+            warnings::report("depth", Some(file), "is reduced to 8 bits per channel");
+        }
+        if self.format.channels != 4 && color.has_alpha() && img.to_rgba8().pixels().any(|p| p[3] < 255) {
+            warnings::report("alpha", Some(file), "has transparent pixels, the transparency is dropped");
+        }
+        if self.format.channels == 1 && color.channel_count() >= 3 {
+            warnings::report("gray", Some(file), "is converted to grayscale, as declared by the pipeline");
+        }
+        if self.format.linear {
+            warnings::report("linear", Some(file), "is converted to linear 8 bit values, which loses precision in the shadows");
+        }
+        img.into_rgb8()
+    }
+
+
+    /// The transparency of a decoded image, for the pipelines in rgba. Images without alpha are opaque.
+    pub fn alpha(&self, img: &DynamicImage) -> Option<GrayImage> {
+        if self.format.channels != 4 || !img.color().has_alpha() {
+            return None;
+        }
+        let rgba = img.to_rgba8();
+        Some(GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba.get_pixel(x, y)[3]])))
+    }
+
+
+    /// Converts an 8 bit srgb image to the format of the pipeline
+    pub fn to_pipeline(&self, img: RgbImage) -> RgbImage {
+        self.format.convert_srgb(img)
+    }
+
+}
+
+
+fn map_values(mut img: RgbImage, f: fn(f32) -> f32) -> RgbImage {
+    let lut: Vec<u8> = (0..=255).map(|v| (f(v as f32 / 255.0) * 255.0).round() as u8).collect();
+    for v in img.iter_mut() {
+        *v = lut[*v as usize];
+    }
+    img
+}
+
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
@@ -0,0 +1,136 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rhai::Engine;
+
+use zip::ZipArchive;
+
+
+pub const PROGRAM_FILE: &str = "program.cl";
+pub const PIPELINE_FILE: &str = "pipeline.rhai";
+pub const CONFIG_FILE: &str = "config.json";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+
+/// A pipeline package (`.aipack`).
+///
+/// A package is a zip archive holding everything needed to run a pipeline:
+///  - `program.cl`: the opencl program
+///  - `pipeline.rhai`: the rhai pipeline
+///  - `config.json` (optional): the pipeline configuration
+///  - `manifest.json` (optional): runner settings, `width` and `height` being
+///    the maximum image dimentions
+///  - any other file (LUTs, reference images...) used by the pipeline
+///
+/// The archive is extracted to a temporary directory of its own that lives as long as the package.
+pub struct Package {
+    dir: PathBuf,
+    pub config: Option<String>,
+    pub size: Option<(usize, usize)>
+}
+
+
+impl Package {
+
+
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Could not read package `{}`: {}", path.display(), e))?;
+        let mut archive = ZipArchive::new(file)
+            .map_err(|e| format!("`{}` is not a valid package: {}", path.display(), e))?;
+
+        let dir = create_temp_dir(path.file_stem().and_then(|s| s.to_str()).unwrap_or("pack"))
+            .map_err(|e| format!("Could not extract package `{}`: {}", path.display(), e))?;
+        let mut pack = Self {
+            dir,
+            config: None,
+            size: None
+        };
+
+        archive.extract(&pack.dir)
+            .map_err(|e| format!("Could not extract package `{}`: {}", path.display(), e))?;
+
+        for required in [PROGRAM_FILE, PIPELINE_FILE] {
+            if !pack.dir.join(required).is_file() {
+                return Err(format!("Package `{}` has no `{}`", path.display(), required));
+            }
+        }
+
+        pack.config = fs::read_to_string(pack.dir.join(CONFIG_FILE)).ok();
+
+        if let Ok(manifest) = fs::read_to_string(pack.dir.join(MANIFEST_FILE)) {
+            let manifest = Engine::new().parse_json(manifest, true)
+                .map_err(|e| format!("Invalid package manifest: {}", e))?;
+            let get_dim = |key: &str| manifest.get(key).and_then(|v| v.as_int().ok());
+
+            if let (Some(w), Some(h)) = (get_dim("width"), get_dim("height")) {
+                pack.size = Some((w as usize, h as usize));
+            }
+        }
+
+        Ok(pack)
+    }
+
+
+    pub fn program(&self) -> String {
+        self.dir.join(PROGRAM_FILE).to_string_lossy().into_owned()
+    }
+
+
+    pub fn pipeline(&self) -> String {
+        self.dir.join(PIPELINE_FILE).to_string_lossy().into_owned()
+    }
+}
+
+
+/// Creates a new directory in the temporary directory, never one that already exists: the
+/// instances of a process (abtest, several devices) open the same package each
+fn create_temp_dir(stem: &str) -> std::io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("imgproc-{}-{}-{:08x}-{}", std::process::id(), stem, nanos, n));
+        match fs::create_dir(&dir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            created => return created.map(|_| dir)
+        }
+    }
+}
+
+
+impl Drop for Package {
+
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
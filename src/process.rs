@@ -0,0 +1,1632 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use std::cell::RefCell;
+use std::collections::{HashMap, BTreeMap};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use image::{RgbImage, GrayImage, DynamicImage};
+use rhai::Map;
+
+use sha2::{Sha256, Digest};
+
+use crate::compute::{CInstance, SourceKind, StageTimings};
+use crate::negotiate::Negotiator;
+use crate::animation;
+use crate::encode::{self, EncodeOptions, OutFormat};
+use crate::decode::{self, DecodeCache, Prefetch};
+use crate::color::ColorManagement;
+use crate::journal::{self, Journal};
+use crate::source::{self, ImageSource};
+use crate::sink::{self, ImageSink, FileTree};
+use crate::json;
+use crate::{ProcessArgs, AnimatedInput, ProgressUnit, Failure};
+use crate::formats;
+use crate::warnings;
+use crate::diskspace::{self, DiskGuard};
+use crate::report::{self, RunReport};
+use crate::trace::{self, Trace};
+use crate::log;
+use crate::{RED, CLEAR};
+
+
+/// Kind of a source given on the command line, checked against the declaration of the pipeline
+pub fn source_kind(src: &str, classes: bool) -> Result<SourceKind, String> {
+    if source::has_scheme(src) {
+        return Ok(SourceKind::Directory);
+    }
+
+    let meta = std::fs::metadata(src).map_err(|e| format!("Could not read `{}`: {}", src, e))?;
+    Ok(match (meta.is_dir(), classes) {
+        (true, true) => SourceKind::Classes,
+        (true, false) => SourceKind::Directory,
+        (false, _) => SourceKind::Image
+    })
+}
+
+
+/// Applies the compute pipeline to the source file or directory, returning the kind of failure
+/// when the run could not be done. `files` restricts a source directory to some of its files
+/// (relative to it), such as the matches of a glob pattern or the files of `--files-from`.
+pub fn process_src(compute: &mut CInstance, src: &str, files: Option<Vec<PathBuf>>, opts: &ProcessArgs) -> Result<(), Failure> {
+    use std::fs::metadata;
+
+    // sources with a scheme are not files
+    let src_meta = if source::has_scheme(src) {
+        None
+    } else {
+        match metadata(src) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                eprintln!("{}Could not read `{}`: {}{}", RED, src, e, CLEAR);
+                return Err(Failure::Arguments);
+            }
+        }
+    };
+    let src_is_dir = src_meta.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+
+    if let Some(animation) = &opts.animate {
+        if let Err(e) = animation::AnimationFormat::from_path(Path::new(animation)) {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            return Err(Failure::Arguments);
+        }
+    }
+
+    if let Err(e) = opts.validate_selection() {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    if files.is_some() && opts.classes {
+        eprintln!("{}The class folders (--classes) cannot be given by a glob pattern or a list of files{}", RED, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    let declared = source_kind(src, opts.classes)
+        .and_then(|kind| compute.declaration().check(kind, opts.batch.unwrap_or(1) > 1));
+    if let Err(e) = declared {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    let color = if opts.color_manage || opts.tag_outputs {
+        match ColorManagement::new(opts.working_space.as_deref().map(Path::new)) {
+            Ok(color) => Some(color),
+            Err(e) => {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                return Err(Failure::Arguments);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut encode_opts = opts.encode_options();
+    if opts.tag_outputs {
+        encode_opts.output_profile = color.as_ref().map(ColorManagement::output_profile);
+    }
+    if let Err(e) = encode_opts.validate() {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(Failure::Arguments);
+    }
+    if opts.estimate.is_some() && !src_is_dir {
+        eprintln!("{}--estimate requires a source directory, a glob pattern or --files-from{}", RED, CLEAR);
+        return Err(Failure::Arguments);
+    }
+    // the confirmation cannot be read from a pipe, which `--files-from -` also reads
+    if opts.estimate.is_some() && !opts.yes && !std::io::stdin().is_terminal() {
+        eprintln!("{}--estimate asks for a confirmation but the standard input is not a terminal (run without asking with --yes){}",
+            RED, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    // the arguments are valid, the run needs the device
+    if let Err(e) = compute.init_device() {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(e.into());
+    }
+    if opts.report.is_some() {
+        compute.time_stages();
+    }
+
+    if (opts.skip_existing || opts.existing_outputs() != ExistingOutput::Overwrite) && opts.output.contains("://") {
+        eprintln!("{}Looking for the existing outputs (--skip-existing, --no-clobber, --backup) requires an output directory{}",
+            RED, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    let journal = if opts.journal {
+        let node = opts.node_name.clone().unwrap_or_else(journal::default_node_name);
+        let journal = if opts.output.contains("://") {
+            Err(String::from("The journal requires an output directory"))
+        } else if src_is_dir {
+            Journal::open(Path::new(src), Path::new(&opts.output), &node)
+        } else {
+            Err(String::from("The journal requires a source directory"))
+        };
+        let journal = journal.and_then(|mut journal| {
+            journal.pin_device(&compute.device_identity(), opts.require_same_device)?;
+            Ok(journal)
+        });
+        match journal {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                return Err(Failure::Arguments);
+            }
+        }
+    } else {
+        None
+    };
+
+    let inputs = Inputs {
+        opts,
+        color: color.filter(|_| opts.color_manage),
+        journal,
+        cache: (opts.decode_cache > 0).then(|| RefCell::new(DecodeCache::new(opts.decode_cache * 1_000_000))),
+        negotiator: Negotiator::new(compute.declaration().format)
+    };
+    // the estimate and the free space check extrapolate from the same sample of the files
+    let min_free = opts.min_free_space.filter(|_| !opts.output.contains("://"));
+    let sample = (src_is_dir && (opts.estimate.is_some() || min_free.is_some())).then(|| {
+        let count = opts.estimate.unwrap_or(PREFLIGHT_SAMPLE);
+        run_sample(compute, Path::new(src), files.as_deref(), count, &inputs, &encode_opts)
+    }).flatten();
+    if opts.estimate.is_some() {
+        match &sample {
+            Some(sample) => if !estimate(sample, !opts.yes) {
+                return Ok(());
+            },
+            None => {
+                eprintln!("{}Could not estimate the run: there is no file to process, or none of the sample could be processed{}", RED, CLEAR);
+                return Err(Failure::Arguments);
+            }
+        }
+    }
+    if let (Some(sample), Some(min_free)) = (&sample, min_free) {
+        if let Err(e) = check_free_space(sample, min_free, opts) {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            return Err(Failure::Arguments);
+        }
+    }
+
+    let sink = match sink::open_sink(&opts.output) {
+        Ok(sink) => sink,
+        Err(e) => {
+            eprintln!("{}{}{}", RED, e, CLEAR);
+            return Err(Failure::Arguments);
+        }
+    };
+    let mut outputs = Outputs::new(opts, encode_opts, sink, Trace::of(compute), inputs.journal.clone());
+
+    if src_meta.is_none() {
+        match source::open_source(src, inputs.color.is_some()) {
+            Ok(source) => process_source(compute, src, source, Path::new(&opts.output), &inputs, &mut outputs),
+            Err(e) => {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                outputs.finish(None);
+                return Err(Failure::Arguments);
+            }
+        }
+    } else if src_is_dir && opts.classes {
+        process_classes(compute, Path::new(src), Path::new(&opts.output), &inputs, &mut outputs);
+    } else if src_is_dir {
+        process_dir(compute, Path::new(src), files, Path::new(&opts.output), &inputs, &mut outputs);
+    } else if src_meta.map(|m| m.is_file()).unwrap_or(false) && opts.select(0..1).any(|i| opts.in_shard(i)) {
+        // a single file is named after its source in the sinks other than a file tree
+        let output = Path::new(&opts.output);
+        let out_file = match Path::new(src).file_name() {
+            Some(name) if !outputs.file_tree => output.join(name),
+            _ => output.to_path_buf()
+        };
+        if opts.skip_existing && up_to_date(Path::new(src), &opts.output_path(&out_file)) {
+            outputs.keep(&opts.output_path(&out_file));
+        } else {
+            process_file(compute, Path::new(src), &out_file, &inputs, &mut outputs);
+        }
+    }
+
+    log::set_context(None);
+    if outputs.finish(compute.stage_timings()) { Ok(()) } else { Err(Failure::Images) }
+}
+
+
+/// Files the run of the source directory `src` (or of its given `src_files`) processes, each with the
+/// directory it is relative to: those of the shard, less the ones kept with `--skip-existing` and the
+/// ones completed or claimed by another node in the journal
+fn run_files(src: &Path, src_files: Option<&[PathBuf]>, inputs: &Inputs) -> Vec<(PathBuf, PathBuf)> {
+    let opts = inputs.opts;
+    let dirs = if opts.classes {
+        let mut dirs: Vec<PathBuf> = std::fs::read_dir(src)
+            .unwrap_or_else(|e| panic!("Could not read files in `{}`: {}", src.display(), e))
+            .filter_map(|f| f.ok())
+            .filter(|f| f.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .map(|f| f.path())
+            .collect();
+        dirs.sort();
+        dirs
+    } else {
+        vec![src.to_path_buf()]
+    };
+
+    // the files, relative to their directory
+    let mut files = Vec::new();
+    for dir in dirs {
+        let dir_files = match src_files {
+            Some(src_files) => src_files.to_vec(),
+            None => list_files(&dir, opts.recursive)
+                .unwrap_or_else(|e| panic!("Could not read files in `{}`: {}", dir.display(), e))
+        };
+        let out_dir = match dir.file_name() {
+            Some(class) if opts.classes => Path::new(&opts.output).join(class),
+            _ => PathBuf::from(&opts.output)
+        };
+        files.extend(opts.select(dir_files.into_iter())
+            .enumerate()
+            .filter(|(i, _)| opts.in_shard(*i))
+            .map(|(_, f)| f)
+            .filter(|f| !opts.skip_existing || !up_to_date(&dir.join(f), &opts.output_path(&out_dir.join(f))))
+            .filter(|f| inputs.journal.as_ref().map(|j| j.pending(&dir.join(f))).unwrap_or(true))
+            .map(|f| (dir.clone(), f)));
+    }
+    files
+}
+
+
+/// Outputs of a sample of the files of a run, processed before it to extrapolate its time and size
+struct Sample {
+    /// files of the run
+    files: usize,
+    /// files of the sample processed
+    processed: usize,
+    /// size of their encoded outputs
+    bytes: usize,
+    /// time spent on each of them, the first one left out when there are others
+    seconds_per_file: f64
+}
+
+
+impl Sample {
+
+
+    /// Extrapolated size of the outputs of the run, in bytes
+    fn run_bytes(&self) -> f64 {
+        self.bytes as f64 * self.files as f64 / self.processed as f64
+    }
+}
+
+
+/// Processes `count` files picked at random among those the run of the source directory `src` (or of
+/// its given `src_files`) processes, without saving them nor writing the files of the pipeline. With
+/// `--keep-going`, the files that fail are left out of the sample, and reported by the run.
+/// `None` when there is no file to process, or none of the sample could be processed.
+fn run_sample(compute: &mut CInstance, src: &Path, src_files: Option<&[PathBuf]>, count: usize, inputs: &Inputs,
+        encode_opts: &EncodeOptions) -> Option<Sample> {
+    let opts = inputs.opts;
+    let files = run_files(src, src_files, inputs);
+    if files.is_empty() {
+        return None;
+    }
+    let sample = crate::compute::sample_indices(files.len(), count.max(1), opts.seed);
+    if !formats::quiet() {
+        println!("Sampling {} of {} files...", sample.len(), files.len());
+    }
+
+    let process = |compute: &mut CInstance, dir: &Path, file: &Path| {
+        if opts.classes {
+            compute.set_class(dir.file_name().map(|c| c.to_string_lossy()).as_deref());
+        }
+
+        let in_file = dir.join(file);
+        let out_file = opts.output_path(&Path::new(&opts.output).join(file));
+        let mut bytes = 0;
+        for (image, alpha, out_file) in inputs.read_all(&in_file, &out_file, compute) {
+            log::set_context(Some(format!("`{}`", out_file.display())));
+            compute.set_input_alpha(alpha);
+            let out = compute.compute(&image);
+            let out = compute.take_native_output().unwrap_or_else(|| out.into());
+            bytes += encode::encode_image(&out, &out_file, encode_opts)
+                .unwrap_or_else(|e| panic!("Could not encode `{}`: {}", out_file.display(), e))
+                .len();
+        }
+        bytes
+    };
+
+    compute.set_dry_run(true);
+    let (mut bytes, mut times) = (0, Vec::with_capacity(sample.len()));
+    for &i in &sample {
+        let (dir, file) = &files[i];
+        let start = Instant::now();
+        if !opts.keep_going {
+            bytes += process(compute, dir, file);
+            times.push(start.elapsed().as_secs_f64());
+            continue;
+        }
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process(compute, dir, file))) {
+            Ok(b) => {
+                bytes += b;
+                times.push(start.elapsed().as_secs_f64());
+            }
+            Err(e) => eprintln!("Warning: `{}` is left out of the sample: {}", dir.join(file).display(),
+                formats::panic_message(e.as_ref()))
+        }
+    }
+    compute.set_dry_run(false);
+    compute.set_class(None);
+    log::set_context(None);
+    // the run gives the same results as without the sample
+    compute.set_seed(opts.seed);
+
+    if times.is_empty() {
+        eprintln!("Warning: no file of the sample could be processed, the run is not extrapolated");
+        return None;
+    }
+    // the first run also allocates the buffers and waits for the kernels to be compiled by the driver
+    let timed = if times.len() > 1 { &times[1..] } else { &times[..] };
+    Some(Sample {
+        files: files.len(),
+        processed: times.len(),
+        bytes,
+        seconds_per_file: timed.iter().sum::<f64>() / timed.len() as f64
+    })
+}
+
+
+/// Prints the run time and output size extrapolated from the sample to the whole run.
+/// Returns whether the user confirms the run, when asked to.
+fn estimate(sample: &Sample, ask: bool) -> bool {
+    use std::io::Write;
+
+    let seconds = (sample.seconds_per_file * sample.files as f64) as u64;
+    println!("Estimated run time: {}:{:02}:{:02} ({:.2}s per file)",
+        seconds / 3600, seconds / 60 % 60, seconds % 60, sample.seconds_per_file);
+    println!("Estimated output size: {:.1} MB", sample.run_bytes() / 1e6);
+    if !ask {
+        return true;
+    }
+
+    print!("Process the {} files? [y/N] ", sample.files);
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap_or(0);
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+
+/// Number of files processed to extrapolate the size of the outputs before a run, without `--estimate`
+const PREFLIGHT_SAMPLE: usize = 4;
+
+
+/// Checks that the output filesystem can hold the outputs of the run, extrapolated from the sample,
+/// with `min_free` megabytes left free
+fn check_free_space(sample: &Sample, min_free: u64, opts: &ProcessArgs) -> Result<(), String> {
+    let free = match diskspace::free_bytes(Path::new(&opts.output)) {
+        Some(free) => free,
+        None => return Ok(())
+    };
+    let bytes = sample.run_bytes();
+    if bytes as u64 + min_free * 1_000_000 > free {
+        return Err(format!("The outputs would take about {:.1} MB, but `{}` has {:.1} MB free and --min-free-space keeps {} MB",
+            bytes / 1e6, opts.output, free as f64 / 1e6, min_free));
+    }
+    Ok(())
+}
+
+
+/// Name of the label records of the generated images
+const LABELS_FILE: &str = "labels.jsonl";
+
+
+/// Generates `count` images into the output directory, named after their index, with the label
+/// records returned by the pipeline in `labels.jsonl` (one json object per line, with its `file`)
+pub fn generate(compute: &mut CInstance, count: u64, opts: &ProcessArgs) -> Result<(), Failure> {
+    use std::io::Write;
+
+    let out_dir = Path::new(&opts.output);
+    if let Err(e) = std::fs::create_dir_all(out_dir) {
+        eprintln!("{}Could not create directory `{}`: {}{}", RED, out_dir.display(), e, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    let encode_opts = opts.encode_options();
+    if let Err(e) = encode_opts.validate()
+            .and_then(|_| opts.validate_selection())
+            .and_then(|_| compute.declaration().check(SourceKind::None, opts.batch.unwrap_or(1) > 1)) {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(Failure::Arguments);
+    }
+
+    let labels_file = out_dir.join(LABELS_FILE);
+    let mut labels = match std::fs::File::create(&labels_file) {
+        Ok(file) => std::io::BufWriter::new(file),
+        Err(e) => {
+            eprintln!("{}Could not create `{}`: {}{}", RED, labels_file.display(), e, CLEAR);
+            return Err(Failure::Arguments);
+        }
+    };
+
+    if let Err(e) = compute.init_device() {
+        eprintln!("{}{}{}", RED, e, CLEAR);
+        return Err(e.into());
+    }
+
+    let trace = Trace::of(compute);
+    let mut outputs = Outputs::new(opts, encode_opts, Box::new(FileTree), trace.clone(), None);
+    // the input of the pipeline, for the side outputs
+    let blank = RgbImage::new(compute.max_size().0 as u32, compute.max_size().1 as u32);
+
+    // the images of the other shards are left to other jobs
+    let indices: Vec<u64> = (0..count).filter(|&i| opts.in_shard(i as usize)).collect();
+
+    let mut progress = Progress::start(indices.len() as u64);
+
+    for &i in &indices {
+        let name = format!("{:06}.png", i);
+        let (img, label) = compute.generate(opts.seed, i);
+        let file = outputs.save(compute, &blank, img, &out_dir.join(name));
+
+        if let Some(mut label) = label {
+            let name = file.strip_prefix(out_dir).unwrap_or(&file);
+            label.insert("file".into(), name.display().to_string().into());
+            if let Some(trace) = &trace {
+                trace.insert(&mut label);
+            }
+            writeln!(labels, "{}", json::format_map(&label))
+                .unwrap_or_else(|e| panic!("Could not write `{}`: {}", labels_file.display(), e));
+        }
+
+        progress.advance(1);
+    }
+
+    labels.flush().unwrap_or_else(|e| panic!("Could not write `{}`: {}", labels_file.display(), e));
+    if outputs.finish(None) { Ok(()) } else { Err(Failure::Images) }
+}
+
+
+/// Name of the checksum manifest
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+
+/// Number of images that can wait for the writer thread before the processing blocks
+const WRITE_QUEUE_LEN: usize = 4;
+
+
+/// Sha256 of the written files
+type Checksums = Vec<(String, PathBuf)>;
+
+
+/// Files that could not be processed or saved with `--keep-going`, with their error
+type Failures = Arc<Mutex<Vec<(PathBuf, String)>>>;
+
+
+/// What to do with an output file that already exists
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExistingOutput {
+    Overwrite,
+    Keep,
+    Backup
+}
+
+
+/// Makes room for an output file that may already exist, returning the existing file (read for its
+/// checksum if `read_kept`) when it is kept instead
+fn make_room(file: &Path, existing: ExistingOutput, read_kept: bool) -> Result<Option<Vec<u8>>, String> {
+    if existing == ExistingOutput::Overwrite || !file.exists() {
+        return Ok(None);
+    }
+
+    if existing == ExistingOutput::Keep {
+        warnings::report("clobber", Some(file), "kept the existing output instead of overwriting it");
+        return if read_kept {
+            std::fs::read(file).map(Some).map_err(|e| format!("Could not read `{}`: {}", file.display(), e))
+        } else {
+            Ok(Some(Vec::new()))
+        };
+    }
+
+    let mut backup = file.as_os_str().to_owned();
+    backup.push("~");
+    std::fs::rename(file, &backup)
+        .map(|_| None)
+        .map_err(|e| format!("Could not back `{}` up: {}", file.display(), e))
+}
+
+
+/// Writes an encoded output to the sink after making room for it, returning the bytes of the file
+fn store(sink: &mut dyn ImageSink, file: &Path, bytes: Vec<u8>, existing: ExistingOutput, checksums: bool) -> Result<Vec<u8>, String> {
+    match make_room(file, existing, checksums)? {
+        Some(kept) => Ok(kept),
+        None => sink.write_file(file, &bytes).map(|_| bytes)
+    }
+}
+
+
+/// Work of the writer threads, in the order it is queued
+enum WriteJob<T> {
+    /// an image to encode, then its encoded file, with the file it is written to
+    Output(T, PathBuf),
+    /// the outputs of a source file are all queued, journaled as done once they are written
+    Done(PathBuf)
+}
+
+
+/// Records the source files in the journal once their outputs are written
+struct DoneRecorder {
+    journal: Option<Journal>,
+    /// whether an output queued since the last sources done could not be written
+    failed: bool,
+    /// whether the last job was a source done
+    after_done: bool
+}
+
+
+impl DoneRecorder {
+
+
+    fn written(&mut self, ok: bool) {
+        if self.after_done {
+            self.failed = false;
+            self.after_done = false;
+        }
+        self.failed |= !ok;
+    }
+
+
+    /// Journals a source, unless one of its outputs, or of those of its batch, failed
+    fn done(&mut self, source: &Path) {
+        self.after_done = true;
+        if let (Some(journal), false) = (&self.journal, self.failed) {
+            journal.done(source);
+        }
+    }
+}
+
+
+/// Encodes and writes images to the sink on a dedicated thread, so that encoding does not hold back the device.
+/// With several encoders, the images are encoded in parallel and written in their order.
+struct Writer {
+    sender: Option<SyncSender<(usize, WriteJob<DynamicImage>)>>,
+    /// number of jobs sent to the writer
+    sent: usize,
+    /// whether the sources done are journaled
+    journaled: bool,
+    encoders: Vec<JoinHandle<()>>,
+    thread: Option<JoinHandle<(Checksums, Box<dyn ImageSink>)>>
+}
+
+
+impl Writer {
+
+
+    /// When `checksums` is set, the sha256 of each written file is kept for the checksum manifest.
+    /// The images are encoded on `encoders` threads when the sink writes encoded images.
+    /// The images that cannot be saved are added to `failures` if given, instead of stopping the writer.
+    /// The sources done are recorded in `journal` once their outputs are written.
+    fn new(opts: EncodeOptions, checksums: bool, existing: ExistingOutput, mut sink: Box<dyn ImageSink>, encoders: usize,
+            failures: Option<Failures>, journal: Option<Journal>) -> Self {
+        let queue_len = WRITE_QUEUE_LEN.max(encoders);
+        let (sender, receiver) = mpsc::sync_channel::<(usize, WriteJob<DynamicImage>)>(queue_len);
+        let journaled = journal.is_some();
+        let mut recorder = DoneRecorder { journal, failed: false, after_done: false };
+
+        if encoders <= 1 || !sink.encodes_images() {
+            let thread = thread::spawn(move || {
+                let mut sums = Vec::new();
+                for (_, job) in receiver {
+                    let (img, file) = match job {
+                        WriteJob::Output(img, file) => (img, file),
+                        WriteJob::Done(source) => {
+                            recorder.done(&source);
+                            continue;
+                        }
+                    };
+                    let written = if sink.encodes_images() {
+                        encode::encode_output(&img, &file, &opts).and_then(|(file, bytes)| {
+                            store(sink.as_mut(), &file, bytes, existing, checksums).map(|bytes| (file, bytes))
+                        })
+                    } else {
+                        sink.write_image(&file, &img, &opts).map(|bytes| (file.clone(), bytes))
+                    };
+                    recorder.written(written.is_ok());
+                    let (file, bytes) = match written {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            write_failed(&failures, file, e);
+                            continue;
+                        }
+                    };
+                    if checksums {
+                        sums.push((format!("{:x}", Sha256::digest(&bytes)), file));
+                    }
+                }
+                (sums, sink)
+            });
+
+            return Self {
+                sender: Some(sender),
+                sent: 0,
+                journaled,
+                encoders: Vec::new(),
+                thread: Some(thread)
+            };
+        }
+
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (encoded_sender, encoded) = mpsc::sync_channel::<(usize, WriteJob<Result<(PathBuf, Vec<u8>), String>>)>(queue_len);
+        let encoders = (0..encoders).map(|_| {
+            let receiver = receiver.clone();
+            let encoded_sender = encoded_sender.clone();
+            let opts = opts.clone();
+            thread::spawn(move || loop {
+                // the lock is released before encoding
+                let next = receiver.lock().unwrap().recv();
+                let (index, job) = match next {
+                    Ok(next) => next,
+                    Err(_) => return
+                };
+                let encoded = match job {
+                    WriteJob::Output(img, file) => WriteJob::Output(encode::encode_output(&img, &file, &opts), file),
+                    WriteJob::Done(source) => WriteJob::Done(source)
+                };
+                if encoded_sender.send((index, encoded)).is_err() {
+                    return;
+                }
+            })
+        }).collect();
+        drop(encoded_sender);
+
+        let thread = thread::spawn(move || {
+            let mut sums = Vec::new();
+            // images encoded before the previous ones
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, job) in encoded {
+                pending.insert(index, job);
+                while let Some(job) = pending.remove(&next) {
+                    next += 1;
+                    let (encoded, file) = match job {
+                        WriteJob::Output(encoded, file) => (encoded, file),
+                        WriteJob::Done(source) => {
+                            recorder.done(&source);
+                            continue;
+                        }
+                    };
+                    let written = encoded.and_then(|(file, bytes)| {
+                        store(sink.as_mut(), &file, bytes, existing, checksums).map(|bytes| (file, bytes))
+                    });
+                    recorder.written(written.is_ok());
+                    let (file, bytes) = match written {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            write_failed(&failures, file, e);
+                            continue;
+                        }
+                    };
+                    if checksums {
+                        sums.push((format!("{:x}", Sha256::digest(&bytes)), file));
+                    }
+                }
+            }
+            (sums, sink)
+        });
+
+        Self {
+            sender: Some(sender),
+            sent: 0,
+            journaled,
+            encoders,
+            thread: Some(thread)
+        }
+    }
+
+
+    fn write(&mut self, img: impl Into<DynamicImage>, file: PathBuf) {
+        self.send(WriteJob::Output(img.into(), file));
+    }
+
+
+    /// Journals a source file once the outputs queued before are written
+    fn done(&mut self, source: PathBuf) {
+        if self.journaled {
+            self.send(WriteJob::Done(source));
+        }
+    }
+
+
+    fn send(&mut self, job: WriteJob<DynamicImage>) {
+        let sent = self.sender.as_ref().map(|s| s.send((self.sent, job)).is_ok()).unwrap_or(false);
+        self.sent += 1;
+        if !sent {
+            // the thread stopped on an error, forward it
+            self.join();
+        }
+    }
+
+
+    /// Waits for the queued images to be written, giving the sink back
+    fn join(&mut self) -> Option<(Checksums, Box<dyn ImageSink>)> {
+        self.sender = None;
+        for encoder in self.encoders.drain(..) {
+            if let Err(e) = encoder.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => Some(result),
+            Some(Err(e)) => std::panic::resume_unwind(e),
+            None => None
+        }
+    }
+}
+
+
+/// Adds an image that could not be saved to the failures, or stops the writer without them
+fn write_failed(failures: &Option<Failures>, file: PathBuf, e: String) {
+    let message = format!("{}Could not save image to `{}`: {}", trace::log_prefix(), file.display(), e);
+    match failures {
+        Some(failures) => {
+            eprintln!("{}{}{}", RED, message, CLEAR);
+            failures.lock().unwrap().push((file, message));
+        }
+        None => panic!("{}", message)
+    }
+}
+
+
+/// Saves the outputs of the pipeline, along with the side outputs requested on the command line
+pub struct Outputs<'a> {
+    opts: &'a ProcessArgs,
+    writer: Writer,
+    /// whether the sink writes the outputs at their path
+    file_tree: bool,
+    /// outputs in processing order, when assembling an animation
+    frames: Vec<RgbImage>,
+    /// records of the outputs, such as the mixing coefficients of the batch augmentations
+    records: Vec<(PathBuf, Map)>,
+    /// json sidecars of the outputs with the metrics emitted by the pipeline, written with the manifests
+    sidecars: Vec<(PathBuf, Map)>,
+    /// files named by the pipeline, by their default path
+    renamed: HashMap<PathBuf, PathBuf>,
+    /// number of outputs saved
+    saved: usize,
+    /// with `--keep-going`
+    failures: Option<Failures>,
+    /// checksums of the outputs of a previous run kept with `--skip-existing`
+    kept: Checksums,
+    /// watch of the free space of the output filesystem
+    disk: Option<DiskGuard>,
+    /// with `--report`
+    report: Option<RunReport>,
+    /// with `--trace-id`
+    trace: Option<Trace>,
+    /// formats each output is also saved in, with `--also-save`
+    also_save: Vec<OutFormat>
+}
+
+
+impl<'a> Outputs<'a> {
+
+
+    /// The sources done are recorded in `journal` once their outputs are written
+    pub fn new(opts: &'a ProcessArgs, mut encode_opts: EncodeOptions, sink: Box<dyn ImageSink>, trace: Option<Trace>,
+            journal: Option<Journal>) -> Self {
+        encode_opts.trace = trace.as_ref().map(Trace::text);
+        let also_save = encode_opts.also_save.clone();
+        let failures = opts.keep_going.then(Failures::default);
+        let file_tree = sink.is_file_tree();
+        let existing = if file_tree { opts.existing_outputs() } else { ExistingOutput::Overwrite };
+        Self {
+            opts,
+            file_tree,
+            writer: Writer::new(encode_opts, opts.checksums, existing, sink, opts.threads.max(1), failures.clone(), journal),
+            frames: Vec::new(),
+            records: Vec::new(),
+            sidecars: Vec::new(),
+            renamed: HashMap::new(),
+            saved: 0,
+            failures,
+            kept: Vec::new(),
+            disk: opts.min_free_space.filter(|_| file_tree)
+                .and_then(|min_free| DiskGuard::new(Path::new(&opts.output), min_free * 1_000_000)),
+            report: opts.report.as_ref().map(|path| RunReport::new(match path {
+                Some(path) => PathBuf::from(path),
+                None if file_tree => manifest_dir(Path::new(&opts.output), true).join(report::REPORT_FILE),
+                None => PathBuf::from(report::REPORT_FILE)
+            }, trace.clone())),
+            trace,
+            also_save
+        }
+    }
+
+
+    /// Keeps the output of a previous run, adding it to the checksums
+    fn keep(&mut self, out_file: &Path) {
+        if self.opts.checksums {
+            match std::fs::read(out_file) {
+                Ok(bytes) => self.kept.push((format!("{:x}", Sha256::digest(&bytes)), out_file.to_path_buf())),
+                Err(e) => eprintln!("Warning: could not read `{}` for its checksum: {}", out_file.display(), e)
+            }
+        }
+    }
+
+
+    /// Runs the processing of some files, which panics when they fail. With `--keep-going`, the
+    /// panic is caught and its message returned, for the files to be added to the failures.
+    fn attempt<F: FnOnce(&mut Self)>(&mut self, process: F) -> Result<(), String> {
+        if self.failures.is_none() {
+            process(self);
+            return Ok(());
+        }
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process(self)))
+            .map_err(|e| formats::panic_message(e.as_ref()))
+    }
+
+
+    /// Adds the time spent on an input to the report
+    fn time(&mut self, in_file: &Path, start: Instant) {
+        if let Some(report) = &mut self.report {
+            report.time(in_file, start.elapsed());
+        }
+    }
+
+
+    /// Adds files to the failures of the run
+    fn fail(&self, files: Vec<PathBuf>, message: &str) {
+        if let Some(failures) = &self.failures {
+            failures.lock().unwrap().extend(files.into_iter().map(|f| (f, message.to_string())));
+        }
+    }
+
+
+    /// Adds the record of `out_file` to the records of the run
+    pub fn record(&mut self, out_file: &Path, mut record: Map) {
+        if let Some(trace) = &self.trace {
+            trace.insert(&mut record);
+        }
+        self.records.push((self.opts.output_path(out_file), record));
+    }
+
+
+    /// Saves the output computed from `input` to `out_file`, or to the file the pipeline names it,
+    /// returning the file
+    pub fn save(&mut self, compute: &mut CInstance, input: &RgbImage, output: RgbImage, out_file: &Path) -> PathBuf {
+        if let Some(disk) = &mut self.disk {
+            disk.wait();
+        }
+        let out_file = self.opts.output_path(out_file);
+        let out_file = out_file.as_path();
+        let named = compute.out_name(out_file, &output, self.saved).unwrap_or_else(|e| panic!("{}", e));
+        if named != out_file {
+            self.renamed.insert(out_file.to_path_buf(), named.clone());
+        }
+        let out_file = named.as_path();
+        self.saved += 1;
+
+        let native = compute.take_native_output();
+        let mut metrics = compute.take_metrics();
+        if !metrics.is_empty() {
+            if let Some(trace) = &self.trace {
+                trace.insert(&mut metrics);
+            }
+            self.sidecars.push((out_file.with_extension("json"), metrics));
+        }
+
+        if self.opts.emit_diff {
+            if input.dimensions() == output.dimensions() {
+                let diff = compute.diff(input, &output);
+                self.writer.write(diff, suffixed_path(out_file, "_diff"));
+            } else {
+                eprintln!("{}Cannot compute the difference for `{}`: the output and input dimentions differ.{}",
+                    RED, out_file.display(), CLEAR);
+            }
+        }
+        let output = compute.declaration().format.to_srgb(output);
+
+        if let Some(size) = self.opts.thumbnails {
+            let thumb_file = self.thumbnail_path(out_file);
+            let thumbnail = compute.resize_to_fit(&output, (size as usize, size as usize));
+            self.writer.write(thumbnail, thumb_file);
+        }
+
+        if self.opts.animate.is_some() {
+            self.frames.push(output.clone());
+        }
+        // the images registered with `add_output`, as `<output>_<name>`
+        for (name, img) in compute.take_named_outputs() {
+            let img = match img {
+                DynamicImage::ImageRgb8(rgb) => compute.declaration().format.to_srgb(rgb).into(),
+                img => img
+            };
+            self.writer.write(img, suffixed_path(out_file, &format!("_{}", name)));
+        }
+        let output = native.unwrap_or_else(|| output.into());
+        for format in &self.also_save {
+            let also_file = out_file.with_extension(format.extension());
+            if also_file != out_file {
+                self.writer.write(output.clone(), also_file);
+            }
+        }
+        self.writer.write(output, out_file.to_path_buf());
+        named
+    }
+
+
+    /// Path of the thumbnail of `out_file`, in the directory parallel to the output one
+    /// (or next to the output when processing a single file)
+    fn thumbnail_path(&self, out_file: &Path) -> PathBuf {
+        let output = Path::new(&self.opts.output);
+        match (out_file.strip_prefix(output), output.file_name()) {
+            (Ok(rel), Some(name)) if !rel.as_os_str().is_empty() => {
+                output.with_file_name(format!("{}_thumbnails", name.to_string_lossy())).join(rel)
+            }
+            _ => suffixed_path(out_file, "_thumbnail")
+        }
+    }
+
+
+    /// Writes the outputs gathered over the whole run and its report with the time spent in each stage
+    /// of the pipeline, returning whether it succeeded
+    pub fn finish(mut self, timings: Option<StageTimings>) -> bool {
+        let mut written = true;
+        if let Some((mut sums, mut sink)) = self.writer.join() {
+            sums.append(&mut self.kept);
+            let dir = manifest_dir(Path::new(&self.opts.output), self.file_tree);
+            let mut manifests = Vec::new();
+            let manifest = |name: &str| match &self.opts.manifest_suffix {
+                Some(suffix) => suffixed_path(&dir.join(name), suffix),
+                None => dir.join(name)
+            };
+            if self.opts.checksums {
+                manifests.push((manifest(CHECKSUMS_FILE), checksums_manifest(sums, dir)));
+            }
+            if !self.records.is_empty() {
+                let records = std::mem::take(&mut self.records);
+                manifests.push((manifest(RECORDS_FILE), records_manifest(records, &self.renamed, dir)));
+            }
+            for (file, metrics) in std::mem::take(&mut self.sidecars) {
+                manifests.push((file, json::format_map(&metrics)));
+            }
+
+            let result = manifests.iter()
+                .try_for_each(|(file, content)| sink.write_file(file, content.as_bytes()))
+                .and_then(|_| sink.finish());
+            if let Err(e) = result {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                written = false;
+            }
+        }
+
+        if let Some(animation) = &self.opts.animate {
+            if let Err(e) = animation::save_animation(&self.frames, Path::new(animation), self.opts.frame_delay) {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                written = false;
+            }
+        }
+
+        let failures = self.failures.map(|f| std::mem::take(&mut *f.lock().unwrap())).unwrap_or_default();
+        if !failures.is_empty() {
+            eprintln!("{}{} files failed:{}", RED, failures.len(), CLEAR);
+            for (file, _) in &failures {
+                eprintln!("  {}", file.display());
+            }
+            written = false;
+        }
+
+        if let Some(report) = &self.report {
+            if let Err(e) = report.write(&failures, timings) {
+                eprintln!("{}{}{}", RED, e, CLEAR);
+                written = false;
+            }
+        }
+        written
+    }
+}
+
+
+/// Directory of the manifests of the run: the output directory, or the directory of the output
+/// when processing a single file to a file tree
+fn manifest_dir(output: &Path, file_tree: bool) -> &Path {
+    if output.is_dir() || !file_tree {
+        output
+    } else {
+        output.parent().unwrap_or_else(|| Path::new(""))
+    }
+}
+
+
+/// `SHA256SUMS` manifest of the outputs
+fn checksums_manifest(mut sums: Checksums, dir: &Path) -> String {
+    sums.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut manifest = String::new();
+    for (sum, file) in sums {
+        let file = file.strip_prefix(dir).unwrap_or(&file);
+        manifest.push_str(&format!("{}  {}\n", sum, file.display()));
+    }
+    manifest
+}
+
+
+/// Name of the records of the outputs
+const RECORDS_FILE: &str = "records.jsonl";
+
+
+/// Records of the outputs, one json object per line with its `file`, the outputs named by the
+/// pipeline being given by their name
+fn records_manifest(records: Vec<(PathBuf, Map)>, renamed: &HashMap<PathBuf, PathBuf>, dir: &Path) -> String {
+    let rename = |file: PathBuf| renamed.get(&file).cloned().unwrap_or(file);
+
+    let mut lines = String::new();
+    for (file, mut record) in records {
+        let file = rename(file);
+        let file = file.strip_prefix(dir).unwrap_or(&file);
+        let partner = record.get("partner").and_then(|p| p.clone().into_string().ok());
+        if let Some(partner) = partner {
+            let partner = rename(dir.join(partner));
+            record.insert("partner".into(), partner.strip_prefix(dir).unwrap_or(&partner).display().to_string().into());
+        }
+        record.insert("file".into(), file.display().to_string().into());
+        lines.push_str(&json::format_map(&record));
+        lines.push('\n');
+    }
+    lines
+}
+
+
+/// Adds a suffix to the file name of a path, before its extension
+pub fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}{}", stem, suffix)
+    };
+    path.with_file_name(name)
+}
+
+
+/// Reads the source images, as requested on the command line
+pub struct Inputs<'a> {
+    opts: &'a ProcessArgs,
+    /// conversion to the working space, with `--color-manage`
+    color: Option<ColorManagement>,
+    /// claims of the input files, with `--journal`
+    journal: Option<Journal>,
+    /// decoded images, with `--decode-cache`
+    cache: Option<RefCell<DecodeCache>>,
+    /// conversion to the pixel format of the pipeline
+    negotiator: Negotiator
+}
+
+
+impl<'a> Inputs<'a> {
+
+
+    /// Reads the images of an input file, with their transparency for the rgba pipelines and the
+    /// output file of each of them.
+    /// Animated files give one image per frame with `--animated frames`, named `<name>_<frame>`,
+    /// and none with `--animated skip`.
+    pub fn read_all(&self, in_file: &Path, out_file: &Path, compute: &CInstance) -> Vec<(RgbImage, Option<GrayImage>, PathBuf)> {
+        if self.opts.animated == AnimatedInput::First {
+            let (image, alpha) = self.read(in_file, compute);
+            return vec![(image, alpha, out_file.to_path_buf())];
+        }
+
+        match decode::decode_animation(in_file).unwrap_or_else(|e| panic!("{}", e)) {
+            None => {
+                let (image, alpha) = self.read(in_file, compute);
+                vec![(image, alpha, out_file.to_path_buf())]
+            }
+            Some(_) if self.opts.animated == AnimatedInput::Skip => {
+                if !formats::quiet() {
+                    println!("Skipping animated image `{}`", in_file.display());
+                }
+                Vec::new()
+            }
+            Some(frames) => {
+                let icc = decode::read_icc_profile(in_file);
+                frames.into_iter().enumerate().map(|(i, frame)| {
+                    let alpha = self.negotiator.alpha(&frame);
+                    let frame = self.negotiator.to_rgb8(frame, in_file);
+                    let frame = self.prepare(in_file, frame, icc.as_deref(), compute);
+                    (frame, alpha, suffixed_path(out_file, &format!("_{:04}", i)))
+                }).collect()
+            }
+        }
+    }
+
+
+    /// Reads an image file as an rgb image, downscaling it to the maximum dimentions with `--downscale`,
+    /// along with its transparency for the rgba pipelines
+    pub fn read(&self, in_file: &Path, compute: &CInstance) -> (RgbImage, Option<GrayImage>) {
+        let max_size = self.decode_size(compute);
+        let img = match &self.cache {
+            Some(cache) => cache.borrow_mut().decode(in_file, max_size),
+            None => decode::decode_dynamic(in_file, max_size)
+        };
+        self.read_decoded(in_file, img, compute)
+    }
+
+
+    /// Dimentions the images are downscaled to while decoding
+    fn decode_size(&self, compute: &CInstance) -> Option<(usize, usize)> {
+        self.opts.downscale.then_some(compute.max_size())
+    }
+
+
+    /// Reads an image file decoded beforehand, see `read`
+    fn read_decoded(&self, in_file: &Path, img: Result<DynamicImage, String>, compute: &CInstance) -> (RgbImage, Option<GrayImage>) {
+        let img = img.unwrap_or_else(|e| panic!("{}", e));
+        let alpha = self.negotiator.alpha(&img);
+        let img = self.negotiator.to_rgb8(img, in_file);
+        let icc = if self.color.is_some() { decode::read_icc_profile(in_file) } else { None };
+        (self.prepare(in_file, img, icc.as_deref(), compute), alpha)
+    }
+
+
+    /// Converts a decoded image to the working space, the format of the pipeline and the maximum dimentions
+    fn prepare(&self, in_file: &Path, mut img: RgbImage, icc: Option<&[u8]>, compute: &CInstance) -> RgbImage {
+        let max_size = compute.max_size();
+
+        if let (Some(color), Some(icc)) = (&self.color, icc) {
+            if let Err(e) = color.to_working_space(&mut img, icc) {
+                eprintln!("Warning: the colors of `{}` are not converted: {}", in_file.display(), e);
+            }
+        }
+        let img = self.negotiator.to_pipeline(img);
+
+        if self.opts.downscale && (img.width() as usize > max_size.0 || img.height() as usize > max_size.1) {
+            warnings::report("downscaled", Some(in_file), &format!("is downscaled to fit in {}x{}", max_size.0, max_size.1));
+            compute.resize_to_fit(&img, max_size)
+        } else {
+            img
+        }
+    }
+}
+
+
+/// Applies the compute pipeline to the input file, saving it to out_file
+fn process_file(compute: &mut CInstance, in_file: &Path, out_file: &Path, inputs: &Inputs, outputs: &mut Outputs) {
+    let start = Instant::now();
+    for (image, alpha, out_file) in inputs.read_all(in_file, out_file, compute) {
+        log::set_context(Some(format!("`{}`", out_file.display())));
+        compute.set_input_alpha(alpha);
+        let out = compute.compute(&image);
+        outputs.save(compute, &image, out, &out_file);
+    }
+    outputs.time(in_file, start);
+}
+
+
+/// Images waiting to be processed in a single run of the pipeline
+struct Batch {
+    images: Vec<(RgbImage, PathBuf)>,
+    /// transparency of the images, for the rgba pipelines which process them one at a time
+    alphas: Vec<Option<GrayImage>>,
+    /// input file of each image, failed when the batch fails
+    inputs: Vec<PathBuf>,
+    /// input files whose images are all in the batch, completed once it is flushed
+    sources: Vec<PathBuf>,
+    height: usize,
+    max_count: usize,
+    max_size: (usize, usize)
+}
+
+
+impl Batch {
+
+
+    fn new(max_count: usize, max_size: (usize, usize)) -> Self {
+        Self {
+            images: Vec::new(),
+            alphas: Vec::new(),
+            inputs: Vec::new(),
+            sources: Vec::new(),
+            height: 0,
+            max_count,
+            max_size
+        }
+    }
+
+
+    /// Whether the image can be stacked with the images of the batch
+    fn fits(&self, img: &RgbImage) -> bool {
+        self.images.len() < self.max_count
+            && img.width() as usize <= self.max_size.0
+            && self.height + img.height() as usize <= self.max_size.1
+    }
+
+
+    fn push(&mut self, img: RgbImage, alpha: Option<GrayImage>, out_file: PathBuf, in_file: &Path) {
+        self.height += img.height() as usize;
+        self.images.push((img, out_file));
+        self.alphas.push(alpha);
+        self.inputs.push(in_file.to_path_buf());
+    }
+
+
+    /// Adds an image of `in_file` to the batch, returning the files that failed meanwhile. The batch
+    /// is flushed before the image when it does not fit and after it once full, so that each launch
+    /// only fails the files of its own images, with `--keep-going`.
+    fn add(&mut self, compute: &mut CInstance, outputs: &mut Outputs, image: RgbImage,
+            alpha: Option<GrayImage>, out_file: PathBuf, in_file: &Path) -> Vec<PathBuf> {
+        let mut failed = Vec::new();
+        if !self.fits(&image) {
+            failed = self.flush(compute, outputs);
+        }
+
+        if self.fits(&image) {
+            self.push(image, alpha, out_file, in_file);
+            if self.images.len() >= self.max_count {
+                failed.extend(self.flush(compute, outputs));
+            }
+        } else {
+            // too big to be batched with anything
+            let result = outputs.attempt(|outputs| {
+                log::set_context(Some(format!("`{}`", out_file.display())));
+                compute.set_input_alpha(alpha);
+                let out = compute.compute(&image);
+                outputs.save(compute, &image, out, &out_file);
+            });
+            if let Err(e) = result {
+                outputs.fail(vec![in_file.to_path_buf()], &e);
+                failed.push(in_file.to_path_buf());
+            }
+        }
+        failed
+    }
+
+
+    /// Completes an input file once all its images are added, when it has not failed: right away
+    /// when they are computed already, otherwise once the batch is flushed
+    fn complete(&mut self, in_file: PathBuf, outputs: &mut Outputs) {
+        if self.inputs.contains(&in_file) {
+            self.sources.push(in_file);
+        } else {
+            outputs.writer.done(in_file);
+        }
+    }
+
+
+    /// Applies the compute pipeline to the images of the batch and saves them, returning the input
+    /// files that failed with `--keep-going`
+    fn flush(&mut self, compute: &mut CInstance, outputs: &mut Outputs) -> Vec<PathBuf> {
+        if self.images.is_empty() {
+            self.clear();
+            return Vec::new();
+        }
+        match outputs.attempt(|outputs| self.launch(compute, outputs)) {
+            Ok(()) => {
+                for source in &self.sources {
+                    outputs.writer.done(source.clone());
+                }
+                self.clear();
+                Vec::new()
+            }
+            Err(e) => {
+                let failed = self.abort();
+                outputs.fail(failed.clone(), &e);
+                failed
+            }
+        }
+    }
+
+
+    /// Computes and saves the images of the batch
+    fn launch(&mut self, compute: &mut CInstance, outputs: &mut Outputs) {
+        if self.images.len() == 1 {
+            let (image, out_file) = self.images.pop().unwrap();
+            log::set_context(Some(format!("`{}`", out_file.display())));
+            compute.set_input_alpha(self.alphas.pop().flatten());
+            let out = compute.compute(&image);
+            outputs.save(compute, &image, out, &out_file);
+        } else if !self.images.is_empty() {
+            let images: Vec<RgbImage> = self.images.iter().map(|(img, _)| img.clone()).collect();
+            log::set_context(Some(format!("`{}` and {} other images", self.images[0].1.display(), images.len() - 1)));
+            let outs = compute.compute_batch(&images);
+
+            for ((out, record), (image, out_file)) in outs.into_iter().zip(self.images.iter()) {
+                if let Some(mut record) = record {
+                    // the partner of a mix is reported by its output file
+                    let partner = record.get("partner").and_then(|p| p.as_int().ok())
+                        .and_then(|p| self.images.get(p as usize));
+                    if let Some((_, partner_file)) = partner {
+                        let partner_file = partner_file.strip_prefix(manifest_dir(Path::new(&outputs.opts.output), outputs.file_tree))
+                            .unwrap_or(partner_file);
+                        record.insert("partner".into(), partner_file.display().to_string().into());
+                    }
+                    outputs.record(out_file, record);
+                }
+                outputs.save(compute, image, out, out_file);
+            }
+        }
+    }
+
+
+    fn clear(&mut self) {
+        self.images.clear();
+        self.alphas.clear();
+        self.inputs.clear();
+        self.sources.clear();
+        self.height = 0;
+    }
+
+
+    /// Drops the images of the batch after a failure, returning the files they come from
+    fn abort(&mut self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        for file in self.inputs.drain(..) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        self.clear();
+        files
+    }
+}
+
+
+/// Files of `dir`, relative to it and sorted, with those of the nested folders when `recursive`
+fn list_files(dir: &Path, recursive: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(rel) = dirs.pop() {
+        for entry in std::fs::read_dir(dir.join(&rel))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_file() {
+                files.push(rel.join(entry.file_name()));
+            } else if file_type.is_dir() && recursive {
+                dirs.push(rel.join(entry.file_name()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+
+/// Whether the output of `in_file` exists and was modified after it, by a previous run
+fn up_to_date(in_file: &Path, out_file: &Path) -> bool {
+    let modified = |file: &Path| std::fs::metadata(file).and_then(|m| m.modified()).ok();
+    match (modified(in_file), modified(out_file)) {
+        (Some(input), Some(output)) => output >= input,
+        _ => false
+    }
+}
+
+
+/// Processes the files of `in_dir` (and of its nested folders with `--recursive`), or the given
+/// `files` relative to it, into `out_dir` at the same relative paths
+fn process_dir(compute: &mut CInstance, in_dir: &Path, files: Option<Vec<PathBuf>>, out_dir: &Path, inputs: &Inputs, outputs: &mut Outputs) {
+    let files = files.unwrap_or_else(|| list_files(in_dir, inputs.opts.recursive)
+        .unwrap_or_else(|e| panic!("Could not read files in `{}`: {}", in_dir.display(), e)));
+    let files: Vec<_> = inputs.opts.select(files.into_iter())
+        .enumerate()
+        .filter(|(i, _)| inputs.opts.in_shard(*i))
+        .map(|(_, f)| f)
+        .collect();
+    let files = if inputs.opts.skip_existing {
+        let (kept, files): (Vec<_>, Vec<_>) = files.into_iter()
+            .partition(|f| up_to_date(&in_dir.join(f), &inputs.opts.output_path(&out_dir.join(f))));
+        if !kept.is_empty() && !formats::quiet() {
+            println!("Skipping {} files whose output is up to date", kept.len());
+        }
+        for file in kept {
+            outputs.keep(&inputs.opts.output_path(&out_dir.join(file)));
+        }
+        files
+    } else {
+        files
+    };
+
+    let mut batch = Batch::new(inputs.opts.batch.unwrap_or(1).max(1), compute.max_size());
+
+    // the still images are decoded ahead on other threads, the animations and cached images on this one
+    let prefetch = (inputs.opts.threads > 1 && inputs.opts.animated == AnimatedInput::First && inputs.cache.is_none())
+        .then(|| Prefetch::start(files.iter().map(|f| in_dir.join(f)).collect(), inputs.decode_size(compute), inputs.opts.threads));
+
+    // the files count for their size with `--progress bytes`, so that the large ones weigh on the estimate
+    let weights: Vec<u64> = match inputs.opts.progress {
+        ProgressUnit::Files => vec![1; files.len()],
+        ProgressUnit::Bytes => files.iter()
+            .map(|f| std::fs::metadata(in_dir.join(f)).map(|m| m.len()).unwrap_or(0))
+            .collect()
+    };
+    let mut progress = Progress::start(weights.iter().sum());
+
+    for (i, file) in files.into_iter().enumerate() {
+        let in_file = in_dir.join(&file);
+        let out_file = out_dir.join(&file);
+        let decoded = prefetch.as_ref().map(|p| p.take(i));
+
+        if inputs.journal.as_ref().map(|j| j.claim(&in_file)) == Some(false) {
+            progress.advance(weights[i]);
+            continue;
+        }
+
+        let start = Instant::now();
+        let mut images = Vec::new();
+        let read = outputs.attempt(|_| images = match decoded {
+            Some(img) => {
+                let (image, alpha) = inputs.read_decoded(&in_file, img, compute);
+                vec![(image, alpha, out_file)]
+            }
+            None => inputs.read_all(&in_file, &out_file, compute)
+        });
+        let mut failed = match read {
+            Ok(()) => false,
+            Err(e) => {
+                outputs.fail(vec![in_file.clone()], &e);
+                true
+            }
+        };
+        for (image, alpha, out_file) in images {
+            if batch.add(compute, outputs, image, alpha, out_file, &in_file).contains(&in_file) {
+                failed = true;
+                break;
+            }
+        }
+        if !failed {
+            batch.complete(in_file.clone(), outputs);
+        }
+        outputs.time(&in_file, start);
+
+        progress.advance(weights[i]);
+    }
+
+    batch.flush(compute, outputs);
+}
+
+
+/// Processes the images of a source given by a `scheme://location` string into `out_dir`.
+/// With `--color-manage`, the images are converted from the ICC profiles the sources read with them.
+/// The images that cannot be read stop the run like unreadable files, or fail with `--keep-going`.
+fn process_source(compute: &mut CInstance, src: &str, source: Box<dyn ImageSource>, out_dir: &Path, inputs: &Inputs, outputs: &mut Outputs) {
+    // the selected images of a source are not known before reading it
+    let total = source.len_hint().filter(|_| !inputs.opts.selects());
+    let mut batch = Batch::new(inputs.opts.batch.unwrap_or(1).max(1), compute.max_size());
+
+    let mut progress = total.map(|total| Progress::start(total as u64));
+
+    for (i, item) in inputs.opts.select(source).enumerate() {
+        if inputs.opts.in_shard(i) {
+            match item {
+                Ok(item) => {
+                    let start = Instant::now();
+                    let in_file = PathBuf::from(&item.id);
+                    let out_file = out_dir.join(&item.id);
+
+                    let alpha = inputs.negotiator.alpha(&item.image);
+                    let mut image = None;
+                    let prepared = outputs.attempt(|_| {
+                        let rgb = inputs.negotiator.to_rgb8(item.image, &in_file);
+                        image = Some(inputs.prepare(&in_file, rgb, item.icc.as_deref(), compute));
+                    });
+                    if let Err(e) = prepared {
+                        outputs.fail(vec![in_file.clone()], &e);
+                    }
+                    if let Some(image) = image {
+                        if !item.metadata.is_empty() {
+                            outputs.record(&out_file, item.metadata);
+                        }
+                        batch.add(compute, outputs, image, alpha, out_file, &in_file);
+                    }
+                    outputs.time(&in_file, start);
+                }
+                Err(e) => {
+                    // the images that cannot be read have no id, only their position in the source
+                    if let Err(e) = outputs.attempt(|_| panic!("{}", e)) {
+                        outputs.fail(vec![PathBuf::from(format!("{}#{}", src, i))], &e);
+                    }
+                }
+            }
+        }
+
+        if let Some(progress) = &mut progress {
+            progress.advance(1);
+        }
+    }
+
+    batch.flush(compute, outputs);
+}
+
+
+/// Processes each class folder of `in_dir` into the folder of the same name in `out_dir`
+fn process_classes(compute: &mut CInstance, in_dir: &Path, out_dir: &Path, inputs: &Inputs, outputs: &mut Outputs) {
+    use std::fs;
+
+    let mut classes: Vec<_> = fs::read_dir(in_dir)
+        .unwrap_or_else(|e| panic!("Could not read files in `{}`: {}", in_dir.display(), e))
+        .filter_map(|f| f.ok())
+        .filter(|f| f.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|f| f.file_name())
+        .collect();
+    classes.sort();
+
+    for class in classes {
+        let class_out = out_dir.join(&class);
+
+        if !formats::quiet() {
+            println!("{}", class.to_string_lossy());
+        }
+        compute.set_class(Some(&class.to_string_lossy()));
+        process_dir(compute, &in_dir.join(&class), None, &class_out, inputs, outputs);
+    }
+
+    compute.set_class(None);
+}
+
+
+/// Progress bar of a run, counting files or input bytes, with the remaining time extrapolated from
+/// the elapsed one
+struct Progress {
+    done: u64,
+    total: u64,
+    start: Instant
+}
+
+
+impl Progress {
+
+
+    /// Prints the empty progress bar, rewritten by `advance`
+    fn start(total: u64) -> Self {
+        if !formats::quiet() {
+            log::start_progress("<----------------------------------------> 0.00%");
+        }
+        Self { done: 0, total, start: Instant::now() }
+    }
+
+
+    /// Adds `amount` to the progress and replaces the last line of the terminal with the progress bar.
+    /// Without escape sequences, a new line is printed at each percent instead.
+    fn advance(&mut self, amount: u64) {
+        let before = self.done;
+        self.done = (self.done + amount).min(self.total);
+        if formats::quiet() || self.total == 0 {
+            return;
+        }
+
+        let (done, total) = (self.done, self.total);
+        let progress_percent = (done as f32 / total as f32) * 100.0;
+        let progress = ((done as f32 / total as f32) * 40.0) as usize;
+        if !formats::colored() && done * 100 / total == before * 100 / total {
+            return;
+        }
+        let bar = format!("<{}{}>", "=".repeat(progress), "-".repeat(40 - progress));
+        let line = if done > 0 && done < total {
+            let seconds = (self.start.elapsed().as_secs_f64() * (total - done) as f64 / done as f64) as u64;
+            format!("{} {:.2}% ({}:{:02}:{:02} left)", bar, progress_percent, seconds / 3600, seconds / 60 % 60, seconds % 60)
+        } else {
+            format!("{} {:.2}%", bar, progress_percent)
+        };
+        log::update_progress(&line, done == total);
+    }
+}
@@ -0,0 +1,119 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Json report of a run, for the jobs monitored by other programs: the count of processed and
+// failed files, the time spent on each input, and the time spent in each stage of the pipeline.
+
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rhai::{Map, Array, FLOAT, INT};
+
+use crate::compute::StageTimings;
+use crate::json;
+use crate::trace::Trace;
+
+
+/// Name of the report in the output directory, when its path is not given
+pub const REPORT_FILE: &str = "report.json";
+
+
+/// Times of the inputs of a run, reported at its end
+pub struct RunReport {
+    path: PathBuf,
+    start: Instant,
+    /// time spent on each input on the processing thread
+    files: Vec<(PathBuf, Duration)>,
+    /// with `--trace-id`
+    trace: Option<Trace>
+}
+
+
+impl RunReport {
+
+
+    pub fn new(path: PathBuf, trace: Option<Trace>) -> Self {
+        Self {
+            path,
+            start: Instant::now(),
+            files: Vec::new(),
+            trace
+        }
+    }
+
+
+    /// Adds an input processed in `time`
+    pub fn time(&mut self, file: &Path, time: Duration) {
+        self.files.push((file.to_path_buf(), time));
+    }
+
+
+    /// Writes the report, with the files that failed and the time spent in each stage of the pipeline
+    pub fn write(&self, failures: &[(PathBuf, String)], timings: Option<StageTimings>) -> Result<(), String> {
+        let seconds = |d: Duration| (d.as_secs_f64() as FLOAT).into();
+
+        let mut images = Array::new();
+        for (file, time) in &self.files {
+            let mut image = Map::new();
+            image.insert("file".into(), file.display().to_string().into());
+            image.insert("seconds".into(), seconds(*time));
+            images.push(image.into());
+        }
+
+        let mut failed = Array::new();
+        for (file, error) in failures {
+            let mut failure = Map::new();
+            failure.insert("file".into(), file.display().to_string().into());
+            failure.insert("error".into(), error.clone().into());
+            failed.push(failure.into());
+        }
+
+        let mut report = Map::new();
+        if let Some(trace) = &self.trace {
+            trace.insert(&mut report);
+        }
+        report.insert("files".into(), (self.files.len() as INT).into());
+        report.insert("succeeded".into(), (self.files.len().saturating_sub(failures.len()) as INT).into());
+        report.insert("failed".into(), (failures.len() as INT).into());
+        report.insert("seconds".into(), seconds(self.start.elapsed()));
+        if let Some(timings) = timings {
+            let mut stages = Map::new();
+            stages.insert("runs".into(), (timings.runs as INT).into());
+            stages.insert("upload_seconds".into(), seconds(timings.upload));
+            stages.insert("compute_seconds".into(), seconds(timings.compute));
+            stages.insert("download_seconds".into(), seconds(timings.download));
+            report.insert("stages".into(), stages.into());
+        }
+        report.insert("failures".into(), failed.into());
+        report.insert("images".into(), images.into());
+
+        std::fs::write(&self.path, json::format_map(&report))
+            .map_err(|e| format!("Could not write `{}`: {}", self.path.display(), e))
+    }
+}
@@ -0,0 +1,115 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Smoke test of the OpenCL stack: a small embedded kernel inverts a generated image on each device,
+// going through the same input and output buffers as the pipelines.
+
+
+use std::panic::{self, AssertUnwindSafe};
+
+use image::{Rgb, RgbImage};
+
+use ocl::{Platform, Device};
+
+use rhai::Array;
+
+use crate::compute::KernelRunner;
+use crate::formats;
+use crate::{RED, GREEN, CLEAR};
+
+
+const SELFTEST_SRC: &str = "
+__kernel void invert(__global const uchar* input, __global uchar* output, const int img_w, const int img_h)
+{
+    const int x = get_global_id(0);
+    const int y = get_global_id(1);
+    if (x >= img_w || y >= img_h) {
+        return;
+    }
+
+    const int i = (x + y * img_w) * 3;
+    output[i]     = 255 - input[i];
+    output[i + 1] = 255 - input[i + 1];
+    output[i + 2] = 255 - input[i + 2];
+}
+";
+
+
+/// Dimentions of the test image, not a multiple of the usual work group sizes
+const SIZE: (usize, usize) = (67, 45);
+
+
+/// Runs the self test on each of `devices`, returning whether it passed on all of them
+pub fn run_selftest(devices: &[(Platform, Device)]) -> bool {
+    if devices.is_empty() {
+        println!("{}FAILED{}  no OpenCL device found", RED, CLEAR);
+        return false;
+    }
+
+    // the failures are reported with the device instead of as a panic
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut failed = 0;
+    for &(platform, device) in devices {
+        let name = format!("{} ({})", device.name().unwrap_or_default(), platform.name().unwrap_or_default());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| check_device(platform, device)))
+            .unwrap_or_else(|e| Err(formats::panic_message(e.as_ref())));
+
+        match result {
+            Ok(()) => println!("{}ok{}      {}", GREEN, CLEAR, name),
+            Err(e) => {
+                println!("{}FAILED{}  {}: {}", RED, CLEAR, name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    panic::set_hook(hook);
+
+    println!("{} passed, {} failed", devices.len() - failed, failed);
+    failed == 0
+}
+
+
+/// Builds the kernel on a device and checks its output on a gradient
+fn check_device(platform: Platform, device: Device) -> Result<(), String> {
+    let mut runner = KernelRunner::from_source(SELFTEST_SRC.into(), SIZE, &[(platform, device)]);
+
+    let input = RgbImage::from_fn(SIZE.0 as u32, SIZE.1 as u32, |x, y| Rgb([(x * 3) as u8, (y * 5) as u8, (x ^ y) as u8]));
+    let args: Array = vec!["input".into(), "output".into()];
+    let output = runner.run("invert", &input, &args)?;
+
+    let wrong = input.pixels().zip(output.pixels())
+        .position(|(i, o)| i.0.iter().zip(o.0).any(|(i, o)| 255 - i != o));
+    match wrong {
+        None => Ok(()),
+        Some(i) => Err(format!("wrong output at ({}, {})", i % SIZE.0, i / SIZE.0))
+    }
+}
+
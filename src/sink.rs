@@ -0,0 +1,352 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Destinations of the outputs, given by the output string: a file tree (plain path), a zip archive
+// (`zip://`), a tensor pack of raw pixels (`pack://`), an http endpoint receiving each file in a POST
+// request (`http://`), or nothing but statistics (`null://`).
+//
+// Each kind of sink implements `ImageSink` and is registered with its scheme in `SINKS`.
+
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use image::DynamicImage;
+
+use rhai::{Map, Array, INT};
+
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+use crate::encode::{self, EncodeOptions};
+use crate::json;
+
+
+/// Destination of the output images and manifests
+pub trait ImageSink: Send {
+
+    /// Saves an image to `path`, in the format of its extension, returning the bytes written
+    fn write_image(&mut self, path: &Path, img: &DynamicImage, opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+        let bytes = encode::encode_image(img, path, opts)?;
+        self.write_file(path, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// Saves a file
+    fn write_file(&mut self, path: &Path, bytes: &[u8]) -> Result<(), String>;
+
+    /// Completes the output once everything is written
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether the outputs are files at their path, so that the manifests go next to them
+    fn is_file_tree(&self) -> bool {
+        false
+    }
+
+    /// Whether `write_image` writes the encoded image, which can then be encoded on other threads
+    fn encodes_images(&self) -> bool {
+        true
+    }
+}
+
+
+/// Opens the sink of an output string whose scheme was stripped
+type SinkConstructor = fn(&str, &Path) -> Result<Box<dyn ImageSink>, String>;
+
+
+/// The kinds of sinks, by scheme
+const SINKS: &[(&str, SinkConstructor)] = &[
+    ("zip", ZipSink::open),
+    ("pack", PackSink::open),
+    ("http", HttpSink::open),
+    ("null", NullSink::open)
+];
+
+
+/// Opens the sink of an output string, a plain path being a file tree
+pub fn open_sink(output: &str) -> Result<Box<dyn ImageSink>, String> {
+    let (scheme, location) = match output.split_once("://") {
+        Some(split) => split,
+        None => return Ok(Box::new(FileTree))
+    };
+
+    match SINKS.iter().find(|(s, _)| *s == scheme) {
+        Some((_, open)) => open(location, Path::new(output)),
+        None => {
+            let schemes: Vec<String> = SINKS.iter().map(|(s, _)| format!("{}://", s)).collect();
+            Err(format!("Unknown output scheme `{}://` (supported: {})", scheme, schemes.join(", ")))
+        }
+    }
+}
+
+
+/// Name of an output relative to the output string `root`, the side outputs next to the output
+/// (such as the thumbnails) being relative to its parent
+fn relative(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .or_else(|_| path.strip_prefix(root.parent().unwrap_or(root)))
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+
+/// Files written at their path
+pub struct FileTree;
+
+
+impl ImageSink for FileTree {
+
+    fn write_file(&mut self, path: &Path, bytes: &[u8]) -> Result<(), String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Could not create directory `{}`: {}", dir.display(), e))?;
+        }
+        // written aside then renamed, so that an interrupted run leaves no truncated output for
+        // --skip-existing to keep
+        let part = path.with_file_name(format!(".{}.part", path.file_name().unwrap_or_default().to_string_lossy()));
+        std::fs::write(&part, bytes)
+            .and_then(|_| std::fs::rename(&part, path))
+            .map_err(|e| format!("Could not save `{}`: {}", path.display(), e))
+    }
+
+    fn is_file_tree(&self) -> bool {
+        true
+    }
+}
+
+
+/// Files stored in a zip archive, named after their path relative to the output
+struct ZipSink {
+    zip: ZipWriter<File>,
+    root: PathBuf,
+    location: String
+}
+
+
+impl ZipSink {
+
+    fn open(location: &str, root: &Path) -> Result<Box<dyn ImageSink>, String> {
+        let file = File::create(location).map_err(|e| format!("Could not create `{}`: {}", location, e))?;
+        Ok(Box::new(Self {
+            zip: ZipWriter::new(file),
+            root: root.to_path_buf(),
+            location: location.to_string()
+        }))
+    }
+}
+
+
+impl ImageSink for ZipSink {
+
+    fn write_file(&mut self, path: &Path, bytes: &[u8]) -> Result<(), String> {
+        // the images are already compressed
+        let method = match image::ImageFormat::from_path(path) {
+            Ok(_) => zip::CompressionMethod::Stored,
+            Err(_) => zip::CompressionMethod::Deflated
+        };
+        self.zip.start_file(relative(&self.root, path), FileOptions::default().compression_method(method))
+            .and_then(|_| self.zip.write_all(bytes).map_err(Into::into))
+            .map_err(|e| format!("Could not write to `{}`: {}", self.location, e))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.zip.finish().map(|_| ()).map_err(|e| format!("Could not write `{}`: {}", self.location, e))
+    }
+}
+
+
+/// Raw rgb pixels of the images concatenated in a single file, with a json index
+/// (`<file>.json`) of the path, offset and dimensions of each image. The other files are
+/// written next to the pack.
+struct PackSink {
+    pack: BufWriter<File>,
+    location: String,
+    root: PathBuf,
+    offset: usize,
+    /// path, offset, width and height of the packed images
+    index: Vec<(String, usize, u32, u32)>
+}
+
+
+impl PackSink {
+
+    fn open(location: &str, root: &Path) -> Result<Box<dyn ImageSink>, String> {
+        let file = File::create(location).map_err(|e| format!("Could not create `{}`: {}", location, e))?;
+        Ok(Box::new(Self {
+            pack: BufWriter::new(file),
+            location: location.to_string(),
+            root: root.to_path_buf(),
+            offset: 0,
+            index: Vec::new()
+        }))
+    }
+}
+
+
+impl ImageSink for PackSink {
+
+    fn write_image(&mut self, path: &Path, img: &DynamicImage, _opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+        // the pack holds rgb pixels whatever the pixel format of the outputs
+        let img = encode::as_rgb8(img);
+        self.pack.write_all(img.as_raw()).map_err(|e| format!("Could not write to `{}`: {}", self.location, e))?;
+
+        self.index.push((relative(&self.root, path), self.offset, img.width(), img.height()));
+        self.offset += img.as_raw().len();
+
+        Ok(img.as_raw().clone())
+    }
+
+    fn encodes_images(&self) -> bool {
+        false
+    }
+
+    fn write_file(&mut self, path: &Path, bytes: &[u8]) -> Result<(), String> {
+        let file = Path::new(&self.location).with_file_name(relative(&self.root, path));
+        FileTree.write_file(&file, bytes)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.pack.flush().map_err(|e| format!("Could not write to `{}`: {}", self.location, e))?;
+
+        let images: Array = self.index.drain(..).map(|(path, offset, width, height)| {
+            let mut entry = Map::new();
+            entry.insert("path".into(), path.into());
+            entry.insert("offset".into(), (offset as INT).into());
+            entry.insert("width".into(), (width as INT).into());
+            entry.insert("height".into(), (height as INT).into());
+            entry.into()
+        }).collect();
+
+        let mut index = Map::new();
+        index.insert("channels".into(), (3 as INT).into());
+        index.insert("images".into(), images.into());
+        let index_file = format!("{}.json", self.location);
+        std::fs::write(&index_file, json::format_map(&index))
+            .map_err(|e| format!("Could not write `{}`: {}", index_file, e))
+    }
+}
+
+
+/// Files sent to an http endpoint, one POST request per file with its relative path in the
+/// `X-Image-Path` header
+struct HttpSink {
+    /// host and port
+    address: String,
+    host: String,
+    path: String,
+    root: PathBuf
+}
+
+
+impl HttpSink {
+
+    fn open(location: &str, root: &Path) -> Result<Box<dyn ImageSink>, String> {
+        let (address, path) = match location.find('/') {
+            Some(i) => (&location[..i], &location[i..]),
+            None => (location, "/")
+        };
+        if address.is_empty() {
+            return Err(String::from("The http output has no host"));
+        }
+
+        Ok(Box::new(Self {
+            address: if address.contains(':') { address.to_string() } else { format!("{}:80", address) },
+            host: address.to_string(),
+            path: path.to_string(),
+            root: root.to_path_buf()
+        }))
+    }
+}
+
+
+impl ImageSink for HttpSink {
+
+    fn write_file(&mut self, path: &Path, bytes: &[u8]) -> Result<(), String> {
+        let name = relative(&self.root, path);
+        let error = |e: std::io::Error| format!("Could not send `{}` to `{}`: {}", name, self.host, e);
+
+        let mut stream = TcpStream::connect(&self.address).map_err(error)?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\n\
+                Content-Length: {}\r\nX-Image-Path: {}\r\nConnection: close\r\n\r\n",
+                self.path, self.host, bytes.len(), name)
+            .and_then(|_| stream.write_all(bytes))
+            .map_err(error)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).map_err(error)?;
+        let status = response.split_whitespace().nth(1).unwrap_or("");
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(format!("Could not send `{}` to `{}`: {}", name, self.host, response.lines().next().unwrap_or("no response")))
+        }
+    }
+}
+
+
+/// Discards the outputs, printing how many images were computed
+struct NullSink {
+    images: usize,
+    pixels: usize
+}
+
+
+impl NullSink {
+
+    fn open(_location: &str, _root: &Path) -> Result<Box<dyn ImageSink>, String> {
+        Ok(Box::new(Self { images: 0, pixels: 0 }))
+    }
+}
+
+
+impl ImageSink for NullSink {
+
+    fn write_image(&mut self, _path: &Path, img: &DynamicImage, _opts: &EncodeOptions) -> Result<Vec<u8>, String> {
+        self.images += 1;
+        self.pixels += (img.width() * img.height()) as usize;
+        Ok(Vec::new())
+    }
+
+    fn encodes_images(&self) -> bool {
+        false
+    }
+
+    fn write_file(&mut self, _path: &Path, _bytes: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if !crate::formats::quiet() {
+            println!("{} images computed ({:.1} megapixels), discarded", self.images, self.pixels as f64 / 1e6);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Image sources given by a `scheme://location` source string.
+//
+// Each kind of source implements `ImageSource` and is registered with its scheme in `SOURCES`,
+// so that a new kind of source only needs a constructor there.
+
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, DynamicImage};
+
+use rhai::Map;
+
+use zip::ZipArchive;
+
+use crate::decode;
+
+
+/// An image read from a source
+pub struct SourceItem {
+    /// Path of the image relative to the source, used as the path of the output
+    pub id: String,
+    pub image: DynamicImage,
+    /// ICC profile embedded in the image, only read for `--color-manage`
+    pub icc: Option<Vec<u8>>,
+    /// Where the image comes from, added to the output records
+    pub metadata: Map
+}
+
+
+/// Iterator over the images of a source
+pub trait ImageSource: Iterator<Item = Result<SourceItem, String>> {
+
+    /// Number of images of the source, when it is known in advance
+    fn len_hint(&self) -> Option<usize> {
+        None
+    }
+}
+
+
+/// Opens a source from the location that follows its scheme, reading the ICC profiles of its images
+/// when asked to
+type SourceConstructor = fn(&str, bool) -> Result<Box<dyn ImageSource>, String>;
+
+
+/// The kinds of sources, by scheme
+const SOURCES: &[(&str, SourceConstructor)] = &[
+    ("dir", FileSource::open_dir),
+    ("list", FileSource::open_list),
+    ("zip", ZipSource::open)
+];
+
+
+/// Whether a source string has a scheme, instead of being a plain path
+pub fn has_scheme(src: &str) -> bool {
+    src.contains("://")
+}
+
+
+/// Opens the source of a `scheme://location` string. The ICC profiles of the images are read with
+/// `icc_profiles`, for the color management.
+pub fn open_source(src: &str, icc_profiles: bool) -> Result<Box<dyn ImageSource>, String> {
+    let (scheme, location) = src.split_once("://")
+        .ok_or_else(|| format!("`{}` has no source scheme", src))?;
+
+    match SOURCES.iter().find(|(s, _)| *s == scheme) {
+        Some((_, open)) => open(location, icc_profiles),
+        None => {
+            let schemes: Vec<String> = SOURCES.iter().map(|(s, _)| format!("{}://", s)).collect();
+            Err(format!("Unknown source scheme `{}://` (supported: {})", scheme, schemes.join(", ")))
+        }
+    }
+}
+
+
+/// Image files, from a directory (`dir://`) or listed in a text file (`list://`, one path per line)
+struct FileSource {
+    files: std::vec::IntoIter<(String, PathBuf)>,
+    len: usize,
+    icc_profiles: bool
+}
+
+
+impl FileSource {
+
+
+    fn open_dir(location: &str, icc_profiles: bool) -> Result<Box<dyn ImageSource>, String> {
+        let mut files: Vec<(String, PathBuf)> = std::fs::read_dir(location)
+            .map_err(|e| format!("Could not read files in `{}`: {}", location, e))?
+            .filter_map(|f| f.ok())
+            .filter(|f| f.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .map(|f| (f.file_name().to_string_lossy().into_owned(), f.path()))
+            .collect();
+        files.sort();
+        Ok(Box::new(Self::new(files, icc_profiles)))
+    }
+
+
+    /// Paths relative to the list file, the outputs keeping their path below the deepest directory
+    /// holding the list and the files, like `--files-from`
+    fn open_list(location: &str, icc_profiles: bool) -> Result<Box<dyn ImageSource>, String> {
+        let content = std::fs::read_to_string(location)
+            .map_err(|e| format!("Could not read `{}`: {}", location, e))?;
+        let base = Path::new(location).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let base = base.canonicalize().map_err(|e| format!("Could not read `{}`: {}", location, e))?;
+
+        let lines: Vec<&str> = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        let paths: Vec<Option<PathBuf>> = lines.iter().map(|line| base.join(line).canonicalize().ok()).collect();
+
+        let mut root = base.clone();
+        for path in paths.iter().flatten() {
+            while !path.starts_with(&root) {
+                root = root.parent().map(Path::to_path_buf).unwrap_or_default();
+            }
+        }
+
+        let files = lines.iter().zip(paths).map(|(line, path)| match path {
+            Some(path) => {
+                let id = path.strip_prefix(&root).expect("the root holds every file").to_string_lossy().into_owned();
+                (id, path)
+            }
+            // the missing files fail once read
+            None => (line.to_string(), base.join(line))
+        }).collect();
+        Ok(Box::new(Self::new(files, icc_profiles)))
+    }
+
+
+    fn new(files: Vec<(String, PathBuf)>, icc_profiles: bool) -> Self {
+        Self {
+            len: files.len(),
+            files: files.into_iter(),
+            icc_profiles
+        }
+    }
+}
+
+
+impl Iterator for FileSource {
+    type Item = Result<SourceItem, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (id, path) = self.files.next()?;
+        Some(decode::decode_dynamic(&path, None).map(|image| SourceItem {
+            id,
+            image,
+            icc: if self.icc_profiles { decode::read_icc_profile(&path) } else { None },
+            metadata: Map::new()
+        }))
+    }
+}
+
+
+impl ImageSource for FileSource {
+
+    fn len_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+
+/// Images of a zip archive (`zip://`), named after their path in the archive
+struct ZipSource {
+    archive: ZipArchive<File>,
+    location: String,
+    /// indices of the image entries
+    entries: std::vec::IntoIter<usize>,
+    len: usize,
+    icc_profiles: bool
+}
+
+
+impl ZipSource {
+
+
+    fn open(location: &str, icc_profiles: bool) -> Result<Box<dyn ImageSource>, String> {
+        let file = File::open(location)
+            .map_err(|e| format!("Could not read `{}`: {}", location, e))?;
+        let mut archive = ZipArchive::new(file)
+            .map_err(|e| format!("`{}` is not a valid zip archive: {}", location, e))?;
+
+        let mut entries = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i).map_err(|e| format!("Could not read `{}`: {}", location, e))?;
+            if entry.is_file() && ImageFormat::from_path(entry.name()).is_ok() {
+                entries.push((entry.name().to_string(), i));
+            }
+        }
+        entries.sort();
+
+        Ok(Box::new(Self {
+            archive,
+            location: location.to_string(),
+            len: entries.len(),
+            entries: entries.into_iter().map(|(_, i)| i).collect::<Vec<_>>().into_iter(),
+            icc_profiles
+        }))
+    }
+}
+
+
+impl Iterator for ZipSource {
+    type Item = Result<SourceItem, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let i = self.entries.next()?;
+        let mut entry = match self.archive.by_index(i) {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(format!("Could not read `{}`: {}", self.location, e)))
+        };
+
+        // the entries are written below the output directory, which they must not leave
+        let name = match entry.enclosed_name() {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => return Some(Err(format!("The entry `{}` of `{}` is not a relative path inside the archive",
+                entry.name(), self.location)))
+        };
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        if let Err(e) = entry.read_to_end(&mut bytes) {
+            return Some(Err(format!("Could not read `{}` in `{}`: {}", name, self.location, e)));
+        }
+
+        let mut metadata = Map::new();
+        metadata.insert("archive".into(), self.location.clone().into());
+        metadata.insert("entry".into(), name.clone().into());
+
+        Some(decode::decode_dynamic_from_memory(&bytes)
+            .map(|image| SourceItem {
+                id: name.clone(),
+                image,
+                icc: if self.icc_profiles { decode::icc_profile_from_memory(&bytes) } else { None },
+                metadata
+            })
+            .map_err(|e| format!("Could not read image `{}` in `{}`: {}", name, self.location, e)))
+    }
+}
+
+
+impl ImageSource for ZipSource {
+
+    fn len_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
@@ -0,0 +1,213 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+// Pauses the processing while the GPU is too hot or draws too much power, reading the sensors
+// through NVML (with the `nvml` feature) or the hwmon sysfs interface of the drm devices.
+
+
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Minimum time between two readings of the sensors while processing
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time between two readings of the sensors while paused
+const PAUSE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Cooling below the temperature limit before resuming, in degrees
+const TEMP_HYSTERESIS: f32 = 5.0;
+
+
+/// Limits of the GPU sensors
+#[derive(Clone, Copy, Default)]
+pub struct ThermalLimits {
+    /// Temperature in degrees Celsius
+    pub max_temp: Option<f32>,
+    /// Power draw in watts
+    pub max_power: Option<f32>
+}
+
+
+enum Sensors {
+    #[cfg(feature = "nvml")]
+    Nvml(Box<nvml_wrapper::Nvml>),
+    /// hwmon directories of the drm devices
+    Sysfs(Vec<PathBuf>)
+}
+
+
+/// Readings of the hottest and most power hungry GPU
+#[derive(Clone, Copy, Default)]
+struct Readings {
+    temp: Option<f32>,
+    power: Option<f32>
+}
+
+
+pub struct ThermalGuard {
+    limits: ThermalLimits,
+    sensors: Sensors,
+    last_check: Option<Instant>
+}
+
+
+impl ThermalGuard {
+
+
+    /// Finds the GPU sensors, returning `None` with a warning when there are none
+    pub fn new(limits: ThermalLimits) -> Option<Self> {
+        let sensors = find_sensors();
+        let guard = sensors.map(|sensors| Self { limits, sensors, last_check: None });
+
+        let readings = guard.as_ref().map(ThermalGuard::read).unwrap_or_default();
+        if limits.max_temp.is_some() && readings.temp.is_none() {
+            eprintln!("Warning: no GPU temperature sensor found, the temperature limit is ignored");
+        }
+        if limits.max_power.is_some() && readings.power.is_none() {
+            eprintln!("Warning: no GPU power sensor found, the power limit is ignored");
+        }
+        guard
+    }
+
+
+    /// Blocks while the GPU is over the limits, checking the sensors at most every few seconds
+    pub fn wait(&mut self) {
+        if self.last_check.map(|t| t.elapsed() < POLL_INTERVAL).unwrap_or(false) {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+
+        let readings = self.read();
+        if !self.over_limits(readings, 0.0) {
+            return;
+        }
+
+        println!("GPU over its limits ({}), pausing", describe(readings));
+        let start = Instant::now();
+        loop {
+            thread::sleep(PAUSE_INTERVAL);
+            let readings = self.read();
+            if !self.over_limits(readings, TEMP_HYSTERESIS) {
+                println!("GPU back under its limits ({}) after {}s, resuming", describe(readings), start.elapsed().as_secs());
+                break;
+            }
+        }
+        self.last_check = Some(Instant::now());
+    }
+
+
+    /// Whether a reading is over its limit, the temperature limit being lowered by `margin`
+    fn over_limits(&self, readings: Readings, margin: f32) -> bool {
+        let hot = matches!((readings.temp, self.limits.max_temp), (Some(t), Some(max)) if t >= max - margin);
+        let power = matches!((readings.power, self.limits.max_power), (Some(p), Some(max)) if p >= max);
+        hot || power
+    }
+
+
+    fn read(&self) -> Readings {
+        match &self.sensors {
+            #[cfg(feature = "nvml")]
+            Sensors::Nvml(nvml) => {
+                use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+
+                let mut readings = Readings::default();
+                for i in 0..nvml.device_count().unwrap_or(0) {
+                    if let Ok(device) = nvml.device_by_index(i) {
+                        let temp = device.temperature(TemperatureSensor::Gpu).ok().map(|t| t as f32);
+                        let power = device.power_usage().ok().map(|mw| mw as f32 / 1000.0);
+                        readings = max_readings(readings, Readings { temp, power });
+                    }
+                }
+                readings
+            }
+            Sensors::Sysfs(dirs) => {
+                let read_value = |file: PathBuf| std::fs::read_to_string(file).ok()
+                    .and_then(|v| v.trim().parse::<f32>().ok());
+                dirs.iter().fold(Readings::default(), |readings, dir| {
+                    max_readings(readings, Readings {
+                        // millidegrees and microwatts
+                        temp: read_value(dir.join("temp1_input")).map(|t| t / 1000.0),
+                        power: read_value(dir.join("power1_average")).map(|p| p / 1_000_000.0)
+                    })
+                })
+            }
+        }
+    }
+}
+
+
+fn max_readings(a: Readings, b: Readings) -> Readings {
+    let max = |a: Option<f32>, b: Option<f32>| match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b)
+    };
+    Readings {
+        temp: max(a.temp, b.temp),
+        power: max(a.power, b.power)
+    }
+}
+
+
+fn describe(readings: Readings) -> String {
+    let mut parts = Vec::new();
+    if let Some(temp) = readings.temp {
+        parts.push(format!("{:.0}°C", temp));
+    }
+    if let Some(power) = readings.power {
+        parts.push(format!("{:.0}W", power));
+    }
+    parts.join(", ")
+}
+
+
+fn find_sensors() -> Option<Sensors> {
+    #[cfg(feature = "nvml")]
+    if let Ok(nvml) = nvml_wrapper::Nvml::init() {
+        return Some(Sensors::Nvml(Box::new(nvml)));
+    }
+
+    // /sys/class/drm/card<N>/device/hwmon/hwmon<M>
+    let mut dirs = Vec::new();
+    for card in std::fs::read_dir("/sys/class/drm").into_iter().flatten().flatten() {
+        let name = card.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("card") || name.contains('-') {
+            continue;
+        }
+        for hwmon in std::fs::read_dir(card.path().join("device/hwmon")).into_iter().flatten().flatten() {
+            dirs.push(hwmon.path());
+        }
+    }
+
+    if dirs.is_empty() {
+        None
+    } else {
+        dirs.sort();
+        Some(Sensors::Sysfs(dirs))
+    }
+}
@@ -0,0 +1,105 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Tracing of the runs with `--trace-id`: the identifier of a run, given or generated, goes with the
+// version of the pipeline and the device in the logs, the records, the report and the metadata of the
+// outputs, so that a bad output can be traced back to the run, pipeline and device that produced it.
+
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rhai::Map;
+
+use crate::compute::CInstance;
+
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+
+/// Sets the identifier of the run, once
+pub fn set_run_id(id: String) {
+    let _ = RUN_ID.set(id);
+}
+
+
+/// Identifier of the run, when it is traced
+pub fn run_id() -> Option<&'static str> {
+    RUN_ID.get().map(String::as_str)
+}
+
+
+/// Identifier of a run from the time and the process id
+pub fn generate_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{:x}-{:x}", nanos as u64, std::process::id())
+}
+
+
+/// Prefix of the log lines of a traced run
+pub fn log_prefix() -> String {
+    run_id().map(|id| format!("[{}] ", id)).unwrap_or_default()
+}
+
+
+/// Trace of the outputs of a compute instance
+#[derive(Clone)]
+pub struct Trace {
+    pub id: String,
+    /// hash of the program, pipeline and configuration
+    pub pipeline: String,
+    pub device: String
+}
+
+
+impl Trace {
+
+
+    /// Trace of the outputs of `compute` in a traced run, whose device is set up
+    pub fn of(compute: &CInstance) -> Option<Self> {
+        run_id().map(|id| Self {
+            id: id.to_string(),
+            pipeline: compute.pipeline_version().to_string(),
+            device: compute.device_identity()
+        })
+    }
+
+
+    /// Adds the trace to a record
+    pub fn insert(&self, record: &mut Map) {
+        record.insert("trace_id".into(), self.id.clone().into());
+        record.insert("pipeline_version".into(), self.pipeline.clone().into());
+        record.insert("device".into(), self.device.clone().into());
+    }
+
+
+    /// The trace as the text of the metadata of the output files
+    pub fn text(&self) -> String {
+        format!("trace_id={} pipeline_version={} device={}", self.id, self.pipeline, self.device)
+    }
+}
@@ -0,0 +1,76 @@
+/*
+MIT License
+
+Copyright (c) 2022 Siandfrance
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+
+
+// Pitfalls of a run that do not stop it, such as the precision lost by a conversion. The first report
+// of each kind is printed when it happens, and the number of reports of the kinds reported several
+// times is summarized at the end of the run.
+
+
+use std::path::Path;
+use std::sync::Mutex;
+
+
+struct Pitfall {
+    kind: &'static str,
+    count: usize,
+    /// the file of the first report, if any
+    file: Option<String>,
+    message: String
+}
+
+
+static PITFALLS: Mutex<Vec<Pitfall>> = Mutex::new(Vec::new());
+
+
+/// Reports a pitfall of the kind `kind`, about `file` when there is one
+pub fn report(kind: &'static str, file: Option<&Path>, message: &str) {
+    let mut pitfalls = PITFALLS.lock().unwrap();
+    if let Some(pitfall) = pitfalls.iter_mut().find(|p| p.kind == kind) {
+        pitfall.count += 1;
+        return;
+    }
+
+    let file = file.map(|f| f.display().to_string());
+    let prefix = crate::trace::log_prefix();
+    match &file {
+        Some(file) => eprintln!("{}Warning: `{}` {}", prefix, file, message),
+        None => eprintln!("{}Warning: {}", prefix, message)
+    }
+    pitfalls.push(Pitfall { kind, count: 1, file, message: message.to_string() });
+}
+
+
+/// Prints the number of reports of the pitfalls reported several times
+pub fn print_summary() {
+    for pitfall in PITFALLS.lock().unwrap().iter().filter(|p| p.count > 1) {
+        match &pitfall.file {
+            Some(file) => eprintln!("Warning: `{}` {}, like {} other inputs", file, pitfall.message, pitfall.count - 1),
+            None => eprintln!("Warning: {} ({} times)", pitfall.message, pitfall.count)
+        }
+    }
+}